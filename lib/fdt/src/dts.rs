@@ -0,0 +1,80 @@
+use super::{Node, PropValue};
+use std::fmt::Write;
+
+/// Whether a property value looks like a list of strings.
+fn is_string_list(value: &[u8]) -> bool {
+    match value.split_last() {
+        Some((0, strings)) => {
+            !strings.is_empty()
+                && strings[0] != 0
+                && !strings.windows(2).any(|w| w == [0, 0])
+                && strings
+                    .iter()
+                    .all(|&c| c == 0 || c == b'\t' || c == b'\n' || (0x20..0x7f).contains(&c))
+        }
+        _ => false,
+    }
+}
+
+/// Format a property value, guessing its type from its content as `dtc` does.
+fn format_value(out: &mut String, value: &PropValue) {
+    let value = &value.0[..];
+    if is_string_list(value) {
+        for (i, string) in value[..value.len() - 1].split(|&c| c == 0).enumerate() {
+            out.push_str(if i == 0 { "\"" } else { ", \"" });
+            for &c in string {
+                match c {
+                    b'"' => out.push_str("\\\""),
+                    b'\\' => out.push_str("\\\\"),
+                    b'\t' => out.push_str("\\t"),
+                    b'\n' => out.push_str("\\n"),
+                    _ => out.push(c as char),
+                }
+            }
+            out.push('"');
+        }
+    } else if value.len() % 4 == 0 {
+        out.push('<');
+        for (i, cell) in value.chunks_exact(4).enumerate() {
+            let cell = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+            write!(out, "{}0x{:x}", if i == 0 { "" } else { " " }, cell).unwrap();
+        }
+        out.push('>');
+    } else {
+        out.push('[');
+        for (i, byte) in value.iter().enumerate() {
+            write!(out, "{}{:02x}", if i == 0 { "" } else { " " }, byte).unwrap();
+        }
+        out.push(']');
+    }
+}
+
+fn format_node(out: &mut String, node: &Node, depth: usize) {
+    let indent = "\t".repeat(depth);
+    let name = if depth == 0 && node.name.is_empty() { "/" } else { &node.name };
+    writeln!(out, "{}{} {{", indent, name).unwrap();
+    for prop in node.properties.iter() {
+        write!(out, "{}\t{}", indent, prop.name).unwrap();
+        if !prop.value.0.is_empty() {
+            out.push_str(" = ");
+            format_value(out, &prop.value);
+        }
+        out.push_str(";\n");
+    }
+    for child in node.child.iter() {
+        out.push('\n');
+        format_node(out, child, depth + 1);
+    }
+    writeln!(out, "{}}};", indent).unwrap();
+}
+
+/// Decompile a device tree into DTS source, in a format similar to `dtc -O dts`.
+///
+/// Property types are not recorded in the tree, so they are guessed: values consisting of
+/// printable NUL-terminated strings are shown as strings, values of whole cells as cells, and
+/// the remaining ones as bytes.
+pub fn to_dts(node: &Node) -> String {
+    let mut out = String::from("/dts-v1/;\n\n");
+    format_node(&mut out, node, 0);
+    out
+}
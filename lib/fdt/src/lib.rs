@@ -3,7 +3,9 @@ extern crate fnv;
 use fnv::FnvHashMap;
 use std::convert::TryInto;
 
+mod dts;
 mod prop_value;
+pub use dts::to_dts;
 pub use prop_value::{PropConversionError, PropValue};
 
 #[derive(Clone)]
@@ -32,6 +34,16 @@ impl Node {
         self.child.push(Self::new(name));
         self.child.last_mut().unwrap()
     }
+
+    /// Find a direct child node with the given name.
+    pub fn find_node(&self, name: &str) -> Option<&Node> {
+        self.child.iter().find(|node| node.name == name)
+    }
+
+    /// Find a property of the current node with the given name.
+    pub fn find_prop(&self, name: &str) -> Option<&PropValue> {
+        self.properties.iter().find(|prop| prop.name == name).map(|prop| &prop.value)
+    }
 }
 
 #[derive(Clone)]
@@ -134,3 +146,139 @@ pub fn encode(node: &Node) -> Vec<u8> {
     vec.append(&mut enc.dt_strings);
     vec
 }
+
+/// Error indicating that a flattened device tree blob is malformed.
+#[derive(Debug)]
+pub struct DecodeError;
+
+struct Decoder<'a> {
+    dt_struct: &'a [u8],
+    dt_strings: &'a [u8],
+    offset: usize,
+}
+
+fn read_u32(slice: &[u8], offset: usize) -> Result<u32, DecodeError> {
+    let bytes = slice.get(offset..offset + 4).ok_or(DecodeError)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_cstr(slice: &[u8], offset: usize) -> Result<&str, DecodeError> {
+    let slice = slice.get(offset..).ok_or(DecodeError)?;
+    let len = slice.iter().position(|&x| x == 0).ok_or(DecodeError)?;
+    std::str::from_utf8(&slice[..len]).map_err(|_| DecodeError)
+}
+
+impl<'a> Decoder<'a> {
+    fn pop(&mut self) -> Result<u32, DecodeError> {
+        let value = read_u32(self.dt_struct, self.offset)?;
+        self.offset += 4;
+        Ok(value)
+    }
+
+    fn align_to_word(&mut self) {
+        self.offset = (self.offset + 3) & !3;
+    }
+
+    fn decode_node(&mut self) -> Result<Node, DecodeError> {
+        if self.pop()? != FDT_BEGIN_NODE {
+            return Err(DecodeError);
+        }
+        let name = read_cstr(self.dt_struct, self.offset)?;
+        self.offset += name.len() + 1;
+        self.align_to_word();
+        let mut node = Node::new(name);
+        loop {
+            match read_u32(self.dt_struct, self.offset)? {
+                FDT_PROP => {
+                    self.offset += 4;
+                    let len = self.pop()? as usize;
+                    let nameoff = self.pop()? as usize;
+                    let name = read_cstr(self.dt_strings, nameoff)?;
+                    let value =
+                        self.dt_struct.get(self.offset..self.offset + len).ok_or(DecodeError)?;
+                    self.offset += len;
+                    self.align_to_word();
+                    node.properties.push(Prop::new(name, value));
+                }
+                FDT_BEGIN_NODE => node.child.push(self.decode_node()?),
+                FDT_END_NODE => {
+                    self.offset += 4;
+                    return Ok(node);
+                }
+                _ => return Err(DecodeError),
+            }
+        }
+    }
+}
+
+/// Decode a flattened device tree blob, e.g. one produced by [`encode`].
+pub fn decode(blob: &[u8]) -> Result<Node, DecodeError> {
+    if read_u32(blob, 0)? != FDT_MAGIC {
+        return Err(DecodeError);
+    }
+    let off_dt_struct = read_u32(blob, 8)? as usize;
+    let off_dt_strings = read_u32(blob, 12)? as usize;
+    let size_dt_strings = read_u32(blob, 32)? as usize;
+    let size_dt_struct = read_u32(blob, 36)? as usize;
+    let mut dec = Decoder {
+        dt_struct: blob.get(off_dt_struct..off_dt_struct + size_dt_struct).ok_or(DecodeError)?,
+        dt_strings: blob
+            .get(off_dt_strings..off_dt_strings + size_dt_strings)
+            .ok_or(DecodeError)?,
+        offset: 0,
+    };
+    dec.decode_node()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_bootargs_round_trip() {
+        let cmdline = r#"console=hvc0 root=/dev/vda rw init="/bin/sh -c 'echo $HOME\t;'" a=b=c"#;
+        let mut root = Node::new("");
+        root.add_prop("#address-cells", 2u32);
+        let chosen = root.add_node("chosen");
+        chosen.add_prop("bootargs", cmdline);
+
+        let decoded = decode(&encode(&root)).unwrap();
+        let bootargs = decoded.find_node("chosen").unwrap().find_prop("bootargs").unwrap();
+        assert_eq!(<&str>::try_from(bootargs).unwrap(), cmdline);
+        assert_eq!(u32::try_from(decoded.find_prop("#address-cells").unwrap()).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_to_dts() {
+        let mut root = Node::new("");
+        root.add_prop("#address-cells", 2u32);
+        root.add_prop("compatible", "riscv-virtio");
+        let memory = root.add_node("memory@40000000");
+        memory.add_prop("device_type", "memory");
+        memory.add_prop("reg", &[0x40000000u64, 0x8000000][..]);
+        let cpus = root.add_node("cpus");
+        let cpu = cpus.add_node("cpu@0");
+        cpu.add_prop("compatible", &["sifive,rocket0", "riscv"][..]);
+        cpu.add_prop("riscv,isa", "rv64imafdc");
+        let virtio = root.add_node("virtio@100000");
+        virtio.add_prop("compatible", "virtio,mmio");
+        virtio.add_prop("interrupt-controller", ());
+        virtio.add_prop("local-mac-address", &[0x02u8, 0, 0, 0, 0, 1][..]);
+        root.add_node("chosen").add_prop("bootargs", "init=\"/bin/sh\"");
+
+        let dts = to_dts(&root);
+        assert!(dts.starts_with("/dts-v1/;\n\n/ {\n\t#address-cells = <0x2>;\n"));
+        assert!(dts.contains(
+            "\tmemory@40000000 {\n\t\tdevice_type = \"memory\";\n\t\treg = <0x0 0x40000000 0x0 0x8000000>;\n\t};\n"
+        ));
+        assert!(dts.contains(
+            "\tcpus {\n\n\t\tcpu@0 {\n\t\t\tcompatible = \"sifive,rocket0\", \"riscv\";\n"
+        ));
+        assert!(dts.contains("\tvirtio@100000 {\n\t\tcompatible = \"virtio,mmio\";\n"));
+        assert!(dts.contains("\t\tinterrupt-controller;\n"));
+        assert!(dts.contains("\t\tlocal-mac-address = [02 00 00 00 00 01];\n"));
+        assert!(dts.contains("\t\tbootargs = \"init=\\\"/bin/sh\\\"\";\n"));
+        assert!(dts.ends_with("\t};\n};\n"));
+    }
+}
@@ -7,6 +7,10 @@
 mod file;
 #[cfg(feature = "block-file")]
 pub use file::File;
+#[cfg(feature = "block-qcow2")]
+mod qcow2;
+#[cfg(feature = "block-qcow2")]
+pub use qcow2::Qcow2;
 #[cfg(feature = "block-shadow")]
 mod shadow;
 #[cfg(feature = "block-shadow")]
@@ -71,3 +75,33 @@ pub trait Block {
         Default::default()
     }
 }
+
+impl<T: Block + ?Sized> Block for Box<T> {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        (**self).write_all_at(buf, offset)
+    }
+
+    fn write_zero_at(&mut self, offset: u64, len: usize) -> Result<()> {
+        (**self).write_zero_at(offset, len)
+    }
+
+    fn discard(&mut self, offset: u64, len: usize) -> Result<()> {
+        (**self).discard(offset, len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn capability(&self) -> Capability {
+        (**self).capability()
+    }
+}
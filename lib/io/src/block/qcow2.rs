@@ -0,0 +1,432 @@
+use super::Block;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+
+const MAGIC: u32 = 0x514649fb;
+
+/// Bits of L1, L2 and refcount table entries holding a host offset.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster referenced has a refcount of exactly one, so it can be written in place.
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zero, regardless of the host offset.
+const ZERO: u64 = 1;
+
+/// Granularity of accesses to the underlying block device.
+const SECTOR_SIZE: u64 = 512;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("qcow2: {}", msg))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Block device backed by a qcow2 image.
+///
+/// Version 2 and 3 images are supported, but not backing files, compression, encryption or
+/// internal snapshots. Clusters are allocated at the end of the image when first written.
+pub struct Qcow2<T> {
+    block: T,
+    cluster_bits: u32,
+    /// Virtual disk size.
+    len: u64,
+    l1_offset: u64,
+    l1: Vec<u64>,
+    refcount_order: u32,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// Host offset of the next cluster to allocate.
+    next_cluster: u64,
+}
+
+impl<T: Block> Qcow2<T> {
+    /// Open a qcow2 image stored in `block`.
+    ///
+    /// [`Err`] is returned if the image is malformed or uses features not supported.
+    pub fn new(mut block: T) -> Result<Self> {
+        let mut header = [0; SECTOR_SIZE as usize];
+        block.read_exact_at(&mut header, 0)?;
+        let u32_at =
+            |offset: usize| u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_be_bytes(header[offset..offset + 8].try_into().unwrap());
+
+        if u32_at(0) != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let version = u32_at(4);
+        if version != 2 && version != 3 {
+            return Err(invalid(&format!("version {} is not supported", version)));
+        }
+        if u64_at(8) != 0 {
+            return Err(invalid("backing files are not supported"));
+        }
+        let cluster_bits = u32_at(20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(&format!("invalid cluster size 2^{}", cluster_bits)));
+        }
+        if u32_at(32) != 0 {
+            return Err(invalid("encryption is not supported"));
+        }
+        if u32_at(60) != 0 {
+            return Err(invalid("internal snapshots are not supported"));
+        }
+        // Version 2 images have no feature bits and always use 16-bit refcounts.
+        let (incompatible_features, refcount_order) =
+            if version == 3 { (u64_at(72), u32_at(96)) } else { (0, 4) };
+        if incompatible_features != 0 {
+            return Err(invalid(&format!(
+                "incompatible features {:#x} are not supported",
+                incompatible_features
+            )));
+        }
+        if !(3..=6).contains(&refcount_order) {
+            return Err(invalid(&format!(
+                "{}-bit refcounts are not supported",
+                1 << refcount_order
+            )));
+        }
+
+        let l1_offset = u64_at(40);
+        let l1 = read_table(&mut block, l1_offset, u32_at(36) as usize)?;
+        let refcount_table_offset = u64_at(48);
+        let refcount_table = read_table(
+            &mut block,
+            refcount_table_offset,
+            (u32_at(56) as usize) << (cluster_bits - 3),
+        )?;
+        let next_cluster = align_up(block.len(), 1 << cluster_bits);
+
+        Ok(Qcow2 {
+            block,
+            cluster_bits,
+            len: u64_at(24),
+            l1_offset,
+            l1,
+            refcount_order,
+            refcount_table_offset,
+            refcount_table,
+            next_cluster,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Split a guest offset into its L1 and L2 indices.
+    fn indices(&self, offset: u64) -> Result<(usize, usize)> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (offset >> (self.cluster_bits + l2_bits)) as usize;
+        if l1_index >= self.l1.len() {
+            return Err(invalid("offset beyond the L1 table"));
+        }
+        let l2_index = ((offset >> self.cluster_bits) & ((1 << l2_bits) - 1)) as usize;
+        Ok((l1_index, l2_index))
+    }
+
+    /// Read the big-endian 64-bit integer at host offset `offset`.
+    fn read_u64(&mut self, offset: u64) -> Result<u64> {
+        let mut sector = [0; SECTOR_SIZE as usize];
+        self.block.read_exact_at(&mut sector, offset & !(SECTOR_SIZE - 1))?;
+        let start = (offset & (SECTOR_SIZE - 1)) as usize;
+        Ok(u64::from_be_bytes(sector[start..start + 8].try_into().unwrap()))
+    }
+
+    /// Write the lowest `width` bytes of `value` as a big-endian integer at host offset `offset`.
+    fn write_int(&mut self, offset: u64, width: usize, value: u64) -> Result<()> {
+        let sector_offset = offset & !(SECTOR_SIZE - 1);
+        let mut sector = [0; SECTOR_SIZE as usize];
+        self.block.read_exact_at(&mut sector, sector_offset)?;
+        let start = (offset & (SECTOR_SIZE - 1)) as usize;
+        sector[start..start + width].copy_from_slice(&value.to_be_bytes()[8 - width..]);
+        self.block.write_all_at(&sector, sector_offset)
+    }
+
+    /// Get the L2 entry of the cluster containing guest offset `offset`.
+    fn l2_entry(&mut self, offset: u64) -> Result<u64> {
+        let (l1_index, l2_index) = self.indices(offset)?;
+        let l2_offset = self.l1[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        self.read_u64(l2_offset + l2_index as u64 * 8)
+    }
+
+    /// Set the refcount of the host cluster at `offset`, allocating a refcount block if needed.
+    fn set_refcount(&mut self, offset: u64, refcount: u64) -> Result<()> {
+        let cluster = offset >> self.cluster_bits;
+        let block_bits = self.cluster_bits + 3 - self.refcount_order;
+        let table_index = (cluster >> block_bits) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(invalid("refcount table is full"));
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & !(SECTOR_SIZE - 1);
+        if block_offset == 0 {
+            block_offset = self.next_cluster;
+            self.next_cluster += self.cluster_size();
+            self.block.write_zero_at(block_offset, self.cluster_size() as usize)?;
+            self.refcount_table[table_index] = block_offset;
+            self.write_int(self.refcount_table_offset + table_index as u64 * 8, 8, block_offset)?;
+            // The new refcount block may describe itself, so this is done once it is reachable.
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let index = cluster & ((1 << block_bits) - 1);
+        let width = 1 << (self.refcount_order - 3);
+        self.write_int(block_offset + index * width as u64, width, refcount)
+    }
+
+    /// Allocate a host cluster, with `data` as its content.
+    fn allocate(&mut self, data: &[u8]) -> Result<u64> {
+        let offset = self.next_cluster;
+        self.next_cluster += self.cluster_size();
+        self.block.write_all_at(data, offset)?;
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Get the host offset of the L2 table of L1 entry `l1_index`, allocating it if needed.
+    fn l2_table_for_write(&mut self, l1_index: usize) -> Result<u64> {
+        let entry = self.l1[l1_index];
+        if entry & OFFSET_MASK != 0 {
+            if entry & COPIED == 0 {
+                return Err(invalid("shared L2 tables are not supported"));
+            }
+            return Ok(entry & OFFSET_MASK);
+        }
+        let l2_offset = self.allocate(&vec![0; self.cluster_size() as usize])?;
+        self.l1[l1_index] = l2_offset | COPIED;
+        self.write_int(self.l1_offset + l1_index as u64 * 8, 8, l2_offset | COPIED)?;
+        Ok(l2_offset)
+    }
+
+    /// Write `data` to the guest cluster containing `offset`. `data` must not cross a cluster.
+    fn write_cluster(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        let (l1_index, l2_index) = self.indices(offset)?;
+        let entry_offset = self.l2_table_for_write(l1_index)? + l2_index as u64 * 8;
+        let entry = self.read_u64(entry_offset)?;
+        let host = entry & OFFSET_MASK;
+        let in_cluster = offset & (self.cluster_size() - 1);
+        if entry & COMPRESSED != 0 {
+            return Err(invalid("compressed clusters are not supported"));
+        }
+        if host != 0 && entry & COPIED == 0 {
+            return Err(invalid("shared clusters are not supported"));
+        }
+        if host != 0 && entry & ZERO == 0 {
+            return self.block.write_all_at(data, host + in_cluster);
+        }
+
+        // The cluster reads as zero, so the rest of it must be filled with zeroes.
+        let mut cluster = vec![0; self.cluster_size() as usize];
+        cluster[in_cluster as usize..][..data.len()].copy_from_slice(data);
+        let host = if host != 0 {
+            // Reuse a cluster preallocated for a zero cluster.
+            self.block.write_all_at(&cluster, host)?;
+            host
+        } else {
+            self.allocate(&cluster)?
+        };
+        self.write_int(entry_offset, 8, host | COPIED)
+    }
+}
+
+/// Read a table of `len` big-endian 64-bit entries.
+fn read_table(block: &mut dyn Block, offset: u64, len: usize) -> Result<Vec<u64>> {
+    let mut buf = vec![0; align_up(len as u64 * 8, SECTOR_SIZE) as usize];
+    block.read_exact_at(&mut buf, offset)?;
+    Ok(buf.chunks_exact(8).take(len).map(|x| u64::from_be_bytes(x.try_into().unwrap())).collect())
+}
+
+impl<T: Block> Block for Qcow2<T> {
+    fn read_exact_at(&mut self, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+        while !buf.is_empty() {
+            let in_cluster = offset & (self.cluster_size() - 1);
+            let len = std::cmp::min(buf.len() as u64, self.cluster_size() - in_cluster) as usize;
+            let (chunk, rest) = buf.split_at_mut(len);
+
+            let entry = self.l2_entry(offset)?;
+            if entry & COMPRESSED != 0 {
+                return Err(invalid("compressed clusters are not supported"));
+            }
+            let host = entry & OFFSET_MASK;
+            if host == 0 || entry & ZERO != 0 {
+                chunk.iter_mut().for_each(|x| *x = 0);
+            } else {
+                self.block.read_exact_at(chunk, host + in_cluster)?;
+            }
+
+            buf = rest;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, mut buf: &[u8], mut offset: u64) -> Result<()> {
+        while !buf.is_empty() {
+            let in_cluster = offset & (self.cluster_size() - 1);
+            let len = std::cmp::min(buf.len() as u64, self.cluster_size() - in_cluster) as usize;
+            self.write_cluster(&buf[..len], offset)?;
+            buf = &buf[len..];
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.block.flush()
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A block device backed by memory, growing when written beyond its end.
+    struct Memory(Vec<u8>);
+
+    impl Block for Memory {
+        fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            let offset = offset as usize;
+            if self.0.len() < offset + buf.len() {
+                self.0.resize(offset + buf.len(), 0);
+            }
+            self.0[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn len(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    const CLUSTER: usize = 4096;
+
+    fn u64_at(image: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(image[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Build a 4 MiB version 3 image with 4 KiB clusters, where only the cluster at 4 KiB is
+    /// allocated and filled with 0xab. The image is built by hand following the qcow2
+    /// specification. It has not been checked against images made by qemu-img.
+    ///
+    /// Clusters are: header, refcount table, refcount block, L1 table, L2 table, data.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 6 * CLUSTER];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            image[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, &MAGIC.to_be_bytes());
+        put(4, &3u32.to_be_bytes());
+        put(20, &12u32.to_be_bytes());
+        put(24, &(4u64 << 20).to_be_bytes());
+        // Each L2 table covers 2 MiB.
+        put(36, &2u32.to_be_bytes());
+        put(40, &(3 * CLUSTER as u64).to_be_bytes());
+        put(48, &(CLUSTER as u64).to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &4u32.to_be_bytes());
+        put(100, &104u32.to_be_bytes());
+
+        put(CLUSTER, &(2 * CLUSTER as u64).to_be_bytes());
+        for cluster in 0..6 {
+            put(2 * CLUSTER + cluster * 2, &1u16.to_be_bytes());
+        }
+        put(3 * CLUSTER, &((4 * CLUSTER as u64) | COPIED).to_be_bytes());
+        put(4 * CLUSTER + 8, &((5 * CLUSTER as u64) | COPIED).to_be_bytes());
+        put(5 * CLUSTER, &[0xab; CLUSTER]);
+        image
+    }
+
+    #[test]
+    fn test_sparse_read() {
+        let mut qcow2 = Qcow2::new(Memory(image())).unwrap();
+        assert_eq!(qcow2.len(), 4 << 20);
+
+        let mut buf = vec![0xff; 2 * CLUSTER];
+        qcow2.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..CLUSTER].iter().all(|&x| x == 0));
+        assert!(buf[CLUSTER..].iter().all(|&x| x == 0xab));
+
+        // Clusters without an L2 table read as zero as well.
+        let mut buf = vec![0xff; 512];
+        qcow2.read_exact_at(&mut buf, (4 << 20) - 512).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+        assert_eq!(qcow2.block.0.len(), 6 * CLUSTER);
+    }
+
+    #[test]
+    fn test_allocate_on_write() {
+        let mut qcow2 = Qcow2::new(Memory(image())).unwrap();
+
+        // Writes to allocated clusters are done in place.
+        qcow2.write_all_at(&[0xcd; 512], CLUSTER as u64).unwrap();
+        assert_eq!(qcow2.block.0.len(), 6 * CLUSTER);
+
+        // A write to an unallocated cluster allocates a zero-filled data cluster.
+        qcow2.write_all_at(&[0xef; 512], 2 * CLUSTER as u64 + 512).unwrap();
+        // Crossing into the second L2 table allocates the table too.
+        qcow2.write_all_at(&[0x12; 1024], (2 << 20) - 512).unwrap();
+
+        let image = std::mem::take(&mut qcow2.block.0);
+        assert_eq!(image.len(), 10 * CLUSTER);
+        assert_eq!(u64_at(&image, 4 * CLUSTER + 16), (6 * CLUSTER as u64) | COPIED);
+        assert_eq!(u64_at(&image, 4 * CLUSTER + 511 * 8), (7 * CLUSTER as u64) | COPIED);
+        assert_eq!(u64_at(&image, 3 * CLUSTER + 8), (8 * CLUSTER as u64) | COPIED);
+        assert_eq!(u64_at(&image, 8 * CLUSTER), (9 * CLUSTER as u64) | COPIED);
+        // All clusters are accounted for.
+        let refcounts: Vec<u8> = image[2 * CLUSTER..2 * CLUSTER + 22].to_vec();
+        assert_eq!(refcounts, [0, 1].repeat(10).into_iter().chain(vec![0, 0]).collect::<Vec<_>>());
+
+        // The data persists after reopening.
+        let mut qcow2 = Qcow2::new(Memory(image)).unwrap();
+        let mut buf = vec![0; 3 * CLUSTER];
+        qcow2.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..CLUSTER].iter().all(|&x| x == 0));
+        assert!(buf[CLUSTER..CLUSTER + 512].iter().all(|&x| x == 0xcd));
+        assert!(buf[CLUSTER + 512..2 * CLUSTER].iter().all(|&x| x == 0xab));
+        assert!(buf[2 * CLUSTER..2 * CLUSTER + 512].iter().all(|&x| x == 0));
+        assert!(buf[2 * CLUSTER + 512..2 * CLUSTER + 1024].iter().all(|&x| x == 0xef));
+        assert!(buf[2 * CLUSTER + 1024..].iter().all(|&x| x == 0));
+        let mut buf = vec![0; 1024];
+        qcow2.read_exact_at(&mut buf, (2 << 20) - 512).unwrap();
+        assert!(buf.iter().all(|&x| x == 0x12));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut encrypted = image();
+        encrypted[35] = 1;
+        assert!(Qcow2::new(Memory(encrypted)).is_err());
+
+        let mut compression_type = image();
+        compression_type[79] = 1 << 3;
+        assert!(Qcow2::new(Memory(compression_type)).is_err());
+
+        // Compressed clusters are only found upon access.
+        let mut compressed = image();
+        compressed[4 * CLUSTER + 8] |= 0x40;
+        let mut qcow2 = Qcow2::new(Memory(compressed)).unwrap();
+        let mut buf = vec![0; 512];
+        qcow2.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(
+            qcow2.read_exact_at(&mut buf, CLUSTER as u64).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert!(qcow2.write_all_at(&buf, CLUSTER as u64).is_err());
+    }
+}
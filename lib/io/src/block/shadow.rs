@@ -4,27 +4,80 @@ use std::io::Result;
 
 /// A shadow block device that captures all write requests to the underlying block device.
 ///
-/// All modified data will be kept in memory and not forwarded to the underlying block device.
-pub struct Shadow<T> {
+/// All modified data will be kept in memory and not forwarded to the underlying block device,
+/// until [`commit`](Shadow::commit) is called.
+pub struct Shadow<T: Block> {
     overlay: FnvHashMap<u64, Box<[u8]>>,
     block: T,
+    commit_on_drop: bool,
 }
 
-impl<T> Shadow<T> {
+impl<T: Block> Shadow<T> {
     /// Construct a new `Shadow`.
     pub fn new(block: T) -> Self {
-        Shadow { block, overlay: FnvHashMap::default() }
+        Shadow { block, overlay: FnvHashMap::default(), commit_on_drop: false }
+    }
+
+    /// Set whether changes are committed to the underlying block device when dropped.
+    pub fn with_commit_on_drop(mut self, commit_on_drop: bool) -> Self {
+        self.commit_on_drop = commit_on_drop;
+        self
+    }
+
+    /// Write all modified data back to the underlying block device.
+    ///
+    /// Data is written in blocks of `blksize` of the underlying block device, so sectors not
+    /// modified are read back from it to fill up partially modified blocks. If an error occurs, the
+    /// changes are kept and the commit can be retried.
+    pub fn commit(&mut self) -> Result<()> {
+        let blksize = self.block.capability().blksize as u64;
+        let mut blocks: Vec<u64> =
+            self.overlay.keys().map(|&offset| offset / blksize * blksize).collect();
+        blocks.sort_unstable();
+        blocks.dedup();
+
+        let mut buf = vec![0; blksize as usize];
+        for block in blocks {
+            let sectors = (block..block + blksize).step_by(512);
+            if sectors.clone().any(|offset| !self.overlay.contains_key(&offset)) {
+                self.block.read_exact_at(&mut buf, block)?;
+            }
+            for (chunk, offset) in buf.chunks_mut(512).zip(sectors) {
+                if let Some(v) = self.overlay.get(&offset) {
+                    chunk.copy_from_slice(v);
+                }
+            }
+            self.block.write_all_at(&buf, block)?;
+        }
+        self.block.flush()?;
+        self.overlay.clear();
+        Ok(())
+    }
+
+    /// Drop all modified data, so the content of the underlying block device is seen again.
+    pub fn discard_changes(&mut self) {
+        self.overlay.clear();
+    }
+}
+
+impl<T: Block> Drop for Shadow<T> {
+    fn drop(&mut self) {
+        if self.commit_on_drop {
+            if let Err(err) = self.commit() {
+                error!(target: "Shadow", "cannot commit changes: {}", err);
+            }
+        }
     }
 }
 
 impl<T: Block> Block for Shadow<T> {
-    fn read_exact_at(&mut self, buf: &mut [u8], mut offset: u64) -> Result<()> {
-        for chunk in buf.chunks_mut(512) {
-            match self.overlay.get(&offset) {
-                None => self.block.read_exact_at(chunk, offset)?,
-                Some(v) => chunk.copy_from_slice(v),
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        // Read as a whole, as the underlying block device may need more than sector alignment.
+        self.block.read_exact_at(buf, offset)?;
+        for (chunk, offset) in buf.chunks_mut(512).zip((offset..).step_by(512)) {
+            if let Some(v) = self.overlay.get(&offset) {
+                chunk.copy_from_slice(v);
             }
-            offset += 512
         }
         Ok(())
     }
@@ -52,3 +105,88 @@ impl<T: Block> Block for Shadow<T> {
         cap
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::File;
+
+    /// A block device backed by memory, with a block size larger than a sector.
+    struct Memory(Vec<u8>);
+
+    impl Block for Memory {
+        fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            assert!(offset % 4096 == 0 && buf.len() % 4096 == 0);
+            buf.copy_from_slice(&self.0[offset as usize..offset as usize + buf.len()]);
+            Ok(())
+        }
+
+        fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            assert!(offset % 4096 == 0 && buf.len() % 4096 == 0);
+            self.0[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn len(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn capability(&self) -> Capability {
+            let mut cap = Capability::default();
+            cap.blksize = 4096;
+            cap
+        }
+    }
+
+    #[test]
+    fn test_commit() {
+        let path = std::env::temp_dir().join(format!("r2vm-shadow-{}", std::process::id()));
+        std::fs::write(&path, vec![0x11; 8192]).unwrap();
+        let open = || {
+            let file = std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+            File::new(file).unwrap()
+        };
+
+        let mut shadow = Shadow::new(open());
+        shadow.write_all_at(&[0x22; 1024], 512).unwrap();
+        let mut buf = vec![0; 8192];
+        open().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&x| x == 0x11));
+
+        shadow.commit().unwrap();
+        open().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..512].iter().all(|&x| x == 0x11));
+        assert!(buf[512..1536].iter().all(|&x| x == 0x22));
+        assert!(buf[1536..].iter().all(|&x| x == 0x11));
+
+        // Discarded changes never land, while the others do once dropped.
+        shadow.write_all_at(&[0x33; 512], 0).unwrap();
+        shadow.discard_changes();
+        drop(shadow);
+        let mut shadow = Shadow::new(open()).with_commit_on_drop(true);
+        shadow.write_all_at(&[0x44; 512], 7680).unwrap();
+        drop(shadow);
+        open().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..512].iter().all(|&x| x == 0x11));
+        assert!(buf[7680..].iter().all(|&x| x == 0x44));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commit_partial_block() {
+        let mut shadow = Shadow::new(Memory(vec![0x11; 3 * 4096]));
+        shadow.write_all_at(&[0x22; 512], 4096 + 1024).unwrap();
+        shadow.write_all_at(&[0x33; 4096], 2 * 4096).unwrap();
+        shadow.commit().unwrap();
+
+        let mut buf = vec![0; 4096];
+        shadow.read_exact_at(&mut buf, 4096).unwrap();
+        assert!(buf[1024..1536].iter().all(|&x| x == 0x22));
+        let memory = &shadow.block.0;
+        assert!(memory[..4096 + 1024].iter().all(|&x| x == 0x11));
+        assert!(memory[4096 + 1024..4096 + 1536].iter().all(|&x| x == 0x22));
+        assert!(memory[4096 + 1536..2 * 4096].iter().all(|&x| x == 0x11));
+        assert!(memory[2 * 4096..].iter().all(|&x| x == 0x33));
+    }
+}
@@ -0,0 +1,173 @@
+//! IOMMU support for device DMA.
+//!
+//! Devices perform DMA through a [`DmaContext`] using bus addresses. Without an IOMMU, bus
+//! addresses are guest physical addresses. [`IommuDma`] wraps a [`DmaContext`] and translates
+//! bus addresses through an [`Iommu`] before forwarding the accesses.
+
+use crate::DmaContext;
+use std::sync::Arc;
+
+/// Granularity of IOMMU translation.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// An I/O memory management unit.
+pub trait Iommu: Send + Sync {
+    /// Translate a bus address into a physical address. The translation applies to the whole
+    /// page containing `addr`. `None` is returned if the device is not allowed to access it.
+    fn translate(&self, addr: u64, write: bool) -> Option<u64>;
+}
+
+/// An IOMMU that maps bus addresses to identical physical addresses.
+pub struct Identity;
+
+impl Iommu for Identity {
+    fn translate(&self, addr: u64, _write: bool) -> Option<u64> {
+        Some(addr)
+    }
+}
+
+/// A [`DmaContext`] that translates addresses through an [`Iommu`].
+///
+/// Accesses are split at page boundaries. Reads from untranslatable addresses produce zeroes and
+/// writes to them are discarded.
+pub struct IommuDma {
+    dma: Arc<dyn DmaContext>,
+    iommu: Arc<dyn Iommu>,
+}
+
+impl IommuDma {
+    /// Create a new `IommuDma` forwarding translated accesses to `dma`.
+    pub fn new(dma: Arc<dyn DmaContext>, iommu: Arc<dyn Iommu>) -> Self {
+        IommuDma { dma, iommu }
+    }
+
+    /// Split the access at `addr` of length `len` into physical chunks and call `f` with the
+    /// offset, the translated address and length of each.
+    fn for_each_chunk(
+        &self,
+        mut addr: u64,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(usize, Option<u64>, usize),
+    ) {
+        let mut offset = 0;
+        while offset < len {
+            let chunk = ((PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize).min(len - offset);
+            let paddr = self.iommu.translate(addr, write);
+            if paddr.is_none() {
+                error!(target: "Iommu", "untranslatable DMA address 0x{:x}", addr);
+            }
+            f(offset, paddr, chunk);
+            offset += chunk;
+            addr += chunk as u64;
+        }
+    }
+}
+
+impl DmaContext for IommuDma {
+    fn dma_read(&self, addr: u64, buf: &mut [u8]) {
+        self.for_each_chunk(addr, buf.len(), false, |offset, paddr, len| {
+            let buf = &mut buf[offset..offset + len];
+            match paddr {
+                Some(paddr) => self.dma.dma_read(paddr, buf),
+                None => buf.iter_mut().for_each(|x| *x = 0),
+            }
+        });
+    }
+
+    fn dma_write(&self, addr: u64, buf: &[u8]) {
+        self.for_each_chunk(addr, buf.len(), true, |offset, paddr, len| {
+            if let Some(paddr) = paddr {
+                self.dma.dma_write(paddr, &buf[offset..offset + len]);
+            }
+        });
+    }
+
+    fn read_u16(&self, addr: u64) -> u16 {
+        match self.iommu.translate(addr, false) {
+            Some(paddr) => self.dma.read_u16(paddr),
+            None => {
+                error!(target: "Iommu", "untranslatable DMA address 0x{:x}", addr);
+                0
+            }
+        }
+    }
+
+    fn write_u16(&self, addr: u64, value: u16) {
+        match self.iommu.translate(addr, true) {
+            Some(paddr) => self.dma.write_u16(paddr, value),
+            None => error!(target: "Iommu", "untranslatable DMA address 0x{:x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    /// DMA context backed by a small physical memory.
+    struct Memory(Mutex<Vec<u8>>);
+
+    impl DmaContext for Memory {
+        fn dma_read(&self, addr: u64, buf: &mut [u8]) {
+            let addr = addr as usize;
+            buf.copy_from_slice(&self.0.lock()[addr..addr + buf.len()]);
+        }
+
+        fn dma_write(&self, addr: u64, buf: &[u8]) {
+            let addr = addr as usize;
+            self.0.lock()[addr..addr + buf.len()].copy_from_slice(buf);
+        }
+
+        fn read_u16(&self, addr: u64) -> u16 {
+            let mut buf = [0; 2];
+            self.dma_read(addr, &mut buf);
+            u16::from_le_bytes(buf)
+        }
+
+        fn write_u16(&self, addr: u64, value: u16) {
+            self.dma_write(addr, &value.to_le_bytes());
+        }
+    }
+
+    /// Maps bus page 0 to physical page 3 and bus page 1 to physical page 1.
+    struct Remap;
+
+    impl Iommu for Remap {
+        fn translate(&self, addr: u64, _write: bool) -> Option<u64> {
+            match addr / PAGE_SIZE {
+                0 => Some(addr + 3 * PAGE_SIZE),
+                1 => Some(addr),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_remapped_dma() {
+        let memory = Arc::new(Memory(Mutex::new(vec![0; 4 * PAGE_SIZE as usize])));
+        let dma = IommuDma::new(memory.clone(), Arc::new(Remap));
+
+        // A write crossing bus pages 0 and 1 is split into the two physical pages.
+        dma.dma_write(0xffc, b"remapped");
+        {
+            let guard = memory.0.lock();
+            assert_eq!(&guard[0x3ffc..0x4000], b"rema");
+            assert_eq!(&guard[0x1000..0x1004], b"pped");
+            assert_eq!(&guard[0xffc..0x1000], &[0; 4]);
+        }
+
+        let mut buf = [0; 8];
+        dma.dma_read(0xffc, &mut buf);
+        assert_eq!(&buf, b"remapped");
+
+        dma.write_u16(0x10, 0x1234);
+        assert_eq!(memory.read_u16(0x3010), 0x1234);
+        assert_eq!(dma.read_u16(0x10), 0x1234);
+
+        // Unmapped pages read as zero and ignore writes.
+        dma.dma_write(0x2000, b"lost");
+        assert_eq!(&memory.0.lock()[0x2000..0x2004], &[0; 4]);
+    }
+}
@@ -3,6 +3,8 @@
 //! This module provides a [`Entropy`] trait which bridges entropy source implementation and
 //! I/O devices implementations.
 
+use std::io::{Read, Write};
+
 pub use rand;
 
 #[doc(no_inline)]
@@ -11,3 +13,102 @@ pub use rand::rngs::OsRng as Os;
 pub use rand::rngs::StdRng as Seeded;
 #[doc(no_inline)]
 pub use rand::RngCore as Entropy;
+
+/// An entropy source that records every byte produced by another source.
+///
+/// All values are produced as bytes, so the recording can be played back by [`Replay`]
+/// regardless of how the bytes are requested.
+pub struct Record<R, W> {
+    source: R,
+    sink: W,
+}
+
+impl<R: Entropy, W: Write> Record<R, W> {
+    /// Create a new `Record` writing bytes produced by `source` to `sink`.
+    pub fn new(source: R, sink: W) -> Self {
+        Record { source, sink }
+    }
+}
+
+impl<R: Entropy, W: Write> Entropy for Record<R, W> {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest).unwrap()
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.source.try_fill_bytes(dest)?;
+        self.sink.write_all(dest).map_err(rand::Error::new)?;
+        // Flush so the recording survives a crashing run.
+        self.sink.flush().map_err(rand::Error::new)
+    }
+}
+
+/// An entropy source that replays bytes recorded by [`Record`]. Running out of recorded bytes is
+/// an error.
+pub struct Replay<R> {
+    recording: R,
+}
+
+impl<R: Read> Replay<R> {
+    /// Create a new `Replay` reading bytes from `recording`.
+    pub fn new(recording: R) -> Self {
+        Replay { recording }
+    }
+}
+
+impl<R: Read> Entropy for Replay<R> {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest).unwrap()
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.recording.read_exact(dest).map_err(rand::Error::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_record_replay() {
+        let mut recording = Vec::new();
+        let mut record = Record::new(Seeded::seed_from_u64(42), &mut recording);
+        let mut bytes = [0; 13];
+        record.fill_bytes(&mut bytes);
+        let (a, b) = (record.next_u32(), record.next_u64());
+        assert_eq!(recording.len(), 13 + 4 + 8);
+
+        let mut replay = Replay::new(&recording[..]);
+        let mut replayed = [0; 13];
+        replay.fill_bytes(&mut replayed);
+        assert_eq!(replayed, bytes);
+        assert_eq!((replay.next_u32(), replay.next_u64()), (a, b));
+        assert!(replay.try_fill_bytes(&mut [0]).is_err());
+    }
+}
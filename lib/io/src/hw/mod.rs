@@ -31,6 +31,9 @@
 
 pub mod intc;
 pub mod network;
+pub mod pci;
+pub mod power;
 pub mod rtc;
+pub mod uart;
 #[cfg(feature = "virtio")]
 pub mod virtio;
@@ -0,0 +1,186 @@
+//! PCI host bridges.
+
+use crate::{IoMemory, IoMemoryMut};
+use parking_lot::Mutex;
+use std::ops::Range;
+use std::sync::Arc;
+
+const PCI_COMMAND: usize = 0x04;
+/// Bit of the command register enabling decoding of memory BARs.
+const PCI_COMMAND_MEMORY: u64 = 0x2;
+
+/// Size of the ECAM region of a single bus, with 32 devices of 8 functions each.
+pub const ECAM_SIZE: usize = 32 << 15;
+
+/// A PCI function with one memory BAR. Accesses to the BAR are made through [`IoMemoryMut`],
+/// relative to the start of the BAR.
+pub trait Function: IoMemoryMut + Send {
+    /// Read from the configuration space.
+    fn config_read(&self, offset: usize, size: u32) -> u64;
+
+    /// Write to the configuration space.
+    fn config_write(&mut self, offset: usize, value: u64, size: u32);
+
+    /// Bus addresses of the memory BAR, as programmed by the driver.
+    fn bar(&self) -> Range<u64>;
+}
+
+type Functions = Arc<Mutex<Vec<Box<dyn Function>>>>;
+
+/// Generic PCI host bridge with ECAM, as in the `pci-host-ecam-generic` device tree binding.
+///
+/// There is a single bus, and each function is a device of its own. The configuration spaces are
+/// accessed through [`IoMemory`] of the host bridge, which is [`ECAM_SIZE`] bytes large. BARs are
+/// accessed through the memory window returned by [`window`](PciHost::window). Bus addresses are
+/// the same as physical addresses.
+pub struct PciHost {
+    functions: Functions,
+}
+
+impl PciHost {
+    pub fn new() -> PciHost {
+        PciHost { functions: Default::default() }
+    }
+
+    /// Attach a function to the bus, and return its device number.
+    pub fn add(&self, function: Box<dyn Function>) -> u32 {
+        let mut functions = self.functions.lock();
+        assert!(functions.len() < 32, "too many PCI devices");
+        functions.push(function);
+        functions.len() as u32 - 1
+    }
+
+    /// Get the memory window for BARs starting at physical address `base`.
+    pub fn window(&self, base: u64) -> PciWindow {
+        PciWindow { functions: self.functions.clone(), base }
+    }
+}
+
+impl Default for PciHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IoMemory for PciHost {
+    fn read(&self, addr: usize, size: u32) -> u64 {
+        let (device, function, offset) = (addr >> 15, addr >> 12 & 7, addr & 0xfff);
+        match self.functions.lock().get(device) {
+            Some(dev) if function == 0 => dev.config_read(offset, size),
+            // Functions not present read as all ones.
+            _ => u64::MAX >> (64 - size * 8),
+        }
+    }
+
+    fn write(&self, addr: usize, value: u64, size: u32) {
+        let (device, function, offset) = (addr >> 15, addr >> 12 & 7, addr & 0xfff);
+        match self.functions.lock().get_mut(device) {
+            Some(dev) if function == 0 => dev.config_write(offset, value, size),
+            _ => (),
+        }
+    }
+}
+
+/// Memory window of a [`PciHost`], through which BARs are accessed.
+pub struct PciWindow {
+    functions: Functions,
+    base: u64,
+}
+
+impl PciWindow {
+    /// Find the function whose BAR contains `addr`, and call `f` with the offset into the BAR.
+    fn access<T>(&self, addr: usize, f: impl FnOnce(&mut dyn Function, usize) -> T) -> Option<T> {
+        let addr = self.base + addr as u64;
+        let mut functions = self.functions.lock();
+        let function = functions.iter_mut().find(|function| {
+            function.config_read(PCI_COMMAND, 2) & PCI_COMMAND_MEMORY != 0
+                && function.bar().contains(&addr)
+        })?;
+        let offset = (addr - function.bar().start) as usize;
+        Some(f(&mut **function, offset))
+    }
+}
+
+impl IoMemory for PciWindow {
+    fn read(&self, addr: usize, size: u32) -> u64 {
+        self.access(addr, |function, offset| function.read_mut(offset, size)).unwrap_or_else(|| {
+            error!(target: "PciHost", "unmapped memory read 0x{:x}", self.base + addr as u64);
+            0
+        })
+    }
+
+    fn write(&self, addr: usize, value: u64, size: u32) {
+        if self.access(addr, |function, offset| function.write_mut(offset, value, size)).is_none() {
+            error!(
+                target: "PciHost",
+                "unmapped memory write 0x{:x} = 0x{:x}",
+                self.base + addr as u64,
+                value
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A function whose BAR holds a single register.
+    struct Register {
+        config: [u8; 64],
+        value: u64,
+    }
+
+    impl IoMemoryMut for Register {
+        fn read_mut(&mut self, addr: usize, _size: u32) -> u64 {
+            self.value + addr as u64
+        }
+
+        fn write_mut(&mut self, _addr: usize, value: u64, _size: u32) {
+            self.value = value;
+        }
+    }
+
+    impl Function for Register {
+        fn config_read(&self, offset: usize, size: u32) -> u64 {
+            let mut bytes = [0; 8];
+            bytes[..size as usize].copy_from_slice(&self.config[offset..offset + size as usize]);
+            u64::from_le_bytes(bytes)
+        }
+
+        fn config_write(&mut self, offset: usize, value: u64, size: u32) {
+            let bytes = value.to_le_bytes();
+            self.config[offset..offset + size as usize].copy_from_slice(&bytes[..size as usize]);
+        }
+
+        fn bar(&self) -> Range<u64> {
+            let start = self.config_read(0x10, 4) & !0xfff;
+            start..start + 0x1000
+        }
+    }
+
+    #[test]
+    fn test_ecam() {
+        let host = PciHost::new();
+        let window = host.window(0x4000_0000);
+        let mut config = [0; 64];
+        config[..4].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        assert_eq!(host.add(Box::new(Register { config, value: 0 })), 0);
+        assert_eq!(host.add(Box::new(Register { config, value: 0 })), 1);
+
+        // Devices are 32 KiB apart, and absent devices and functions read as all ones.
+        assert_eq!(host.read(0, 4), 0x1234_5678);
+        assert_eq!(host.read(1 << 15, 2), 0x5678);
+        assert_eq!(host.read(1 << 12, 4), 0xffff_ffff);
+        assert_eq!(host.read(2 << 15, 2), 0xffff);
+
+        // BARs are only decoded once memory space is enabled.
+        host.write((1 << 15) + 0x10, 0x4000_2000, 4);
+        window.write(0x2000, 42, 8);
+        assert_eq!(window.read(0x2008, 8), 0);
+        host.write((1 << 15) + PCI_COMMAND, PCI_COMMAND_MEMORY, 2);
+        window.write(0x2000, 42, 8);
+        assert_eq!(window.read(0x2008, 8), 50);
+        assert_eq!(window.read(0x3000, 8), 0);
+    }
+}
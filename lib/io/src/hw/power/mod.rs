@@ -0,0 +1,6 @@
+//! Power management devices.
+
+#[cfg(feature = "power-syscon")]
+mod syscon;
+#[cfg(feature = "power-syscon")]
+pub use syscon::{Syscon, SysconAction};
@@ -0,0 +1,80 @@
+use crate::IoMemory;
+
+/// Action requested by the guest through [`Syscon`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SysconAction {
+    Poweroff,
+    Reboot,
+}
+
+/// A minimal system controller with a single 32-bit register.
+///
+/// Writing the poweroff or reboot magic value to the register requests the corresponding action,
+/// which is passed to the handler supplied on construction. This is compatible with Linux's
+/// `syscon-poweroff` and `syscon-reboot` drivers. Reads always return 0.
+pub struct Syscon {
+    poweroff: u32,
+    reboot: u32,
+    handler: Box<dyn Fn(SysconAction) + Send + Sync>,
+}
+
+impl Syscon {
+    /// Create a new `Syscon` with the given magic values.
+    pub fn new(
+        poweroff: u32,
+        reboot: u32,
+        handler: impl Fn(SysconAction) + Send + Sync + 'static,
+    ) -> Self {
+        Syscon { poweroff, reboot, handler: Box::new(handler) }
+    }
+}
+
+impl IoMemory for Syscon {
+    fn read(&self, addr: usize, size: u32) -> u64 {
+        if addr != 0 || size != 4 {
+            error!(target: "Syscon", "illegal register read 0x{:x}", addr);
+        }
+        0
+    }
+
+    fn write(&self, addr: usize, value: u64, size: u32) {
+        if addr != 0 || size != 4 {
+            error!(target: "Syscon", "illegal register write 0x{:x} = 0x{:x}", addr, value);
+            return;
+        }
+        let value = value as u32;
+        if value == self.poweroff {
+            (self.handler)(SysconAction::Poweroff);
+        } else if value == self.reboot {
+            (self.handler)(SysconAction::Reboot);
+        } else {
+            warn!(target: "Syscon", "unknown command 0x{:x}", value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_poweroff() {
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let syscon = {
+            let actions = actions.clone();
+            Syscon::new(0x5555, 0x7777, move |action| actions.lock().push(action))
+        };
+
+        // Unknown values and wrongly sized writes are ignored
+        syscon.write(0, 0x1234, 4);
+        syscon.write(0, 0x5555, 8);
+        assert!(actions.lock().is_empty());
+
+        syscon.write(0, 0x5555, 4);
+        assert_eq!(*actions.lock(), [SysconAction::Poweroff]);
+        syscon.write(0, 0x7777, 4);
+        assert_eq!(*actions.lock(), [SysconAction::Poweroff, SysconAction::Reboot]);
+    }
+}
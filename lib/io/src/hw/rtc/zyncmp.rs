@@ -1,4 +1,6 @@
-use crate::{IoMemory, IrqPin};
+use crate::{IoMemory, IrqPin, RuntimeContext};
+use std::sync::Arc;
+use std::time::Duration;
 
 const ADDR_SET_TM_WR: usize = 0x00;
 const ADDR_SET_TM_RD: usize = 0x04;
@@ -18,11 +20,28 @@ const CTRL_BATT_EN: u32 = 1 << 31;
 /// An implementation of Xilinx Zynq Ultrascale+ MPSoC RTC.
 ///
 /// Currently this implementation is read only.
-pub struct ZyncMp {}
+pub struct ZyncMp {
+    /// If present, the time is derived from the runtime context instead of the host clock.
+    epoch: Option<(Arc<dyn RuntimeContext>, Duration)>,
+}
 
 impl ZyncMp {
     pub fn new(_alarm_irq: Box<dyn IrqPin>, _sec_irq: Box<dyn IrqPin>) -> ZyncMp {
-        ZyncMp {}
+        ZyncMp { epoch: None }
+    }
+
+    /// Report time as `epoch` (since UNIX epoch) plus the current time of the runtime context,
+    /// instead of the host's wall clock.
+    pub fn with_epoch(mut self, ctx: Arc<dyn RuntimeContext>, epoch: Duration) -> ZyncMp {
+        self.epoch = Some((ctx, epoch));
+        self
+    }
+
+    fn now(&self) -> Duration {
+        match self.epoch {
+            Some((ref ctx, epoch)) => epoch + ctx.now(),
+            None => std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap(),
+        }
     }
 }
 
@@ -34,10 +53,7 @@ impl IoMemory for ZyncMp {
             return 0;
         }
         let val = match addr {
-            ADDR_SET_TM_RD | ADDR_CUR_TM => std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            ADDR_SET_TM_RD | ADDR_CUR_TM => self.now().as_secs(),
             ADDR_CALIB_RD => 0x198233,
             ADDR_CUR_TICK => 0xffff,
             ADDR_ALRM => 0,
@@ -74,3 +90,18 @@ impl IoMemory for ZyncMp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{NoIrq, TestRuntime};
+
+    #[test]
+    fn test_fixed_epoch() {
+        let runtime = Arc::new(TestRuntime::at(Duration::from_secs(5)));
+        let rtc = ZyncMp::new(Box::new(NoIrq), Box::new(NoIrq))
+            .with_epoch(runtime, Duration::from_secs(1577836800));
+        assert_eq!(rtc.read(ADDR_CUR_TM, 4), 1577836805);
+        assert_eq!(rtc.read(ADDR_SET_TM_RD, 4), 1577836805);
+    }
+}
@@ -0,0 +1,6 @@
+//! UART devices.
+
+#[cfg(feature = "uart-ns16550")]
+mod ns16550;
+#[cfg(feature = "uart-ns16550")]
+pub use ns16550::Ns16550;
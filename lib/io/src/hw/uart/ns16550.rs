@@ -0,0 +1,278 @@
+use crate::serial::Serial;
+use crate::{IoMemory, IrqPin, RuntimeContext};
+use futures::future::{AbortHandle, Abortable};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+const REG_RBR_THR: usize = 0;
+const REG_IER: usize = 1;
+const REG_IIR_FCR: usize = 2;
+const REG_LCR: usize = 3;
+const REG_MCR: usize = 4;
+const REG_LSR: usize = 5;
+const REG_MSR: usize = 6;
+const REG_SCR: usize = 7;
+
+const IER_ERBFI: u8 = 0x01;
+const IER_ETBEI: u8 = 0x02;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+
+const LCR_DLAB: u8 = 0x80;
+
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+/// DCD, DSR and CTS asserted.
+const MSR_DEFAULT: u8 = 0xB0;
+
+/// An ns16550a-compatible UART.
+///
+/// Transmission completes immediately, so the transmitter is always reported as empty. Received
+/// bytes are queued until read by the guest. Divisor latches are kept but have no effect.
+pub struct Ns16550 {
+    rx_handle: AbortHandle,
+    inner: Arc<Inner>,
+}
+
+struct State {
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    rx: VecDeque<u8>,
+    /// Whether a THR empty interrupt is pending. Cleared when IIR reports it or THR is written.
+    thre_pending: bool,
+}
+
+struct Inner {
+    serial: Box<dyn Serial>,
+    irq: Box<dyn IrqPin>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn update_irq(&self, state: &State) {
+        let rx = state.ier & IER_ERBFI != 0 && !state.rx.is_empty();
+        let tx = state.ier & IER_ETBEI != 0 && state.thre_pending;
+        self.irq.set_level(rx || tx);
+    }
+
+    fn receive(&self, data: &[u8]) {
+        let mut state = self.state.lock();
+        state.rx.extend(data);
+        self.update_irq(&state);
+    }
+}
+
+impl Drop for Ns16550 {
+    fn drop(&mut self) {
+        self.rx_handle.abort();
+    }
+}
+
+impl Ns16550 {
+    /// Create a new UART bridged to the given serial device.
+    pub fn new(
+        ctx: Arc<dyn RuntimeContext>,
+        irq: Box<dyn IrqPin>,
+        serial: Box<dyn Serial>,
+    ) -> Self {
+        let state = State {
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            rx: VecDeque::new(),
+            thre_pending: false,
+        };
+        let inner = Arc::new(Inner { serial, irq, state: Mutex::new(state) });
+
+        let rx_inner = inner.clone();
+        let (rx_handle, reg) = AbortHandle::new_pair();
+        ctx.spawn(Box::pin(async move {
+            let _ = Abortable::new(
+                async move {
+                    let mut buffer = [0; 2048];
+                    loop {
+                        let len = rx_inner.serial.read(&mut buffer).await.unwrap();
+                        rx_inner.receive(&buffer[..len]);
+                    }
+                },
+                reg,
+            )
+            .await;
+        }));
+
+        Ns16550 { rx_handle, inner }
+    }
+}
+
+impl IoMemory for Ns16550 {
+    fn read(&self, addr: usize, size: u32) -> u64 {
+        if size != 1 {
+            error!(target: "Ns16550", "illegal register read 0x{:x}", addr);
+            return 0;
+        }
+
+        let mut state = self.inner.state.lock();
+        let value = match addr {
+            REG_RBR_THR if state.lcr & LCR_DLAB != 0 => state.dll,
+            REG_RBR_THR => {
+                let value = state.rx.pop_front().unwrap_or(0);
+                self.inner.update_irq(&state);
+                value
+            }
+            REG_IER if state.lcr & LCR_DLAB != 0 => state.dlm,
+            REG_IER => state.ier,
+            REG_IIR_FCR => {
+                let fifo = if state.fcr & FCR_FIFO_ENABLE != 0 { IIR_FIFO_ENABLED } else { 0 };
+                let id = if state.ier & IER_ERBFI != 0 && !state.rx.is_empty() {
+                    IIR_RDA
+                } else if state.ier & IER_ETBEI != 0 && state.thre_pending {
+                    // Reading IIR acknowledges the THR empty interrupt.
+                    state.thre_pending = false;
+                    self.inner.update_irq(&state);
+                    IIR_THRE
+                } else {
+                    IIR_NO_INT
+                };
+                fifo | id
+            }
+            REG_LCR => state.lcr,
+            REG_MCR => state.mcr,
+            REG_LSR => {
+                let dr = if state.rx.is_empty() { 0 } else { LSR_DR };
+                dr | LSR_THRE | LSR_TEMT
+            }
+            REG_MSR => MSR_DEFAULT,
+            REG_SCR => state.scr,
+            _ => {
+                error!(target: "Ns16550", "illegal register read 0x{:x}", addr);
+                0
+            }
+        };
+        value as u64
+    }
+
+    fn write(&self, addr: usize, value: u64, size: u32) {
+        if size != 1 {
+            error!(target: "Ns16550", "illegal register write 0x{:x} = 0x{:x}", addr, value);
+            return;
+        }
+
+        let value = value as u8;
+        let mut state = self.inner.state.lock();
+        match addr {
+            REG_RBR_THR if state.lcr & LCR_DLAB != 0 => state.dll = value,
+            REG_RBR_THR => {
+                // The transmitter becomes empty again as soon as the byte is handed over.
+                let _ = self.inner.serial.try_write(&[value]);
+                state.thre_pending = true;
+                self.inner.update_irq(&state);
+            }
+            REG_IER if state.lcr & LCR_DLAB != 0 => state.dlm = value,
+            REG_IER => {
+                // Enabling the THR empty interrupt while the transmitter is empty raises it.
+                if value & IER_ETBEI != 0 && state.ier & IER_ETBEI == 0 {
+                    state.thre_pending = true;
+                }
+                state.ier = value & 0x0F;
+                self.inner.update_irq(&state);
+            }
+            REG_IIR_FCR => {
+                // Bit 1 clears the receive FIFO.
+                if value & 0x02 != 0 {
+                    state.rx.clear();
+                    self.inner.update_irq(&state);
+                }
+                state.fcr = value;
+            }
+            REG_LCR => state.lcr = value,
+            REG_MCR => state.mcr = value,
+            REG_LSR | REG_MSR => (),
+            REG_SCR => state.scr = value,
+            _ => error!(target: "Ns16550", "illegal register write 0x{:x} = 0x{:x}", addr, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRuntime;
+    use std::io::Result;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll};
+
+    #[derive(Default)]
+    struct MockIrq(Arc<AtomicBool>);
+
+    impl IrqPin for MockIrq {
+        fn set_level(&self, level: bool) {
+            self.0.store(level, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Default)]
+    struct MockSerial(Arc<Mutex<Vec<u8>>>);
+
+    impl Serial for MockSerial {
+        fn poll_write(&self, _cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>> {
+            self.0.lock().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_read(&self, _cx: &mut Context, _buf: &mut [u8]) -> Poll<Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_tx() {
+        let serial = MockSerial::default();
+        let output = serial.0.clone();
+        let uart = Ns16550::new(
+            Arc::new(TestRuntime::default()),
+            Box::new(MockIrq::default()),
+            Box::new(serial),
+        );
+        uart.write(REG_RBR_THR, b'A' as u64, 1);
+        assert_eq!(&*output.lock(), b"A");
+        assert_eq!(uart.read(REG_LSR, 1) as u8 & LSR_THRE, LSR_THRE);
+    }
+
+    #[test]
+    fn test_rx_interrupt() {
+        let irq = MockIrq::default();
+        let level = irq.0.clone();
+        let uart = Ns16550::new(
+            Arc::new(TestRuntime::default()),
+            Box::new(irq),
+            Box::new(MockSerial::default()),
+        );
+        uart.write(REG_IER, IER_ERBFI as u64, 1);
+        assert!(!level.load(Ordering::Relaxed));
+
+        uart.inner.receive(b"x");
+        assert!(level.load(Ordering::Relaxed));
+        assert_eq!(uart.read(REG_IIR_FCR, 1) as u8, IIR_RDA);
+        assert_eq!(uart.read(REG_LSR, 1) as u8 & LSR_DR, LSR_DR);
+        assert_eq!(uart.read(REG_RBR_THR, 1), b'x' as u64);
+        assert!(!level.load(Ordering::Relaxed));
+        assert_eq!(uart.read(REG_IIR_FCR, 1) as u8, IIR_NO_INT);
+    }
+}
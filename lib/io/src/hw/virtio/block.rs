@@ -1,17 +1,44 @@
-use super::{Device, DeviceId, Queue};
+use super::{Buffer, BufferWriter, Device, DeviceId, Queue};
 use crate::block::Block as BlockDevice;
 use crate::{IrqPin, RuntimeContext};
 use parking_lot::Mutex;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
 
-#[allow(dead_code)]
 const VIRTIO_BLK_F_RO: usize = 5;
+const VIRTIO_BLK_F_FLUSH: usize = 9;
+const VIRTIO_BLK_F_DISCARD: usize = 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: usize = 14;
 
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
 /// This is an un-documented.
 const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+/// Flag of a discard or write zeroes segment asking for the sectors to be unmapped.
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Length of the device ID string returned by `VIRTIO_BLK_T_GET_ID`.
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// Maximum number of segments in a discard or write zeroes request.
+const MAX_DISCARD_SEG: u32 = 32;
+
+// Offsets of fields in the configuration space.
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const CONFIG_MAX_DISCARD_SEG: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: usize = 44;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS: usize = 48;
+const CONFIG_MAX_WRITE_ZEROES_SEG: usize = 52;
+const CONFIG_SIZE: usize = 60;
 
 #[repr(C)]
 struct VirtioBlkReqHeader {
@@ -20,10 +47,74 @@ struct VirtioBlkReqHeader {
     sector: u64,
 }
 
+/// A segment of a discard or write zeroes request.
+#[repr(C)]
+struct VirtioBlkDiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+/// When writes to a virtio block device are flushed to the backing block device.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CacheMode {
+    /// Writes are flushed upon flush requests only. `VIRTIO_BLK_F_FLUSH` is offered so the guest
+    /// issues them; if the driver does not accept it, writes are flushed as in `Writethrough`.
+    Writeback,
+    /// Each write is flushed before it completes.
+    Writethrough,
+    /// Writes are never flushed, not even upon flush requests.
+    None,
+}
+
+/// When writes are flushed, given the cache mode and whether `VIRTIO_BLK_F_FLUSH` is negotiated.
+#[derive(Clone, Copy)]
+struct FlushPolicy {
+    on_write: bool,
+    on_request: bool,
+}
+
+impl FlushPolicy {
+    fn new(mode: CacheMode, flush_negotiated: bool) -> Self {
+        match mode {
+            CacheMode::Writeback => FlushPolicy { on_write: !flush_negotiated, on_request: true },
+            CacheMode::Writethrough => FlushPolicy { on_write: true, on_request: true },
+            CacheMode::None => FlushPolicy { on_write: false, on_request: false },
+        }
+    }
+
+    fn write(self, file: &mut dyn BlockDevice, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        file.write_all_at(buf, offset)?;
+        self.flush_written(file)
+    }
+
+    /// Flush after contents are changed other than by `write`, e.g. discarded.
+    fn flush_written(self, file: &mut dyn BlockDevice) -> std::io::Result<()> {
+        if self.on_write {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(self, file: &mut dyn BlockDevice) -> std::io::Result<()> {
+        if self.on_request {
+            file.flush()
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// A virtio block device.
 pub struct Block {
     status: u32,
-    config: [u8; 8],
+    config: [u8; CONFIG_SIZE],
+    discard: bool,
+    id: [u8; VIRTIO_BLK_ID_BYTES],
+    max_in_flight: usize,
+    cache_mode: CacheMode,
+    readonly: bool,
+    driver_feature: u32,
     ctx: Arc<dyn RuntimeContext>,
     inner: Arc<Inner>,
 }
@@ -44,61 +135,237 @@ impl Block {
         if len % 512 != 0 {
             panic!("Size of block device must be multiple of 512 bytes");
         }
+        let capability = file.capability();
         let inner = Arc::new(Inner { file: Mutex::new(file), irq });
-        Block { status: 0, config: (len / 512).to_le_bytes(), ctx, inner }
+
+        let mut config = [0; CONFIG_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            config[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(CONFIG_CAPACITY, &(len / 512).to_le_bytes());
+        if capability.discard {
+            let max_sectors = std::cmp::min(len / 512, u32::MAX as u64) as u32;
+            let alignment = (capability.blksize / 512) as u32;
+            put(CONFIG_MAX_DISCARD_SECTORS, &max_sectors.to_le_bytes());
+            put(CONFIG_MAX_DISCARD_SEG, &MAX_DISCARD_SEG.to_le_bytes());
+            put(CONFIG_DISCARD_SECTOR_ALIGNMENT, &alignment.to_le_bytes());
+            put(CONFIG_MAX_WRITE_ZEROES_SECTORS, &max_sectors.to_le_bytes());
+            put(CONFIG_MAX_WRITE_ZEROES_SEG, &MAX_DISCARD_SEG.to_le_bytes());
+        }
+
+        Block {
+            status: 0,
+            config,
+            discard: capability.discard,
+            id: [0; VIRTIO_BLK_ID_BYTES],
+            max_in_flight: super::DEFAULT_MAX_IN_FLIGHT,
+            cache_mode: CacheMode::Writethrough,
+            readonly: false,
+            driver_feature: 0,
+            ctx,
+            inner,
+        }
     }
 
-    fn start_task(&self, mut queue: Queue) {
+    /// Set the device ID (serial) reported to the guest. IDs longer than 20 bytes are truncated.
+    pub fn with_id(mut self, id: &str) -> Block {
+        let len = std::cmp::min(id.len(), VIRTIO_BLK_ID_BYTES);
+        self.id = [0; VIRTIO_BLK_ID_BYTES];
+        self.id[..len].copy_from_slice(&id.as_bytes()[..len]);
+        self
+    }
+
+    /// Set the maximum number of requests taken from the queue at a time.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Block {
+        assert_ne!(max_in_flight, 0);
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Set when writes are flushed to the backing block device. Defaults to
+    /// [`CacheMode::Writethrough`].
+    pub fn with_cache_mode(mut self, cache_mode: CacheMode) -> Block {
+        self.cache_mode = cache_mode;
+        self
+    }
+
+    /// Set whether the device is read-only. Write requests to a read-only device fail.
+    pub fn with_readonly(mut self, readonly: bool) -> Block {
+        self.readonly = readonly;
+        self
+    }
+
+    fn start_task(&self, queue: Queue) {
         let inner = self.inner.clone();
-        self.ctx.spawn_blocking("virtio_blk", Box::pin(async move {
-            while let Ok(mut buffer) = queue.take().await {
-                let (mut reader, mut writer) = buffer.reader_writer();
-
-                let header: VirtioBlkReqHeader = unsafe {
-                    let mut header: [u8; 16] = std::mem::MaybeUninit::uninit().assume_init();
-                    reader.read_exact(&mut header).unwrap();
-                    std::mem::transmute(header)
-                };
-
-                let mut file = inner.file.lock();
-                match header.r#type {
-                    VIRTIO_BLK_T_IN => {
-                        let mut io_buffer = Vec::with_capacity(writer.len());
-                        unsafe { io_buffer.set_len(io_buffer.capacity() - 1) };
-                        (*file).read_exact_at(&mut io_buffer, header.sector * 512).unwrap();
-                        trace!(target: "VirtioBlk", "read {} bytes from sector {:x}", io_buffer.len(), header.sector);
-
-                        io_buffer.push(0);
-                        writer.write_all(&io_buffer).unwrap();
+        let id = self.id;
+        let max_in_flight = self.max_in_flight;
+        let readonly = self.readonly;
+        let policy =
+            FlushPolicy::new(self.cache_mode, self.driver_feature & (1 << VIRTIO_BLK_F_FLUSH) != 0);
+        self.ctx.spawn_blocking(
+            "virtio_blk",
+            Box::pin(async move {
+                super::serve(queue, max_in_flight, &*inner.irq, |batch| {
+                    let mut file = inner.file.lock();
+                    for buffer in batch.iter_mut() {
+                        handle_request(&mut **file, policy, readonly, &id, buffer);
                     }
-                    VIRTIO_BLK_T_OUT => {
-                        let mut io_buffer = Vec::with_capacity(reader.len() - 16);
-                        unsafe { io_buffer.set_len(io_buffer.capacity()) };
-                        reader.read_exact(&mut io_buffer).unwrap();
+                })
+                .await
+            }),
+        );
+    }
+}
 
-                        file.write_all_at(&io_buffer, header.sector * 512).unwrap();
-                        // We must make sure the data has been flushed into the disk before returning
-                        file.flush().unwrap();
-                        trace!(target: "VirtioBlk", "write {} bytes from sector {:x}", io_buffer.len(), header.sector);
+/// Process a single request from the guest.
+fn handle_request(
+    file: &mut dyn BlockDevice,
+    policy: FlushPolicy,
+    readonly: bool,
+    id: &[u8; VIRTIO_BLK_ID_BYTES],
+    buffer: &mut Buffer,
+) {
+    let (mut reader, mut writer) = buffer.reader_writer();
 
-                        writer.write_all(&[0]).unwrap();
-                    }
-                    VIRTIO_BLK_T_GET_ID => {
-                        // Fill in a dummy ID for now.
-                        let len = writer.len();
-                        writer.write_all(&vec![0; len]).unwrap();
-                    }
-                    _ => {
-                        error!(target: "VirtioBlk", "unsupported block operation type {}", header.r#type);
-                        continue;
-                    }
+    let header: VirtioBlkReqHeader = unsafe {
+        let mut header: [u8; 16] = std::mem::MaybeUninit::uninit().assume_init();
+        reader.read_exact(&mut header).unwrap();
+        std::mem::transmute(header)
+    };
+
+    match header.r#type {
+        VIRTIO_BLK_T_IN => {
+            let mut io_buffer = Vec::with_capacity(writer.len());
+            unsafe { io_buffer.set_len(io_buffer.capacity() - 1) };
+            file.read_exact_at(&mut io_buffer, header.sector * 512).unwrap();
+            trace!(target: "VirtioBlk", "read {} bytes from sector {:x}", io_buffer.len(), header.sector);
+
+            io_buffer.push(0);
+            writer.write_all(&io_buffer).unwrap();
+        }
+        VIRTIO_BLK_T_OUT if readonly => {
+            error!(target: "VirtioBlk", "write to read-only device at sector {:x}", header.sector);
+            write_status(&mut writer, VIRTIO_BLK_S_IOERR);
+        }
+        VIRTIO_BLK_T_OUT => {
+            let mut io_buffer = Vec::with_capacity(reader.len() - 16);
+            unsafe { io_buffer.set_len(io_buffer.capacity()) };
+            reader.read_exact(&mut io_buffer).unwrap();
+
+            policy.write(file, &io_buffer, header.sector * 512).unwrap();
+            trace!(target: "VirtioBlk", "write {} bytes from sector {:x}", io_buffer.len(), header.sector);
+
+            writer.write_all(&[VIRTIO_BLK_S_OK]).unwrap();
+        }
+        VIRTIO_BLK_T_FLUSH => {
+            let status = match policy.flush(file) {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(err) => {
+                    error!(target: "VirtioBlk", "flush failed: {}", err);
+                    VIRTIO_BLK_S_IOERR
                 }
+            };
+            trace!(target: "VirtioBlk", "flush");
 
-                drop(buffer);
-                inner.irq.pulse();
+            writer.write_all(&[status]).unwrap();
+        }
+        VIRTIO_BLK_T_GET_ID => {
+            writer.write_all(&id_response(id, writer.len())).unwrap();
+        }
+        VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES if readonly => {
+            error!(target: "VirtioBlk", "discard on read-only device");
+            write_status(&mut writer, VIRTIO_BLK_S_IOERR);
+        }
+        VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+            let mut segments = vec![0; reader.len() - 16];
+            reader.read_exact(&mut segments).unwrap();
+            let status = discard_write_zeroes(file, policy, header.r#type, &segments);
+            write_status(&mut writer, status);
+        }
+        _ => {
+            error!(target: "VirtioBlk", "unsupported block operation type {}", header.r#type);
+            write_status(&mut writer, VIRTIO_BLK_S_UNSUPP);
+        }
+    }
+}
+
+/// Discard or write zeroes to each segment of a request, and return the status.
+fn discard_write_zeroes(
+    file: &mut dyn BlockDevice,
+    policy: FlushPolicy,
+    r#type: u32,
+    segments: &[u8],
+) -> u8 {
+    let segment_size = std::mem::size_of::<VirtioBlkDiscardWriteZeroes>();
+    if segments.len() % segment_size != 0
+        || segments.len() / segment_size > MAX_DISCARD_SEG as usize
+    {
+        error!(target: "VirtioBlk", "invalid discard segments of {} bytes", segments.len());
+        return VIRTIO_BLK_S_IOERR;
+    }
+    let blksize = file.capability().blksize as u64;
+
+    // Validate all segments before applying any, so a rejected request leaves the device intact.
+    let mut ranges = Vec::with_capacity(segments.len() / segment_size);
+    for segment in segments.chunks_exact(segment_size) {
+        let segment: VirtioBlkDiscardWriteZeroes =
+            unsafe { std::ptr::read_unaligned(segment.as_ptr() as *const _) };
+        let offset = segment.sector.checked_mul(512);
+        let len = segment.num_sectors as u64 * 512;
+        let end = offset.and_then(|offset| offset.checked_add(len));
+        match (offset, end) {
+            (Some(offset), Some(end))
+                if offset % blksize == 0 && len % blksize == 0 && end <= file.len() =>
+            {
+                ranges.push((offset, len))
+            }
+            _ => {
+                error!(target: "VirtioBlk", "invalid discard of {} sectors at sector {:x}", segment.num_sectors, segment.sector);
+                return VIRTIO_BLK_S_IOERR;
             }
-        }));
+        }
+        // The unmap flag is only defined for write zeroes.
+        let flags =
+            if r#type == VIRTIO_BLK_T_DISCARD { 0 } else { VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP };
+        if segment.flags & !flags != 0 {
+            return VIRTIO_BLK_S_UNSUPP;
+        }
+    }
+
+    for (offset, len) in ranges {
+        let result = if r#type == VIRTIO_BLK_T_DISCARD {
+            file.discard(offset, len as usize)
+        } else {
+            file.write_zero_at(offset, len as usize)
+        };
+        if let Err(err) = result {
+            error!(target: "VirtioBlk", "discard failed: {}", err);
+            return VIRTIO_BLK_S_IOERR;
+        }
+        trace!(target: "VirtioBlk", "discard {} bytes at {:x}", len, offset);
+    }
+    if let Err(err) = policy.flush_written(file) {
+        error!(target: "VirtioBlk", "flush failed: {}", err);
+        return VIRTIO_BLK_S_IOERR;
     }
+    VIRTIO_BLK_S_OK
+}
+
+/// Write the status byte, which is the last byte of the writable part of the request.
+fn write_status(writer: &mut BufferWriter, status: u8) {
+    if writer.len() != 0 {
+        writer.seek(SeekFrom::End(-1)).unwrap();
+        writer.write_all(&[status]).unwrap();
+    }
+}
+
+/// Build the response of `VIRTIO_BLK_T_GET_ID` for a writable buffer of `len` bytes, which
+/// consists of the ID padded with zeroes, followed by the status byte.
+fn id_response(id: &[u8; VIRTIO_BLK_ID_BYTES], len: usize) -> Vec<u8> {
+    let mut response = vec![0; len];
+    let id_len = std::cmp::min(len.saturating_sub(1), VIRTIO_BLK_ID_BYTES);
+    response[..id_len].copy_from_slice(&id[..id_len]);
+    response
 }
 
 impl Device for Block {
@@ -106,9 +373,22 @@ impl Device for Block {
         DeviceId::Block
     }
     fn device_feature(&self) -> u32 {
-        0
+        let mut features =
+            1 << super::VIRTIO_RING_F_INDIRECT_DESC | 1 << super::VIRTIO_RING_F_EVENT_IDX;
+        if self.cache_mode == CacheMode::Writeback {
+            features |= 1 << VIRTIO_BLK_F_FLUSH;
+        }
+        if self.readonly {
+            features |= 1 << VIRTIO_BLK_F_RO;
+        }
+        if self.discard {
+            features |= 1 << VIRTIO_BLK_F_DISCARD | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
+        features
+    }
+    fn driver_feature(&mut self, value: u32) {
+        self.driver_feature = value;
     }
-    fn driver_feature(&mut self, _value: u32) {}
     fn get_status(&self) -> u32 {
         self.status
     }
@@ -128,3 +408,355 @@ impl Device for Block {
         self.start_task(queue)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::queue::testing::Driver;
+    use super::*;
+    use crate::block::Capability;
+    use crate::testing::{NoIrq, TestRuntime};
+    use std::convert::TryInto;
+
+    /// A block device that counts writes and flushes, and records discards and zeroed ranges.
+    /// Each sector reads as its number.
+    #[derive(Default)]
+    struct MockBlock {
+        writes: usize,
+        flushes: usize,
+        discards: Vec<(u64, usize)>,
+        zeroes: Vec<(u64, usize)>,
+    }
+
+    impl BlockDevice for MockBlock {
+        fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = ((offset + i as u64) / 512) as u8;
+            }
+            Ok(())
+        }
+
+        fn write_all_at(&mut self, _buf: &[u8], _offset: u64) -> std::io::Result<()> {
+            self.writes += 1;
+            Ok(())
+        }
+
+        fn write_zero_at(&mut self, offset: u64, len: usize) -> std::io::Result<()> {
+            self.zeroes.push((offset, len));
+            Ok(())
+        }
+
+        fn discard(&mut self, offset: u64, len: usize) -> std::io::Result<()> {
+            self.discards.push((offset, len));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+
+        fn len(&self) -> u64 {
+            0x100000
+        }
+
+        fn capability(&self) -> Capability {
+            Capability { blksize: 4096, discard: true }
+        }
+    }
+
+    #[test]
+    fn test_writeback() {
+        let mut file = MockBlock::default();
+        let policy = FlushPolicy::new(CacheMode::Writeback, true);
+        policy.write(&mut file, &[0; 512], 0).unwrap();
+        policy.write(&mut file, &[0; 512], 512).unwrap();
+        assert_eq!((file.writes, file.flushes), (2, 0));
+        policy.flush(&mut file).unwrap();
+        assert_eq!(file.flushes, 1);
+
+        // Without the flush feature the guest never asks for flushes.
+        let mut file = MockBlock::default();
+        FlushPolicy::new(CacheMode::Writeback, false).write(&mut file, &[0; 512], 0).unwrap();
+        assert_eq!(file.flushes, 1);
+
+        let mut file = MockBlock::default();
+        FlushPolicy::new(CacheMode::Writethrough, false).write(&mut file, &[0; 512], 0).unwrap();
+        assert_eq!(file.flushes, 1);
+
+        let mut file = MockBlock::default();
+        let policy = FlushPolicy::new(CacheMode::None, true);
+        policy.write(&mut file, &[0; 512], 0).unwrap();
+        policy.flush(&mut file).unwrap();
+        assert_eq!(file.flushes, 0);
+    }
+
+    #[test]
+    fn test_get_id_response() {
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        id[..9].copy_from_slice(b"disk-0042");
+        let response = id_response(&id, VIRTIO_BLK_ID_BYTES + 1);
+        assert_eq!(&response[..9], b"disk-0042");
+        assert!(response[9..].iter().all(|&x| x == 0));
+
+        // A short buffer receives a truncated ID, with the last byte left as the status.
+        assert_eq!(id_response(&id, 5), b"disk\0");
+    }
+
+    /// Submit a request without data to the device, with the writable part split into descriptors
+    /// of given lengths. Returns the number of bytes written and the content of the writable part.
+    fn request(file: &mut MockBlock, r#type: u32, writable: &[u32]) -> (u32, Vec<u8>) {
+        request_with(file, r#type, false, 0, writable)
+    }
+
+    /// Like `request`, but with `readable` bytes of data following the header, to a device that
+    /// may be read-only.
+    fn request_with(
+        file: &mut MockBlock,
+        r#type: u32,
+        readonly: bool,
+        readable: u32,
+        writable: &[u32],
+    ) -> (u32, Vec<u8>) {
+        let (mut driver, mut queue) = Driver::new();
+        let data = driver.data_addr();
+        let header = VirtioBlkReqHeader { r#type, reserved: 0, sector: 0 };
+        unsafe { std::ptr::write(data as *mut VirtioBlkReqHeader, header) };
+        let mut descs = vec![(data, 16, false)];
+        if readable != 0 {
+            descs.push((data + 16, readable, false));
+        }
+        let writable_addr = data + 16 + readable as u64;
+        let total: u32 = writable.iter().sum();
+        unsafe { std::ptr::write_bytes(writable_addr as *mut u8, 0xff, total as usize) };
+        let mut addr = writable_addr;
+        for &len in writable {
+            descs.push((addr, len, true));
+            addr += len as u64;
+        }
+        driver.submit(&descs);
+
+        let policy = FlushPolicy::new(CacheMode::Writeback, true);
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        id[..9].copy_from_slice(b"disk-0042");
+        let mut buffer = queue.try_take().ok().unwrap().unwrap();
+        handle_request(file, policy, readonly, &id, &mut buffer);
+        drop(buffer);
+
+        let completions = queue.completions();
+        assert_eq!(completions.len(), 1);
+        let written =
+            unsafe { std::slice::from_raw_parts(writable_addr as *const u8, total as usize) };
+        (completions[0].len, written.to_vec())
+    }
+
+    #[test]
+    fn test_flush_request() {
+        let mut file = MockBlock::default();
+        assert_eq!(request(&mut file, VIRTIO_BLK_T_FLUSH, &[1]), (1, vec![VIRTIO_BLK_S_OK]));
+        assert_eq!(file.flushes, 1);
+    }
+
+    #[test]
+    fn test_get_id_request() {
+        let mut file = MockBlock::default();
+
+        // Linux passes the ID and the status byte in separate descriptors.
+        let (len, written) = request(&mut file, VIRTIO_BLK_T_GET_ID, &[20, 1]);
+        assert_eq!(len, 21);
+        assert_eq!(&written[..9], b"disk-0042");
+        assert!(written[9..].iter().all(|&x| x == 0));
+
+        // The ID is truncated to fit the buffer provided, and the status still comes last.
+        assert_eq!(request(&mut file, VIRTIO_BLK_T_GET_ID, &[5]), (5, b"disk\0".to_vec()));
+        assert_eq!(file.flushes, 0);
+    }
+
+    #[test]
+    fn test_unsupported_request() {
+        let mut file = MockBlock::default();
+        let (_, written) = request(&mut file, 42, &[4]);
+        assert_eq!(written, [0xff, 0xff, 0xff, VIRTIO_BLK_S_UNSUPP]);
+    }
+
+    #[test]
+    fn test_read_completion() {
+        let (mut driver, mut queue) = Driver::new();
+        let data = driver.data_addr();
+        let header = VirtioBlkReqHeader { r#type: VIRTIO_BLK_T_IN, reserved: 0, sector: 1 };
+        unsafe { std::ptr::write(data as *mut VirtioBlkReqHeader, header) };
+        let head =
+            driver.submit(&[(data, 16, false), (data + 512, 1024, true), (data + 1536, 1, true)]);
+
+        let mut file = MockBlock::default();
+        let policy = FlushPolicy::new(CacheMode::Writethrough, false);
+        let mut buffer = queue.try_take().ok().unwrap().unwrap();
+        handle_request(&mut file, policy, false, &[0; VIRTIO_BLK_ID_BYTES], &mut buffer);
+        drop(buffer);
+
+        // Two sectors of data and the status byte are written.
+        let completions = queue.completions();
+        assert_eq!(completions.len(), 1);
+        assert_eq!(
+            (completions[0].used_idx, completions[0].id, completions[0].len),
+            (0, head, 1025)
+        );
+        let read = unsafe { std::slice::from_raw_parts((data + 512) as *const u8, 1025) };
+        assert!(read[..512].iter().all(|&x| x == 1));
+        assert!(read[512..1024].iter().all(|&x| x == 2));
+        assert_eq!(read[1024], 0);
+    }
+
+    #[test]
+    fn test_readonly() {
+        let mut file = MockBlock::default();
+        let result = request_with(&mut file, VIRTIO_BLK_T_OUT, true, 512, &[1]);
+        assert_eq!(result, (1, vec![VIRTIO_BLK_S_IOERR]));
+        assert_eq!((file.writes, file.flushes), (0, 0));
+    }
+
+    /// Submit a discard or write zeroes request with the given segments, and return the status.
+    fn discard(file: &mut MockBlock, r#type: u32, segments: &[(u64, u32, u32)]) -> u8 {
+        let (mut driver, mut queue) = Driver::new();
+        let data = driver.data_addr();
+        let header = VirtioBlkReqHeader { r#type, reserved: 0, sector: 0 };
+        unsafe { std::ptr::write(data as *mut VirtioBlkReqHeader, header) };
+        for (i, &(sector, num_sectors, flags)) in segments.iter().enumerate() {
+            let segment = VirtioBlkDiscardWriteZeroes { sector, num_sectors, flags };
+            unsafe { std::ptr::write((data + 16 + i as u64 * 16) as *mut _, segment) };
+        }
+        let status = data + 1024;
+        driver.submit(&[
+            (data, 16, false),
+            (data + 16, segments.len() as u32 * 16, false),
+            (status, 1, true),
+        ]);
+
+        let policy = FlushPolicy::new(CacheMode::Writethrough, false);
+        let mut buffer = queue.try_take().ok().unwrap().unwrap();
+        handle_request(file, policy, false, &[0; VIRTIO_BLK_ID_BYTES], &mut buffer);
+        drop(buffer);
+        assert_eq!(queue.completions()[0].len, 1);
+        unsafe { *(status as *const u8) }
+    }
+
+    #[test]
+    fn test_discard() {
+        let device = Block::new(
+            Arc::new(TestRuntime::default()),
+            Box::new(NoIrq),
+            Box::new(MockBlock::default()),
+        );
+        let features = device.device_feature();
+        assert_ne!(features & 1 << VIRTIO_BLK_F_DISCARD, 0);
+        assert_ne!(features & 1 << VIRTIO_BLK_F_WRITE_ZEROES, 0);
+        let config = |offset: usize| {
+            u32::from_le_bytes(device.config_space()[offset..offset + 4].try_into().unwrap())
+        };
+        assert_eq!(config(CONFIG_MAX_DISCARD_SECTORS), 2048);
+        assert_eq!(config(CONFIG_MAX_DISCARD_SEG), MAX_DISCARD_SEG);
+        assert_eq!(config(CONFIG_DISCARD_SECTOR_ALIGNMENT), 8);
+
+        // Each segment is discarded on its own.
+        let mut file = MockBlock::default();
+        let status = discard(&mut file, VIRTIO_BLK_T_DISCARD, &[(0, 8, 0), (64, 16, 0), (8, 8, 0)]);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(file.discards, [(0, 4096), (32768, 8192), (4096, 4096)]);
+        assert_eq!(file.flushes, 1);
+
+        let status = discard(&mut file, VIRTIO_BLK_T_WRITE_ZEROES, &[(16, 8, 1), (2040, 8, 0)]);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(file.zeroes, [(8192, 4096), (0xff000, 4096)]);
+
+        // Segments must be aligned to blocks and within the device.
+        let mut file = MockBlock::default();
+        assert_eq!(discard(&mut file, VIRTIO_BLK_T_DISCARD, &[(1, 8, 0)]), VIRTIO_BLK_S_IOERR);
+        assert_eq!(discard(&mut file, VIRTIO_BLK_T_DISCARD, &[(8, 4, 0)]), VIRTIO_BLK_S_IOERR);
+        assert_eq!(discard(&mut file, VIRTIO_BLK_T_DISCARD, &[(2048, 8, 0)]), VIRTIO_BLK_S_IOERR);
+        assert_eq!(discard(&mut file, VIRTIO_BLK_T_DISCARD, &[(0, 8, 1)]), VIRTIO_BLK_S_UNSUPP);
+        assert!(file.discards.is_empty());
+
+        // Sectors whose byte offset overflows are rejected, and so is the whole request, even if
+        // earlier segments are valid.
+        let overflow = u64::MAX / 512 + 1;
+        let status = discard(&mut file, VIRTIO_BLK_T_DISCARD, &[(0, 8, 0), (overflow, 8, 0)]);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        let status =
+            discard(&mut file, VIRTIO_BLK_T_WRITE_ZEROES, &[(0, 8, 0), (u64::MAX / 512, 8, 0)]);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        let status = discard(&mut file, VIRTIO_BLK_T_DISCARD, &[(0, 8, 0), (8, 8, 1)]);
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+        assert!(file.discards.is_empty());
+        assert!(file.zeroes.is_empty());
+        assert_eq!(file.flushes, 0);
+    }
+
+    /// A block device whose reads only complete once allowed to.
+    struct SlowBlock(Mutex<std::sync::mpsc::Receiver<()>>);
+
+    impl BlockDevice for SlowBlock {
+        fn read_exact_at(&mut self, buf: &mut [u8], _offset: u64) -> std::io::Result<()> {
+            self.0.lock().recv().unwrap();
+            buf.iter_mut().for_each(|x| *x = 0x5a);
+            Ok(())
+        }
+
+        fn write_all_at(&mut self, _buf: &[u8], _offset: u64) -> std::io::Result<()> {
+            panic!("not used by this test")
+        }
+
+        fn len(&self) -> u64 {
+            512
+        }
+    }
+
+    /// An interrupt pin counting pulses.
+    struct CountingIrq(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl IrqPin for CountingIrq {
+        fn set_level(&self, level: bool) {
+            if level {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_completion() {
+        let (mut driver, queue) = Driver::new();
+        let inner = queue.inner.clone();
+        let (allow, wait) = std::sync::mpsc::channel();
+        let irqs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut block = Block::new(
+            Arc::new(TestRuntime::default()),
+            Box::new(CountingIrq(irqs.clone())),
+            Box::new(SlowBlock(Mutex::new(wait))),
+        );
+        block.queue_ready(0, queue);
+
+        let data = driver.data_addr();
+        let header = VirtioBlkReqHeader { r#type: VIRTIO_BLK_T_IN, reserved: 0, sector: 0 };
+        unsafe { std::ptr::write(data as *mut VirtioBlkReqHeader, header) };
+        driver.submit(&[(data, 16, false), (data + 512, 512, true), (data + 1024, 1, true)]);
+        let notify = || inner.lock().waker.take().map(|waker| waker.wake());
+
+        // Notifying returns while the read is still in progress, without completing the request.
+        notify();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(inner.lock().completions.is_empty());
+        assert_eq!(irqs.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        // The request completes and raises the interrupt once the read finishes.
+        allow.send(()).unwrap();
+        while irqs.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            std::thread::yield_now();
+        }
+        assert_eq!(inner.lock().completions[0].len, 513);
+        let read = unsafe { std::slice::from_raw_parts((data + 512) as *const u8, 513) };
+        assert!(read[..512].iter().all(|&x| x == 0x5a));
+        assert_eq!(read[512], VIRTIO_BLK_S_OK);
+
+        // Stop the task, which then finds the queue not ready.
+        inner.lock().ready = false;
+        notify();
+    }
+}
@@ -1,4 +1,4 @@
-use super::{Device, DeviceId, Queue};
+use super::{ConfigChange, Device, DeviceId, Queue};
 use crate::serial::Serial;
 use crate::{IrqPin, RuntimeContext};
 use futures::future::{AbortHandle, Abortable};
@@ -7,11 +7,17 @@ use std::io::{Read, Write};
 use std::sync::Arc;
 
 const VIRTIO_CONSOLE_F_SIZE: usize = 0;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: usize = 2;
 
-fn size_to_config(col: u16, row: u16) -> [u8; 4] {
+/// Offset of `emerg_wr` in the config space, which outputs a character when written.
+const CONFIG_EMERG_WR: usize = 8;
+
+/// Build the config space: `cols` and `rows`, followed by `max_nr_ports` and `emerg_wr`, which
+/// are both zero.
+fn size_to_config(col: u16, row: u16) -> [u8; 12] {
     let col = col.to_le_bytes();
     let row = row.to_le_bytes();
-    [col[0], col[1], row[0], row[1]]
+    [col[0], col[1], row[0], row[1], 0, 0, 0, 0, 0, 0, 0, 0]
 }
 
 /// A virtio console device.
@@ -29,8 +35,8 @@ pub struct Console {
 struct Inner {
     console: Box<dyn Serial>,
     irq: Box<dyn IrqPin>,
-    // Keep config with whether it has changed
-    config: Mutex<([u8; 4], bool)>,
+    config: Mutex<[u8; 12]>,
+    config_change: ConfigChange,
 }
 
 impl Drop for Console {
@@ -68,8 +74,12 @@ impl Console {
 
         // Mark the config changed by default so the driver will poll
         // the size from the very beginning.
-        let config = Mutex::new((size_to_config(col, row), resize));
-        let inner = Arc::new(Inner { console, irq, config });
+        let config = Mutex::new(size_to_config(col, row));
+        let config_change = ConfigChange::default();
+        if resize {
+            config_change.mark();
+        }
+        let inner = Arc::new(Inner { console, irq, config, config_change });
         let mut ret =
             Console { status: 0, resize, rx_handle: None, resize_handle: None, ctx, inner };
 
@@ -131,10 +141,8 @@ impl Console {
                     loop {
                         inner.console.wait_window_size_changed().await.unwrap();
                         let (col, row) = inner.console.get_window_size().unwrap();
-                        let mut guard = inner.config.lock();
-                        guard.0 = size_to_config(col, row);
-                        guard.1 = true;
-                        inner.irq.pulse();
+                        *inner.config.lock() = size_to_config(col, row);
+                        inner.config_change.notify(&*inner.irq);
                     }
                 },
                 reg,
@@ -150,7 +158,11 @@ impl Device for Console {
         DeviceId::Console
     }
     fn device_feature(&self) -> u32 {
-        if self.resize { 1 << VIRTIO_CONSOLE_F_SIZE } else { 0 }
+        let mut features = 1 << VIRTIO_CONSOLE_F_EMERG_WRITE;
+        if self.resize {
+            features |= 1 << VIRTIO_CONSOLE_F_SIZE;
+        }
+        features
     }
 
     fn driver_feature(&mut self, _value: u32) {}
@@ -161,7 +173,18 @@ impl Device for Console {
         self.status = status
     }
     fn with_config_space(&self, f: &mut dyn FnMut(&[u8])) {
-        f(&self.inner.config.lock().0)
+        f(&*self.inner.config.lock())
+    }
+    fn config_write(&mut self, offset: usize, value: u64, _size: u32) {
+        if offset != CONFIG_EMERG_WR {
+            error!(target: "VirtioConsole", "config register write 0x{:x} = 0x{:x}", offset, value);
+            return;
+        }
+        // Used by the driver before queues are set up or when they cannot be relied on, e.g. in
+        // a panic, so output without waiting.
+        if let Err(err) = self.inner.console.try_write(&[value as u8]) {
+            warn!(target: "VirtioConsole", "emergency write dropped: {}", err);
+        }
     }
     fn num_queues(&self) -> usize {
         2
@@ -184,12 +207,59 @@ impl Device for Console {
     }
 
     fn interrupt_status(&mut self) -> u32 {
-        if self.inner.config.lock().1 { 3 } else { 1 }
+        self.inner.config_change.interrupt_status()
     }
 
     fn interrupt_ack(&mut self, ack: u32) {
-        if ack & 2 != 0 {
-            self.inner.config.lock().1 = false
+        self.inner.config_change.interrupt_ack(ack)
+    }
+
+    fn config_generation(&mut self) -> u32 {
+        self.inner.config_change.generation()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::queue::testing::Driver;
+    use super::*;
+    use crate::testing::{NoIrq, TestRuntime};
+    use std::io::Result;
+    use std::task::{Context, Poll};
+
+    /// A serial device collecting output and never producing input.
+    #[derive(Default)]
+    struct Sink(Mutex<Vec<u8>>);
+
+    impl Serial for Sink {
+        fn poll_write(&self, _cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>> {
+            self.0.lock().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_read(&self, _cx: &mut Context, _buf: &mut [u8]) -> Poll<Result<usize>> {
+            Poll::Pending
         }
     }
+
+    #[test]
+    fn test_transmit() {
+        let sink = Arc::new(Sink::default());
+        let tasks = Arc::new(TestRuntime::default());
+        let mut console =
+            Console::new(tasks.clone(), Box::new(NoIrq), Box::new(sink.clone()), false);
+        assert_ne!(console.device_feature() & 1 << VIRTIO_CONSOLE_F_EMERG_WRITE, 0);
+
+        // Characters can be output through the config space before any queue is set up.
+        console.config_write(CONFIG_EMERG_WR, b'!' as u64, 4);
+        assert_eq!(&*sink.0.lock(), b"!");
+
+        let (mut driver, queue) = Driver::new();
+        console.queue_ready(1, queue);
+        let data = driver.data_addr();
+        unsafe { std::ptr::copy_nonoverlapping(b"hello, world".as_ptr(), data as *mut u8, 12) };
+        driver.submit(&[(data, 5, false), (data + 5, 7, false)]);
+        tasks.poll_all();
+        assert_eq!(&*sink.0.lock(), b"!hello, world");
+    }
 }
@@ -1,6 +1,6 @@
+use super::transport::{QueueRegister, Transport};
 use super::Device;
 use crate::{DmaContext, IoMemoryMut};
-use parking_lot::Mutex;
 use std::sync::Arc;
 
 const ADDR_MAGIC_VALUE: usize = 0x000;
@@ -32,39 +32,47 @@ const ADDR_CONFIG: usize = 0x100;
 ///
 /// Note: Currently drop is not properly implemented and may cause memory and resource leak.
 pub struct Mmio {
-    device: Box<dyn Device>,
-    queues: Vec<Arc<Mutex<super::queue::QueueInner>>>,
+    transport: Transport,
     device_features_sel: bool,
     driver_features_sel: bool,
-    queue_sel: usize,
-    dma_ctx: Arc<dyn DmaContext>,
 }
 
 impl Mmio {
     /// Create a virtio device with MMIO transport.
     pub fn new(dma_ctx: Arc<dyn DmaContext>, dev: Box<dyn Device>) -> Mmio {
-        let num_queues = dev.num_queues();
-        let mut queues = Vec::with_capacity(num_queues);
-        for i in 0..num_queues {
-            let len_max = dev.max_queue_len(i);
-            let queue = super::queue::QueueInner::new(dma_ctx.clone(), len_max);
-            queues.push(queue);
-        }
         Mmio {
-            device: dev,
-            queues,
+            transport: Transport::new(dma_ctx, dev, "Mmio"),
             device_features_sel: false,
             driver_features_sel: false,
-            queue_sel: 0,
-            dma_ctx,
         }
     }
+
+    /// Set the number of descriptors of a chain to read from guest memory at once.
+    pub fn with_prefetch(mut self, prefetch: u16) -> Mmio {
+        self.transport.set_prefetch(prefetch);
+        self
+    }
+}
+
+/// Register of the selected queue at `addr`.
+fn queue_register(addr: usize) -> Option<QueueRegister> {
+    Some(match addr {
+        ADDR_QUEUE_NUM => QueueRegister::Size,
+        ADDR_QUEUE_READY => QueueRegister::Ready,
+        ADDR_QUEUE_DESC_LOW => QueueRegister::DescLow,
+        ADDR_QUEUE_DESC_HIGH => QueueRegister::DescHigh,
+        ADDR_QUEUE_AVAIL_LOW => QueueRegister::DriverLow,
+        ADDR_QUEUE_AVAIL_HIGH => QueueRegister::DriverHigh,
+        ADDR_QUEUE_USED_LOW => QueueRegister::DeviceLow,
+        ADDR_QUEUE_USED_HIGH => QueueRegister::DeviceHigh,
+        _ => return None,
+    })
 }
 
 impl IoMemoryMut for Mmio {
     fn read_mut(&mut self, addr: usize, size: u32) -> u64 {
         if addr >= ADDR_CONFIG {
-            let value = self.device.config_read(addr - ADDR_CONFIG, size);
+            let value = self.transport.device.config_read(addr - ADDR_CONFIG, size);
             trace!(target: "Mmio", "config register read 0x{:x} = 0x{:x}", addr, value);
             return value;
         }
@@ -75,7 +83,7 @@ impl IoMemoryMut for Mmio {
         let ret = match addr {
             ADDR_MAGIC_VALUE => 0x74726976,
             ADDR_VERSION => 2,
-            ADDR_DEVICE_ID => self.device.device_id() as u32,
+            ADDR_DEVICE_ID => self.transport.device.device_id() as u32,
             // This field is a PCI vendor, we use 0xFFFF because it indicates invalid (N/A)
             ADDR_VENDOR_ID => 0xffff,
             ADDR_DEVICE_FEATURES => {
@@ -83,38 +91,25 @@ impl IoMemoryMut for Mmio {
                     // VIRTIO_F_VERSION_1 is always set
                     1
                 } else {
-                    self.device.device_feature()
+                    self.transport.device.device_feature()
                 }
             }
             ADDR_DEVICE_FEATURES_SEL => self.device_features_sel as u32,
             ADDR_DRIVER_FEATURES_SEL => self.driver_features_sel as u32,
-            ADDR_QUEUE_SEL => self.queue_sel as u32,
-            ADDR_QUEUE_NUM_MAX => match self.queues.get(self.queue_sel) {
-                None => 0,
-                Some(queue) => queue.lock().num_max as u32,
-            },
+            ADDR_QUEUE_SEL => self.transport.queue_sel as u32,
+            ADDR_QUEUE_NUM_MAX => self.transport.queue_num_max() as u32,
             ADDR_QUEUE_NUM | ADDR_QUEUE_READY | ADDR_QUEUE_DESC_LOW..=ADDR_QUEUE_USED_HIGH => {
-                if self.queue_sel >= self.device.num_queues() {
-                    error!(target: "Mmio", "attempting to access unavailable queue {}", self.queue_sel);
-                    return 0;
-                }
-                let queue = self.queues[self.queue_sel].lock();
-                match addr {
-                    ADDR_QUEUE_NUM => queue.num as u32,
-                    ADDR_QUEUE_READY => queue.ready as u32,
-                    ADDR_QUEUE_DESC_LOW => queue.desc_addr as u32,
-                    ADDR_QUEUE_DESC_HIGH => (queue.desc_addr >> 32) as u32,
-                    ADDR_QUEUE_AVAIL_LOW => queue.avail_addr as u32,
-                    ADDR_QUEUE_AVAIL_HIGH => (queue.avail_addr >> 32) as u32,
-                    ADDR_QUEUE_USED_LOW => queue.used_addr as u32,
-                    ADDR_QUEUE_USED_HIGH => (queue.used_addr >> 32) as u32,
-                    _ => unreachable!(),
+                match queue_register(addr) {
+                    Some(reg) => self.transport.queue_read(reg),
+                    None => {
+                        error!(target: "Mmio", "illegal register read 0x{:x}", addr);
+                        0
+                    }
                 }
             }
-            // As currently config space is readonly, the interrupt status must be an used buffer.
-            ADDR_INTERRUPT_STATUS => self.device.interrupt_status(),
-            ADDR_STATUS => self.device.get_status(),
-            ADDR_CONFIG_GENERATION => 0,
+            ADDR_INTERRUPT_STATUS => self.transport.device.interrupt_status(),
+            ADDR_STATUS => self.transport.device.get_status(),
+            ADDR_CONFIG_GENERATION => self.transport.device.config_generation(),
             _ => {
                 error!(target: "Mmio", "illegal register read 0x{:x}", addr);
                 0
@@ -126,7 +121,7 @@ impl IoMemoryMut for Mmio {
 
     fn write_mut(&mut self, addr: usize, value: u64, size: u32) {
         if addr >= ADDR_CONFIG {
-            self.device.config_write(addr - ADDR_CONFIG, value, size);
+            self.transport.device.config_write(addr - ADDR_CONFIG, value, size);
             trace!(target: "Mmio", "config register write 0x{:x} = 0x{:x}", addr, value);
             return;
         }
@@ -152,9 +147,7 @@ impl IoMemoryMut for Mmio {
                         error!(target: "Mmio", "DriverFeatures do not have VIRTIO_F_VERSION_1 set")
                     }
                 } else {
-                    // Only the lowest 24-bits are for the device.
-                    self.device.driver_feature(value & 0xffffff);
-                    trace!(target: "Mmio", "DriverFeatures set to {:24b}", value);
+                    self.transport.driver_feature(value);
                 }
             }
             ADDR_DRIVER_FEATURES_SEL => {
@@ -166,86 +159,113 @@ impl IoMemoryMut for Mmio {
                     error!(target: "Mmio", "DriverFeaturesSel register is set to {}", value)
                 }
             }
-            ADDR_QUEUE_SEL => self.queue_sel = value as usize,
-            ADDR_QUEUE_NOTIFY => {
-                if self.queue_sel >= self.device.num_queues() {
-                    error!(target: "Mmio", "attempting to access unavailable queue {}", self.queue_sel);
-                    return;
-                }
-                if let Some(waker) = self.queues[self.queue_sel].lock().waker.take() {
-                    waker.wake();
-                }
-            }
+            ADDR_QUEUE_SEL => self.transport.queue_sel = value as usize,
+            ADDR_QUEUE_NOTIFY => self.transport.notify(self.transport.queue_sel),
             ADDR_QUEUE_NUM..=ADDR_QUEUE_READY | ADDR_QUEUE_DESC_LOW..=ADDR_QUEUE_USED_HIGH => {
-                if self.queue_sel >= self.device.num_queues() {
-                    error!(target: "Mmio", "attempting to access unavailable queue {}", self.queue_sel);
-                    return;
-                }
-                let mut queue = self.queues[self.queue_sel].lock();
-                match addr {
-                    ADDR_QUEUE_NUM => {
-                        if value.is_power_of_two() && value <= queue.num_max as u32 {
-                            queue.num = value as u16
-                        } else {
-                            error!(target: "Mmio", "invalid queue size {}", value)
-                        }
-                    }
-                    ADDR_QUEUE_READY => queue.ready = (value & 1) != 0,
-                    ADDR_QUEUE_DESC_LOW => {
-                        queue.desc_addr = (queue.desc_addr & !0xffffffff) | value as u64
-                    }
-                    ADDR_QUEUE_DESC_HIGH => {
-                        queue.desc_addr = (queue.desc_addr & 0xffffffff) | (value as u64) << 32
+                match queue_register(addr) {
+                    Some(reg) => self.transport.queue_write(reg, value),
+                    None => {
+                        error!(target: "Mmio", "illegal register write 0x{:x} = 0x{:x}", addr, value)
                     }
-                    ADDR_QUEUE_AVAIL_LOW => {
-                        queue.avail_addr = (queue.avail_addr & !0xffffffff) | value as u64
-                    }
-                    ADDR_QUEUE_AVAIL_HIGH => {
-                        queue.avail_addr = (queue.avail_addr & 0xffffffff) | (value as u64) << 32
-                    }
-                    ADDR_QUEUE_USED_LOW => {
-                        queue.used_addr = (queue.used_addr & !0xffffffff) | value as u64
-                    }
-                    ADDR_QUEUE_USED_HIGH => {
-                        queue.used_addr = (queue.used_addr & 0xffffffff) | (value as u64) << 32
-                    }
-                    _ => unreachable!(),
-                }
-                std::mem::drop(queue);
-
-                if addr == ADDR_QUEUE_READY && value & 1 != 0 {
-                    self.device.queue_ready(
-                        self.queue_sel,
-                        super::Queue { inner: self.queues[self.queue_sel].clone() },
-                    );
                 }
             }
-            ADDR_INTERRUPT_ACK => self.device.interrupt_ack(value),
+            ADDR_INTERRUPT_ACK => self.transport.device.interrupt_ack(value),
             ADDR_STATUS => {
                 if value == 0 {
-                    self.device.reset();
-                    // Upon reset, reset all queues, and replace them with new queue instances.
-                    // Replacing them can hopefully allow devices to gracefully terminate tasks.
-                    for (i, queue) in self.queues.iter_mut().enumerate() {
-                        {
-                            let mut lock = queue.lock();
-                            lock.reset();
-                            lock.waker.take().map(|x| x.wake());
-                        }
-                        let inner = super::queue::QueueInner::new(
-                            self.dma_ctx.clone(),
-                            self.device.max_queue_len(i),
-                        );
-                        *queue = inner;
-                    }
-                    self.queue_sel = 0;
+                    self.transport.reset();
                     self.device_features_sel = false;
                     self.driver_features_sel = false;
                 } else {
-                    self.device.set_status(value);
+                    self.transport.device.set_status(value);
                 }
             }
             _ => error!(target: "Mmio", "illegal register write 0x{:x} = 0x{:x}", addr, value),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ConfigChange, DeviceId, Queue};
+    use super::*;
+    use crate::IrqPin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// DMA context for devices without queues.
+    struct NoDma;
+
+    impl DmaContext for NoDma {
+        fn dma_read(&self, _addr: u64, _buf: &mut [u8]) {
+            unreachable!()
+        }
+
+        fn dma_write(&self, _addr: u64, _buf: &[u8]) {
+            unreachable!()
+        }
+
+        fn read_u16(&self, _addr: u64) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&self, _addr: u64, _value: u16) {
+            unreachable!()
+        }
+    }
+
+    struct CountingIrq(Arc<AtomicUsize>);
+
+    impl IrqPin for CountingIrq {
+        fn set_level(&self, level: bool) {
+            if level {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// A device whose configuration is changed from outside.
+    struct Resizable(Arc<ConfigChange>);
+
+    impl Device for Resizable {
+        fn device_id(&self) -> DeviceId {
+            DeviceId::Block
+        }
+        fn get_status(&self) -> u32 {
+            0
+        }
+        fn set_status(&mut self, _status: u32) {}
+        fn num_queues(&self) -> usize {
+            0
+        }
+        fn reset(&mut self) {}
+        fn queue_ready(&mut self, _idx: usize, _queue: Queue) {}
+        fn interrupt_status(&mut self) -> u32 {
+            self.0.interrupt_status()
+        }
+        fn interrupt_ack(&mut self, ack: u32) {
+            self.0.interrupt_ack(ack)
+        }
+        fn config_generation(&mut self) -> u32 {
+            self.0.generation()
+        }
+    }
+
+    #[test]
+    fn test_config_change_interrupt() {
+        let change = Arc::new(ConfigChange::default());
+        let mut mmio = Mmio::new(Arc::new(NoDma), Box::new(Resizable(change.clone())));
+        assert_eq!(mmio.read_mut(ADDR_INTERRUPT_STATUS, 4), 1);
+        let generation = mmio.read_mut(ADDR_CONFIG_GENERATION, 4);
+
+        let pulses = Arc::new(AtomicUsize::new(0));
+        change.notify(&CountingIrq(pulses.clone()));
+        assert_eq!(pulses.load(Ordering::Relaxed), 1);
+        assert_eq!(mmio.read_mut(ADDR_INTERRUPT_STATUS, 4), 3);
+        assert_ne!(mmio.read_mut(ADDR_CONFIG_GENERATION, 4), generation);
+
+        // Acknowledging used buffers alone leaves the config change pending.
+        mmio.write_mut(ADDR_INTERRUPT_ACK, 1, 4);
+        assert_eq!(mmio.read_mut(ADDR_INTERRUPT_STATUS, 4), 3);
+        mmio.write_mut(ADDR_INTERRUPT_ACK, 2, 4);
+        assert_eq!(mmio.read_mut(ADDR_INTERRUPT_STATUS, 4), 1);
+    }
+}
@@ -1,8 +1,13 @@
+use crate::IrqPin;
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 mod mmio;
+mod pci;
 mod queue;
+mod transport;
 pub use mmio::Mmio;
+pub use pci::Pci;
 pub use queue::{Buffer, BufferReader, BufferWriter, Queue, QueueNotReady};
 
 #[cfg(feature = "virtio-network")]
@@ -13,7 +18,7 @@ pub use network::Network;
 #[cfg(feature = "virtio-block")]
 mod block;
 #[cfg(feature = "virtio-block")]
-pub use block::Block;
+pub use block::{Block, CacheMode};
 
 #[cfg(feature = "virtio-rng")]
 mod rng;
@@ -30,6 +35,94 @@ mod console;
 #[cfg(feature = "virtio-console")]
 pub use console::Console;
 
+/// Default bound on the number of requests a device worker takes from a queue at a time.
+#[cfg(any(feature = "virtio-block", feature = "virtio-p9"))]
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// Serve requests from `queue` until it stops being ready.
+///
+/// This is the common loop of device workers. At most `max_in_flight` buffers are taken from the
+/// queue at a time and passed to `handler`, after which they are completed together with a single
+/// interrupt. Further buffers are left in the available ring until then, so a driver flooding the
+/// queue cannot make the device hold an unbounded number of requests.
+#[cfg(any(feature = "virtio-block", feature = "virtio-p9"))]
+async fn serve(
+    mut queue: Queue,
+    max_in_flight: usize,
+    irq: &dyn IrqPin,
+    mut handler: impl FnMut(&mut [Buffer]),
+) {
+    while let Ok(mut batch) = queue.take_batch(max_in_flight).await {
+        handler(&mut batch);
+        queue.put_batch(batch);
+        if queue.should_notify() {
+            irq.pulse();
+        }
+    }
+}
+
+/// Feature bit indicating that the driver can use indirect descriptor tables. Queues always
+/// support them, so devices may offer the feature freely.
+const VIRTIO_RING_F_INDIRECT_DESC: usize = 28;
+
+/// Feature bit indicating that the driver and device can suppress notifications with
+/// `used_event` and `avail_event`. Devices offering it check [`Queue::should_notify`] before
+/// sending an interrupt for used buffers.
+const VIRTIO_RING_F_EVENT_IDX: usize = 29;
+
+/// Interrupt status bit indicating that a device has used buffers.
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// Interrupt status bit indicating that the configuration space of a device has changed.
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// Configuration change state of a device, which can be shared with its tasks.
+///
+/// A device that changes its configuration space calls [`notify`](ConfigChange::notify), which
+/// sets [`INTERRUPT_CONFIG_CHANGE`] in the interrupt status until the driver acknowledges it and
+/// bumps the configuration generation, so the driver can detect torn reads.
+#[derive(Default)]
+pub struct ConfigChange {
+    pending: AtomicBool,
+    generation: AtomicU32,
+}
+
+impl ConfigChange {
+    /// Record a configuration change without sending an interrupt.
+    pub fn mark(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.pending.store(true, Ordering::Release);
+    }
+
+    /// Record a configuration change and send an interrupt to the driver.
+    pub fn notify(&self, irq: &dyn IrqPin) {
+        self.mark();
+        irq.pulse();
+    }
+
+    /// Interrupt status to report to the driver. Used buffer notifications are not tracked, so
+    /// [`INTERRUPT_USED_BUFFER`] is always set.
+    pub fn interrupt_status(&self) -> u32 {
+        if self.pending.load(Ordering::Acquire) {
+            INTERRUPT_USED_BUFFER | INTERRUPT_CONFIG_CHANGE
+        } else {
+            INTERRUPT_USED_BUFFER
+        }
+    }
+
+    /// Handle an interrupt acknowledgement from the driver.
+    pub fn interrupt_ack(&self, ack: u32) {
+        if ack & INTERRUPT_CONFIG_CHANGE != 0 {
+            self.pending.store(false, Ordering::Release);
+        }
+    }
+
+    /// Current configuration generation.
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
 /// Types of virtio devices.
 #[derive(Clone, Copy)]
 #[non_exhaustive]
@@ -109,11 +202,18 @@ pub trait Device: Send {
     /// Notify the device that the queue is ready
     fn queue_ready(&mut self, idx: usize, queue: Queue);
 
-    /// Query what has caused the interrupt to be sent.
+    /// Query what has caused the interrupt to be sent. Devices whose configuration space can
+    /// change should report [`INTERRUPT_CONFIG_CHANGE`], e.g. using [`ConfigChange`].
     fn interrupt_status(&mut self) -> u32 {
-        1
+        INTERRUPT_USED_BUFFER
     }
 
     /// Answer the interrupt.
     fn interrupt_ack(&mut self, _ack: u32) {}
+
+    /// Get the configuration generation, which must change whenever the configuration space
+    /// changes.
+    fn config_generation(&mut self) -> u32 {
+        0
+    }
 }
@@ -6,7 +6,7 @@ use futures::future::AbortHandle;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
 
-const VIRTIO_BLK_F_MAC: usize = 5;
+const VIRTIO_NET_F_MAC: usize = 5;
 
 #[repr(C)]
 struct VirtioNetHeader {
@@ -56,6 +56,7 @@ impl Network {
                 if reader.len() <= hdr_len {
                     // Unexpected packet
                     error!(target: "VirtioNet", "illegal transmission with size {} smaller than header {}", reader.len(), hdr_len);
+                    continue;
                 }
 
                 // We don't need any of the fields of the header, so just skip it.
@@ -68,7 +69,9 @@ impl Network {
                 drop(buffer);
 
                 inner.net.send(&io_buffer).await.unwrap();
-                inner.irq.pulse();
+                if tx.should_notify() {
+                    inner.irq.pulse();
+                }
             }
         }));
     }
@@ -104,13 +107,15 @@ impl Network {
                                     "discard packet of size {:x} because it does not fit into buffer of size {:x}",
                                     len, writer.len()
                                 );
-                                return;
+                                continue;
                             }
                             writer.write_all(&header).unwrap();
                             writer.write_all(&buffer[..len]).unwrap();
                             drop(dma_buffer);
 
-                            inner.irq.pulse();
+                            if rx.should_notify() {
+                                inner.irq.pulse();
+                            }
                         }
                         Ok(None) => info!(
                             target: "VirtioNet",
@@ -130,7 +135,7 @@ impl Device for Network {
         DeviceId::Network
     }
     fn device_feature(&self) -> u32 {
-        1 << VIRTIO_BLK_F_MAC
+        1 << VIRTIO_NET_F_MAC | 1 << super::VIRTIO_RING_F_EVENT_IDX
     }
     fn driver_feature(&mut self, _value: u32) {}
     fn get_status(&self) -> u32 {
@@ -158,3 +163,77 @@ impl Device for Network {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::queue::testing::{Driver, HostDma};
+    use super::*;
+    use crate::testing::{NoIrq, TestRuntime};
+    use crate::DmaContext;
+    use parking_lot::Mutex;
+    use std::collections::VecDeque;
+    use std::task::{Context, Poll};
+
+    /// A network that echoes each frame sent back to the receiver.
+    #[derive(Default)]
+    struct Echo(Mutex<VecDeque<Vec<u8>>>);
+
+    impl NetworkDevice for Echo {
+        fn poll_send(&self, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.0.lock().push_back(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_recv(&self, _cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+            match self.0.lock().pop_front() {
+                Some(frame) => {
+                    buf[..frame.len()].copy_from_slice(&frame);
+                    Poll::Ready(Ok(frame.len()))
+                }
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let tasks = Arc::new(TestRuntime::default());
+        let mac = MacAddress::parse_str("02:00:00:00:00:01").unwrap();
+        let mut device = Network::new(tasks.clone(), Box::new(NoIrq), Echo::default(), mac);
+        assert_eq!(device.device_feature() & 1 << VIRTIO_NET_F_MAC, 1 << VIRTIO_NET_F_MAC);
+        assert_eq!(device.config_space(), &[2, 0, 0, 0, 0, 1]);
+
+        let hdr_len = std::mem::size_of::<VirtioNetHeader>();
+        assert_eq!(hdr_len, 12);
+        let frame: Vec<u8> = (0..60).collect();
+
+        // The first receive buffer is too small, so the first frame is dropped, but the second
+        // one must still be received.
+        let (mut rx_driver, rx) = Driver::new();
+        let rx_addr = rx_driver.data_addr() + 0x100;
+        rx_driver.submit(&[(rx_driver.data_addr(), 16, true)]);
+        rx_driver.submit(&[(rx_addr, 2048, true)]);
+        let (mut tx_driver, tx) = Driver::new();
+        for &tx_addr in &[tx_driver.data_addr(), tx_driver.data_addr() + 0x100] {
+            HostDma.dma_write(tx_addr, &[0; 12]);
+            HostDma.dma_write(tx_addr + 12, &frame);
+            tx_driver.submit(&[(tx_addr, (hdr_len + frame.len()) as u32, false)]);
+        }
+        device.queue_ready(0, rx);
+        device.queue_ready(1, tx);
+
+        // Transmit, then receive the echoed frames.
+        tasks.poll_all();
+        tasks.poll_all();
+
+        // The second frame is received with a header, in which only `num_buffers` is set.
+        assert_eq!(HostDma.read_u16(rx_driver.used_addr() + 2), 2);
+        let mut used = [0; 8];
+        HostDma.dma_read(rx_driver.used_addr() + 12, &mut used);
+        assert_eq!(used, [1, 0, 0, 0, 72, 0, 0, 0]);
+        let mut received = vec![0; hdr_len + frame.len()];
+        HostDma.dma_read(rx_addr, &mut received);
+        assert_eq!(received[..hdr_len], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(received[hdr_len..], frame[..]);
+    }
+}
@@ -16,6 +16,7 @@ const VIRTIO_9P_MOUNT_TAG: u32 = 1;
 pub struct P9<FS: FileSystem> {
     status: u32,
     config: Box<[u8]>,
+    max_in_flight: usize,
     ctx: Arc<dyn RuntimeContext>,
     inner: Arc<Inner<FS>>,
 }
@@ -53,33 +54,48 @@ where
             irq: Arc::new(irq),
         });
 
-        P9 { status: 0, config: config.into_boxed_slice(), ctx, inner }
+        P9 {
+            status: 0,
+            config: config.into_boxed_slice(),
+            max_in_flight: super::DEFAULT_MAX_IN_FLIGHT,
+            ctx,
+            inner,
+        }
     }
 
-    fn start_task(&self, mut queue: Queue) {
+    /// Set the maximum number of requests taken from the queue at a time.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        assert_ne!(max_in_flight, 0);
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    fn start_task(&self, queue: Queue) {
         let inner = self.inner.clone();
+        let max_in_flight = self.max_in_flight;
         self.ctx.spawn_blocking(
             "virtio-p9",
             Box::pin(async move {
-                while let Ok(mut buffer) = queue.take().await {
-                    let (mut reader, mut writer) = buffer.reader_writer();
-
-                    reader.seek(SeekFrom::Start(4)).unwrap();
-                    let (tag, fcall) = <(u16, Fcall)>::decode(&mut reader).unwrap();
+                super::serve(queue, max_in_flight, &**inner.irq, |batch| {
+                    let mut handler = inner.handler.lock();
+                    for buffer in batch.iter_mut() {
+                        let (mut reader, mut writer) = buffer.reader_writer();
 
-                    trace!(target: "9p", "received {}, {:?}", tag, fcall);
-                    let resp = inner.handler.lock().handle_fcall(fcall);
-                    trace!(target: "9p", "send {}, {:?}", tag, resp);
+                        reader.seek(SeekFrom::Start(4)).unwrap();
+                        let (tag, fcall) = <(u16, Fcall)>::decode(&mut reader).unwrap();
 
-                    writer.seek(SeekFrom::Start(4)).unwrap();
-                    (tag, resp).encode(&mut writer).unwrap();
-                    let size = writer.seek(SeekFrom::Current(0)).unwrap();
-                    writer.seek(SeekFrom::Start(0)).unwrap();
-                    writer.write_u32::<LE>(size as u32).unwrap();
+                        trace!(target: "9p", "received {}, {:?}", tag, fcall);
+                        let resp = handler.handle_fcall(fcall);
+                        trace!(target: "9p", "send {}, {:?}", tag, resp);
 
-                    drop(buffer);
-                    inner.irq.pulse();
-                }
+                        writer.seek(SeekFrom::Start(4)).unwrap();
+                        (tag, resp).encode(&mut writer).unwrap();
+                        let size = writer.seek(SeekFrom::Current(0)).unwrap();
+                        writer.seek(SeekFrom::Start(0)).unwrap();
+                        writer.write_u32::<LE>(size as u32).unwrap();
+                    }
+                })
+                .await
             }),
         );
     }
@@ -0,0 +1,423 @@
+use super::transport::{QueueRegister, Transport};
+use super::{Device, DeviceId};
+use crate::hw::pci::Function;
+use crate::{DmaContext, IoMemoryMut};
+use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::Arc;
+
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_REVISION_ID: usize = 0x08;
+const PCI_CLASS_PROG: usize = 0x09;
+const PCI_HEADER_TYPE: usize = 0x0e;
+const PCI_BAR0: usize = 0x10;
+const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+const PCI_SUBSYSTEM_ID: usize = 0x2e;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_INTERRUPT_LINE: usize = 0x3c;
+const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+/// Device id of modern virtio devices are this plus the virtio device id.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Layout of BAR 0. Each structure gets a page of its own.
+const BAR_COMMON_CFG: usize = 0x0000;
+const BAR_ISR_CFG: usize = 0x1000;
+const BAR_DEVICE_CFG: usize = 0x2000;
+const BAR_NOTIFY_CFG: usize = 0x3000;
+
+/// Size of BAR 0, which holds all virtio structures.
+const BAR_SIZE: usize = 0x4000;
+
+/// Distance between the notification addresses of consecutive queues.
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+const ADDR_DEVICE_FEATURE_SELECT: usize = 0x00;
+const ADDR_DEVICE_FEATURE: usize = 0x04;
+const ADDR_DRIVER_FEATURE_SELECT: usize = 0x08;
+const ADDR_DRIVER_FEATURE: usize = 0x0c;
+const ADDR_MSIX_CONFIG: usize = 0x10;
+const ADDR_NUM_QUEUES: usize = 0x12;
+const ADDR_DEVICE_STATUS: usize = 0x14;
+const ADDR_CONFIG_GENERATION: usize = 0x15;
+const ADDR_QUEUE_SELECT: usize = 0x16;
+const ADDR_QUEUE_SIZE: usize = 0x18;
+const ADDR_QUEUE_MSIX_VECTOR: usize = 0x1a;
+const ADDR_QUEUE_ENABLE: usize = 0x1c;
+const ADDR_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const ADDR_QUEUE_DESC_LOW: usize = 0x20;
+const ADDR_QUEUE_DESC_HIGH: usize = 0x24;
+const ADDR_QUEUE_DRIVER_LOW: usize = 0x28;
+const ADDR_QUEUE_DRIVER_HIGH: usize = 0x2c;
+const ADDR_QUEUE_DEVICE_LOW: usize = 0x30;
+const ADDR_QUEUE_DEVICE_HIGH: usize = 0x34;
+
+/// MSI-X is not supported, so vectors always read as none.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// Virtio device with PCI transport.
+///
+/// The function is attached to a PCI host bridge through [`Function`]. All virtio structures live
+/// in a 16 KiB 64-bit memory BAR 0. Interrupts are sent using INTx by the device itself, as MSI-X
+/// is not supported.
+///
+/// Note: Currently drop is not properly implemented and may cause memory and resource leak.
+pub struct Pci {
+    transport: Transport,
+    /// Standard header and capability list.
+    header: [u8; 256],
+    device_feature_select: u32,
+    driver_feature_select: u32,
+}
+
+impl Pci {
+    /// Create a virtio device with PCI transport.
+    pub fn new(dma_ctx: Arc<dyn DmaContext>, dev: Box<dyn Device>) -> Pci {
+        let header = Self::header(dev.device_id());
+        Pci {
+            transport: Transport::new(dma_ctx, dev, "Pci"),
+            header,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+        }
+    }
+
+    /// Set the number of descriptors of a chain to read from guest memory at once.
+    pub fn with_prefetch(mut self, prefetch: u16) -> Pci {
+        self.transport.set_prefetch(prefetch);
+        self
+    }
+
+    /// Build the read-only part of the configuration space.
+    fn header(device_id: DeviceId) -> [u8; 256] {
+        let mut header = [0; 256];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            header[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(PCI_VENDOR_ID, &VIRTIO_PCI_VENDOR_ID.to_le_bytes());
+        put(PCI_DEVICE_ID, &(VIRTIO_PCI_DEVICE_ID_BASE + device_id as u16).to_le_bytes());
+        // The capability list is present.
+        put(PCI_STATUS, &0x10u16.to_le_bytes());
+        // Modern-only devices have revision 1 or above.
+        put(PCI_REVISION_ID, &[1]);
+        let class: u32 = match device_id {
+            DeviceId::Network => 0x020000,
+            DeviceId::Block => 0x010000,
+            DeviceId::Console => 0x078000,
+            _ => 0xff0000,
+        };
+        put(PCI_CLASS_PROG, &class.to_le_bytes()[..3]);
+        put(PCI_HEADER_TYPE, &[0]);
+        put(PCI_BAR0, &[0b100]);
+        put(PCI_SUBSYSTEM_VENDOR_ID, &VIRTIO_PCI_VENDOR_ID.to_le_bytes());
+        put(PCI_SUBSYSTEM_ID, &0x40u16.to_le_bytes());
+        put(PCI_INTERRUPT_PIN, &[1]);
+
+        // Capabilities are laid out one after another. Each is a `virtio_pci_cap`, with the
+        // multiplier appended for notifications.
+        let caps = [
+            (VIRTIO_PCI_CAP_COMMON_CFG, BAR_COMMON_CFG, 0x38),
+            (VIRTIO_PCI_CAP_NOTIFY_CFG, BAR_NOTIFY_CFG, 0x1000),
+            (VIRTIO_PCI_CAP_ISR_CFG, BAR_ISR_CFG, 1),
+            (VIRTIO_PCI_CAP_DEVICE_CFG, BAR_DEVICE_CFG, 0x1000),
+        ];
+        let mut cap_ptr = 0x40;
+        put(PCI_CAPABILITY_LIST, &[cap_ptr as u8]);
+        for (i, &(cfg_type, offset, length)) in caps.iter().enumerate() {
+            let cap_len = if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG { 20 } else { 16 };
+            let next = if i == caps.len() - 1 { 0 } else { cap_ptr + cap_len };
+            put(cap_ptr, &[PCI_CAP_ID_VNDR, next as u8, cap_len as u8, cfg_type, 0]);
+            put(cap_ptr + 8, &(offset as u32).to_le_bytes());
+            put(cap_ptr + 12, &(length as u32).to_le_bytes());
+            if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                put(cap_ptr + 16, &NOTIFY_OFF_MULTIPLIER.to_le_bytes());
+            }
+            cap_ptr += cap_len;
+        }
+        header
+    }
+
+    fn common_read(&mut self, addr: usize, size: u32) -> u64 {
+        let ret = match (addr, size) {
+            (ADDR_DEVICE_FEATURE_SELECT, 4) => self.device_feature_select,
+            (ADDR_DEVICE_FEATURE, 4) => match self.device_feature_select {
+                0 => self.transport.device.device_feature(),
+                // VIRTIO_F_VERSION_1 is always set
+                1 => 1,
+                _ => 0,
+            },
+            (ADDR_DRIVER_FEATURE_SELECT, 4) => self.driver_feature_select,
+            (ADDR_MSIX_CONFIG, 2) => VIRTIO_MSI_NO_VECTOR as u32,
+            (ADDR_NUM_QUEUES, 2) => self.transport.device.num_queues() as u32,
+            (ADDR_DEVICE_STATUS, 1) => self.transport.device.get_status(),
+            (ADDR_CONFIG_GENERATION, 1) => self.transport.device.config_generation() & 0xff,
+            (ADDR_QUEUE_SELECT, 2) => self.transport.queue_sel as u32,
+            (ADDR_QUEUE_MSIX_VECTOR, 2) => VIRTIO_MSI_NO_VECTOR as u32,
+            // Queues are notified at consecutive addresses.
+            (ADDR_QUEUE_NOTIFY_OFF, 2) => self.transport.queue_sel as u32,
+            (ADDR_QUEUE_SIZE, 2)
+            | (ADDR_QUEUE_ENABLE, 2)
+            | (ADDR_QUEUE_DESC_LOW..=ADDR_QUEUE_DEVICE_HIGH, 4) => match queue_register(addr) {
+                Some(reg) => self.transport.queue_read(reg),
+                None => {
+                    error!(target: "Pci", "illegal register read 0x{:x}", addr);
+                    0
+                }
+            },
+            _ => {
+                error!(target: "Pci", "illegal register read 0x{:x}", addr);
+                0
+            }
+        };
+        trace!(target: "Pci", "Read {:x} => {:x}", addr, ret);
+        ret as u64
+    }
+
+    fn common_write(&mut self, addr: usize, value: u64, size: u32) {
+        let value = value as u32;
+        trace!(target: "Pci", "register write 0x{:x} = 0x{:x}", addr, value);
+        match (addr, size) {
+            (ADDR_DEVICE_FEATURE_SELECT, 4) => self.device_feature_select = value,
+            (ADDR_DRIVER_FEATURE_SELECT, 4) => self.driver_feature_select = value,
+            (ADDR_DRIVER_FEATURE, 4) => match self.driver_feature_select {
+                0 => self.transport.driver_feature(value),
+                1 if value != 1 => {
+                    error!(target: "Pci", "DriverFeatures do not have VIRTIO_F_VERSION_1 set")
+                }
+                _ => (),
+            },
+            (ADDR_MSIX_CONFIG, 2) => (),
+            (ADDR_DEVICE_STATUS, 1) => {
+                if value == 0 {
+                    self.transport.reset();
+                    self.device_feature_select = 0;
+                    self.driver_feature_select = 0;
+                } else {
+                    self.transport.device.set_status(value);
+                }
+            }
+            (ADDR_QUEUE_SELECT, 2) => self.transport.queue_sel = value as usize,
+            (ADDR_QUEUE_MSIX_VECTOR, 2) => (),
+            (ADDR_QUEUE_SIZE, 2)
+            | (ADDR_QUEUE_ENABLE, 2)
+            | (ADDR_QUEUE_DESC_LOW..=ADDR_QUEUE_DEVICE_HIGH, 4) => match queue_register(addr) {
+                Some(reg) => self.transport.queue_write(reg, value),
+                None => {
+                    error!(target: "Pci", "illegal register write 0x{:x} = 0x{:x}", addr, value)
+                }
+            },
+            _ => error!(target: "Pci", "illegal register write 0x{:x} = 0x{:x}", addr, value),
+        }
+    }
+}
+
+/// Register of the selected queue at `addr` of the common configuration structure.
+fn queue_register(addr: usize) -> Option<QueueRegister> {
+    Some(match addr {
+        ADDR_QUEUE_SIZE => QueueRegister::Size,
+        ADDR_QUEUE_ENABLE => QueueRegister::Ready,
+        ADDR_QUEUE_DESC_LOW => QueueRegister::DescLow,
+        ADDR_QUEUE_DESC_HIGH => QueueRegister::DescHigh,
+        ADDR_QUEUE_DRIVER_LOW => QueueRegister::DriverLow,
+        ADDR_QUEUE_DRIVER_HIGH => QueueRegister::DriverHigh,
+        ADDR_QUEUE_DEVICE_LOW => QueueRegister::DeviceLow,
+        ADDR_QUEUE_DEVICE_HIGH => QueueRegister::DeviceHigh,
+        _ => return None,
+    })
+}
+
+impl Function for Pci {
+    fn config_read(&self, offset: usize, size: u32) -> u64 {
+        // The extended configuration space is not used.
+        if offset >= self.header.len() {
+            return 0;
+        }
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(&self.header[offset..offset + size as usize]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Only the command register, BAR 0 and the interrupt line are writable.
+    fn config_write(&mut self, offset: usize, value: u64, size: u32) {
+        trace!(target: "Pci", "config space write 0x{:x} = 0x{:x}", offset, value);
+        for i in 0..size as usize {
+            match offset + i {
+                PCI_COMMAND..=0x05 | PCI_BAR0..=0x17 | PCI_INTERRUPT_LINE => {
+                    self.header[offset + i] = (value >> (i * 8)) as u8
+                }
+                _ => (),
+            }
+        }
+        // The size of the BAR is probed by writing all ones, and reading back the bits that
+        // stick. The lowest bits indicate a 64-bit memory BAR.
+        let bar = self.bar().start | 0b100;
+        self.header[PCI_BAR0..PCI_BAR0 + 8].copy_from_slice(&bar.to_le_bytes());
+    }
+
+    fn bar(&self) -> Range<u64> {
+        let bar = u64::from_le_bytes(self.header[PCI_BAR0..PCI_BAR0 + 8].try_into().unwrap());
+        let start = bar & !(BAR_SIZE as u64 - 1);
+        start..start.wrapping_add(BAR_SIZE as u64)
+    }
+}
+
+impl IoMemoryMut for Pci {
+    fn read_mut(&mut self, addr: usize, size: u32) -> u64 {
+        match addr {
+            BAR_COMMON_CFG..=0xfff => self.common_read(addr, size),
+            BAR_ISR_CFG => {
+                // Reading the ISR status acknowledges the interrupt.
+                let status = self.transport.device.interrupt_status();
+                self.transport.device.interrupt_ack(status);
+                status as u64
+            }
+            BAR_DEVICE_CFG..=0x2fff => {
+                let value = self.transport.device.config_read(addr - BAR_DEVICE_CFG, size);
+                trace!(target: "Pci", "config register read 0x{:x} = 0x{:x}", addr, value);
+                value
+            }
+            _ => {
+                error!(target: "Pci", "illegal register read 0x{:x}", addr);
+                0
+            }
+        }
+    }
+
+    fn write_mut(&mut self, addr: usize, value: u64, size: u32) {
+        match addr {
+            BAR_COMMON_CFG..=0xfff => self.common_write(addr, value, size),
+            BAR_DEVICE_CFG..=0x2fff => {
+                self.transport.device.config_write(addr - BAR_DEVICE_CFG, value, size);
+                trace!(target: "Pci", "config register write 0x{:x} = 0x{:x}", addr, value);
+            }
+            BAR_NOTIFY_CFG..=0x3fff => {
+                self.transport.notify((addr - BAR_NOTIFY_CFG) / NOTIFY_OFF_MULTIPLIER as usize)
+            }
+            _ => error!(target: "Pci", "illegal register write 0x{:x} = 0x{:x}", addr, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DeviceId, Queue};
+    use super::*;
+
+    /// DMA context for devices without queues being used.
+    struct NoDma;
+
+    impl DmaContext for NoDma {
+        fn dma_read(&self, _addr: u64, _buf: &mut [u8]) {
+            unreachable!()
+        }
+
+        fn dma_write(&self, _addr: u64, _buf: &[u8]) {
+            unreachable!()
+        }
+
+        fn read_u16(&self, _addr: u64) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&self, _addr: u64, _value: u16) {
+            unreachable!()
+        }
+    }
+
+    struct Dummy;
+
+    impl Device for Dummy {
+        fn device_id(&self) -> DeviceId {
+            DeviceId::Block
+        }
+        fn device_feature(&self) -> u32 {
+            0x1234
+        }
+        fn get_status(&self) -> u32 {
+            0
+        }
+        fn set_status(&mut self, _status: u32) {}
+        fn config_space(&self) -> &[u8] {
+            &[0x11, 0x22, 0x33, 0x44]
+        }
+        fn num_queues(&self) -> usize {
+            2
+        }
+        fn reset(&mut self) {}
+        fn queue_ready(&mut self, _idx: usize, _queue: Queue) {}
+    }
+
+    #[test]
+    fn test_config_space() {
+        let mut pci = Pci::new(Arc::new(NoDma), Box::new(Dummy));
+        assert_eq!(pci.config_read(PCI_VENDOR_ID, 2), 0x1af4);
+        assert_eq!(pci.config_read(PCI_DEVICE_ID, 2), 0x1042);
+        assert_eq!(pci.config_read(PCI_VENDOR_ID, 4), 0x1042_1af4);
+        assert_ne!(pci.config_read(PCI_STATUS, 2) & 0x10, 0);
+
+        // Walk the capability list.
+        let mut caps = Vec::new();
+        let mut ptr = pci.config_read(PCI_CAPABILITY_LIST, 1) as usize;
+        while ptr != 0 {
+            assert_eq!(ptr % 4, 0);
+            assert_eq!(pci.config_read(ptr, 1), PCI_CAP_ID_VNDR as u64);
+            let cfg_type = pci.config_read(ptr + 3, 1) as u8;
+            let bar = pci.config_read(ptr + 4, 1);
+            let offset = pci.config_read(ptr + 8, 4) as usize;
+            let length = pci.config_read(ptr + 12, 4) as usize;
+            assert_eq!(bar, 0);
+            assert!(offset + length <= BAR_SIZE);
+            if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                assert_eq!(pci.config_read(ptr + 2, 1), 20);
+                let multiplier = pci.config_read(ptr + 16, 4) as usize;
+                // Every queue has its notification address within the structure.
+                let notify_off = pci.read_mut(ADDR_QUEUE_NOTIFY_OFF, 2) as usize;
+                assert!(notify_off * multiplier + 2 <= length);
+                pci.write_mut(ADDR_QUEUE_SELECT, 1, 2);
+                let notify_off = pci.read_mut(ADDR_QUEUE_NOTIFY_OFF, 2) as usize;
+                assert!(notify_off * multiplier + 2 <= length);
+                pci.write_mut(ADDR_QUEUE_SELECT, 0, 2);
+            }
+            caps.push((cfg_type, offset));
+            ptr = pci.config_read(ptr + 1, 1) as usize;
+        }
+        assert_eq!(
+            caps,
+            [
+                (VIRTIO_PCI_CAP_COMMON_CFG, BAR_COMMON_CFG),
+                (VIRTIO_PCI_CAP_NOTIFY_CFG, BAR_NOTIFY_CFG),
+                (VIRTIO_PCI_CAP_ISR_CFG, BAR_ISR_CFG),
+                (VIRTIO_PCI_CAP_DEVICE_CFG, BAR_DEVICE_CFG),
+            ]
+        );
+
+        // Probe the size of BAR 0, then program it.
+        pci.config_write(PCI_BAR0, 0xffffffff, 4);
+        pci.config_write(PCI_BAR0 + 4, 0xffffffff, 4);
+        let size = pci.config_read(PCI_BAR0, 4) | pci.config_read(PCI_BAR0 + 4, 4) << 32;
+        assert_eq!(size & 0b111, 0b100);
+        assert_eq!(!(size & !0b1111) + 1, BAR_SIZE as u64);
+        pci.config_write(PCI_BAR0, 0x4000_0000, 4);
+        pci.config_write(PCI_BAR0 + 4, 0x1, 4);
+        assert_eq!(pci.bar(), 0x1_4000_0000..0x1_4000_4000);
+        assert_eq!(pci.config_read(PCI_BAR0, 4), 0x4000_0004);
+
+        // The structures in the BAR.
+        assert_eq!(pci.read_mut(ADDR_NUM_QUEUES, 2), 2);
+        assert_eq!(pci.read_mut(ADDR_DEVICE_FEATURE, 4), 0x1234);
+        pci.write_mut(ADDR_DEVICE_FEATURE_SELECT, 1, 4);
+        assert_eq!(pci.read_mut(ADDR_DEVICE_FEATURE, 4), 1);
+        assert_eq!(pci.read_mut(BAR_DEVICE_CFG + 2, 2), 0x4433);
+        assert_eq!(pci.read_mut(BAR_ISR_CFG, 1), 1);
+    }
+}
@@ -8,8 +8,6 @@ use std::task::{Context, Poll, Waker};
 
 const VIRTQ_DESC_F_NEXT: u16 = 1;
 const VIRTQ_DESC_F_WRITE: u16 = 2;
-// We don't support indirect yet
-#[allow(dead_code)]
 const VIRTQ_DESC_F_INDIRECT: u16 = 4;
 
 /// Error when trying to take buffers from a virtio queue that is not ready.
@@ -24,6 +22,18 @@ struct VirtqDesc {
     next: u16,
 }
 
+/// A buffer put back to the used ring, recorded so tests can observe device behaviour.
+#[cfg(test)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) struct Completion {
+    /// Index of the used ring element that the buffer is written to.
+    pub used_idx: u16,
+    /// Index of the head descriptor of the buffer.
+    pub id: u16,
+    /// Number of bytes written into the buffer by the device.
+    pub len: u32,
+}
+
 /// Queue structures shared by both virtio and the device
 pub(super) struct QueueInner {
     pub ready: bool,
@@ -34,8 +44,17 @@ pub(super) struct QueueInner {
     pub used_addr: u64,
     pub last_avail_idx: u16,
     pub last_used_idx: u16,
+    /// Number of descriptors read from guest memory at once when following a chain. Values below
+    /// 2 disable prefetching.
+    pub prefetch: u16,
+    /// Whether `VIRTIO_RING_F_EVENT_IDX` is negotiated.
+    pub event_idx: bool,
+    /// Value of `last_used_idx` when it was last decided whether to notify the driver.
+    pub signalled_used_idx: u16,
     pub waker: Option<Waker>,
     pub dma_ctx: Arc<dyn DmaContext>,
+    #[cfg(test)]
+    pub completions: Vec<Completion>,
 }
 
 impl QueueInner {
@@ -50,7 +69,12 @@ impl QueueInner {
             waker: None,
             last_avail_idx: 0,
             last_used_idx: 0,
+            prefetch: 0,
+            event_idx: false,
+            signalled_used_idx: 0,
             dma_ctx,
+            #[cfg(test)]
+            completions: Vec::new(),
         }))
     }
 
@@ -63,6 +87,84 @@ impl QueueInner {
         self.waker = None;
         self.last_avail_idx = 0;
         self.last_used_idx = 0;
+        self.signalled_used_idx = 0;
+    }
+
+    /// Read `count` consecutive descriptors at `addr` with a single guest memory access.
+    fn read_descs(&self, addr: u64, count: usize) -> Vec<VirtqDesc> {
+        let mut bytes = vec![0; count * std::mem::size_of::<VirtqDesc>()];
+        self.dma_ctx.dma_read(addr, &mut bytes);
+        bytes
+            .chunks_exact(std::mem::size_of::<VirtqDesc>())
+            .map(|bytes| {
+                let mut desc = [0; std::mem::size_of::<VirtqDesc>()];
+                desc.copy_from_slice(bytes);
+                unsafe { std::mem::transmute(desc) }
+            })
+            .collect()
+    }
+
+    /// Follow the descriptor chain with head `idx`, adding its descriptors to `avail`.
+    fn read_chain(&self, mut idx: u16, avail: &mut Buffer) {
+        // Descriptors read ahead, and the slot of the first one.
+        let mut prefetched = Vec::new();
+        let mut prefetched_slot = 0;
+        for _ in 0..self.num {
+            let slot = idx & (self.num - 1);
+            if slot < prefetched_slot || slot >= prefetched_slot + prefetched.len() as u16 {
+                // Never read beyond the end of the descriptor table.
+                let count = std::cmp::min(std::cmp::max(self.prefetch, 1), self.num - slot);
+                prefetched = self.read_descs(self.desc_addr + slot as u64 * 16, count as usize);
+                prefetched_slot = slot;
+            }
+            let desc = prefetched[(slot - prefetched_slot) as usize];
+
+            if (desc.flags & VIRTQ_DESC_F_INDIRECT) != 0 {
+                self.read_indirect(desc, avail);
+                return;
+            }
+            avail.push(desc);
+
+            // Follow the linked list until we've see a descritpro without NEXT flag.
+            if (desc.flags & VIRTQ_DESC_F_NEXT) == 0 {
+                return;
+            }
+            idx = desc.next;
+        }
+
+        // A chain can never be longer than the queue without visiting a descriptor twice.
+        error!(target: "Virtio", "descriptor chain loops, truncated to {} descriptors", self.num);
+    }
+
+    /// Add descriptors of the indirect table that `desc` refers to to `avail`.
+    fn read_indirect(&self, desc: VirtqDesc, avail: &mut Buffer) {
+        let count = desc.len as usize / std::mem::size_of::<VirtqDesc>();
+        if count == 0 || count > self.num as usize {
+            error!(target: "Virtio", "indirect descriptor table of {} entries", count);
+            return;
+        }
+        let table = self.read_descs(desc.addr, count);
+
+        let mut idx = 0;
+        for _ in 0..count {
+            let desc = match table.get(idx as usize) {
+                Some(&desc) => desc,
+                None => {
+                    error!(target: "Virtio", "indirect descriptor {} is out of bound", idx);
+                    return;
+                }
+            };
+            if (desc.flags & VIRTQ_DESC_F_INDIRECT) != 0 {
+                error!(target: "Virtio", "indirect descriptor table refers to another one");
+                return;
+            }
+            avail.push(desc);
+            if (desc.flags & VIRTQ_DESC_F_NEXT) == 0 {
+                return;
+            }
+            idx = desc.next;
+        }
+        error!(target: "Virtio", "indirect descriptor chain loops, truncated to {} descriptors", count);
     }
 
     /// Try to get a buffer from the available ring. If there are no new buffers, `None` will be
@@ -78,20 +180,33 @@ impl QueueInner {
 
         // No extra elements in this queue
         if self.last_avail_idx == avail_idx {
-            return Ok(None);
+            if !self.event_idx {
+                return Ok(None);
+            }
+
+            // Ask the driver to notify once the next buffer is available through `avail_event`,
+            // so it need not notify while we are still working on buffers. The buffer may
+            // have been added before the driver sees the request, so check again.
+            let avail_event_addr = self.used_addr + 4 + self.num as u64 * 8;
+            self.dma_ctx.write_u16(avail_event_addr, self.last_avail_idx);
+            std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+            if self.dma_ctx.read_u16(self.avail_addr + 2) == self.last_avail_idx {
+                return Ok(None);
+            }
         }
 
         // Obtain the corresponding descriptor index for a given index of available ring.
         // Each index is 2 bytes, and there are flags and idx (2 bytes each) before the ring, so
         // we have + 4 here.
         let idx_ptr = self.avail_addr + 4 + (self.last_avail_idx & (self.num - 1)) as u64 * 2;
-        let mut idx = self.dma_ctx.read_u16(idx_ptr);
+        let idx = self.dma_ctx.read_u16(idx_ptr);
 
         // Now we have obtained this descriptor, increment the index to skip over this.
         self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
 
         let mut avail = Buffer {
             queue: arc.clone(),
+            completed: false,
             idx,
             bytes_written: 0,
             read: Vec::new(),
@@ -101,44 +216,51 @@ impl QueueInner {
             dma_ctx: self.dma_ctx.clone(),
         };
 
-        loop {
-            let mut desc = [0; std::mem::size_of::<VirtqDesc>()];
-            self.dma_ctx.dma_read(self.desc_addr + (idx & (self.num - 1)) as u64 * 16, &mut desc);
-            let desc: VirtqDesc = unsafe { std::mem::transmute(desc) };
-
-            // Add to the corresponding buffer (read/write)
-            if (desc.flags & VIRTQ_DESC_F_WRITE) == 0 {
-                avail.read.push((desc.addr, desc.len as usize));
-            } else {
-                avail.write.push((desc.addr, desc.len as usize));
-            };
-
-            avail.read_len = avail.read.iter().map(|(_, len)| len).sum();
-            avail.write_len = avail.write.iter().map(|(_, len)| len).sum();
-
-            // Follow the linked list until we've see a descritpro without NEXT flag.
-            if (desc.flags & VIRTQ_DESC_F_NEXT) == 0 {
-                break;
-            }
-            idx = desc.next;
-        }
-
+        self.read_chain(idx, &mut avail);
         Ok(Some(avail))
     }
 
-    /// Put back a buffer to the ring.
-    fn put(&mut self, avail: &Buffer) {
-        if !self.ready {
-            return;
+    /// Take up to `max` buffers currently in the available ring.
+    fn try_take_batch(
+        &mut self,
+        arc: &Arc<Mutex<Self>>,
+        max: usize,
+    ) -> Result<Vec<Buffer>, QueueNotReady> {
+        let mut batch = Vec::new();
+        while batch.len() < max {
+            match self.try_take(arc)? {
+                Some(buffer) => batch.push(buffer),
+                None => break,
+            }
         }
+        Ok(batch)
+    }
 
+    /// Write a buffer into the used ring, without making it visible to the driver.
+    fn put_elem(&mut self, avail: &Buffer) {
         let elem_ptr = self.used_addr + 4 + (self.last_used_idx & (self.num - 1)) as u64 * 8;
         let mut buffer = [0; 8];
         buffer[0..4].copy_from_slice(&(avail.idx as u32).to_le_bytes());
         buffer[4..8].copy_from_slice(&(avail.bytes_written as u32).to_le_bytes());
         self.dma_ctx.dma_write(elem_ptr, &buffer);
 
+        #[cfg(test)]
+        self.completions.push(Completion {
+            used_idx: self.last_used_idx,
+            id: avail.idx,
+            len: avail.bytes_written as u32,
+        });
+
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
+    }
+
+    /// Put back a buffer to the ring.
+    fn put(&mut self, avail: &Buffer) {
+        if !self.ready {
+            return;
+        }
+
+        self.put_elem(avail);
         self.dma_ctx.write_u16(self.used_addr + 2, self.last_used_idx);
     }
 }
@@ -186,11 +308,91 @@ impl Queue {
 
         Take { queue: self }.await
     }
+
+    /// Get up to `max` buffers from the available ring.
+    ///
+    /// Unlike [`take`](Self::take), this drains the available ring with a single acquisition of
+    /// the queue's lock. Buffers beyond `max` are left in the available ring for later calls.
+    /// The future returned will only resolve when there is at least one buffer available.
+    /// If the queue is not ready, `Err(QueueNotReady)` will be returned.
+    pub async fn take_batch(&mut self, max: usize) -> Result<Vec<Buffer>, QueueNotReady> {
+        /// The future returned for calling async `take_batch` function of `Queue`.
+        struct TakeBatch<'a> {
+            queue: &'a mut Queue,
+            max: usize,
+        }
+
+        impl Future for TakeBatch<'_> {
+            type Output = Result<Vec<Buffer>, QueueNotReady>;
+
+            fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+                let mut inner = self.queue.inner.lock();
+                match inner.try_take_batch(&self.queue.inner, self.max) {
+                    Err(v) => Poll::Ready(Err(v)),
+                    Ok(v) if v.is_empty() => {
+                        inner.waker = Some(ctx.waker().clone());
+                        Poll::Pending
+                    }
+                    Ok(v) => Poll::Ready(Ok(v)),
+                }
+            }
+        }
+
+        TakeBatch { queue: self, max }.await
+    }
+
+    /// Put back a batch of buffers to the used ring, making them visible to the driver at once.
+    pub fn put_batch(&self, buffers: Vec<Buffer>) {
+        let mut inner = self.inner.lock();
+        for mut buffer in buffers {
+            buffer.completed = true;
+            if inner.ready {
+                inner.put_elem(&buffer);
+            }
+        }
+        if inner.ready {
+            inner.dma_ctx.write_u16(inner.used_addr + 2, inner.last_used_idx);
+        }
+    }
+
+    /// Check whether the driver needs to be notified of buffers put back to the used ring since
+    /// the last call.
+    ///
+    /// If `VIRTIO_RING_F_EVENT_IDX` is negotiated, the driver is only notified once the used
+    /// index passes `used_event` it has set. Otherwise it is always notified.
+    pub fn should_notify(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if !inner.event_idx {
+            return true;
+        }
+        if !inner.ready {
+            return false;
+        }
+        let old = inner.signalled_used_idx;
+        inner.signalled_used_idx = inner.last_used_idx;
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+        let used_event = inner.dma_ctx.read_u16(inner.avail_addr + 4 + inner.num as u64 * 2);
+        need_event(used_event, inner.last_used_idx, old)
+    }
+
+    /// Get all buffers put back to the used ring so far, in order.
+    #[cfg(test)]
+    pub(super) fn completions(&self) -> Vec<Completion> {
+        self.inner.lock().completions.clone()
+    }
+}
+
+/// Whether moving an index from `old` to `new` passes `event`, i.e. `event` is within
+/// `old..new`, taking wrap-around into account.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
 }
 
 /// A buffer passed from the kernel to the virtio device.
 pub struct Buffer {
     queue: Arc<Mutex<QueueInner>>,
+    /// Whether the buffer has been put back to the used ring already.
+    completed: bool,
     idx: u16,
     bytes_written: usize,
     read: Vec<(u64, usize)>,
@@ -202,11 +404,24 @@ pub struct Buffer {
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        self.queue.lock().put(self);
+        if !self.completed {
+            self.queue.lock().put(self);
+        }
     }
 }
 
 impl Buffer {
+    /// Add a descriptor to the corresponding part of this buffer.
+    fn push(&mut self, desc: VirtqDesc) {
+        if (desc.flags & VIRTQ_DESC_F_WRITE) == 0 {
+            self.read.push((desc.addr, desc.len as usize));
+            self.read_len += desc.len as usize;
+        } else {
+            self.write.push((desc.addr, desc.len as usize));
+            self.write_len += desc.len as usize;
+        }
+    }
+
     /// Get the readonly part of this buffer.
     pub fn reader(&self) -> BufferReader<'_> {
         BufferReader {
@@ -420,3 +635,318 @@ impl<'a> Write for BufferWriter<'a> {
         Ok(len)
     }
 }
+
+/// Helpers for testing devices without a guest driver.
+#[cfg(test)]
+pub(super) mod testing {
+    use super::*;
+
+    /// DMA context where guest addresses are host addresses.
+    pub struct HostDma;
+
+    impl DmaContext for HostDma {
+        fn dma_read(&self, addr: u64, buf: &mut [u8]) {
+            unsafe {
+                std::ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len())
+            };
+        }
+
+        fn dma_write(&self, addr: u64, buf: &[u8]) {
+            unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), addr as *mut u8, buf.len()) };
+        }
+
+        fn read_u16(&self, addr: u64) -> u16 {
+            unsafe { std::ptr::read(addr as *const u16) }
+        }
+
+        fn write_u16(&self, addr: u64, value: u16) {
+            unsafe { std::ptr::write(addr as *mut u16, value) }
+        }
+    }
+
+    /// Number of entries in queues created by [`Driver`].
+    pub const NUM: u16 = 16;
+
+    /// Minimal driver side of a ready queue, with rings and data buffers in host memory.
+    ///
+    /// The first page holds the rings, and the remaining `0x3000` bytes starting at
+    /// [`data_addr`](Driver::data_addr) are free for request data.
+    pub struct Driver {
+        memory: Vec<u64>,
+        next_desc: u16,
+        avail_idx: u16,
+    }
+
+    impl Driver {
+        pub fn new() -> (Driver, Queue) {
+            let driver = Driver { memory: vec![0; 0x800], next_desc: 0, avail_idx: 0 };
+            let inner = QueueInner::new(Arc::new(HostDma), NUM);
+            {
+                let mut inner = inner.lock();
+                inner.desc_addr = driver.base();
+                inner.avail_addr = driver.avail_addr();
+                inner.used_addr = driver.used_addr();
+                inner.ready = true;
+            }
+            (driver, Queue { inner })
+        }
+
+        fn base(&self) -> u64 {
+            self.memory.as_ptr() as u64
+        }
+
+        pub fn avail_addr(&self) -> u64 {
+            self.base() + 0x400
+        }
+
+        pub fn used_addr(&self) -> u64 {
+            self.base() + 0x800
+        }
+
+        pub fn data_addr(&self) -> u64 {
+            self.base() + 0x1000
+        }
+
+        /// Make a chain of descriptors available to the device. Each descriptor is given as
+        /// `(addr, len, device_writable)`. Returns the index of the head descriptor.
+        pub fn submit(&mut self, chain: &[(u64, u32, bool)]) -> u16 {
+            let head = self.next_desc;
+            for (i, &(addr, len, writable)) in chain.iter().enumerate() {
+                let idx = self.next_desc;
+                self.next_desc = (self.next_desc + 1) % NUM;
+                let next = if i + 1 != chain.len() { Some(self.next_desc) } else { None };
+                write_desc(self.base() + idx as u64 * 16, addr, len, writable, next);
+            }
+            self.make_available(head)
+        }
+
+        /// Make a chain of descriptors available to the device through an indirect descriptor
+        /// table written at `table`. Returns the index of the head descriptor.
+        pub fn submit_indirect(&mut self, table: u64, chain: &[(u64, u32, bool)]) -> u16 {
+            for (i, &(addr, len, writable)) in chain.iter().enumerate() {
+                let next = if i + 1 != chain.len() { Some(i as u16 + 1) } else { None };
+                write_desc(table + i as u64 * 16, addr, len, writable, next);
+            }
+            let head = self.next_desc;
+            self.next_desc = (self.next_desc + 1) % NUM;
+            let desc = VirtqDesc {
+                addr: table,
+                len: chain.len() as u32 * 16,
+                flags: VIRTQ_DESC_F_INDIRECT,
+                next: 0,
+            };
+            let desc: [u8; 16] = unsafe { std::mem::transmute(desc) };
+            HostDma.dma_write(self.base() + head as u64 * 16, &desc);
+            self.make_available(head)
+        }
+
+        fn make_available(&mut self, head: u16) -> u16 {
+            let avail_addr = self.avail_addr();
+            HostDma.write_u16(avail_addr + 4 + (self.avail_idx % NUM) as u64 * 2, head);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            HostDma.write_u16(avail_addr + 2, self.avail_idx);
+            head
+        }
+    }
+
+    /// Write a descriptor at `addr`, chained to the descriptor `next` if any.
+    pub fn write_desc(addr: u64, buf: u64, len: u32, writable: bool, next: Option<u16>) {
+        let mut flags = if writable { VIRTQ_DESC_F_WRITE } else { 0 };
+        if next.is_some() {
+            flags |= VIRTQ_DESC_F_NEXT;
+        }
+        let desc = VirtqDesc { addr: buf, len, flags, next: next.unwrap_or(0) };
+        let desc: [u8; 16] = unsafe { std::mem::transmute(desc) };
+        HostDma.dma_write(addr, &desc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{self, Driver, HostDma, NUM};
+    use super::*;
+
+    /// Create a ready queue and submit one single-descriptor, device-writable request per slot.
+    fn setup() -> (Driver, Queue) {
+        let (mut driver, queue) = Driver::new();
+        for i in 0..NUM {
+            driver.submit(&[(driver.data_addr() + i as u64 * 8, 8, true)]);
+        }
+        (driver, queue)
+    }
+
+    #[test]
+    fn test_batch() {
+        let (driver, mut queue) = setup();
+        let used_addr = driver.used_addr();
+
+        // All requests should be taken in a single pass.
+        let mut batch = queue.inner.lock().try_take_batch(&queue.inner, usize::MAX).ok().unwrap();
+        assert_eq!(batch.len(), NUM as usize);
+        for buffer in batch.iter_mut() {
+            buffer.writer().write_all(&[0xFF; 4]).unwrap();
+        }
+
+        // Nothing is visible to the driver until the batch is put back.
+        assert_eq!(HostDma.read_u16(used_addr + 2), 0);
+        queue.put_batch(batch);
+        assert_eq!(HostDma.read_u16(used_addr + 2), NUM);
+        for i in 0..NUM as u64 {
+            let mut elem = [0; 8];
+            HostDma.dma_read(used_addr + 4 + i * 8, &mut elem);
+            assert_eq!(elem, [i as u8, 0, 0, 0, 4, 0, 0, 0]);
+        }
+        assert!(queue.try_take().ok().unwrap().is_none());
+        assert_eq!(queue.completions().len(), NUM as usize);
+    }
+
+    #[test]
+    fn test_batch_limit() {
+        let (driver, mut queue) = setup();
+        let used_addr = driver.used_addr();
+
+        // Buffers beyond the bound are left in the available ring until later.
+        let mut taken = 0;
+        while taken < NUM as usize {
+            let batch = queue.inner.lock().try_take_batch(&queue.inner, 5).ok().unwrap();
+            assert_eq!(batch.len(), std::cmp::min(5, NUM as usize - taken));
+            taken += batch.len();
+            queue.put_batch(batch);
+            assert_eq!(HostDma.read_u16(used_addr + 2), taken as u16);
+        }
+        assert!(queue.try_take().ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_indirect() {
+        let (mut driver, mut queue) = Driver::new();
+        let data = driver.data_addr();
+        HostDma.dma_write(data, b"hello, world");
+        let table = data + 0x1000;
+        let chain = [
+            (data, 7, false),
+            (data + 7, 5, false),
+            (data + 0x100, 4, true),
+            (data + 0x200, 4, true),
+        ];
+        let head = driver.submit_indirect(table, &chain);
+
+        // The reader and writer see the descriptors of the table concatenated.
+        let mut buffer = queue.try_take().ok().unwrap().unwrap();
+        assert_eq!(buffer.idx, head);
+        let mut read = String::new();
+        buffer.reader().read_to_string(&mut read).unwrap();
+        assert_eq!(read, "hello, world");
+        buffer.writer().write_all(b"abcdefgh").unwrap();
+        drop(buffer);
+        let mut written = [0; 4];
+        HostDma.dma_read(data + 0x100, &mut written);
+        assert_eq!(&written, b"abcd");
+        HostDma.dma_read(data + 0x200, &mut written);
+        assert_eq!(&written, b"efgh");
+        assert_eq!(queue.completions()[0].len, 8);
+
+        // Loops and tables larger than the queue are cut short.
+        driver.submit_indirect(table, &[(data, 1, false), (data, 1, false)]);
+        testing::write_desc(table + 16, data, 1, false, Some(0));
+        let buffer = queue.try_take().ok().unwrap().unwrap();
+        assert_eq!(buffer.read_len, 2);
+        drop(buffer);
+        driver.submit_indirect(table, &vec![(data, 1, false); NUM as usize + 1]);
+        assert_eq!(queue.try_take().ok().unwrap().unwrap().read_len, 0);
+    }
+
+    #[test]
+    fn test_need_event() {
+        assert!(need_event(5, 6, 5));
+        assert!(need_event(5, 8, 2));
+        assert!(!need_event(5, 5, 2));
+        assert!(!need_event(5, 9, 6));
+        // Indices wrap around.
+        assert!(need_event(0xffff, 1, 0xfffe));
+        assert!(need_event(0, 1, 0xffff));
+        assert!(!need_event(2, 1, 0xffff));
+    }
+
+    #[test]
+    fn test_event_idx() {
+        let (driver, mut queue) = setup();
+        queue.inner.lock().event_idx = true;
+        HostDma.write_u16(driver.avail_addr() + 4 + NUM as u64 * 2, 5);
+
+        // A driver setting `used_event` far ahead is not interrupted until the used index passes
+        // it, i.e. after the buffer following it is used.
+        for i in 1..=NUM {
+            let buffer = queue.try_take().ok().unwrap().unwrap();
+            queue.put_batch(vec![buffer]);
+            assert_eq!(queue.should_notify(), i == 6, "used index {}", i);
+        }
+
+        // Once out of buffers, the device asks to be notified of the next one.
+        let avail_event_addr = driver.used_addr() + 4 + NUM as u64 * 8;
+        assert!(queue.try_take().ok().unwrap().is_none());
+        assert_eq!(HostDma.read_u16(avail_event_addr), NUM);
+
+        // Buffers put back together are covered by a single interrupt.
+        let (_driver, queue) = setup();
+        queue.inner.lock().event_idx = true;
+        let batch = queue.inner.lock().try_take_batch(&queue.inner, 2).ok().unwrap();
+        queue.put_batch(batch);
+        assert!(queue.should_notify());
+        assert!(!queue.should_notify());
+    }
+
+    /// DMA context counting guest memory reads.
+    #[derive(Default)]
+    struct CountingDma(std::sync::atomic::AtomicUsize);
+
+    impl DmaContext for CountingDma {
+        fn dma_read(&self, addr: u64, buf: &mut [u8]) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            HostDma.dma_read(addr, buf)
+        }
+
+        fn dma_write(&self, addr: u64, buf: &[u8]) {
+            HostDma.dma_write(addr, buf)
+        }
+
+        fn read_u16(&self, addr: u64) -> u16 {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            HostDma.read_u16(addr)
+        }
+
+        fn write_u16(&self, addr: u64, value: u16) {
+            HostDma.write_u16(addr, value)
+        }
+    }
+
+    /// Take a chain wrapping around the end of the descriptor table, and return it with the number
+    /// of guest memory reads needed.
+    fn take_long_chain(prefetch: u16) -> (Vec<(u64, usize)>, usize) {
+        let (mut driver, mut queue) = Driver::new();
+        driver.submit(&[(driver.data_addr(), 8, false); 3]);
+        queue.try_take().ok().unwrap().unwrap();
+        let chain: Vec<_> = (0..15).map(|i| (driver.data_addr() + i * 8, 8, i >= 7)).collect();
+        driver.submit(&chain);
+
+        let dma = Arc::new(CountingDma::default());
+        queue.inner.lock().dma_ctx = dma.clone();
+        queue.inner.lock().prefetch = prefetch;
+        let buffer = queue.try_take().ok().unwrap().unwrap();
+        let descs = buffer.read.iter().chain(buffer.write.iter()).copied().collect();
+        assert_eq!((buffer.read_len, buffer.write_len), (56, 64));
+        (descs, dma.0.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let (descs, reads) = take_long_chain(0);
+        // The avail index and ring entry, then one read per descriptor.
+        assert_eq!(reads, 2 + 15);
+
+        // Prefetching stops at the end of the table, so slots 3..16 and 0..2 take two reads.
+        let (prefetched, reads) = take_long_chain(16);
+        assert_eq!(prefetched, descs);
+        assert_eq!(reads, 2 + 2);
+    }
+}
@@ -70,3 +70,40 @@ impl Device for Rng {
         self.start_task(queue);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::queue::testing::{Driver, HostDma};
+    use super::*;
+    use crate::entropy::rand::SeedableRng;
+    use crate::entropy::Seeded;
+    use crate::testing::{NoIrq, TestRuntime};
+    use crate::DmaContext;
+
+    /// Read `len` bytes from a device seeded with `seed`, in requests of 16 bytes.
+    fn read(seed: u64, len: usize) -> Vec<u8> {
+        let tasks = Arc::new(TestRuntime::default());
+        let rng = Box::new(Seeded::seed_from_u64(seed));
+        let mut device = Rng::new(tasks.clone(), Box::new(NoIrq), rng);
+        assert_eq!(device.device_id() as u32, DeviceId::Entropy as u32);
+        let (mut driver, queue) = Driver::new();
+        for offset in (0..len).step_by(16) {
+            driver.submit(&[(driver.data_addr() + offset as u64, 16, true)]);
+        }
+        device.queue_ready(0, queue);
+        tasks.poll_all();
+
+        assert_eq!(HostDma.read_u16(driver.used_addr() + 2) as usize, len / 16);
+        let mut bytes = vec![0; len];
+        HostDma.dma_read(driver.data_addr(), &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_seeded() {
+        let bytes = read(42, 64);
+        assert_eq!(bytes, read(42, 64));
+        assert_ne!(bytes, read(43, 64));
+        assert_ne!(bytes[..16], bytes[16..32]);
+    }
+}
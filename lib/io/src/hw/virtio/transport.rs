@@ -0,0 +1,161 @@
+use super::queue::QueueInner;
+use super::{Device, Queue};
+use crate::DmaContext;
+use parking_lot::{Mutex, MutexGuard};
+use std::sync::Arc;
+
+/// Registers of the selected queue. Both transports expose them, at different addresses.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) enum QueueRegister {
+    Size,
+    Ready,
+    DescLow,
+    DescHigh,
+    DriverLow,
+    DriverHigh,
+    DeviceLow,
+    DeviceHigh,
+}
+
+/// State of a virtio device that does not depend on the transport: the device itself, its queues
+/// and the queue selected by the driver.
+pub(super) struct Transport {
+    pub device: Box<dyn Device>,
+    queues: Vec<Arc<Mutex<QueueInner>>>,
+    pub queue_sel: usize,
+    dma_ctx: Arc<dyn DmaContext>,
+    prefetch: u16,
+    /// Log target of the transport.
+    target: &'static str,
+}
+
+impl Transport {
+    pub fn new(
+        dma_ctx: Arc<dyn DmaContext>,
+        device: Box<dyn Device>,
+        target: &'static str,
+    ) -> Self {
+        let queues = (0..device.num_queues())
+            .map(|i| QueueInner::new(dma_ctx.clone(), device.max_queue_len(i)))
+            .collect();
+        Transport { device, queues, queue_sel: 0, dma_ctx, prefetch: 0, target }
+    }
+
+    /// Set the number of descriptors of a chain to read from guest memory at once.
+    pub fn set_prefetch(&mut self, prefetch: u16) {
+        self.prefetch = prefetch;
+        for queue in self.queues.iter() {
+            queue.lock().prefetch = prefetch;
+        }
+    }
+
+    /// Maximum size of the selected queue, or 0 if it does not exist.
+    pub fn queue_num_max(&self) -> u16 {
+        self.queues.get(self.queue_sel).map_or(0, |queue| queue.lock().num_max)
+    }
+
+    /// Lock the selected queue, or log an error if it does not exist.
+    fn selected(&self) -> Option<MutexGuard<'_, QueueInner>> {
+        match self.queues.get(self.queue_sel) {
+            None => {
+                error!(target: self.target, "attempting to access unavailable queue {}", self.queue_sel);
+                None
+            }
+            Some(queue) => Some(queue.lock()),
+        }
+    }
+
+    /// Read a register of the selected queue. Unavailable queues read as zero.
+    pub fn queue_read(&self, reg: QueueRegister) -> u32 {
+        let queue = match self.selected() {
+            None => return 0,
+            Some(queue) => queue,
+        };
+        match reg {
+            QueueRegister::Size => queue.num as u32,
+            QueueRegister::Ready => queue.ready as u32,
+            QueueRegister::DescLow => queue.desc_addr as u32,
+            QueueRegister::DescHigh => (queue.desc_addr >> 32) as u32,
+            QueueRegister::DriverLow => queue.avail_addr as u32,
+            QueueRegister::DriverHigh => (queue.avail_addr >> 32) as u32,
+            QueueRegister::DeviceLow => queue.used_addr as u32,
+            QueueRegister::DeviceHigh => (queue.used_addr >> 32) as u32,
+        }
+    }
+
+    /// Write a register of the selected queue. The device is handed the queue once it is ready.
+    pub fn queue_write(&mut self, reg: QueueRegister, value: u32) {
+        let mut queue = match self.selected() {
+            None => return,
+            Some(queue) => queue,
+        };
+        let low = |addr: u64| (addr & !0xffffffff) | value as u64;
+        let high = |addr: u64| (addr & 0xffffffff) | (value as u64) << 32;
+        match reg {
+            QueueRegister::Size => {
+                if value.is_power_of_two() && value <= queue.num_max as u32 {
+                    queue.num = value as u16
+                } else {
+                    error!(target: self.target, "invalid queue size {}", value)
+                }
+            }
+            QueueRegister::Ready => queue.ready = (value & 1) != 0,
+            QueueRegister::DescLow => queue.desc_addr = low(queue.desc_addr),
+            QueueRegister::DescHigh => queue.desc_addr = high(queue.desc_addr),
+            QueueRegister::DriverLow => queue.avail_addr = low(queue.avail_addr),
+            QueueRegister::DriverHigh => queue.avail_addr = high(queue.avail_addr),
+            QueueRegister::DeviceLow => queue.used_addr = low(queue.used_addr),
+            QueueRegister::DeviceHigh => queue.used_addr = high(queue.used_addr),
+        }
+        std::mem::drop(queue);
+
+        if reg == QueueRegister::Ready && value & 1 != 0 {
+            let inner = self.queues[self.queue_sel].clone();
+            self.device.queue_ready(self.queue_sel, Queue { inner });
+        }
+    }
+
+    /// Accept the lower 32 bits of features from the driver.
+    pub fn driver_feature(&mut self, value: u32) {
+        let event_idx = value & (1 << super::VIRTIO_RING_F_EVENT_IDX) != 0;
+        for queue in self.queues.iter() {
+            queue.lock().event_idx = event_idx;
+        }
+        // Only the lowest 24-bits are for the device.
+        self.device.driver_feature(value & 0xffffff);
+        trace!(target: self.target, "DriverFeatures set to {:24b}", value);
+    }
+
+    /// Wake up the device waiting for buffers to be added to queue `idx`.
+    pub fn notify(&self, idx: usize) {
+        match self.queues.get(idx) {
+            None => error!(target: self.target, "attempting to notify unavailable queue {}", idx),
+            Some(queue) => {
+                if let Some(waker) = queue.lock().waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    /// Reset the device and its queues. The queue selected is reset as well, but other registers
+    /// of the transport are left to the caller.
+    pub fn reset(&mut self) {
+        self.device.reset();
+        // Upon reset, reset all queues, and replace them with new queue instances.
+        // Replacing them can hopefully allow devices to gracefully terminate tasks.
+        for (i, queue) in self.queues.iter_mut().enumerate() {
+            {
+                let mut lock = queue.lock();
+                lock.reset();
+                if let Some(waker) = lock.waker.take() {
+                    waker.wake();
+                }
+            }
+            let inner = QueueInner::new(self.dma_ctx.clone(), self.device.max_queue_len(i));
+            inner.lock().prefetch = self.prefetch;
+            *queue = inner;
+        }
+        self.queue_sel = 0;
+    }
+}
@@ -4,6 +4,7 @@ extern crate log;
 pub mod hw;
 
 pub mod block;
+pub mod dma;
 pub mod network;
 pub mod serial;
 
@@ -12,11 +13,16 @@ pub mod entropy;
 #[cfg(feature = "fs")]
 pub mod fs;
 
+#[cfg(test)]
+mod testing;
+
 use futures::future::BoxFuture;
 use std::time::Duration;
 
 /// Context for device DMA operations.
 ///
+/// Addresses are bus addresses. Use [`dma::IommuDma`] to translate them through an IOMMU.
+///
 /// This is required to be [`Send`] + [`Sync`] so devices that use them can be `Send`.
 pub trait DmaContext: Send + Sync {
     /// Perform a DMA read at given address.
@@ -17,6 +17,7 @@ struct Inner {
     size_changed: bool,
     size_changed_wakers: Vec<Waker>,
     processor: Box<dyn FnMut(u8) -> Option<u8> + Send>,
+    tap: Option<Box<dyn FnMut(&[u8]) + Send>>,
 }
 
 /// A [`Serial`] implementation that uses stdin/stdout TTY.
@@ -74,6 +75,7 @@ impl Console {
             size_changed: false,
             size_changed_wakers: Vec::new(),
             processor: Box::new(|x| Some(x)),
+            tap: None,
         }));
 
         // Register the exit hook if not already done.
@@ -125,6 +127,20 @@ impl Console {
     pub fn set_processor(&mut self, processor: impl FnMut(u8) -> Option<u8> + Send + 'static) {
         self.0.lock().processor = Box::new(processor);
     }
+
+    /// Set a function that observes all bytes written to the console, e.g. to watch for guest
+    /// output.
+    pub fn set_output_tap(&mut self, tap: impl FnMut(&[u8]) + Send + 'static) {
+        self.0.lock().tap = Some(Box::new(tap));
+    }
+
+    /// Feed bytes to the console as if they were typed on the TTY, e.g. to script guest input.
+    /// The bytes are delivered to the reader verbatim, without going through the processor.
+    pub fn inject_input(&self, data: &[u8]) {
+        let mut guard = self.0.lock();
+        guard.rx_buffer.extend(data);
+        guard.rx_waker.drain(..).for_each(|w| w.wake());
+    }
 }
 
 /// Handle SIGWINCH for tty size change notification
@@ -145,6 +161,9 @@ impl Serial for Console {
         let mut out = std::io::stdout();
         out.write_all(buf)?;
         out.flush()?;
+        if let Some(tap) = self.0.lock().tap.as_mut() {
+            tap(buf);
+        }
         Poll::Ready(Ok(buf.len()))
     }
 
@@ -201,3 +220,35 @@ impl Serial for Console {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_input() {
+        // Construct directly, as `Console::new` takes over the TTY.
+        let console = Console(Arc::new(Mutex::new(Inner {
+            old_tty: unsafe { std::mem::zeroed() },
+            rx_buffer: VecDeque::new(),
+            rx_waker: Vec::new(),
+            size_changed: false,
+            size_changed_wakers: Vec::new(),
+            processor: Box::new(|_| None),
+            tap: None,
+        })));
+        console.inject_input(b"echo hi\n");
+
+        // Read byte by byte, like SBI console_getchar does.
+        let serial: &dyn Serial = &console;
+        let mut input = Vec::new();
+        let mut byte = 0;
+        while let Ok(1) = serial.try_read(std::slice::from_mut(&mut byte)) {
+            input.push(byte);
+        }
+        assert_eq!(input, b"echo hi\n");
+
+        // Dropping would restore the TTY to the bogus saved config.
+        std::mem::forget(console);
+    }
+}
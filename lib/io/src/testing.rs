@@ -0,0 +1,66 @@
+//! Runtime and interrupt stubs shared by device tests.
+
+use crate::{IrqPin, RuntimeContext};
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use std::task::Context;
+use std::time::Duration;
+
+/// An interrupt pin that is not connected.
+pub struct NoIrq;
+
+impl IrqPin for NoIrq {
+    fn set_level(&self, _level: bool) {}
+}
+
+/// A runtime whose clock stands still.
+///
+/// Spawned tasks are kept for the test to poll with [`poll_all`](TestRuntime::poll_all). Blocking
+/// tasks run on their own threads instead, as in the emulator.
+#[derive(Default)]
+pub struct TestRuntime {
+    now: Duration,
+    tasks: Mutex<Vec<BoxFuture<'static, ()>>>,
+}
+
+impl TestRuntime {
+    /// Create a runtime whose clock reads `now`.
+    pub fn at(now: Duration) -> Self {
+        TestRuntime { now, tasks: Mutex::new(Vec::new()) }
+    }
+
+    /// Poll each task once. Device tasks never finish while their queues are ready.
+    pub fn poll_all(&self) {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for task in self.tasks.lock().iter_mut() {
+            assert!(task.as_mut().poll(&mut cx).is_pending());
+        }
+    }
+}
+
+impl RuntimeContext for TestRuntime {
+    fn now(&self) -> Duration {
+        self.now
+    }
+
+    fn create_timer(&self, time: Duration) -> BoxFuture<'static, ()> {
+        // Time does not advance, so timers not already due never fire.
+        if time <= self.now {
+            Box::pin(futures::future::ready(()))
+        } else {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.tasks.lock().push(task);
+    }
+
+    fn spawn_blocking(&self, name: &str, task: BoxFuture<'static, ()>) {
+        std::thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || futures::executor::block_on(task))
+            .unwrap();
+    }
+}
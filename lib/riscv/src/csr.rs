@@ -15,7 +15,8 @@ impl Csr {
     pub const Time: Csr = Csr(0xC01);
     pub const Instret: Csr = Csr(0xC02);
 
-    // These CSRs are Rv32I only, and they are considered invalid in RV64I
+    // These CSRs are Rv32I only, and they are considered invalid in RV64I. We nevertheless provide
+    // them for compatibility.
     pub const Cycleh: Csr = Csr(0xC80);
     pub const Timeh: Csr = Csr(0xC81);
     pub const Instreth: Csr = Csr(0xC82);
@@ -29,6 +30,7 @@ impl Csr {
     pub const Scause: Csr = Csr(0x142);
     pub const Stval: Csr = Csr(0x143);
     pub const Sip: Csr = Csr(0x144);
+    pub const Stimecmp: Csr = Csr(0x14D);
     pub const Satp: Csr = Csr(0x180);
 
     pub const Mvendorid: Csr = Csr(0xF11);
@@ -53,7 +55,81 @@ impl Csr {
     pub const Minstret: Csr = Csr(0xB02);
 }
 
+/// Names of all CSRs that have a named constant.
+const CSR_NAMES: &[(Csr, &str)] = &[
+    (Csr::Fflags, "fflags"),
+    (Csr::Frm, "frm"),
+    (Csr::Fcsr, "fcsr"),
+    (Csr::Cycle, "cycle"),
+    (Csr::Time, "time"),
+    (Csr::Instret, "instret"),
+    (Csr::Cycleh, "cycleh"),
+    (Csr::Timeh, "timeh"),
+    (Csr::Instreth, "instreth"),
+    (Csr::Sstatus, "sstatus"),
+    (Csr::Sie, "sie"),
+    (Csr::Stvec, "stvec"),
+    (Csr::Scounteren, "scounteren"),
+    (Csr::Sscratch, "sscratch"),
+    (Csr::Sepc, "sepc"),
+    (Csr::Scause, "scause"),
+    (Csr::Stval, "stval"),
+    (Csr::Sip, "sip"),
+    (Csr::Stimecmp, "stimecmp"),
+    (Csr::Satp, "satp"),
+    (Csr::Mvendorid, "mvendorid"),
+    (Csr::Marchid, "marchid"),
+    (Csr::Mimpid, "mimpid"),
+    (Csr::Mhartid, "mhartid"),
+    (Csr::Mstatus, "mstatus"),
+    (Csr::Misa, "misa"),
+    (Csr::Medeleg, "medeleg"),
+    (Csr::Mideleg, "mideleg"),
+    (Csr::Mie, "mie"),
+    (Csr::Mtvec, "mtvec"),
+    (Csr::Mcounteren, "mcounteren"),
+    (Csr::Mscratch, "mscratch"),
+    (Csr::Mepc, "mepc"),
+    (Csr::Mcause, "mcause"),
+    (Csr::Mtval, "mtval"),
+    (Csr::Mip, "mip"),
+    (Csr::Mcycle, "mcycle"),
+    (Csr::Mtime, "mtime"),
+    (Csr::Minstret, "minstret"),
+];
+
+/// Numbered CSRs, named by a prefix followed by the index. Each entry has the prefix, the CSR
+/// with index 0, and the first and last valid index.
+const CSR_FAMILIES: &[(&str, u16, u16, u16)] =
+    &[("hpmcounter", 0xC00, 3, 31), ("pmpcfg", 0x3A0, 0, 15), ("pmpaddr", 0x3B0, 0, 63)];
+
 impl Csr {
+    /// Look up a CSR by its name, e.g. `"sstatus"`, `"hpmcounter3"` or `"pmpaddr0"`.
+    pub fn from_name(name: &str) -> Option<Csr> {
+        if let Some(&(csr, _)) = CSR_NAMES.iter().find(|&&(_, n)| n == name) {
+            return Some(csr);
+        }
+        for &(prefix, base, first, last) in CSR_FAMILIES {
+            if !name.starts_with(prefix) {
+                continue;
+            }
+            let id = &name[prefix.len()..];
+            if !id.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            return match id.parse::<u16>() {
+                Ok(id) if (first..=last).contains(&id) => Some(Csr(base + id)),
+                _ => None,
+            };
+        }
+        None
+    }
+
+    /// Get the name of the CSR, if it has one.
+    pub fn name(self) -> Option<&'static str> {
+        CSR_NAMES.iter().find(|&&(csr, _)| csr == self).map(|&(_, name)| name)
+    }
+
     /// Get the minimal privilege level required to access the CSR
     pub fn min_prv_level(self) -> u8 {
         ((self.0 >> 8) & 0b11) as u8
@@ -66,27 +142,47 @@ impl Csr {
 
 impl fmt::Display for Csr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.pad(match *self {
-            Csr::Fflags => "fflags",
-            Csr::Frm => "frm",
-            Csr::Fcsr => "fcsr",
-            Csr::Cycle => "cycle",
-            Csr::Time => "time",
-            Csr::Instret => "instret",
-            Csr::Cycleh => "cycleh",
-            Csr::Timeh => "timeh",
-            Csr::Instreth => "instreth",
-            Csr::Sstatus => "sstatus",
-            Csr::Sie => "sie",
-            Csr::Stvec => "stvec",
-            Csr::Scounteren => "scounteren",
-            Csr::Sscratch => "sscratch",
-            Csr::Sepc => "sepc",
-            Csr::Scause => "scause",
-            Csr::Stval => "stval",
-            Csr::Sip => "sip",
-            Csr::Satp => "satp",
-            v => return write!(f, "#0x{:x}", v.0),
-        })
+        if let Some(name) = self.name() {
+            return f.pad(name);
+        }
+        for &(prefix, base, first, last) in CSR_FAMILIES {
+            if (base + first..=base + last).contains(&self.0) {
+                return write!(f, "{}{}", prefix, self.0 - base);
+            }
+        }
+        write!(f, "#0x{:x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csr_name_round_trip() {
+        for &(csr, name) in CSR_NAMES {
+            assert!(Csr::from_name(name) == Some(csr));
+            assert_eq!(csr.name(), Some(name));
+            assert_eq!(format!("{}", csr), name);
+        }
+        assert!(Csr::from_name("hpmcounter3") == Some(Csr(0xC03)));
+        assert!(Csr::from_name("hpmcounter31") == Some(Csr(0xC1F)));
+        assert_eq!(format!("{}", Csr(0xC1F)), "hpmcounter31");
+        assert!(Csr::from_name("hpmcounter32").is_none());
+        assert!(Csr::from_name("hpmcounter+3").is_none());
+        for id in 0..16 {
+            let name = format!("pmpcfg{}", id);
+            assert!(Csr::from_name(&name) == Some(Csr(0x3A0 + id)));
+            assert_eq!(format!("{}", Csr(0x3A0 + id)), name);
+        }
+        for id in 0..64 {
+            let name = format!("pmpaddr{}", id);
+            assert!(Csr::from_name(&name) == Some(Csr(0x3B0 + id)));
+            assert_eq!(format!("{}", Csr(0x3B0 + id)), name);
+        }
+        assert!(Csr::from_name("pmpcfg16").is_none());
+        assert!(Csr::from_name("pmpaddr64").is_none());
+        assert_eq!(format!("{}", Csr(0x3F0)), "#0x3f0");
+        assert!(Csr::from_name("nonexistent").is_none());
     }
 }
@@ -169,6 +169,17 @@ fn cj_imm(bits: u16) -> i32 {
         | ((bits & 0b00000000_00111000) as i32) >> 2
 }
 
+/// Decode `add x0, x0, rs2`, which is a Zihintntl hint for `rs2` in x2 to x5.
+fn ntl_hint(rs2: u8) -> Option<Op> {
+    match rs2 {
+        2 => Some(Op::NtlP1),
+        3 => Some(Op::NtlPall),
+        4 => Some(Op::NtlS1),
+        5 => Some(Op::NtlAll),
+        _ => None,
+    }
+}
+
 //
 // #endregion
 
@@ -264,10 +275,15 @@ pub fn decode_compressed(bits: u16) -> Op {
                         // translate to addi x2, x2, imm
                         Op::Addi { rd: 2, rs1: 2, imm }
                     } else {
+                        let imm = ci_imm(bits);
+                        if imm == 0 {
+                            // Reserved
+                            return Op::Illegal;
+                        }
                         // rd = x0 is HINT
                         // C.LUI
                         // translate to lui rd, imm
-                        Op::Lui { rd, imm: ci_imm(bits) << 12 }
+                        Op::Lui { rd, imm: imm << 12 }
                     }
                 }
                 0b100 => {
@@ -395,18 +411,25 @@ pub fn decode_compressed(bits: u16) -> Op {
                         }
                     } else {
                         let rs1 = c_rs1(bits);
-                        if rs1 == 0 {
-                            // C.EBREAK
-                            Op::Ebreak
-                        } else if rs2 == 0 {
-                            // C.JALR
-                            // translate to jalr x1, rs1, 0
-                            Op::Jalr { rd: 1, rs1, imm: 0 }
+                        if rs2 == 0 {
+                            if rs1 == 0 {
+                                // C.EBREAK
+                                Op::Ebreak
+                            } else {
+                                // C.JALR
+                                // translate to jalr x1, rs1, 0
+                                Op::Jalr { rd: 1, rs1, imm: 0 }
+                            }
                         } else {
-                            // rd = 0 is HINT
+                            // rd = 0 is HINT, including C.NTL.* for rs2 in x2 to x5
                             // C.ADD
                             // translate to add rd, rd, rs2
                             let rd = c_rd(bits);
+                            if rd == 0 {
+                                if let Some(op) = ntl_hint(rs2) {
+                                    return op;
+                                }
+                            }
                             Op::Add { rd, rs1: rd, rs2 }
                         }
                     }
@@ -489,25 +512,37 @@ pub fn decode(bits: u32) -> Op {
             let imm = i_imm(bits);
             match function {
                 0b000 => Op::Addi { rd, rs1, imm },
-                0b001 => {
-                    if imm >= 64 {
-                        Op::Illegal
-                    } else {
-                        Op::Slli { rd, rs1, imm }
-                    }
-                }
+                // The upper 6 bits of imm select the op. imm is sign-extended, so reserved
+                // encodings with imm[11] set are negative.
+                0b001 => match imm >> 6 {
+                    0b000000 => Op::Slli { rd, rs1, imm },
+                    0b001010 => Op::Bseti { rd, rs1, imm: imm & 63 },
+                    0b010010 => Op::Bclri { rd, rs1, imm: imm & 63 },
+                    0b011000 => match imm & 63 {
+                        0b00000 => Op::Clz { rd, rs1 },
+                        0b00001 => Op::Ctz { rd, rs1 },
+                        0b00010 => Op::Cpop { rd, rs1 },
+                        0b00100 => Op::SextB { rd, rs1 },
+                        0b00101 => Op::SextH { rd, rs1 },
+                        _ => Op::Illegal,
+                    },
+                    0b011010 => Op::Binvi { rd, rs1, imm: imm & 63 },
+                    _ => Op::Illegal,
+                },
                 0b010 => Op::Slti { rd, rs1, imm },
                 0b011 => Op::Sltiu { rd, rs1, imm },
                 0b100 => Op::Xori { rd, rs1, imm },
-                0b101 => {
-                    if imm & !0x400 >= 64 {
-                        Op::Illegal
-                    } else if (imm & 0x400) != 0 {
-                        Op::Srai { rd, rs1, imm: imm & !0x400 }
-                    } else {
-                        Op::Srli { rd, rs1, imm }
-                    }
-                }
+                0b101 => match imm >> 6 {
+                    0b000000 => Op::Srli { rd, rs1, imm },
+                    0b010000 => Op::Srai { rd, rs1, imm: imm & 63 },
+                    0b010010 => Op::Bexti { rd, rs1, imm: imm & 63 },
+                    0b011000 => Op::Rori { rd, rs1, imm: imm & 63 },
+                    _ => match imm {
+                        0x287 => Op::OrcB { rd, rs1 },
+                        0x6b8 => Op::Rev8 { rd, rs1 },
+                        _ => Op::Illegal,
+                    },
+                },
                 0b110 => Op::Ori { rd, rs1, imm },
                 0b111 => Op::Andi { rd, rs1, imm },
                 // full case
@@ -532,22 +567,25 @@ pub fn decode(bits: u32) -> Op {
             let imm = i_imm(bits);
             match function {
                 0b000 => Op::Addiw { rd, rs1, imm },
-                0b001 => {
-                    if imm >= 32 {
-                        Op::Illegal
-                    } else {
-                        Op::Slliw { rd, rs1, imm }
-                    }
-                }
-                0b101 => {
-                    if imm & !0x400 >= 32 {
-                        Op::Illegal
-                    } else if (imm & 0x400) != 0 {
-                        Op::Sraiw { rd, rs1, imm: imm & !0x400 }
-                    } else {
-                        Op::Srliw { rd, rs1, imm }
-                    }
-                }
+                // The upper 7 bits of imm select the op, except for slli.uw which has a 6-bit
+                // shift amount.
+                0b001 => match imm >> 5 {
+                    0b0000000 => Op::Slliw { rd, rs1, imm },
+                    0b0000100 | 0b0000101 => Op::SlliUw { rd, rs1, imm: imm & 63 },
+                    0b0110000 => match imm & 31 {
+                        0b00000 => Op::Clzw { rd, rs1 },
+                        0b00001 => Op::Ctzw { rd, rs1 },
+                        0b00010 => Op::Cpopw { rd, rs1 },
+                        _ => Op::Illegal,
+                    },
+                    _ => Op::Illegal,
+                },
+                0b101 => match imm >> 5 {
+                    0b0000000 => Op::Srliw { rd, rs1, imm },
+                    0b0100000 => Op::Sraiw { rd, rs1, imm: imm & 31 },
+                    0b0110000 => Op::Roriw { rd, rs1, imm: imm & 31 },
+                    _ => Op::Illegal,
+                },
                 _ => Op::Illegal,
             }
         }
@@ -649,6 +687,9 @@ pub fn decode(bits: u32) -> Op {
                     _ => unreachable!(),
                 },
                 0b0000000 => match function {
+                    0b000 if rd == 0 && rs1 == 0 => {
+                        ntl_hint(rs2).unwrap_or(Op::Add { rd, rs1, rs2 })
+                    }
                     0b000 => Op::Add { rd, rs1, rs2 },
                     0b001 => Op::Sll { rd, rs1, rs2 },
                     0b010 => Op::Slt { rd, rs1, rs2 },
@@ -662,7 +703,43 @@ pub fn decode(bits: u32) -> Op {
                 },
                 0b0100000 => match function {
                     0b000 => Op::Sub { rd, rs1, rs2 },
+                    0b100 => Op::Xnor { rd, rs1, rs2 },
                     0b101 => Op::Sra { rd, rs1, rs2 },
+                    0b110 => Op::Orn { rd, rs1, rs2 },
+                    0b111 => Op::Andn { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
+                // Zba and Zbb
+                0b0000101 => match function {
+                    0b100 => Op::Min { rd, rs1, rs2 },
+                    0b101 => Op::Minu { rd, rs1, rs2 },
+                    0b110 => Op::Max { rd, rs1, rs2 },
+                    0b111 => Op::Maxu { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
+                0b0010000 => match function {
+                    0b010 => Op::Sh1add { rd, rs1, rs2 },
+                    0b100 => Op::Sh2add { rd, rs1, rs2 },
+                    0b110 => Op::Sh3add { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
+                0b0110000 => match function {
+                    0b001 => Op::Rol { rd, rs1, rs2 },
+                    0b101 => Op::Ror { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
+                // Zbs
+                0b0010100 => match function {
+                    0b001 => Op::Bset { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
+                0b0100100 => match function {
+                    0b001 => Op::Bclr { rd, rs1, rs2 },
+                    0b101 => Op::Bext { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
+                0b0110100 => match function {
+                    0b001 => Op::Binv { rd, rs1, rs2 },
                     _ => Op::Illegal,
                 },
                 _ => Op::Illegal,
@@ -695,6 +772,23 @@ pub fn decode(bits: u32) -> Op {
                     0b101 => Op::Sraw { rd, rs1, rs2 },
                     _ => Op::Illegal,
                 },
+                // Zba and Zbb
+                0b0000100 => match function {
+                    0b000 => Op::AddUw { rd, rs1, rs2 },
+                    0b100 if rs2 == 0 => Op::ZextH { rd, rs1 },
+                    _ => Op::Illegal,
+                },
+                0b0010000 => match function {
+                    0b010 => Op::Sh1addUw { rd, rs1, rs2 },
+                    0b100 => Op::Sh2addUw { rd, rs1, rs2 },
+                    0b110 => Op::Sh3addUw { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
+                0b0110000 => match function {
+                    0b001 => Op::Rolw { rd, rs1, rs2 },
+                    0b101 => Op::Rorw { rd, rs1, rs2 },
+                    _ => Op::Illegal,
+                },
                 _ => Op::Illegal,
             }
         }
@@ -874,7 +968,10 @@ pub fn decode(bits: u32) -> Op {
         }
 
         /* JALR */
-        0b1100111 => Op::Jalr { rd, rs1, imm: i_imm(bits) },
+        0b1100111 => match function {
+            0b000 => Op::Jalr { rd, rs1, imm: i_imm(bits) },
+            _ => Op::Illegal,
+        },
 
         /* JAL */
         0b1101111 => Op::Jal { rd, imm: j_imm(bits) },
@@ -940,4 +1037,136 @@ mod tests {
             _ => false,
         });
     }
+
+    fn is_illegal(op: Op) -> bool {
+        match op {
+            Op::Illegal => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_reserved_encodings() {
+        // c.addi4spn with nzuimm = 0, including the all-zero instruction
+        assert!(is_illegal(decode_compressed(0x0000)));
+        assert!(is_illegal(decode_compressed(0x0008)));
+        // c.addi16sp with nzimm = 0
+        assert!(is_illegal(decode_compressed(0x6101)));
+        // c.lui a0, 0
+        assert!(is_illegal(decode_compressed(0x6501)));
+        // c.addiw x0, 1
+        assert!(is_illegal(decode_compressed(0x2005)));
+        // c.lwsp x0, 0(sp)
+        assert!(is_illegal(decode_compressed(0x4002)));
+        // c.ldsp x0, 0(sp)
+        assert!(is_illegal(decode_compressed(0x6002)));
+        // c.jr x0
+        assert!(is_illegal(decode_compressed(0x8002)));
+
+        // fadd.s fa0, fa1, fa2 with reserved rounding modes 0b101 and 0b110
+        assert!(is_illegal(decode(0x00c5d553)));
+        assert!(is_illegal(decode(0x00c5e553)));
+        // fmadd.s fa0, fa1, fa2, fa3 with reserved rounding mode 0b101
+        assert!(is_illegal(decode(0x68c5d543)));
+        // slli/srai a0, a0 with imm[11] set
+        assert!(is_illegal(decode(0x80051513)));
+        assert!(is_illegal(decode(0xc0055513)));
+        // slliw/sraiw a0, a0 with imm[5] set
+        assert!(is_illegal(decode(0x0205151b)));
+        assert!(is_illegal(decode(0x4205551b)));
+        // jalr with funct3 != 0
+        assert!(is_illegal(decode(0x00009067)));
+        // The all-ones word
+        assert!(is_illegal(decode(0xffffffff)));
+    }
+
+    #[test]
+    fn test_ntl_hints() {
+        use crate::Extension;
+        // add x0, x0, x2 to x5, and c.add x0, x2 to x5
+        let hints = [
+            (0x00200033, 0x900a, "ntl.p1"),
+            (0x00300033, 0x900e, "ntl.pall"),
+            (0x00400033, 0x9012, "ntl.s1"),
+            (0x00500033, 0x9016, "ntl.all"),
+        ];
+        for &(bits, c_bits, mnemonic) in hints.iter() {
+            let op = decode(bits);
+            assert_eq!(op.mnemonic(), mnemonic);
+            assert_eq!(op.extension(), Extension::Zihintntl);
+            assert_eq!(op.get_regs(), (0, 0, 0));
+            assert!(decode_compressed(c_bits) == op);
+        }
+
+        // Other adds to x0 remain plain hints.
+        assert!(matches!(decode(0x00600033), Op::Add { rd: 0, rs1: 0, rs2: 6 }));
+        assert!(matches!(decode(0x00208033), Op::Add { rd: 0, rs1: 1, rs2: 2 }));
+        assert!(matches!(decode_compressed(0x901a), Op::Add { rd: 0, rs1: 0, rs2: 6 }));
+        // c.ebreak
+        assert!(matches!(decode_compressed(0x9002), Op::Ebreak));
+    }
+
+    #[test]
+    fn test_lr_sc_ordering() {
+        use crate::Ordering;
+        // lr.w.aqrl a0, (a1)
+        let op = decode(0x1605a52f);
+        assert!(matches!(op, Op::LrW { rd: 10, rs1: 11, aqrl: Ordering::SeqCst }));
+        assert!(op.to_string().starts_with("lr.w.aqrl "));
+        // lr.d.aq a0, (a1)
+        let op = decode(0x1405b52f);
+        assert!(matches!(op, Op::LrD { aqrl: Ordering::Acquire, .. }));
+        assert!(op.to_string().starts_with("lr.d.aq "));
+        // sc.w.rl a0, a2, (a1)
+        let op = decode(0x1ac5a52f);
+        assert!(matches!(op, Op::ScW { rd: 10, rs1: 11, rs2: 12, aqrl: Ordering::Release }));
+        assert!(op.to_string().starts_with("sc.w.rl "));
+    }
+
+    #[test]
+    fn test_amo_ordering() {
+        use core::sync::atomic::Ordering as MemOrder;
+        let ordering = |bits| match decode(bits) {
+            Op::AmoaddW { rd: 10, rs1: 11, rs2: 12, aqrl } => MemOrder::from(aqrl),
+            _ => unreachable!(),
+        };
+        // amoadd.w a0, a2, (a1)
+        assert_eq!(ordering(0x00c5a52f), MemOrder::Relaxed);
+        // amoadd.w.aq a0, a2, (a1)
+        assert_eq!(ordering(0x04c5a52f), MemOrder::Acquire);
+        // amoadd.w.rl a0, a2, (a1)
+        assert_eq!(ordering(0x02c5a52f), MemOrder::Release);
+        // amoadd.w.aqrl a0, a2, (a1)
+        assert_eq!(ordering(0x06c5a52f), MemOrder::SeqCst);
+        assert!(decode(0x06c5a52f).to_string().starts_with("amoadd.w.aqrl "));
+    }
+
+    #[test]
+    fn test_op_extension() {
+        use crate::Extension;
+        // addi a0, a0, 1
+        assert_eq!(decode(0x00150513).extension(), Extension::I);
+        // c.addi a0, 1 is classified by its expanded form
+        assert_eq!(decode_compressed(0x0505).extension(), Extension::I);
+        // mul a0, a1, a2
+        assert_eq!(decode(0x02c58533).extension(), Extension::M);
+        // amoadd.w a0, a2, (a1)
+        assert_eq!(decode(0x00c5a52f).extension(), Extension::A);
+        // fadd.s fa0, fa1, fa2
+        assert_eq!(decode(0x00c58553).extension(), Extension::F);
+        // fadd.d fa0, fa1, fa2
+        assert_eq!(decode(0x02c58553).extension(), Extension::D);
+        // csrr a0, sstatus
+        assert_eq!(decode(0x10002573).extension(), Extension::Zicsr);
+        // fence.i
+        assert_eq!(decode(0x0000100f).extension(), Extension::Zifencei);
+        // sret
+        assert_eq!(decode(0x10200073).extension(), Extension::Privileged);
+        // sh1add a0, a1, a2
+        assert_eq!(decode(0x20c5a533).extension(), Extension::Zba);
+        // rev8 a0, a1
+        assert_eq!(decode(0x6b85d513).extension(), Extension::Zbb);
+        // bseti a0, a1, 63
+        assert_eq!(decode(0x2bf59513).extension(), Extension::Zbs);
+    }
 }
@@ -1,3 +1,4 @@
+use alloc::string::{String, ToString};
 use core::fmt;
 
 #[rustfmt::skip]
@@ -12,6 +13,22 @@ pub const fn register_name(reg: u8) -> &'static str {
     REG_NAMES[reg as usize]
 }
 
+/// Look up a general purpose register by its ABI name (including `fp`) or its numeric name, e.g.
+/// `"a0"` or `"x10"`.
+pub fn register_from_name(name: &str) -> Option<u8> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(index) = REG_NAMES.iter().position(|&x| x == name) {
+        return Some(index as u8);
+    }
+    let num = name.strip_prefix('x')?;
+    if num.len() > 1 && num.starts_with('0') {
+        return None;
+    }
+    num.parse().ok().filter(|&x| x < 32)
+}
+
 use super::op::{Op, Ordering};
 
 impl Op {
@@ -176,6 +193,50 @@ impl Op {
             Op::FmsubD { .. } => "fmsub.d",
             Op::FnmsubD { .. } => "fnmsub.d",
             Op::FnmaddD { .. } => "fnmadd.d",
+            Op::SlliUw { .. } => "slli.uw",
+            Op::Sh1add { .. } => "sh1add",
+            Op::Sh2add { .. } => "sh2add",
+            Op::Sh3add { .. } => "sh3add",
+            Op::AddUw { .. } => "add.uw",
+            Op::Sh1addUw { .. } => "sh1add.uw",
+            Op::Sh2addUw { .. } => "sh2add.uw",
+            Op::Sh3addUw { .. } => "sh3add.uw",
+            Op::Clz { .. } => "clz",
+            Op::Ctz { .. } => "ctz",
+            Op::Cpop { .. } => "cpop",
+            Op::SextB { .. } => "sext.b",
+            Op::SextH { .. } => "sext.h",
+            Op::Rori { .. } => "rori",
+            Op::OrcB { .. } => "orc.b",
+            Op::Rev8 { .. } => "rev8",
+            Op::Clzw { .. } => "clzw",
+            Op::Ctzw { .. } => "ctzw",
+            Op::Cpopw { .. } => "cpopw",
+            Op::Roriw { .. } => "roriw",
+            Op::Andn { .. } => "andn",
+            Op::Orn { .. } => "orn",
+            Op::Xnor { .. } => "xnor",
+            Op::Min { .. } => "min",
+            Op::Minu { .. } => "minu",
+            Op::Max { .. } => "max",
+            Op::Maxu { .. } => "maxu",
+            Op::Rol { .. } => "rol",
+            Op::Ror { .. } => "ror",
+            Op::ZextH { .. } => "zext.h",
+            Op::Rolw { .. } => "rolw",
+            Op::Rorw { .. } => "rorw",
+            Op::Bclri { .. } => "bclri",
+            Op::Bexti { .. } => "bexti",
+            Op::Binvi { .. } => "binvi",
+            Op::Bseti { .. } => "bseti",
+            Op::Bclr { .. } => "bclr",
+            Op::Bext { .. } => "bext",
+            Op::Binv { .. } => "binv",
+            Op::Bset { .. } => "bset",
+            Op::NtlP1 => "ntl.p1",
+            Op::NtlPall => "ntl.pall",
+            Op::NtlS1 => "ntl.s1",
+            Op::NtlAll => "ntl.all",
             Op::Mret { .. } => "mret",
             Op::Sret { .. } => "sret",
             Op::Wfi { .. } => "wfi",
@@ -277,7 +338,11 @@ impl Op {
             Op::Ebreak |
             Op::Mret |
             Op::Sret |
-            Op::Wfi => (),
+            Op::Wfi |
+            Op::NtlP1 |
+            Op::NtlPall |
+            Op::NtlS1 |
+            Op::NtlAll => (),
             Op::SfenceVma { rs1, rs2 } =>
                 write!(fmt, "{}, {}", register_name(rs1), register_name(rs2))?,
             Op::Sb { rs1, rs2, imm } |
@@ -299,8 +364,27 @@ impl Op {
             Op::Srai { rd, rs1, imm } |
             Op::Slliw { rd, rs1, imm } |
             Op::Srliw { rd, rs1, imm } |
-            Op::Sraiw { rd, rs1, imm } =>
+            Op::Sraiw { rd, rs1, imm } |
+            Op::SlliUw { rd, rs1, imm } |
+            Op::Rori { rd, rs1, imm } |
+            Op::Roriw { rd, rs1, imm } |
+            Op::Bclri { rd, rs1, imm } |
+            Op::Bexti { rd, rs1, imm } |
+            Op::Binvi { rd, rs1, imm } |
+            Op::Bseti { rd, rs1, imm } =>
                 write!(fmt, "{}, {}, {}", register_name(rd), register_name(rs1), imm)?,
+            Op::Clz { rd, rs1 } |
+            Op::Ctz { rd, rs1 } |
+            Op::Cpop { rd, rs1 } |
+            Op::SextB { rd, rs1 } |
+            Op::SextH { rd, rs1 } |
+            Op::OrcB { rd, rs1 } |
+            Op::Rev8 { rd, rs1 } |
+            Op::Clzw { rd, rs1 } |
+            Op::Ctzw { rd, rs1 } |
+            Op::Cpopw { rd, rs1 } |
+            Op::ZextH { rd, rs1 } =>
+                write!(fmt, "{}, {}", register_name(rd), register_name(rs1))?,
             Op::Add { rd, rs1, rs2 } |
             Op::Sub { rd, rs1, rs2 } |
             Op::Sll { rd, rs1, rs2 } |
@@ -328,7 +412,29 @@ impl Op {
             Op::Divw { rd, rs1, rs2 } |
             Op::Divuw { rd, rs1, rs2 } |
             Op::Remw { rd, rs1, rs2 } |
-            Op::Remuw { rd, rs1, rs2 } =>
+            Op::Remuw { rd, rs1, rs2 } |
+            Op::Sh1add { rd, rs1, rs2 } |
+            Op::Sh2add { rd, rs1, rs2 } |
+            Op::Sh3add { rd, rs1, rs2 } |
+            Op::AddUw { rd, rs1, rs2 } |
+            Op::Sh1addUw { rd, rs1, rs2 } |
+            Op::Sh2addUw { rd, rs1, rs2 } |
+            Op::Sh3addUw { rd, rs1, rs2 } |
+            Op::Andn { rd, rs1, rs2 } |
+            Op::Orn { rd, rs1, rs2 } |
+            Op::Xnor { rd, rs1, rs2 } |
+            Op::Min { rd, rs1, rs2 } |
+            Op::Minu { rd, rs1, rs2 } |
+            Op::Max { rd, rs1, rs2 } |
+            Op::Maxu { rd, rs1, rs2 } |
+            Op::Rol { rd, rs1, rs2 } |
+            Op::Ror { rd, rs1, rs2 } |
+            Op::Rolw { rd, rs1, rs2 } |
+            Op::Rorw { rd, rs1, rs2 } |
+            Op::Bclr { rd, rs1, rs2 } |
+            Op::Bext { rd, rs1, rs2 } |
+            Op::Binv { rd, rs1, rs2 } |
+            Op::Bset { rd, rs1, rs2 } =>
                 write!(fmt, "{}, {}, {}", register_name(rd), register_name(rs1), register_name(rs2))?,
             // CSR instructions store immediates differently.
             Op::Csrrw { rd, rs1, csr } |
@@ -445,6 +551,11 @@ impl Op {
     }
 }
 
+/// Format an instruction with its program counter and binary encoding, e.g. for logs and traces.
+pub fn format_instr(pc: u64, bits: u32, op: &Op) -> String {
+    op.pretty_print(pc, bits).to_string()
+}
+
 /// Be cautious if you want to rely on the printed the information from this trait implementation.
 /// For compressed jump and branches, the immediate will be incorrect. Use `Op::pretty_print` instead.
 impl fmt::Display for Op {
@@ -477,3 +588,28 @@ impl<'a> fmt::Display for Disasm<'a> {
         self.op.print(fmt, Some(self.pc))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode, decode_compressed};
+
+    #[test]
+    fn test_format_instr() {
+        let cases: &[(u64, u32, &str)] = &[
+            (0x1000, 0x00150513, "    1000:       00150513        addi    a0, a0, 1"),
+            (0x1000, 0x4595, "    1000:       4595            addi    a1, zero, 5"),
+            (0x1000, 0xfe0718e3, "    1000:       fe0718e3        bne     a4, zero, pc - 16 <ff0>"),
+            (0x80001000, 0x0000a083, "80001000:       0000a083        lw      ra, 0(ra)"),
+            (
+                0xffffffe000000000,
+                0x00008067,
+                "ffffffe000000000:       00008067        jalr    zero, 0(ra)",
+            ),
+        ];
+        for &(pc, bits, expected) in cases {
+            let op = if bits & 3 == 3 { decode(bits) } else { decode_compressed(bits as u16) };
+            assert_eq!(format_instr(pc, bits, &op), expected);
+        }
+    }
+}
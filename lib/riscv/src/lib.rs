@@ -1,12 +1,19 @@
 #![cfg_attr(not(test), no_std)]
 
+extern crate alloc;
+
 mod csr;
 mod decode;
 mod disasm;
 pub mod mmu;
 mod op;
+pub mod pmp;
+mod trap;
+mod verify;
 
 pub use csr::Csr;
 pub use decode::{decode, decode_compressed};
-pub use disasm::register_name;
-pub use op::{Op, Ordering};
+pub use disasm::{format_instr, register_from_name, register_name};
+pub use op::{Extension, Op, Ordering};
+pub use trap::Trap;
+pub use verify::verify_decode;
@@ -8,6 +8,14 @@ pub const PTE_U: u64 = 0x10;
 pub const PTE_G: u64 = 0x20;
 pub const PTE_A: u64 = 0x40;
 pub const PTE_D: u64 = 0x80;
+/// Page-based memory type (Svpbmt).
+pub const PTE_PBMT: u64 = 3 << 61;
+/// NAPOT translation contiguity (Svnapot).
+pub const PTE_N: u64 = 1 << 63;
+/// Reserved bits, which must be zero.
+const PTE_RESERVED: u64 = 0x7f << 54;
+/// Mask of the PPN after shifting out the flags.
+const PPN_MASK: u64 = (1 << 44) - 1;
 
 /// Type of access. This excludes STATUS, PRV and other states that may influence permission check.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -17,28 +25,44 @@ pub enum AccessType {
     Execute,
 }
 
-/// Walk the page table under SV39. We don't support SV48 at the moment.
+/// Walk the page table under SV39 or SV48, as selected by the mode field of `satp`.
+///
+/// Memory types selected by Svpbmt are validated but otherwise ignored, as all memory is treated
+/// alike; they are not part of the returned PTE. 64 KiB NAPOT mappings of Svnapot are supported,
+/// for which a PTE of the 4K page containing `vpn` is returned just like for other mappings.
 pub fn walk_page(satp: u64, vpn: u64, mut read_mem: impl FnMut(u64) -> u64) -> u64 {
+    let levels = match satp >> 60 {
+        8 => 3,
+        9 => 4,
+        _ => return 0,
+    };
+    let vpn_bits = levels * 9;
+
     // Check if the address is canonical.
-    if (((vpn << (64 - 27)) as i64) >> (64 - 27 - 12)) as u64 >> 12 != vpn {
+    if (((vpn << (64 - vpn_bits)) as i64) >> (64 - vpn_bits - 12)) as u64 >> 12 != vpn {
         return 0;
     }
 
-    let mut ppn = satp & ((1u64 << 44) - 1);
+    let mut ppn = satp & PPN_MASK;
     let mut global = false;
 
-    for i in 0..3 {
-        let bits_left = 18 - i * 9;
+    for i in 0..levels {
+        let bits_left = (levels - 1 - i) * 9;
         let index = (vpn >> bits_left) & 511;
         let pte_addr = (ppn << 12) + index * 8;
         let pte = read_mem(pte_addr);
-        ppn = pte >> 10;
+        ppn = (pte >> 10) & PPN_MASK;
 
         // Check for invalid PTE
         if pte & PTE_V == 0 {
             return 0;
         }
 
+        // Check for reserved bits and encodings. Memory type 3 is reserved.
+        if pte & PTE_RESERVED != 0 || pte & PTE_PBMT == PTE_PBMT {
+            return 0;
+        }
+
         // Check for malformed PTEs
         if pte & (PTE_R | PTE_W | PTE_X) == PTE_W {
             return 0;
@@ -52,8 +76,11 @@ pub fn walk_page(satp: u64, vpn: u64, mut read_mem: impl FnMut(u64) -> u64) -> u
             global = true
         }
 
-        // Not leaf yet
+        // Not leaf yet. Memory types and NAPOT are reserved in non-leaf PTEs.
         if pte & (PTE_R | PTE_W | PTE_X) == 0 {
+            if pte & (PTE_PBMT | PTE_N) != 0 {
+                return 0;
+            }
             continue;
         }
 
@@ -62,6 +89,16 @@ pub fn walk_page(satp: u64, vpn: u64, mut read_mem: impl FnMut(u64) -> u64) -> u
             return 0;
         }
 
+        // NAPOT mappings are only defined for 4K pages, with PPN[3:0] = 0b1000 encoding 64 KiB.
+        let (ppn, bits_left) = if pte & PTE_N != 0 {
+            if bits_left != 0 || ppn & 0xf != 0b1000 {
+                return 0;
+            }
+            (ppn & !0xf, 4)
+        } else {
+            (ppn, bits_left)
+        };
+
         // Synthesis a 4K PTE
         let ppn = ppn | (vpn & ((1 << bits_left) - 1));
         return ppn << 10 | pte & ((1 << 10) - 1) | (if global { PTE_G } else { 0 });
@@ -1,6 +1,7 @@
 use super::Csr;
 
-/// Ordering semantics for atomics.
+/// Ordering semantics for atomics, as encoded by the `aq` and `rl` bits. Setting both bits gives
+/// sequential consistency.
 #[derive(Clone, Copy, PartialEq)]
 pub enum Ordering {
     Relaxed = 0,
@@ -21,6 +22,36 @@ impl From<Ordering> for MemOrder {
     }
 }
 
+impl Ordering {
+    /// Memory ordering of the load part of an atomic operation. Loads cannot have release
+    /// semantics, so `.rl` alone imposes no ordering on them.
+    pub fn load_ordering(self) -> MemOrder {
+        match self {
+            Ordering::Relaxed | Ordering::Release => MemOrder::Relaxed,
+            Ordering::Acquire => MemOrder::Acquire,
+            Ordering::SeqCst => MemOrder::SeqCst,
+        }
+    }
+}
+
+/// ISA extensions that a RISC-V op may belong to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Extension {
+    I,
+    M,
+    A,
+    F,
+    D,
+    Zba,
+    Zbb,
+    Zbs,
+    Zicsr,
+    Zifencei,
+    Zihintntl,
+    /// Instructions defined by the privileged specification.
+    Privileged,
+}
+
 /// This includes all supported RISC-V ops.
 /// Ops are sorted in the following order
 /// * Canonical order of extension
@@ -238,6 +269,68 @@ pub enum Op {
     /* Base Opcode = NMADD */
     FnmaddD { frd: u8, frs1: u8, frs2: u8, frs3: u8, rm: u8 },
 
+    /* Zba extension */
+    /* Base Opcode = OP-IMM-32 */
+    SlliUw { rd: u8, rs1: u8, imm: i32 },
+    /* Base Opcode = OP */
+    Sh1add { rd: u8, rs1: u8, rs2: u8 },
+    Sh2add { rd: u8, rs1: u8, rs2: u8 },
+    Sh3add { rd: u8, rs1: u8, rs2: u8 },
+    /* Base Opcode = OP-32 */
+    AddUw { rd: u8, rs1: u8, rs2: u8 },
+    Sh1addUw { rd: u8, rs1: u8, rs2: u8 },
+    Sh2addUw { rd: u8, rs1: u8, rs2: u8 },
+    Sh3addUw { rd: u8, rs1: u8, rs2: u8 },
+
+    /* Zbb extension */
+    /* Base Opcode = OP-IMM */
+    Clz { rd: u8, rs1: u8 },
+    Ctz { rd: u8, rs1: u8 },
+    Cpop { rd: u8, rs1: u8 },
+    SextB { rd: u8, rs1: u8 },
+    SextH { rd: u8, rs1: u8 },
+    Rori { rd: u8, rs1: u8, imm: i32 },
+    OrcB { rd: u8, rs1: u8 },
+    Rev8 { rd: u8, rs1: u8 },
+    /* Base Opcode = OP-IMM-32 */
+    Clzw { rd: u8, rs1: u8 },
+    Ctzw { rd: u8, rs1: u8 },
+    Cpopw { rd: u8, rs1: u8 },
+    Roriw { rd: u8, rs1: u8, imm: i32 },
+    /* Base Opcode = OP */
+    Andn { rd: u8, rs1: u8, rs2: u8 },
+    Orn { rd: u8, rs1: u8, rs2: u8 },
+    Xnor { rd: u8, rs1: u8, rs2: u8 },
+    Min { rd: u8, rs1: u8, rs2: u8 },
+    Minu { rd: u8, rs1: u8, rs2: u8 },
+    Max { rd: u8, rs1: u8, rs2: u8 },
+    Maxu { rd: u8, rs1: u8, rs2: u8 },
+    Rol { rd: u8, rs1: u8, rs2: u8 },
+    Ror { rd: u8, rs1: u8, rs2: u8 },
+    /* Base Opcode = OP-32 */
+    ZextH { rd: u8, rs1: u8 },
+    Rolw { rd: u8, rs1: u8, rs2: u8 },
+    Rorw { rd: u8, rs1: u8, rs2: u8 },
+
+    /* Zbs extension */
+    /* Base Opcode = OP-IMM */
+    Bclri { rd: u8, rs1: u8, imm: i32 },
+    Bexti { rd: u8, rs1: u8, imm: i32 },
+    Binvi { rd: u8, rs1: u8, imm: i32 },
+    Bseti { rd: u8, rs1: u8, imm: i32 },
+    /* Base Opcode = OP */
+    Bclr { rd: u8, rs1: u8, rs2: u8 },
+    Bext { rd: u8, rs1: u8, rs2: u8 },
+    Binv { rd: u8, rs1: u8, rs2: u8 },
+    Bset { rd: u8, rs1: u8, rs2: u8 },
+
+    /* Zihintntl extension */
+    /* Base Opcode = OP, encoded as add x0, x0, x2 to x5 */
+    NtlP1,
+    NtlPall,
+    NtlS1,
+    NtlAll,
+
     /* Privileged */
     Mret,
     Sret,
@@ -246,6 +339,87 @@ pub enum Op {
 }
 
 impl Op {
+    /// Get the ISA extension that the op belongs to.
+    ///
+    /// Compressed instructions are decoded into their expanded form, so they are classified by the
+    /// extension of the expanded op. `Op::Illegal` is classified as part of the base ISA.
+    #[rustfmt::skip]
+    pub fn extension(self) -> Extension {
+        match self {
+            Op::Illegal |
+            Op::Lb {..} | Op::Lh {..} | Op::Lw {..} | Op::Ld {..} |
+            Op::Lbu {..} | Op::Lhu {..} | Op::Lwu {..} |
+            Op::Fence |
+            Op::Addi {..} | Op::Slli {..} | Op::Slti {..} | Op::Sltiu {..} | Op::Xori {..} |
+            Op::Srli {..} | Op::Srai {..} | Op::Ori {..} | Op::Andi {..} |
+            Op::Auipc {..} |
+            Op::Addiw {..} | Op::Slliw {..} | Op::Srliw {..} | Op::Sraiw {..} |
+            Op::Sb {..} | Op::Sh {..} | Op::Sw {..} | Op::Sd {..} |
+            Op::Add {..} | Op::Sub {..} | Op::Sll {..} | Op::Slt {..} | Op::Sltu {..} |
+            Op::Xor {..} | Op::Srl {..} | Op::Sra {..} | Op::Or {..} | Op::And {..} |
+            Op::Lui {..} |
+            Op::Addw {..} | Op::Subw {..} | Op::Sllw {..} | Op::Srlw {..} | Op::Sraw {..} |
+            Op::Beq {..} | Op::Bne {..} | Op::Blt {..} | Op::Bge {..} | Op::Bltu {..} |
+            Op::Bgeu {..} |
+            Op::Jalr {..} | Op::Jal {..} |
+            Op::Ecall | Op::Ebreak => Extension::I,
+
+            Op::FenceI => Extension::Zifencei,
+
+            Op::Csrrw {..} | Op::Csrrs {..} | Op::Csrrc {..} |
+            Op::Csrrwi {..} | Op::Csrrsi {..} | Op::Csrrci {..} => Extension::Zicsr,
+
+            Op::Mul {..} | Op::Mulh {..} | Op::Mulhsu {..} | Op::Mulhu {..} |
+            Op::Div {..} | Op::Divu {..} | Op::Rem {..} | Op::Remu {..} |
+            Op::Mulw {..} | Op::Divw {..} | Op::Divuw {..} | Op::Remw {..} |
+            Op::Remuw {..} => Extension::M,
+
+            Op::LrW {..} | Op::LrD {..} | Op::ScW {..} | Op::ScD {..} |
+            Op::AmoswapW {..} | Op::AmoswapD {..} | Op::AmoaddW {..} | Op::AmoaddD {..} |
+            Op::AmoxorW {..} | Op::AmoxorD {..} | Op::AmoandW {..} | Op::AmoandD {..} |
+            Op::AmoorW {..} | Op::AmoorD {..} | Op::AmominW {..} | Op::AmominD {..} |
+            Op::AmomaxW {..} | Op::AmomaxD {..} | Op::AmominuW {..} | Op::AmominuD {..} |
+            Op::AmomaxuW {..} | Op::AmomaxuD {..} => Extension::A,
+
+            Op::Flw {..} | Op::Fsw {..} |
+            Op::FaddS {..} | Op::FsubS {..} | Op::FmulS {..} | Op::FdivS {..} |
+            Op::FsqrtS {..} | Op::FsgnjS {..} | Op::FsgnjnS {..} | Op::FsgnjxS {..} |
+            Op::FminS {..} | Op::FmaxS {..} |
+            Op::FcvtWS {..} | Op::FcvtWuS {..} | Op::FcvtLS {..} | Op::FcvtLuS {..} |
+            Op::FmvXW {..} | Op::FclassS {..} | Op::FeqS {..} | Op::FltS {..} | Op::FleS {..} |
+            Op::FcvtSW {..} | Op::FcvtSWu {..} | Op::FcvtSL {..} | Op::FcvtSLu {..} |
+            Op::FmvWX {..} |
+            Op::FmaddS {..} | Op::FmsubS {..} | Op::FnmsubS {..} | Op::FnmaddS {..} => Extension::F,
+
+            Op::Fld {..} | Op::Fsd {..} |
+            Op::FaddD {..} | Op::FsubD {..} | Op::FmulD {..} | Op::FdivD {..} |
+            Op::FsqrtD {..} | Op::FsgnjD {..} | Op::FsgnjnD {..} | Op::FsgnjxD {..} |
+            Op::FminD {..} | Op::FmaxD {..} | Op::FcvtSD {..} | Op::FcvtDS {..} |
+            Op::FcvtWD {..} | Op::FcvtWuD {..} | Op::FcvtLD {..} | Op::FcvtLuD {..} |
+            Op::FmvXD {..} | Op::FclassD {..} | Op::FeqD {..} | Op::FltD {..} | Op::FleD {..} |
+            Op::FcvtDW {..} | Op::FcvtDWu {..} | Op::FcvtDL {..} | Op::FcvtDLu {..} |
+            Op::FmvDX {..} |
+            Op::FmaddD {..} | Op::FmsubD {..} | Op::FnmsubD {..} | Op::FnmaddD {..} => Extension::D,
+
+            Op::SlliUw {..} | Op::Sh1add {..} | Op::Sh2add {..} | Op::Sh3add {..} |
+            Op::AddUw {..} | Op::Sh1addUw {..} | Op::Sh2addUw {..} | Op::Sh3addUw {..} => Extension::Zba,
+
+            Op::Clz {..} | Op::Ctz {..} | Op::Cpop {..} | Op::SextB {..} | Op::SextH {..} |
+            Op::Rori {..} | Op::OrcB {..} | Op::Rev8 {..} |
+            Op::Clzw {..} | Op::Ctzw {..} | Op::Cpopw {..} | Op::Roriw {..} |
+            Op::Andn {..} | Op::Orn {..} | Op::Xnor {..} |
+            Op::Min {..} | Op::Minu {..} | Op::Max {..} | Op::Maxu {..} | Op::Rol {..} | Op::Ror {..} |
+            Op::ZextH {..} | Op::Rolw {..} | Op::Rorw {..} => Extension::Zbb,
+
+            Op::Bclri {..} | Op::Bexti {..} | Op::Binvi {..} | Op::Bseti {..} |
+            Op::Bclr {..} | Op::Bext {..} | Op::Binv {..} | Op::Bset {..} => Extension::Zbs,
+
+            Op::NtlP1 | Op::NtlPall | Op::NtlS1 | Op::NtlAll => Extension::Zihintntl,
+
+            Op::Mret | Op::Sret | Op::Wfi | Op::SfenceVma {..} => Extension::Privileged,
+        }
+    }
+
     pub fn can_change_control_flow(&self) -> bool {
         match self {
             // Branch and jump instructions will definitely disrupt the control flow.
@@ -375,6 +549,47 @@ impl Op {
             | Op::Divuw { rd, rs1, rs2 }
             | Op::Remw { rd, rs1, rs2 }
             | Op::Remuw { rd, rs1, rs2 } => (rd, rs1, rs2),
+            Op::SlliUw { rd, rs1, .. }
+            | Op::Rori { rd, rs1, .. }
+            | Op::Roriw { rd, rs1, .. }
+            | Op::Bclri { rd, rs1, .. }
+            | Op::Bexti { rd, rs1, .. }
+            | Op::Binvi { rd, rs1, .. }
+            | Op::Bseti { rd, rs1, .. } => (rd, rs1, 0),
+            Op::Clz { rd, rs1 }
+            | Op::Ctz { rd, rs1 }
+            | Op::Cpop { rd, rs1 }
+            | Op::SextB { rd, rs1 }
+            | Op::SextH { rd, rs1 }
+            | Op::OrcB { rd, rs1 }
+            | Op::Rev8 { rd, rs1 }
+            | Op::Clzw { rd, rs1 }
+            | Op::Ctzw { rd, rs1 }
+            | Op::Cpopw { rd, rs1 }
+            | Op::ZextH { rd, rs1 } => (rd, rs1, 0),
+            Op::Sh1add { rd, rs1, rs2 }
+            | Op::Sh2add { rd, rs1, rs2 }
+            | Op::Sh3add { rd, rs1, rs2 }
+            | Op::AddUw { rd, rs1, rs2 }
+            | Op::Sh1addUw { rd, rs1, rs2 }
+            | Op::Sh2addUw { rd, rs1, rs2 }
+            | Op::Sh3addUw { rd, rs1, rs2 }
+            | Op::Andn { rd, rs1, rs2 }
+            | Op::Orn { rd, rs1, rs2 }
+            | Op::Xnor { rd, rs1, rs2 }
+            | Op::Min { rd, rs1, rs2 }
+            | Op::Minu { rd, rs1, rs2 }
+            | Op::Max { rd, rs1, rs2 }
+            | Op::Maxu { rd, rs1, rs2 }
+            | Op::Rol { rd, rs1, rs2 }
+            | Op::Ror { rd, rs1, rs2 }
+            | Op::Rolw { rd, rs1, rs2 }
+            | Op::Rorw { rd, rs1, rs2 }
+            | Op::Bclr { rd, rs1, rs2 }
+            | Op::Bext { rd, rs1, rs2 }
+            | Op::Binv { rd, rs1, rs2 }
+            | Op::Bset { rd, rs1, rs2 } => (rd, rs1, rs2),
+            Op::NtlP1 | Op::NtlPall | Op::NtlS1 | Op::NtlAll => (0, 0, 0),
             Op::Csrrw { rd, rs1, .. } | Op::Csrrs { rd, rs1, .. } | Op::Csrrc { rd, rs1, .. } => {
                 (rd, rs1, 0)
             }
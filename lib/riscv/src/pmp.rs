@@ -0,0 +1,115 @@
+//! Physical memory protection.
+//!
+//! Configurations are stored as they appear in RV64 `pmpcfg` CSRs, i.e. each `u64` holds the
+//! 8-bit configurations of 8 consecutive entries, with the lowest-numbered entry in the lowest
+//! byte. The granularity is 4 bytes, so all address-matching modes are supported.
+
+use super::mmu::AccessType;
+
+pub const PMP_R: u8 = 0x01;
+pub const PMP_W: u8 = 0x02;
+pub const PMP_X: u8 = 0x04;
+/// Address-matching mode.
+pub const PMP_A: u8 = 0x18;
+pub const PMP_TOR: u8 = 0x08;
+pub const PMP_NA4: u8 = 0x10;
+pub const PMP_NAPOT: u8 = 0x18;
+pub const PMP_L: u8 = 0x80;
+
+/// Bits of `pmpaddr` that are implemented, i.e. bits 55:2 of a physical address.
+pub const PMP_ADDR_MASK: u64 = (1 << 54) - 1;
+
+/// Get the configuration of the `index`-th entry.
+pub fn entry_cfg(cfg: &[u64], index: usize) -> u8 {
+    (cfg[index / 8] >> (index % 8 * 8)) as u8
+}
+
+/// Check whether any entry is enabled, i.e. has an address-matching mode other than OFF.
+pub fn is_active(cfg: &[u64]) -> bool {
+    cfg.iter().any(|&word| word & 0x1818181818181818 != 0)
+}
+
+/// Get the range of physical addresses matched by the `index`-th entry, or `None` if it is off.
+fn entry_range(cfg: &[u64], addr: &[u64], index: usize) -> Option<(u64, u64)> {
+    let pmpaddr = addr[index] & PMP_ADDR_MASK;
+    match entry_cfg(cfg, index) & PMP_A {
+        PMP_TOR => {
+            let low = if index == 0 { 0 } else { (addr[index - 1] & PMP_ADDR_MASK) << 2 };
+            Some((low, pmpaddr << 2))
+        }
+        PMP_NA4 => Some((pmpaddr << 2, (pmpaddr << 2) + 4)),
+        PMP_NAPOT => {
+            // The number of trailing ones encodes the size of the region.
+            let ones = (!pmpaddr).trailing_zeros();
+            let low = (pmpaddr & !((1 << ones) - 1)) << 2;
+            Some((low, low + (1 << (ones + 3))))
+        }
+        _ => None,
+    }
+}
+
+/// Check whether an access to physical address `paddr` at privilege level `prv` is allowed.
+///
+/// The lowest-numbered entry matching `paddr` determines the permission. Machine mode is only
+/// restricted by locked entries. An access not matching any entry is allowed in machine mode.
+/// Other privilege levels are denied unmatched accesses only if some entry is enabled, so
+/// software unaware of PMP is not affected.
+pub fn check(cfg: &[u64], addr: &[u64], paddr: u64, access: AccessType, prv: u8) -> bool {
+    for index in 0..addr.len() {
+        let (low, high) = match entry_range(cfg, addr, index) {
+            Some(v) => v,
+            None => continue,
+        };
+        if paddr < low || paddr >= high {
+            continue;
+        }
+        let entry = entry_cfg(cfg, index);
+        if prv == 3 && entry & PMP_L == 0 {
+            return true;
+        }
+        let bit = match access {
+            AccessType::Read => PMP_R,
+            AccessType::Write => PMP_W,
+            AccessType::Execute => PMP_X,
+        };
+        return entry & bit != 0;
+    }
+    prv == 3 || !is_active(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pmp_check() {
+        let mut cfg = [0; 2];
+        let mut addr = [0; 16];
+        // Without enabled entries, everything is allowed.
+        assert!(check(&cfg, &addr, 0x8000_0000, AccessType::Write, 0));
+
+        // Entry 0: NAPOT 0x8000_0000..0x8000_1000, read-only.
+        // Entry 1: TOR from the address of entry 0 to 0x9000_0000, locked and read-write.
+        // Entry 9: NA4 0x1000..0x1004, execute-only.
+        addr[0] = (0x8000_0000 >> 2) | 0x1ff;
+        addr[1] = 0x9000_0000 >> 2;
+        addr[9] = 0x1000 >> 2;
+        cfg[0] = ((PMP_NAPOT | PMP_R) as u64) | ((PMP_TOR | PMP_L | PMP_R | PMP_W) as u64) << 8;
+        cfg[1] = ((PMP_NA4 | PMP_X) as u64) << 8;
+
+        // The first matching entry wins.
+        assert!(check(&cfg, &addr, 0x8000_0ff8, AccessType::Read, 1));
+        assert!(!check(&cfg, &addr, 0x8000_0ff8, AccessType::Write, 1));
+        assert!(check(&cfg, &addr, 0x8000_1000, AccessType::Write, 1));
+        assert!(!check(&cfg, &addr, 0x8000_1000, AccessType::Execute, 1));
+        // Machine mode is only restricted by locked entries.
+        assert!(check(&cfg, &addr, 0x8000_0ff8, AccessType::Write, 3));
+        assert!(!check(&cfg, &addr, 0x8000_1000, AccessType::Execute, 3));
+        // NA4
+        assert!(check(&cfg, &addr, 0x1000, AccessType::Execute, 0));
+        assert!(!check(&cfg, &addr, 0x1004, AccessType::Execute, 0));
+        // Unmatched accesses are denied below machine mode once entries are enabled.
+        assert!(!check(&cfg, &addr, 0x9000_0000, AccessType::Read, 1));
+        assert!(check(&cfg, &addr, 0x9000_0000, AccessType::Read, 3));
+    }
+}
@@ -0,0 +1,151 @@
+/// A trap, i.e. an exception or an interrupt. Exceptions carry their trap value.
+///
+/// This is the structured form of a `cause` and `tval` pair as written to `mcause` and `mtval`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trap {
+    InstructionMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvCallFromU,
+    EnvCallFromS,
+    EnvCallFromM,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+
+    UserSoftware,
+    SupervisorSoftware,
+    MachineSoftware,
+    UserTimer,
+    SupervisorTimer,
+    MachineTimer,
+    UserExternal,
+    SupervisorExternal,
+    MachineExternal,
+
+    /// A reserved or custom cause.
+    Other {
+        cause: u64,
+        tval: u64,
+    },
+}
+
+impl Trap {
+    /// Get the trap for a `cause` and `tval` pair.
+    pub fn new(cause: u64, tval: u64) -> Trap {
+        if cause >> 63 != 0 {
+            return match cause & !(1 << 63) {
+                0 => Trap::UserSoftware,
+                1 => Trap::SupervisorSoftware,
+                3 => Trap::MachineSoftware,
+                4 => Trap::UserTimer,
+                5 => Trap::SupervisorTimer,
+                7 => Trap::MachineTimer,
+                8 => Trap::UserExternal,
+                9 => Trap::SupervisorExternal,
+                11 => Trap::MachineExternal,
+                _ => Trap::Other { cause, tval },
+            };
+        }
+        match cause {
+            0 => Trap::InstructionMisaligned(tval),
+            1 => Trap::InstructionAccessFault(tval),
+            2 => Trap::IllegalInstruction(tval),
+            3 => Trap::Breakpoint(tval),
+            4 => Trap::LoadMisaligned(tval),
+            5 => Trap::LoadAccessFault(tval),
+            6 => Trap::StoreMisaligned(tval),
+            7 => Trap::StoreAccessFault(tval),
+            8 => Trap::EnvCallFromU,
+            9 => Trap::EnvCallFromS,
+            11 => Trap::EnvCallFromM,
+            12 => Trap::InstructionPageFault(tval),
+            13 => Trap::LoadPageFault(tval),
+            15 => Trap::StorePageFault(tval),
+            _ => Trap::Other { cause, tval },
+        }
+    }
+
+    /// Whether this trap is an interrupt.
+    pub fn is_interrupt(&self) -> bool {
+        self.cause() >> 63 != 0
+    }
+
+    /// The value of `mcause` for this trap.
+    pub fn cause(&self) -> u64 {
+        const INTERRUPT: u64 = 1 << 63;
+        match *self {
+            Trap::InstructionMisaligned(_) => 0,
+            Trap::InstructionAccessFault(_) => 1,
+            Trap::IllegalInstruction(_) => 2,
+            Trap::Breakpoint(_) => 3,
+            Trap::LoadMisaligned(_) => 4,
+            Trap::LoadAccessFault(_) => 5,
+            Trap::StoreMisaligned(_) => 6,
+            Trap::StoreAccessFault(_) => 7,
+            Trap::EnvCallFromU => 8,
+            Trap::EnvCallFromS => 9,
+            Trap::EnvCallFromM => 11,
+            Trap::InstructionPageFault(_) => 12,
+            Trap::LoadPageFault(_) => 13,
+            Trap::StorePageFault(_) => 15,
+            Trap::UserSoftware => INTERRUPT,
+            Trap::SupervisorSoftware => INTERRUPT | 1,
+            Trap::MachineSoftware => INTERRUPT | 3,
+            Trap::UserTimer => INTERRUPT | 4,
+            Trap::SupervisorTimer => INTERRUPT | 5,
+            Trap::MachineTimer => INTERRUPT | 7,
+            Trap::UserExternal => INTERRUPT | 8,
+            Trap::SupervisorExternal => INTERRUPT | 9,
+            Trap::MachineExternal => INTERRUPT | 11,
+            Trap::Other { cause, .. } => cause,
+        }
+    }
+
+    /// The value of `mtval` for this trap. It is 0 for traps without a trap value.
+    pub fn tval(&self) -> u64 {
+        match *self {
+            Trap::InstructionMisaligned(tval)
+            | Trap::InstructionAccessFault(tval)
+            | Trap::IllegalInstruction(tval)
+            | Trap::Breakpoint(tval)
+            | Trap::LoadMisaligned(tval)
+            | Trap::LoadAccessFault(tval)
+            | Trap::StoreMisaligned(tval)
+            | Trap::StoreAccessFault(tval)
+            | Trap::InstructionPageFault(tval)
+            | Trap::LoadPageFault(tval)
+            | Trap::StorePageFault(tval)
+            | Trap::Other { tval, .. } => tval,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trap_cause() {
+        assert_eq!(Trap::new(2, 0x13), Trap::IllegalInstruction(0x13));
+        assert_eq!(Trap::new(9, 0), Trap::EnvCallFromS);
+        assert_eq!(Trap::new(13, 0x1000), Trap::LoadPageFault(0x1000));
+        assert_eq!(Trap::new(15, 0x2000), Trap::StorePageFault(0x2000));
+        assert_eq!(Trap::new(1 << 63 | 5, 0), Trap::SupervisorTimer);
+        assert_eq!(Trap::new(1 << 63 | 9, 0), Trap::SupervisorExternal);
+        assert_eq!(Trap::new(10, 1), Trap::Other { cause: 10, tval: 1 });
+        assert_eq!(Trap::new(1 << 63 | 2, 0), Trap::Other { cause: 1 << 63 | 2, tval: 0 });
+
+        for &(cause, tval) in &[(2, 0x13), (8, 0), (13, 0x1000), (1 << 63 | 7, 0), (24, 5)] {
+            let trap = Trap::new(cause, tval);
+            assert_eq!((trap.cause(), trap.tval()), (cause, tval));
+            assert_eq!(trap.is_interrupt(), cause >> 63 != 0);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Table-driven reference decoder for cross-checking [`decode`](crate::decode).
+//!
+//! The reference decoder only identifies instructions by their mask and match values, which makes
+//! it slow but simple enough to be obviously correct. It covers 32-bit RV64GC instructions and the
+//! Zba, Zbb, Zbs and Zihintntl extensions; compressed instructions are not verified.
+
+use super::op::Op;
+
+/// `(mask, match, mnemonic)` of all 32-bit instructions supported.
+#[rustfmt::skip]
+const INSTRUCTIONS: &[(u32, u32, &str)] = &[
+    (0x0000007f, 0x00000037, "lui"),
+    (0x0000007f, 0x00000017, "auipc"),
+    (0x0000007f, 0x0000006f, "jal"),
+    (0x0000707f, 0x00000067, "jalr"),
+    (0x0000707f, 0x00000063, "beq"),
+    (0x0000707f, 0x00001063, "bne"),
+    (0x0000707f, 0x00004063, "blt"),
+    (0x0000707f, 0x00005063, "bge"),
+    (0x0000707f, 0x00006063, "bltu"),
+    (0x0000707f, 0x00007063, "bgeu"),
+    (0x0000707f, 0x00000003, "lb"),
+    (0x0000707f, 0x00001003, "lh"),
+    (0x0000707f, 0x00002003, "lw"),
+    (0x0000707f, 0x00003003, "ld"),
+    (0x0000707f, 0x00004003, "lbu"),
+    (0x0000707f, 0x00005003, "lhu"),
+    (0x0000707f, 0x00006003, "lwu"),
+    (0x0000707f, 0x00000023, "sb"),
+    (0x0000707f, 0x00001023, "sh"),
+    (0x0000707f, 0x00002023, "sw"),
+    (0x0000707f, 0x00003023, "sd"),
+    (0x0000707f, 0x00000013, "addi"),
+    (0x0000707f, 0x00002013, "slti"),
+    (0x0000707f, 0x00003013, "sltiu"),
+    (0x0000707f, 0x00004013, "xori"),
+    (0x0000707f, 0x00006013, "ori"),
+    (0x0000707f, 0x00007013, "andi"),
+    (0xfc00707f, 0x00001013, "slli"),
+    (0xfc00707f, 0x00005013, "srli"),
+    (0xfc00707f, 0x40005013, "srai"),
+    // Zihintntl hints are encodings of add and must precede it.
+    (0xffffffff, 0x00200033, "ntl.p1"),
+    (0xffffffff, 0x00300033, "ntl.pall"),
+    (0xffffffff, 0x00400033, "ntl.s1"),
+    (0xffffffff, 0x00500033, "ntl.all"),
+    (0xfe00707f, 0x00000033, "add"),
+    (0xfe00707f, 0x40000033, "sub"),
+    (0xfe00707f, 0x00001033, "sll"),
+    (0xfe00707f, 0x00002033, "slt"),
+    (0xfe00707f, 0x00003033, "sltu"),
+    (0xfe00707f, 0x00004033, "xor"),
+    (0xfe00707f, 0x00005033, "srl"),
+    (0xfe00707f, 0x40005033, "sra"),
+    (0xfe00707f, 0x00006033, "or"),
+    (0xfe00707f, 0x00007033, "and"),
+    (0x0000707f, 0x0000001b, "addiw"),
+    (0xfe00707f, 0x0000101b, "slliw"),
+    (0xfe00707f, 0x0000501b, "srliw"),
+    (0xfe00707f, 0x4000501b, "sraiw"),
+    (0xfe00707f, 0x0000003b, "addw"),
+    (0xfe00707f, 0x4000003b, "subw"),
+    (0xfe00707f, 0x0000103b, "sllw"),
+    (0xfe00707f, 0x0000503b, "srlw"),
+    (0xfe00707f, 0x4000503b, "sraw"),
+    (0xfe00707f, 0x02000033, "mul"),
+    (0xfe00707f, 0x02001033, "mulh"),
+    (0xfe00707f, 0x02002033, "mulhsu"),
+    (0xfe00707f, 0x02003033, "mulhu"),
+    (0xfe00707f, 0x02004033, "div"),
+    (0xfe00707f, 0x02005033, "divu"),
+    (0xfe00707f, 0x02006033, "rem"),
+    (0xfe00707f, 0x02007033, "remu"),
+    (0xfe00707f, 0x0200003b, "mulw"),
+    (0xfe00707f, 0x0200403b, "divw"),
+    (0xfe00707f, 0x0200503b, "divuw"),
+    (0xfe00707f, 0x0200603b, "remw"),
+    (0xfe00707f, 0x0200703b, "remuw"),
+    (0x0000707f, 0x0000000f, "fence"),
+    (0x0000707f, 0x0000100f, "fence.i"),
+    (0xffffffff, 0x00000073, "ecall"),
+    (0xffffffff, 0x00100073, "ebreak"),
+    (0xffffffff, 0x10200073, "sret"),
+    (0xffffffff, 0x30200073, "mret"),
+    (0xffffffff, 0x10500073, "wfi"),
+    (0xfe007fff, 0x12000073, "sfence.vma"),
+    (0x0000707f, 0x00001073, "csrrw"),
+    (0x0000707f, 0x00002073, "csrrs"),
+    (0x0000707f, 0x00003073, "csrrc"),
+    (0x0000707f, 0x00005073, "csrrwi"),
+    (0x0000707f, 0x00006073, "csrrsi"),
+    (0x0000707f, 0x00007073, "csrrci"),
+    (0xf9f0707f, 0x1000202f, "lr.w"),
+    (0xf800707f, 0x1800202f, "sc.w"),
+    (0xf800707f, 0x0800202f, "amoswap.w"),
+    (0xf800707f, 0x0000202f, "amoadd.w"),
+    (0xf800707f, 0x2000202f, "amoxor.w"),
+    (0xf800707f, 0x6000202f, "amoand.w"),
+    (0xf800707f, 0x4000202f, "amoor.w"),
+    (0xf800707f, 0x8000202f, "amomin.w"),
+    (0xf800707f, 0xa000202f, "amomax.w"),
+    (0xf800707f, 0xc000202f, "amominu.w"),
+    (0xf800707f, 0xe000202f, "amomaxu.w"),
+    (0xf9f0707f, 0x1000302f, "lr.d"),
+    (0xf800707f, 0x1800302f, "sc.d"),
+    (0xf800707f, 0x0800302f, "amoswap.d"),
+    (0xf800707f, 0x0000302f, "amoadd.d"),
+    (0xf800707f, 0x2000302f, "amoxor.d"),
+    (0xf800707f, 0x6000302f, "amoand.d"),
+    (0xf800707f, 0x4000302f, "amoor.d"),
+    (0xf800707f, 0x8000302f, "amomin.d"),
+    (0xf800707f, 0xa000302f, "amomax.d"),
+    (0xf800707f, 0xc000302f, "amominu.d"),
+    (0xf800707f, 0xe000302f, "amomaxu.d"),
+    (0x0000707f, 0x00002007, "flw"),
+    (0x0000707f, 0x00003007, "fld"),
+    (0x0000707f, 0x00002027, "fsw"),
+    (0x0000707f, 0x00003027, "fsd"),
+    (0x0600007f, 0x00000043, "fmadd.s"),
+    (0x0600007f, 0x00000047, "fmsub.s"),
+    (0x0600007f, 0x0000004b, "fnmsub.s"),
+    (0x0600007f, 0x0000004f, "fnmadd.s"),
+    (0x0600007f, 0x02000043, "fmadd.d"),
+    (0x0600007f, 0x02000047, "fmsub.d"),
+    (0x0600007f, 0x0200004b, "fnmsub.d"),
+    (0x0600007f, 0x0200004f, "fnmadd.d"),
+    (0xfe00007f, 0x00000053, "fadd.s"),
+    (0xfe00007f, 0x08000053, "fsub.s"),
+    (0xfe00007f, 0x10000053, "fmul.s"),
+    (0xfe00007f, 0x18000053, "fdiv.s"),
+    (0xfff0007f, 0x58000053, "fsqrt.s"),
+    (0xfe00707f, 0x20000053, "fsgnj.s"),
+    (0xfe00707f, 0x20001053, "fsgnjn.s"),
+    (0xfe00707f, 0x20002053, "fsgnjx.s"),
+    (0xfe00707f, 0x28000053, "fmin.s"),
+    (0xfe00707f, 0x28001053, "fmax.s"),
+    (0xfff0007f, 0xc0000053, "fcvt.w.s"),
+    (0xfff0007f, 0xc0100053, "fcvt.wu.s"),
+    (0xfff0007f, 0xc0200053, "fcvt.l.s"),
+    (0xfff0007f, 0xc0300053, "fcvt.lu.s"),
+    (0xfff0707f, 0xe0000053, "fmv.x.w"),
+    (0xfff0707f, 0xe0001053, "fclass.s"),
+    (0xfe00707f, 0xa0002053, "feq.s"),
+    (0xfe00707f, 0xa0001053, "flt.s"),
+    (0xfe00707f, 0xa0000053, "fle.s"),
+    (0xfff0007f, 0xd0000053, "fcvt.s.w"),
+    (0xfff0007f, 0xd0100053, "fcvt.s.wu"),
+    (0xfff0007f, 0xd0200053, "fcvt.s.l"),
+    (0xfff0007f, 0xd0300053, "fcvt.s.lu"),
+    (0xfff0707f, 0xf0000053, "fmv.w.x"),
+    (0xfe00007f, 0x02000053, "fadd.d"),
+    (0xfe00007f, 0x0a000053, "fsub.d"),
+    (0xfe00007f, 0x12000053, "fmul.d"),
+    (0xfe00007f, 0x1a000053, "fdiv.d"),
+    (0xfff0007f, 0x5a000053, "fsqrt.d"),
+    (0xfe00707f, 0x22000053, "fsgnj.d"),
+    (0xfe00707f, 0x22001053, "fsgnjn.d"),
+    (0xfe00707f, 0x22002053, "fsgnjx.d"),
+    (0xfe00707f, 0x2a000053, "fmin.d"),
+    (0xfe00707f, 0x2a001053, "fmax.d"),
+    (0xfff0007f, 0x40100053, "fcvt.s.d"),
+    (0xfff0007f, 0x42000053, "fcvt.d.s"),
+    (0xfff0007f, 0xc2000053, "fcvt.w.d"),
+    (0xfff0007f, 0xc2100053, "fcvt.wu.d"),
+    (0xfff0007f, 0xc2200053, "fcvt.l.d"),
+    (0xfff0007f, 0xc2300053, "fcvt.lu.d"),
+    (0xfff0707f, 0xe2000053, "fmv.x.d"),
+    (0xfff0707f, 0xe2001053, "fclass.d"),
+    (0xfe00707f, 0xa2002053, "feq.d"),
+    (0xfe00707f, 0xa2001053, "flt.d"),
+    (0xfe00707f, 0xa2000053, "fle.d"),
+    (0xfff0007f, 0xd2000053, "fcvt.d.w"),
+    (0xfff0007f, 0xd2100053, "fcvt.d.wu"),
+    (0xfff0007f, 0xd2200053, "fcvt.d.l"),
+    (0xfff0007f, 0xd2300053, "fcvt.d.lu"),
+    (0xfff0707f, 0xf2000053, "fmv.d.x"),
+    (0xfc00707f, 0x0800101b, "slli.uw"),
+    (0xfe00707f, 0x20002033, "sh1add"),
+    (0xfe00707f, 0x20004033, "sh2add"),
+    (0xfe00707f, 0x20006033, "sh3add"),
+    (0xfe00707f, 0x0800003b, "add.uw"),
+    (0xfe00707f, 0x2000203b, "sh1add.uw"),
+    (0xfe00707f, 0x2000403b, "sh2add.uw"),
+    (0xfe00707f, 0x2000603b, "sh3add.uw"),
+    (0xfff0707f, 0x60001013, "clz"),
+    (0xfff0707f, 0x60101013, "ctz"),
+    (0xfff0707f, 0x60201013, "cpop"),
+    (0xfff0707f, 0x60401013, "sext.b"),
+    (0xfff0707f, 0x60501013, "sext.h"),
+    (0xfc00707f, 0x60005013, "rori"),
+    (0xfff0707f, 0x28705013, "orc.b"),
+    (0xfff0707f, 0x6b805013, "rev8"),
+    (0xfff0707f, 0x6000101b, "clzw"),
+    (0xfff0707f, 0x6010101b, "ctzw"),
+    (0xfff0707f, 0x6020101b, "cpopw"),
+    (0xfe00707f, 0x6000501b, "roriw"),
+    (0xfe00707f, 0x40007033, "andn"),
+    (0xfe00707f, 0x40006033, "orn"),
+    (0xfe00707f, 0x40004033, "xnor"),
+    (0xfe00707f, 0x0a004033, "min"),
+    (0xfe00707f, 0x0a005033, "minu"),
+    (0xfe00707f, 0x0a006033, "max"),
+    (0xfe00707f, 0x0a007033, "maxu"),
+    (0xfe00707f, 0x60001033, "rol"),
+    (0xfe00707f, 0x60005033, "ror"),
+    (0xfff0707f, 0x0800403b, "zext.h"),
+    (0xfe00707f, 0x6000103b, "rolw"),
+    (0xfe00707f, 0x6000503b, "rorw"),
+    (0xfc00707f, 0x48001013, "bclri"),
+    (0xfc00707f, 0x48005013, "bexti"),
+    (0xfc00707f, 0x68001013, "binvi"),
+    (0xfc00707f, 0x28001013, "bseti"),
+    (0xfe00707f, 0x48001033, "bclr"),
+    (0xfe00707f, 0x48005033, "bext"),
+    (0xfe00707f, 0x68001033, "binv"),
+    (0xfe00707f, 0x28001033, "bset"),
+];
+
+/// Decode the mnemonic of a 32-bit instruction using the reference table.
+fn reference_mnemonic(bits: u32) -> &'static str {
+    for &(mask, value, mnemonic) in INSTRUCTIONS {
+        if bits & mask != value {
+            continue;
+        }
+        // Floating point instructions with a rounding mode field are illegal with reserved modes.
+        let opcode = bits & 0x7f;
+        let fp = opcode == 0x53 || (0x43..=0x4f).contains(&opcode);
+        let rm = (bits >> 12) & 7;
+        if fp && mask & 0x7000 == 0 && (rm == 5 || rm == 6) {
+            return "illegal";
+        }
+        // CSR instructions writing read-only CSRs are illegal. CSRRS and CSRRC with rs1 = 0, and
+        // their immediate versions with zero immediates, do not write.
+        if opcode == 0x73 && rm != 0 {
+            let writes = rm & 0b010 == 0 || (bits >> 15) & 31 != 0;
+            if bits >> 30 == 0b11 && writes {
+                return "illegal";
+            }
+        }
+        return mnemonic;
+    }
+    "illegal"
+}
+
+/// Check the decoding `op` of a 32-bit instruction `bits` against the reference decoder. The
+/// mnemonic and integer register operands are checked. On mismatch, the mnemonic expected by the
+/// reference decoder is returned.
+pub fn verify_decode(bits: u32, op: &Op) -> Result<(), &'static str> {
+    let expected = reference_mnemonic(bits);
+    if op.mnemonic() != expected {
+        return Err(expected);
+    }
+    // Registers that are not used are reported as 0 and their fields may hold other data.
+    let (rd, rs1, rs2) = op.get_regs();
+    let fields = [(rd, bits >> 7), (rs1, bits >> 15), (rs2, bits >> 20)];
+    if fields.iter().any(|&(reg, field)| reg != 0 && reg as u32 != field & 31) {
+        return Err(expected);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode;
+
+    #[test]
+    fn test_verify_decode() {
+        // Sweep a sample of the encoding space with all opcodes.
+        let mut bits: u32 = 0x12345678;
+        for i in 0..0x40000u32 {
+            // A simple LCG spreads the samples across fields.
+            bits = bits.wrapping_mul(1664525).wrapping_add(1013904223);
+            let bits = (bits & !0x7f) | (i & 0x7c) | 3;
+            let op = decode(bits);
+            assert_eq!(
+                verify_decode(bits, &op),
+                Ok(()),
+                "{:08x} decoded as {}",
+                bits,
+                op.mnemonic()
+            );
+        }
+
+        // An op with the wrong mnemonic or operands is flagged.
+        let add = 0x00b50533; // add a0, a0, a1
+        assert_eq!(verify_decode(add, &Op::Sub { rd: 10, rs1: 10, rs2: 11 }), Err("add"));
+        assert_eq!(verify_decode(add, &Op::Add { rd: 10, rs1: 11, rs2: 11 }), Err("add"));
+        assert_eq!(verify_decode(0, &Op::Addi { rd: 0, rs1: 0, imm: 0 }), Err("illegal"));
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 
@@ -32,17 +33,52 @@ pub struct Config {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub firmware: Option<PathBuf>,
 
+    /// Additional images loaded into memory after the kernel and firmware, e.g. a separate
+    /// firmware or a device tree blob. Images are not checked for overlap; later ones win.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub image: Vec<ImageConfig>,
+
+    /// Architectural hartid of each hart, as reported by `mhartid`, passed in `a0` at reset and
+    /// listed in the device tree. If empty, harts are numbered sequentially from 0.
+    #[serde(default)]
+    pub hartid: Vec<u64>,
+
+    /// Hartid of the boot hart. The boot hart is created first, so it starts executing before all
+    /// other harts. Defaults to the first hart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_hart: Option<u64>,
+
+    /// Initial register values for bring-up debugging, applied after the kernel and firmware are
+    /// loaded. Entry i maps register names such as `a0`, `x10` or `pc` to values for the i-th
+    /// hart created, i.e. the boot hart comes first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub registers: Vec<BTreeMap<String, u64>>,
+
     /// Memory size, in MiB.
     #[serde(default = "default_memory")]
     pub memory: usize,
 
+    /// Byte pattern repeated across RAM at boot, before any image is loaded, e.g. `[0xff]` or
+    /// `[0xde, 0xad, 0xbe, 0xef]`. Useful for catching guest code that relies on memory being
+    /// zeroed. RAM is left zeroed if empty, which is the default.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub memory_fill: Vec<u8>,
+
     /// Linux boot command line
     #[serde(default = "default_cmdline")]
     pub cmdline: String,
 
+    /// Platform-level interrupt controller. It always exists, by default at 0x200000.
+    #[serde(default)]
+    pub plic: DeviceConfig<PlicConfig>,
+
     #[serde(default)]
     pub clint: Option<DeviceConfig<ClintConfig>>,
 
+    /// System controller for guest-initiated power-off and reboot.
+    #[serde(default)]
+    pub syscon: Option<DeviceConfig<SysconConfig>>,
+
     #[serde(default)]
     pub console: ConsoleConfig,
 
@@ -65,6 +101,141 @@ pub struct Config {
     /// Network adapters
     #[serde(default)]
     pub network: Vec<DeviceConfig<NetworkConfig>>,
+
+    /// PCI host bridge, by default at 0x30000000. If present, virtio devices are attached to it
+    /// with the PCI transport instead of using virtio-mmio.
+    #[serde(default)]
+    pub pci: Option<DeviceConfig<PciConfig>>,
+
+    /// Number of descriptors virtio devices read from guest memory at once when following a
+    /// descriptor chain. Prefetching helps with long scatter-gather chains. 0 disables it.
+    #[serde(default)]
+    pub virtio_prefetch: u16,
+
+    /// CSRs that are not implemented but should read as zero and ignore writes instead of raising
+    /// illegal instruction exceptions. Accesses to them are logged. Useful for guests probing
+    /// vendor-specific or debug CSRs.
+    #[serde(default)]
+    pub permissive_csr: Vec<u16>,
+
+    /// Cycles between a device raising or lowering an interrupt and the hart observing it, to
+    /// model interrupt delivery latency. 0 delivers interrupts immediately.
+    #[serde(default)]
+    pub interrupt_latency: u64,
+
+    /// Fault injection, for testing guest error handling.
+    #[serde(default)]
+    pub fault: FaultConfig,
+
+    /// Extra cycles charged to approximate cache coherence overhead.
+    #[serde(default)]
+    pub coherence: CoherenceConfig,
+
+    /// Identity of the platform reported to the guest.
+    #[serde(default)]
+    pub identity: IdentityConfig,
+}
+
+impl Config {
+    /// Get the architectural hartid of each hart in creation order, with the boot hart first.
+    pub fn hartids(&self) -> Result<Vec<u64>, String> {
+        let mut hartids = if self.hartid.is_empty() {
+            (0..self.core as u64).collect()
+        } else if self.hartid.len() == self.core {
+            self.hartid.clone()
+        } else {
+            return Err(format!("{} hartids specified for {} cores", self.hartid.len(), self.core));
+        };
+        for (i, id) in hartids.iter().enumerate() {
+            if hartids[..i].contains(id) {
+                return Err(format!("duplicate hartid {}", id));
+            }
+        }
+        if let Some(boot_hart) = self.boot_hart {
+            let index = hartids
+                .iter()
+                .position(|&id| id == boot_hart)
+                .ok_or_else(|| format!("boot hart {} does not exist", boot_hart))?;
+            let id = hartids.remove(index);
+            hartids.insert(0, id);
+        }
+        Ok(hartids)
+    }
+
+    /// Check that initial register values are only given for existing harts and registers.
+    pub fn check_registers(&self) -> Result<(), String> {
+        if self.registers.len() > self.core {
+            return Err(format!(
+                "initial registers specified for {} harts but there are only {} cores",
+                self.registers.len(),
+                self.core
+            ));
+        }
+        for registers in self.registers.iter() {
+            for name in registers.keys() {
+                if name != "pc" && riscv::register_from_name(name).is_none() {
+                    return Err(format!("unknown register '{}'", name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether harts start in machine mode, i.e. a firmware or an entry image is given.
+    pub fn machine_mode(&self) -> bool {
+        self.firmware.is_some() || self.image.iter().any(|image| image.entry)
+    }
+
+    /// Append extra tokens to the kernel command line. Tokens are separated by a single space and
+    /// placed after the configured ones, in the order given.
+    pub fn append_cmdline(&mut self, extra: &str) {
+        for token in extra.split_whitespace() {
+            if !self.cmdline.is_empty() && !self.cmdline.ends_with(char::is_whitespace) {
+                self.cmdline.push(' ');
+            }
+            self.cmdline.push_str(token);
+        }
+    }
+
+    /// Check that images, occupying `sizes` bytes each once loaded, fit in memory and at most one
+    /// is the entry.
+    pub fn check_images(&self, sizes: &[u64]) -> Result<(), String> {
+        let entries = self.image.iter().filter(|image| image.entry).count();
+        if entries > 1 {
+            return Err(format!("{} images are designated as the entry", entries));
+        }
+        if entries != 0 && self.firmware.is_some() {
+            return Err("an entry image cannot be used together with firmware".to_owned());
+        }
+        let memory = 0x40000000..0x40000000 + self.memory as u64 * 1024 * 1024;
+        for (image, &size) in self.image.iter().zip(sizes) {
+            let end = image.addr.checked_add(size);
+            if !memory.contains(&image.addr) || end.map_or(true, |end| end > memory.end) {
+                return Err(format!(
+                    "image {} of 0x{:x} bytes is loaded at 0x{:x}, outside of memory",
+                    image.path.display(),
+                    size,
+                    image.addr
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageConfig {
+    /// Location of the image. ELF images are loaded with their lowest segment at `addr`, other
+    /// files are copied verbatim.
+    pub path: PathBuf,
+
+    /// Guest physical address to load the image at.
+    pub addr: u64,
+
+    /// Whether harts start executing this image in machine mode instead of the kernel. As with
+    /// `firmware`, the kernel entry point is passed in `a2`. At most one image can be the entry.
+    #[serde(default)]
+    pub entry: bool,
 }
 
 /// Specifies which particular address is to be used for an IO device
@@ -77,9 +248,42 @@ pub struct DeviceConfig<T> {
     pub config: T,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PlicConfig {}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ClintConfig {}
 
+fn default_pci_window() -> usize {
+    0x1000000
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PciConfig {
+    /// Size of the memory window BARs are assigned from. It follows the configuration space.
+    #[serde(default = "default_pci_window")]
+    pub window: usize,
+}
+
+fn default_poweroff() -> u32 {
+    0x5555
+}
+fn default_reboot() -> u32 {
+    0x7777
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysconConfig {
+    /// Value the guest writes to the syscon register to power off.
+    #[serde(default = "default_poweroff")]
+    pub poweroff: u32,
+
+    /// Value the guest writes to the syscon register to reboot. Resetting the guest is not
+    /// supported, so this exits R2VM just like power-off.
+    #[serde(default = "default_reboot")]
+    pub reboot: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConsoleConfig {
     /// Whether a virtio console device should be exposed. Note that under current implementation,
@@ -90,11 +294,16 @@ pub struct ConsoleConfig {
     /// Whether resizing feature should be enabled. Only useful when virtio is enabled.
     #[serde(default = "return_true")]
     pub resize: bool,
+
+    /// Whether an ns16550a UART should be exposed. It shares input with the virtio console, so
+    /// usually only one of them should be enabled.
+    #[serde(default)]
+    pub uart: bool,
 }
 
 impl Default for ConsoleConfig {
     fn default() -> Self {
-        ConsoleConfig { virtio: true, resize: true }
+        ConsoleConfig { virtio: true, resize: true, uart: false }
     }
 }
 
@@ -106,6 +315,55 @@ pub struct DriveConfig {
 
     /// Path to backing file.
     pub path: PathBuf,
+
+    /// Format of the backing file.
+    #[serde(default)]
+    pub format: DriveFormat,
+
+    /// Device ID (serial) reported to the guest, truncated to 20 bytes. Defaults to the file name
+    /// of `path`.
+    #[serde(default)]
+    pub id: Option<String>,
+
+    /// When writes are flushed to the file.
+    #[serde(default)]
+    pub cache: CacheMode,
+
+    /// Whether the drive is read-only to the guest.
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DriveFormat {
+    /// The file holds the disk content as is.
+    Raw,
+    /// The file is a qcow2 image.
+    Qcow2,
+}
+
+impl Default for DriveFormat {
+    fn default() -> Self {
+        DriveFormat::Raw
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Flush writes only when the guest requests so.
+    Writeback,
+    /// Flush each write before it completes.
+    Writethrough,
+    /// Never flush writes.
+    None,
+}
+
+impl Default for CacheMode {
+    fn default() -> Self {
+        CacheMode::Writethrough
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -122,8 +380,26 @@ fn default_seed() -> u64 {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RandomConfig {
     pub r#type: RandomType,
-    #[serde(default = "default_seed")]
-    pub seed: u64,
+
+    /// Seed of the pseudo-random source, also used for `os` in deterministic mode. If absent, a
+    /// fixed seed distinct for each device is used, so devices produce independent streams.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+
+    /// Record all bytes produced to this file, so the run can be reproduced with `replay`.
+    #[serde(default)]
+    pub record: Option<PathBuf>,
+
+    /// Produce bytes recorded by `record` instead of using `type`.
+    #[serde(default)]
+    pub replay: Option<PathBuf>,
+}
+
+impl RandomConfig {
+    /// Get the seed of the `index`-th random device.
+    pub fn seed(&self, index: usize) -> u64 {
+        self.seed.unwrap_or_else(|| default_seed().wrapping_add(index as u64))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -184,3 +460,133 @@ pub struct NetworkConfig {
     #[serde(default)]
     pub forward: Vec<ForwardConfig>,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FaultConfig {
+    /// Seed used for random fault injection.
+    #[serde(default = "default_seed")]
+    pub seed: u64,
+
+    /// Average number of cycles between random bit flips in RAM. 0 disables random bit flips.
+    #[serde(default)]
+    pub bit_flip_interval: u64,
+
+    /// Physical addresses to poison.
+    #[serde(default)]
+    pub poison: Vec<PoisonConfig>,
+
+    /// ECC errors to inject.
+    #[serde(default)]
+    pub ecc: Vec<EccConfig>,
+
+    /// Average number of cycles between background memory scrubbing passes, which detect injected
+    /// ECC errors. 0 disables scrubbing, so errors are detected as soon as they are injected.
+    #[serde(default)]
+    pub scrub_interval: u64,
+
+    /// PLIC interrupt raised as a machine check when an uncorrectable ECC error is detected. It
+    /// must not be used by any device.
+    #[serde(default)]
+    pub ecc_irq: Option<u32>,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            seed: default_seed(),
+            bit_flip_interval: 0,
+            poison: Vec::new(),
+            ecc: Vec::new(),
+            scrub_interval: 0,
+            ecc_irq: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PoisonConfig {
+    /// Physical address to poison. Loads and stores to the 8-byte word containing it will raise
+    /// access faults.
+    pub addr: u64,
+
+    /// The cycle at which the address becomes poisoned.
+    #[serde(default)]
+    pub cycle: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EccConfig {
+    /// Physical address of the error.
+    pub addr: u64,
+
+    /// The cycle at which the error occurs.
+    #[serde(default)]
+    pub cycle: u64,
+
+    /// Whether the error is uncorrectable. Correctable errors are only logged when detected, while
+    /// uncorrectable ones poison the address and raise `ecc_irq`.
+    #[serde(default)]
+    pub uncorrectable: bool,
+}
+
+/// Extra cycles charged to operations that cause cache coherence traffic. All costs are zero by
+/// default, i.e. coherence overhead is not modelled.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CoherenceConfig {
+    /// Extra cycles for each AMO instruction.
+    #[serde(default)]
+    pub amo: usize,
+
+    /// Extra cycles for each LR instruction.
+    #[serde(default)]
+    pub lr: usize,
+
+    /// Extra cycles for each SC instruction.
+    #[serde(default)]
+    pub sc: usize,
+
+    /// Extra cycles for each remote hart targeted by a TLB shootdown.
+    #[serde(default)]
+    pub remote_sfence: usize,
+}
+
+/// SBI implementation ID reported by r2vm by default. It is not assigned by the SBI specification,
+/// but is the ASCII encoding of "r2vm" so it is unlikely to collide with assigned IDs.
+pub const SBI_IMPL_ID_R2VM: u64 = 0x7232766d;
+
+/// Identity of the platform reported to the guest, via the SBI base extension and the
+/// `mvendorid`, `marchid` and `mimpid` CSRs. Guests branching on them can be made to believe they
+/// run on a specific platform.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct IdentityConfig {
+    /// SBI implementation ID.
+    pub impl_id: u64,
+
+    /// SBI implementation version. Defaults to the version of r2vm, with the major version in
+    /// bits 31:16 and the minor version in bits 15:0.
+    pub impl_version: u64,
+
+    /// Value of `mvendorid`. 0 means not implemented.
+    pub mvendorid: u64,
+
+    /// Value of `marchid`. 0 means not implemented.
+    pub marchid: u64,
+
+    /// Value of `mimpid`. 0 means not implemented.
+    pub mimpid: u64,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        let major: u64 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
+        let minor: u64 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
+        IdentityConfig {
+            impl_id: SBI_IMPL_ID_R2VM,
+            impl_version: major << 16 | minor,
+            mvendorid: 0,
+            marchid: 0,
+            mimpid: 0,
+        }
+    }
+}
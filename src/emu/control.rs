@@ -0,0 +1,226 @@
+//! Control socket for running r2vm as a service, enabled by `--control`.
+//!
+//! Clients connect to a Unix socket and send commands, one per line. Each reply ends with a line
+//! that is either `ok` or `error <reason>`. The commands are:
+//!
+//! * `stats`: reply with `cycle <n>` and a `hart <hartid> instret <n>` line for each hart, and
+//!   `paused <0|1>`. A hart whose instret does not advance between two requests is not making
+//!   progress, unless it is paused or waiting for interrupts.
+//! * `pause`: pause all harts. The reply is sent once none of them is executing.
+//! * `resume`: resume paused harts.
+//! * `shutdown`: resume paused harts and shut down the emulator.
+
+use super::interp::SharedContext;
+use parking_lot::{Condvar, Mutex};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// A hart as seen from the control socket.
+pub struct Hart {
+    pub hartid: u64,
+    pub shared: &'static SharedContext,
+}
+
+#[derive(Default)]
+struct PauseState {
+    paused: bool,
+    /// Number of threads blocked by the pause.
+    parked: usize,
+}
+
+/// Harts and hooks for the control socket to act upon.
+pub struct Control {
+    harts: Vec<Hart>,
+    /// Number of threads harts run on. Once a hart blocks its thread, all harts on it are paused.
+    /// This is queried on each pause, as switching models can change it.
+    threads: fn() -> usize,
+    cycle: fn() -> u64,
+    shutdown: fn(),
+    pause: Arc<(Mutex<PauseState>, Condvar)>,
+}
+
+impl Control {
+    pub fn new(
+        harts: Vec<Hart>,
+        threads: fn() -> usize,
+        cycle: fn() -> u64,
+        shutdown: fn(),
+    ) -> Control {
+        Control { harts, threads, cycle, shutdown, pause: Default::default() }
+    }
+
+    fn stats(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "cycle {}", (self.cycle)())?;
+        for hart in self.harts.iter() {
+            let instret = hart.shared.instret.load(Ordering::Relaxed);
+            writeln!(out, "hart {} instret {}", hart.hartid, instret)?;
+        }
+        writeln!(out, "paused {}", self.pause.0.lock().paused as u8)
+    }
+
+    /// Pause all harts, and wait until none of them is executing.
+    fn pause(&self) {
+        let (lock, condvar) = &*self.pause;
+        let mut state = lock.lock();
+        if state.paused {
+            return;
+        }
+        state.paused = true;
+        for hart in self.harts.iter() {
+            let pause = self.pause.clone();
+            hart.shared.run_on(move || {
+                let (lock, condvar) = &*pause;
+                let mut state = lock.lock();
+                if !state.paused {
+                    return;
+                }
+                state.parked += 1;
+                condvar.notify_all();
+                while state.paused {
+                    condvar.wait(&mut state);
+                }
+                state.parked -= 1;
+            });
+        }
+        let threads = (self.threads)();
+        while state.parked < threads {
+            condvar.wait(&mut state);
+        }
+    }
+
+    fn resume(&self) {
+        let (lock, condvar) = &*self.pause;
+        lock.lock().paused = false;
+        condvar.notify_all();
+    }
+
+    /// Serve commands of a client until it disconnects.
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            match line?.trim() {
+                "stats" => self.stats(&mut out)?,
+                "pause" => self.pause(),
+                "resume" => self.resume(),
+                "shutdown" => {
+                    self.resume();
+                    (self.shutdown)();
+                }
+                command => {
+                    writeln!(out, "error unknown command '{}'", command)?;
+                    continue;
+                }
+            }
+            writeln!(out, "ok")?;
+        }
+        Ok(())
+    }
+}
+
+/// Serve the control socket at `path` from a separate thread. Clients are served one at a time.
+pub fn start(path: &Path, control: Control) -> io::Result<()> {
+    // Remove the socket left over by a previous run, if any, but never other files.
+    if std::fs::symlink_metadata(path).map_or(false, |meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::thread::Builder::new().name("control".to_owned()).spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(err) = stream.and_then(|stream| control.serve(stream)) {
+                error!(target: "Control", "connection lost: {}", err);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+    #[test]
+    fn test_control_socket() {
+        let shared: &'static SharedContext = Box::leak(Box::new(SharedContext::new()));
+        // A hart retiring instructions and running tasks queued for it, as `check_interrupt` does.
+        std::thread::spawn(move || loop {
+            shared.instret.fetch_add(1, Ordering::Relaxed);
+            let tasks: Vec<_> = shared.tasks.lock().drain(..).collect();
+            for task in tasks {
+                task();
+            }
+            if SHUTDOWN.load(Ordering::Relaxed) {
+                break;
+            }
+            std::thread::yield_now();
+        });
+
+        let path = std::env::temp_dir().join(format!("r2vm-control-{}", std::process::id()));
+        let harts = vec![Hart { hartid: 0, shared }];
+        let control = Control::new(harts, || 1, || 42, || SHUTDOWN.store(true, Ordering::Relaxed));
+        start(&path, control).unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = |command: &str| {
+            writeln!(&stream, "{}", command).unwrap();
+            let mut reply = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_owned();
+                if line == "ok" || line.starts_with("error") {
+                    return (reply, line);
+                }
+                reply.push(line);
+            }
+        };
+        let instret_of = |stats: &[String]| -> u64 {
+            stats[1].strip_prefix("hart 0 instret ").unwrap().parse().unwrap()
+        };
+
+        let (stats, status) = request("stats");
+        assert_eq!(status, "ok");
+        assert_eq!(stats[0], "cycle 42");
+        assert_eq!(stats[2], "paused 0");
+
+        // Instret stops advancing once paused.
+        assert_eq!(request("pause").1, "ok");
+        let (stats, _) = request("stats");
+        assert_eq!(stats[2], "paused 1");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(instret_of(&request("stats").0), instret_of(&stats));
+
+        assert_eq!(request("resume").1, "ok");
+        while instret_of(&request("stats").0) == instret_of(&stats) {
+            std::thread::yield_now();
+        }
+
+        assert!(request("halt").1.starts_with("error"));
+        assert_eq!(request("shutdown").1, "ok");
+        assert!(SHUTDOWN.load(Ordering::Relaxed));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_start_keeps_other_files() {
+        let path = std::env::temp_dir().join(format!("r2vm-control-file-{}", std::process::id()));
+        std::fs::write(&path, b"data").unwrap();
+        let control = Control::new(Vec::new(), || 1, || 0, || ());
+        assert!(start(&path, control).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+
+        // A socket left over is replaced.
+        std::fs::remove_file(&path).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        start(&path, Control::new(Vec::new(), || 1, || 0, || ())).unwrap();
+        UnixStream::connect(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+//! Recording of the guest control-flow graph encountered during a run, for `--dump-cfg`.
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use riscv::Op;
+use std::collections::BTreeSet;
+use std::io::Write;
+
+/// Kind of an edge between two blocks.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum EdgeKind {
+    /// Execution continues to the next instruction, e.g. a branch not taken.
+    Fallthrough,
+    /// A taken conditional branch.
+    Branch,
+    /// An unconditional direct jump.
+    Jump,
+}
+
+/// Control-flow graph of decoded blocks, identified by their starting virtual address. Only
+/// edges that can be determined statically are recorded, so indirect jumps have no successor.
+#[derive(Default)]
+pub struct ControlFlowGraph {
+    blocks: BTreeSet<u64>,
+    edges: BTreeSet<(u64, u64, EdgeKind)>,
+}
+
+impl ControlFlowGraph {
+    /// Record a block starting at `start` and ending at `end` (exclusive). `last` is the final
+    /// instruction of the block and its address, or `None` if it is not known.
+    pub fn add_block(&mut self, start: u64, end: u64, last: Option<(u64, &Op)>) {
+        self.blocks.insert(start);
+        let (pc, op) = match last {
+            Some(v) => v,
+            None => return,
+        };
+        match *op {
+            Op::Beq { imm, .. }
+            | Op::Bne { imm, .. }
+            | Op::Blt { imm, .. }
+            | Op::Bge { imm, .. }
+            | Op::Bltu { imm, .. }
+            | Op::Bgeu { imm, .. } => {
+                self.edges.insert((start, pc.wrapping_add(imm as u64), EdgeKind::Branch));
+                self.edges.insert((start, end, EdgeKind::Fallthrough));
+            }
+            Op::Jal { imm, .. } => {
+                self.edges.insert((start, pc.wrapping_add(imm as u64), EdgeKind::Jump));
+            }
+            // Traps and fence.i also change control flow, but not in a way we can tell.
+            ref op if op.can_change_control_flow() => (),
+            // The block is split without a control-flow change, e.g. at a page boundary.
+            _ => {
+                self.edges.insert((start, end, EdgeKind::Fallthrough));
+            }
+        }
+    }
+
+    /// Iterate over all edges recorded, as `(from, to, kind)`.
+    pub fn edges(&self) -> impl Iterator<Item = (u64, u64, EdgeKind)> + '_ {
+        self.edges.iter().copied()
+    }
+
+    /// Write the graph in Graphviz DOT format.
+    pub fn write_dot(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(w, "digraph cfg {{")?;
+        writeln!(w, "    node [shape=box];")?;
+        for block in self.blocks.iter() {
+            writeln!(w, "    b{:x} [label=\"{:#x}\"];", block, block)?;
+        }
+        for &(from, to, kind) in self.edges.iter() {
+            let style = match kind {
+                EdgeKind::Fallthrough => "dashed",
+                EdgeKind::Branch => "solid",
+                EdgeKind::Jump => "bold",
+            };
+            writeln!(w, "    b{:x} -> b{:x} [style={}];", from, to, style)?;
+        }
+        writeln!(w, "}}")
+    }
+}
+
+/// The graph of all blocks decoded so far, across all harts.
+pub static CFG: Lazy<Mutex<ControlFlowGraph>> = Lazy::new(Default::default);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_back_edge() {
+        // 1000: li a0, 10
+        // 1004: addi a0, a0, -1
+        // 1008: bnez a0, 1004
+        // 100c: ret
+        let code: [(u64, u32); 4] = [
+            (0x1000, 0x00a00513),
+            (0x1004, 0xfff50513),
+            (0x1008, 0xfe051ee3),
+            (0x100c, 0x00008067),
+        ];
+
+        // Decode blocks the same way the translator does: stop after a control-flow change.
+        let mut cfg = ControlFlowGraph::default();
+        let mut worklist = vec![0x1000];
+        let mut visited = BTreeSet::new();
+        while let Some(start) = worklist.pop() {
+            if !visited.insert(start) {
+                continue;
+            }
+            let first = code.iter().position(|&(pc, _)| pc == start).unwrap();
+            let mut last = None;
+            for &(pc, bits) in &code[first..] {
+                let op = riscv::decode(bits);
+                last = Some((pc, op));
+                if op.can_change_control_flow() {
+                    break;
+                }
+            }
+            let (pc, op) = last.unwrap();
+            cfg.add_block(start, pc + 4, Some((pc, &op)));
+            worklist.extend(cfg.edges().filter(|e| e.0 == start).map(|e| e.1));
+        }
+
+        let edges: Vec<_> = cfg.edges().collect();
+        assert_eq!(
+            edges,
+            [
+                (0x1000, 0x1004, EdgeKind::Branch),
+                (0x1000, 0x100c, EdgeKind::Fallthrough),
+                // The back edge of the loop
+                (0x1004, 0x1004, EdgeKind::Branch),
+                (0x1004, 0x100c, EdgeKind::Fallthrough),
+            ]
+        );
+
+        let mut dot = Vec::new();
+        cfg.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("b1004 -> b1004 [style=solid];"));
+        assert!(dot.contains("b100c [label=\"0x100c\"];"));
+    }
+}
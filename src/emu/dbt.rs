@@ -6,7 +6,7 @@
 //! | [RSP-8]  | return address                                       |   |   |   |
 //! | [RSP-16] | next translated PC, when executing a helper function |   |   |   |
 
-use super::interp::{Context, SharedContext};
+use super::interp::{Context, DivCheck, SharedContext};
 use crate::sim::{get_memory_model, new_pipeline_model, PipelineModel};
 use fiber::raw::{fiber_sleep_raw, fiber_yield_raw};
 use riscv::{Csr, Op};
@@ -42,6 +42,9 @@ extern "C" {
     fn read_csr();
     fn write_csr();
     fn riscv_step();
+    fn instruction_hook();
+    fn spin_hint();
+    fn perf_marker();
     fn helper_trap();
     fn helper_misalign();
     fn translate_cache_miss();
@@ -78,6 +81,16 @@ pub struct DbtCompiler<'a> {
     /// Useful only in lockstep mode.
     cycles: usize,
 
+    /// Number of instructions after which pending interrupts are polled within the block. 0 means
+    /// interrupts are only checked at block boundaries.
+    interrupt_stride: usize,
+
+    /// Number of instructions compiled since the last interrupt poll.
+    insn_since_poll: usize,
+
+    /// Whether `instruction_hook` is called before each instruction.
+    hook: bool,
+
     pub(super) model: Option<Box<dyn PipelineModel>>,
 
     /// The offset past the speculative guard.
@@ -99,15 +112,18 @@ impl Drop for PlaceHolder {
 
 enum SlowPath {
     DCache {
-        ebx: u32,
+        rbx: u64,
         write: bool,
         jcc_misalign: Option<PlaceHolder>,
         jcc_miss: PlaceHolder,
         label_fin: Label,
     },
     /// Slow path for a icache access miss
-    ICache(u32, PlaceHolder, Label),
-    Trap(u32, PlaceHolder),
+    ICache(u64, PlaceHolder, Label),
+    Trap(u64, PlaceHolder),
+    /// Slow path for an interrupt poll within a block. The PC, instret and minstret offsets at
+    /// the poll point are recorded so they can be committed before leaving the block.
+    Interrupt(i64, i32, u32, PlaceHolder),
 }
 
 impl<'a> DbtCompiler<'a> {
@@ -122,6 +138,9 @@ impl<'a> DbtCompiler<'a> {
             pc_cur: 0,
             instret: 0,
             cycles: 0,
+            interrupt_stride: crate::get_flags().interrupt_stride,
+            insn_since_poll: 0,
+            hook: ctx.hook_enabled(),
             model: Some(new_pipeline_model(ctx.hartid as usize)),
             speculative_len: 0,
         }
@@ -276,17 +295,19 @@ impl<'a> DbtCompiler<'a> {
         self.emit(Mov(loc_of_register(rd), Imm(imm as i64)));
     }
 
-    /// We use EBX to convey message to helper_trap, which will adjust PC and INSTRET to reflect
-    /// the precise location of exception.
-    /// EBX encodes both the pc offset and the insret offset of the instruction relative to
-    /// the end of the basic block.
-    /// This function gets the EBX to set.
-    fn get_ebx(&mut self) -> u32 {
+    /// We use RBX to convey message to helper_trap, which will adjust PC, INSTRET and MINSTRET to
+    /// reflect the precise location of exception.
+    /// RBX encodes the pc offset and the insret offset of the instruction relative to the end of
+    /// the basic block, and the number of memory accesses made by instructions before it.
+    /// The faulting instruction itself is not retired, so its memory access must not be counted
+    /// in `self.minstret` when this is called.
+    /// This function gets the RBX to set.
+    fn get_rbx(&mut self) -> u64 {
         // Calculate the differences that need to apply
         let pc_rel = self.pc_cur as i16;
         let instret_rel = self.instret as i16;
         // Pack them into a single register
-        (instret_rel as u16 as u32) << 16 | pc_rel as u16 as u32
+        (self.minstret as u64) << 32 | (instret_rel as u16 as u64) << 16 | pc_rel as u16 as u64
     }
 
     fn emit_helper_call(&mut self, helper: unsafe extern "C" fn()) {
@@ -317,8 +338,36 @@ impl<'a> DbtCompiler<'a> {
         self.emit(Test(Reg(Register::AL), OpReg(Register::AL)));
         let jcc_trap = self.emit_jcc_long(ConditionCode::NotEqual);
 
-        let ebx = self.get_ebx();
-        self.slow_path.push(SlowPath::Trap(ebx, jcc_trap));
+        let rbx = self.get_rbx();
+        self.slow_path.push(SlowPath::Trap(rbx, jcc_trap));
+    }
+
+    /// Call `instruction_hook` for the op at the current PC, encoded as `bits`.
+    fn emit_hook_call(&mut self, op: &Op, bits: u32) {
+        self.emit(Mov(Reg(Register::RDI), OpReg(Register::RBP)));
+        let op: i64 = unsafe { std::mem::transmute(*op) };
+        self.emit(Mov(Reg(Register::RSI), Imm(op)));
+        self.emit(Mov(Reg(Register::RDX), Imm(self.pc_cur)));
+        self.emit(Mov(Reg(Register::ECX), Imm(bits as i32 as i64)));
+        self.emit_helper_call(instruction_hook);
+    }
+
+    /// Call `spin_hint` for a spin loop starting at the current PC.
+    pub fn emit_spin_hint(&mut self, load: &Op) {
+        self.emit(Mov(Reg(Register::RDI), OpReg(Register::RBP)));
+        let op: i64 = unsafe { std::mem::transmute(*load) };
+        self.emit(Mov(Reg(Register::RSI), Imm(op)));
+        self.emit_helper_call(spin_hint);
+    }
+
+    /// Call `perf_marker` for a performance marker with the given ID.
+    fn emit_perf_marker(&mut self, id: i32) {
+        // Account cycles so far, so the marker sees an accurate mcycle.
+        self.before_side_effect();
+        self.emit(Mov(Reg(Register::RDI), OpReg(Register::RBP)));
+        self.emit(Mov(Reg(Register::RSI), Imm(id as i64)));
+        self.emit(Mov(Reg(Register::RDX), Imm(self.instret as i64)));
+        self.emit_helper_call(perf_marker);
     }
 
     /// This should be called when the generated code will create some side-effect visible to other
@@ -384,17 +433,19 @@ impl<'a> DbtCompiler<'a> {
 
         let target = self.pc_start.wrapping_add(pc_offset as u64);
 
-        // emit_icache_access will fill ebx with values in case of an exception happening.
-        // We would like to produce correct ebx which is all 0.
-        // Save old pc_cur, instret
+        // emit_icache_access will fill rbx with values in case of an exception happening.
+        // We would like to produce correct rbx which is all 0.
+        // Save old pc_cur, instret, minstret
         let old_pc_cur = self.pc_cur;
         self.pc_cur = 0;
         let old_instret = self.instret;
         self.instret = 0;
+        let old_minstret = self.minstret;
+        self.minstret = 0;
 
         if same_page(self.pc_start, target) {
             if !same_cache_line(self.pc_start.wrapping_add(self.pc_end as u64) - 1, target) {
-                assert_eq!(self.get_ebx(), 0);
+                assert_eq!(self.get_rbx(), 0);
                 self.emit_icache_access(0, false);
             }
             self.emit_helper_call(helper_patch_direct_jump);
@@ -405,6 +456,7 @@ impl<'a> DbtCompiler<'a> {
         // Restore
         self.pc_cur = old_pc_cur;
         self.instret = old_instret;
+        self.minstret = old_minstret;
         self.cycles = old_cycles;
 
         let label_not = self.label();
@@ -496,11 +548,11 @@ impl<'a> DbtCompiler<'a> {
         }
         let label_fin = self.label();
 
-        let ebx = self.get_ebx();
-        self.slow_path.push(SlowPath::ICache(ebx, jcc_miss, label_fin));
+        let rbx = self.get_rbx();
+        self.slow_path.push(SlowPath::ICache(rbx, jcc_miss, label_fin));
     }
 
-    fn emit_icache_slow(&mut self, ebx: u32, jcc_miss: PlaceHolder, label_fin: Label) {
+    fn emit_icache_slow(&mut self, rbx: u64, jcc_miss: PlaceHolder, label_fin: Label) {
         let label_miss = self.label();
         self.patch(jcc_miss, label_miss);
 
@@ -511,7 +563,7 @@ impl<'a> DbtCompiler<'a> {
         let jcc_fin = self.emit_jcc_long(ConditionCode::Equal);
         self.patch(jcc_fin, label_fin);
 
-        self.emit(Mov(Reg(Register::EBX), Imm(ebx as i64)));
+        self.emit(Mov(Reg(Register::RBX), Imm(rbx as i64)));
         self.emit_helper_jmp(helper_trap);
     }
 
@@ -532,11 +584,53 @@ impl<'a> DbtCompiler<'a> {
         self.emit_helper_jcc(ConditionCode::NotEqual, helper_check_interrupt);
     }
 
-    fn emit_trap(&mut self, ebx: u32, jcc_trap: PlaceHolder) {
+    /// Poll for interrupts in the middle of a block if the configured stride is reached.
+    ///
+    /// Unlike `emit_interrupt_check`, the fast path is just a compare and a not-taken branch; PC
+    /// and instret are only committed in the slow path when the alarm is actually set.
+    fn emit_interrupt_poll(&mut self) {
+        if self.interrupt_stride == 0 || self.insn_since_poll < self.interrupt_stride {
+            return;
+        }
+        self.insn_since_poll = 0;
+
+        // Control flow may leave in this function
+        self.before_side_effect();
+
+        let offset = offset_of!(Context, shared) + offset_of!(SharedContext, alarm);
+        self.emit(Cmp(Mem(Register::RBP + offset as i32), Imm(0)));
+        let jcc_int = self.emit_jcc_long(ConditionCode::NotEqual);
+        self.slow_path.push(SlowPath::Interrupt(self.pc_cur, self.instret, self.minstret, jcc_int));
+    }
+
+    fn emit_interrupt_slow(
+        &mut self,
+        pc_cur: i64,
+        instret: i32,
+        minstret: u32,
+        jcc_int: PlaceHolder,
+    ) {
+        let label_int = self.label();
+        self.patch(jcc_int, label_int);
+
+        let backup = (self.pc_cur, self.instret, self.minstret);
+        self.pc_cur = pc_cur;
+        self.instret = instret;
+        self.minstret = minstret;
+        self.post_adjust_pc_instret();
+        self.pc_cur = backup.0;
+        self.instret = backup.1;
+        self.minstret = backup.2;
+
+        // Leave the block. If no interrupt is taken, execution resumes at the next instruction.
+        self.emit_helper_jmp(helper_check_interrupt);
+    }
+
+    fn emit_trap(&mut self, rbx: u64, jcc_trap: PlaceHolder) {
         let label_trap = self.label();
         self.patch(jcc_trap, label_trap);
 
-        self.emit(Mov(Reg(Register::EBX), Imm(ebx as i64)));
+        self.emit(Mov(Reg(Register::RBX), Imm(rbx as i64)));
         self.emit_helper_jmp(helper_trap);
     }
 
@@ -597,13 +691,13 @@ impl<'a> DbtCompiler<'a> {
         ));
         let label_fin = self.label();
 
-        let ebx = self.get_ebx();
-        self.slow_path.push(SlowPath::DCache { ebx, write, jcc_misalign, jcc_miss, label_fin });
+        let rbx = self.get_rbx();
+        self.slow_path.push(SlowPath::DCache { rbx, write, jcc_misalign, jcc_miss, label_fin });
     }
 
     fn dcache_access_slow(
         &mut self,
-        ebx: u32,
+        rbx: u64,
         write: bool,
         jcc_misalign: Option<PlaceHolder>,
         jcc_miss: PlaceHolder,
@@ -613,7 +707,7 @@ impl<'a> DbtCompiler<'a> {
             let label_misalign = self.label();
             self.patch(jcc_misalign, label_misalign);
 
-            self.emit(Mov(Reg(Register::EBX), Imm(ebx as i64)));
+            self.emit(Mov(Reg(Register::RBX), Imm(rbx as i64)));
             self.emit_helper_jmp(helper_misalign);
         }
 
@@ -633,7 +727,7 @@ impl<'a> DbtCompiler<'a> {
         let jcc_fin = self.emit_jcc_long(ConditionCode::Equal);
         self.patch(jcc_fin, label_fin);
 
-        self.emit(Mov(Reg(Register::EBX), Imm(ebx as i64)));
+        self.emit(Mov(Reg(Register::RBX), Imm(rbx as i64)));
         self.emit_helper_jmp(helper_trap);
     }
 
@@ -642,8 +736,6 @@ impl<'a> DbtCompiler<'a> {
     fn emit_load(&mut self, rs1: u8, imm: i32, size: Size) {
         self.before_side_effect();
 
-        self.minstret += 1;
-
         // RSI = addr
         self.emit(Mov(Reg(Register::RSI), loc_of_register(rs1).into()));
         if imm != 0 {
@@ -651,13 +743,12 @@ impl<'a> DbtCompiler<'a> {
         }
 
         self.dcache_access(size, false);
+        self.minstret += 1;
     }
 
     fn emit_store(&mut self, rs1: u8, rs2: u8, imm: i32, size: Size) {
         self.before_side_effect();
 
-        self.minstret += 1;
-
         // RSI = addr
         self.emit(Mov(Reg(Register::RSI), loc_of_register(rs1).into()));
         if imm != 0 {
@@ -665,6 +756,7 @@ impl<'a> DbtCompiler<'a> {
         }
 
         self.dcache_access(size, true);
+        self.minstret += 1;
 
         let reg = Register::RDX.resize(size);
         self.emit(Mov(Reg(reg), loc_of_register(rs2).resize(size).into()));
@@ -1527,9 +1619,9 @@ impl<'a> DbtCompiler<'a> {
     fn amo_op_w(&mut self, rd: u8, rs1: u8, rs2: u8, action: impl FnOnce(&mut Self)) {
         self.before_side_effect();
 
-        self.minstret += 1;
         self.load_reg(Register::RSI, rs1);
         self.dcache_access(Size::Dword, true);
+        self.minstret += 1;
         self.load_reg(Register::EAX, rs2);
         // Perform action on RSI, RAX and store to RAX
         action(self);
@@ -1539,9 +1631,9 @@ impl<'a> DbtCompiler<'a> {
     fn amo_op_d(&mut self, rd: u8, rs1: u8, rs2: u8, action: impl FnOnce(&mut Self)) {
         self.before_side_effect();
 
-        self.minstret += 1;
         self.load_reg(Register::RSI, rs1);
         self.dcache_access(Size::Qword, true);
+        self.minstret += 1;
         self.load_reg(Register::RAX, rs2);
         // Perform action on RSI, RAX and store to RAX
         action(self);
@@ -1551,9 +1643,9 @@ impl<'a> DbtCompiler<'a> {
     fn cmpxchg_w(&mut self, rd: u8, rs1: u8, rs2: u8, action: impl FnOnce(&mut Self)) {
         self.before_side_effect();
 
-        self.minstret += 1;
         self.load_reg(Register::RSI, rs1);
         self.dcache_access(Size::Dword, true);
+        self.minstret += 1;
         self.load_reg(Register::EDX, rs2);
         let mem = Mem((Register::RSI + 0).dword());
 
@@ -1572,9 +1664,9 @@ impl<'a> DbtCompiler<'a> {
     fn cmpxchg_d(&mut self, rd: u8, rs1: u8, rs2: u8, action: impl FnOnce(&mut Self)) {
         self.before_side_effect();
 
-        self.minstret += 1;
         self.load_reg(Register::RSI, rs1);
         self.dcache_access(Size::Qword, true);
+        self.minstret += 1;
         self.load_reg(Register::RDX, rs2);
         let mem = Mem(Register::RSI + 0);
 
@@ -1614,8 +1706,8 @@ impl<'a> DbtCompiler<'a> {
     fn emit_trap_check(&mut self) {
         self.emit(Test(Reg(Register::AL), OpReg(Register::AL)));
         let trap = self.emit_jcc_long(ConditionCode::NotEqual);
-        let ebx = self.get_ebx();
-        self.slow_path.push(SlowPath::Trap(ebx, trap));
+        let rbx = self.get_rbx();
+        self.slow_path.push(SlowPath::Trap(rbx, trap));
     }
 
     //
@@ -1662,6 +1754,7 @@ impl<'a> DbtCompiler<'a> {
             /* OP-IMM */
             Op::Addi { rd, rs1, imm } => self.emit_addi(rd, rs1, imm),
             Op::Slli { rd, rs1, imm } => self.emit_slli(rd, rs1, imm),
+            Op::Slti { rd: 0, rs1: 0, imm } => self.emit_perf_marker(imm),
             Op::Slti { rd, rs1, imm } => self.emit_slti(rd, rs1, imm),
             Op::Sltiu { rd, rs1, imm } => self.emit_sltiu(rd, rs1, imm),
             Op::Xori { rd, rs1, imm } => self.emit_xori(rd, rs1, imm),
@@ -1736,12 +1829,14 @@ impl<'a> DbtCompiler<'a> {
                 _ => false,
             } => {
                 self.pre_adjust_pc_instret(comp);
-                let backup = (self.pc_cur, self.instret);
+                let backup = (self.pc_cur, self.instret, self.minstret);
                 self.pc_cur = if comp { -2 } else { -4 };
                 self.instret = -1;
+                self.minstret = 0;
                 self.emit_step_call(op);
                 self.pc_cur = backup.0;
                 self.instret = backup.1;
+                self.minstret = backup.2;
                 self.with_model(|this, model| model.after_instruction(this, op, comp));
                 self.emit_interrupt_check();
                 self.emit_chain_tail();
@@ -1842,6 +1937,17 @@ impl<'a> DbtCompiler<'a> {
             Op::FnmaddD {..} => self.emit_step_call(op),
 
             /* M-extension */
+            // Divisions are checked by the interpreter if they are not to follow the spec.
+            Op::Div {..} |
+            Op::Divu {..} |
+            Op::Rem {..} |
+            Op::Remu {..} |
+            Op::Divw {..} |
+            Op::Divuw {..} |
+            Op::Remw {..} |
+            Op::Remuw {..} if crate::get_flags().div_check != DivCheck::Spec => {
+                self.emit_step_call(op)
+            }
             Op::Mul { rd, rs1, rs2 } => self.emit_mul(rd, rs1, rs2),
             Op::Mulh { rd, rs1, rs2 } => self.emit_mulh(rd, rs1, rs2, false),
             Op::Mulhsu { rd, rs1, rs2 } => self.emit_mulhsu(rd, rs1, rs2),
@@ -1856,14 +1962,59 @@ impl<'a> DbtCompiler<'a> {
             Op::Remw { rd, rs1, rs2 } => self.emit_divw(rd, rs1, rs2, false, true),
             Op::Remuw { rd, rs1, rs2 } => self.emit_divw(rd, rs1, rs2, true, true),
 
+            /* Zba, Zbb and Zbs extensions */
+            Op::SlliUw {..} |
+            Op::Sh1add {..} |
+            Op::Sh2add {..} |
+            Op::Sh3add {..} |
+            Op::AddUw {..} |
+            Op::Sh1addUw {..} |
+            Op::Sh2addUw {..} |
+            Op::Sh3addUw {..} |
+            Op::Clz {..} |
+            Op::Ctz {..} |
+            Op::Cpop {..} |
+            Op::SextB {..} |
+            Op::SextH {..} |
+            Op::Rori {..} |
+            Op::OrcB {..} |
+            Op::Rev8 {..} |
+            Op::Clzw {..} |
+            Op::Ctzw {..} |
+            Op::Cpopw {..} |
+            Op::Roriw {..} |
+            Op::Andn {..} |
+            Op::Orn {..} |
+            Op::Xnor {..} |
+            Op::Min {..} |
+            Op::Minu {..} |
+            Op::Max {..} |
+            Op::Maxu {..} |
+            Op::Rol {..} |
+            Op::Ror {..} |
+            Op::ZextH {..} |
+            Op::Rolw {..} |
+            Op::Rorw {..} |
+            Op::Bclri {..} |
+            Op::Bexti {..} |
+            Op::Binvi {..} |
+            Op::Bseti {..} |
+            Op::Bclr {..} |
+            Op::Bext {..} |
+            Op::Binv {..} |
+            Op::Bset {..} => self.emit_step_call(op),
+
+            /* Zihintntl extension */
+            Op::NtlP1 | Op::NtlPall | Op::NtlS1 | Op::NtlAll => (),
+
             /* A-extension */
             Op::LrW { rd, rs1, .. } => {
                 self.before_side_effect();
 
-                self.minstret += 1;
                 self.load_reg(Register::RSI, rs1);
                 self.emit(Mov(Reg(Register::RBX), OpReg(Register::RSI)));
                 self.dcache_access(Size::Dword, true);
+                self.minstret += 1;
                 self.emit(Movsx(Register::RAX, Mem((Register::RSI + 0).dword())));
                 self.store_reg(rd, Register::RAX);
                 self.emit(Mov(Mem(memory_of!(lr_addr)), OpReg(Register::RBX)));
@@ -1872,10 +2023,10 @@ impl<'a> DbtCompiler<'a> {
             Op::LrD { rd, rs1, .. } => {
                 self.before_side_effect();
 
-                self.minstret += 1;
                 self.load_reg(Register::RSI, rs1);
                 self.emit(Mov(Reg(Register::RBX), OpReg(Register::RSI)));
                 self.dcache_access(Size::Qword, true);
+                self.minstret += 1;
                 self.emit(Mov(Reg(Register::RAX), OpMem(Register::RSI + 0)));
                 self.store_reg(rd, Register::RAX);
                 self.emit(Mov(Mem(memory_of!(lr_addr)), OpReg(Register::RBX)));
@@ -2033,12 +2184,14 @@ impl<'a> DbtCompiler<'a> {
             Op::FenceI |
             Op::SfenceVma {..} => {
                 self.pre_adjust_pc_instret(comp);
-                let backup = (self.pc_cur, self.instret);
+                let backup = (self.pc_cur, self.instret, self.minstret);
                 self.pc_cur = if comp { -2 } else { -4 };
                 self.instret = -1;
+                self.minstret = 0;
                 self.emit_step_call(op);
                 self.pc_cur = backup.0;
                 self.instret = backup.1;
+                self.minstret = backup.2;
                 self.with_model(|this, model| model.after_instruction(this, op, comp));
                 self.emit_interrupt_check();
                 self.emit_chain_tail();
@@ -2049,6 +2202,8 @@ impl<'a> DbtCompiler<'a> {
     /// Compile an op. The op should follow the previous one, and it should not be an op that
     /// might possibly change control flow.
     pub fn compile_op(&mut self, op: &Op, compressed: bool, bits: u32) {
+        self.emit_interrupt_poll();
+
         // First check if the op cross cache block boundary and we need to access the icache.
         self.pc_end = self.pc_cur + if compressed { 2 } else { 4 };
         if cfg!(feature = "sanitize") {
@@ -2078,6 +2233,9 @@ impl<'a> DbtCompiler<'a> {
             }
         }
 
+        if self.hook {
+            self.emit_hook_call(op, bits);
+        }
         self.with_model(|this, model| model.before_instruction(this, op, compressed));
         self.emit_op(op, compressed);
         self.with_model(|this, model| model.after_instruction(this, op, compressed));
@@ -2085,6 +2243,7 @@ impl<'a> DbtCompiler<'a> {
         // Advance counters
         self.pc_cur = self.pc_end;
         self.instret += 1;
+        self.insn_since_poll += 1;
     }
 
     /// Compile an conditional execution instruction pair.
@@ -2100,6 +2259,8 @@ impl<'a> DbtCompiler<'a> {
     /// within an extended basic block, but to allow for conditional load/move, this is an easy
     /// optimisation.
     pub fn compile_cond_op(&mut self, bop: &Op, bcomp: bool, op: &Op, comp: bool) {
+        self.emit_interrupt_poll();
+
         let blen = if bcomp { 2 } else { 4 };
         let olen = if comp { 2 } else { 4 };
 
@@ -2163,6 +2324,7 @@ impl<'a> DbtCompiler<'a> {
         // Advance counters past both instructions
         self.pc_cur = self.pc_end;
         self.instret += 1;
+        self.insn_since_poll += 2;
     }
 
     pub fn begin(&mut self, pc: u64) {
@@ -2188,13 +2350,16 @@ impl<'a> DbtCompiler<'a> {
         // Generate slow path
         for slow in std::mem::replace(&mut self.slow_path, Vec::new()) {
             match slow {
-                SlowPath::DCache { ebx, write, jcc_misalign, jcc_miss, label_fin } => {
-                    self.dcache_access_slow(ebx, write, jcc_misalign, jcc_miss, label_fin)
+                SlowPath::DCache { rbx, write, jcc_misalign, jcc_miss, label_fin } => {
+                    self.dcache_access_slow(rbx, write, jcc_misalign, jcc_miss, label_fin)
+                }
+                SlowPath::ICache(rbx, jcc_miss, label_fin) => {
+                    self.emit_icache_slow(rbx, jcc_miss, label_fin);
                 }
-                SlowPath::ICache(ebx, jcc_miss, label_fin) => {
-                    self.emit_icache_slow(ebx, jcc_miss, label_fin);
+                SlowPath::Trap(rbx, jcc_trap) => self.emit_trap(rbx, jcc_trap),
+                SlowPath::Interrupt(pc_cur, instret, minstret, jcc_int) => {
+                    self.emit_interrupt_slow(pc_cur, instret, minstret, jcc_int)
                 }
-                SlowPath::Trap(ebx, jcc_trap) => self.emit_trap(ebx, jcc_trap),
             }
         }
 
@@ -2256,9 +2421,11 @@ impl<'a> DbtCompiler<'a> {
     /// Finish compilation with the last instruction spanning across two pages.
     pub fn end_cross(&mut self, lo_bits: u16) {
         self.pre_adjust_pc_instret(false);
-        // emit_icache_access implicitly uses get_ebx, so need to get pc_cur and instret right.
+        // emit_icache_access implicitly uses get_rbx, so need to get pc_cur, instret and minstret
+        // right.
         self.pc_cur = -4;
         self.instret = -1;
+        self.minstret = 0;
 
         // Access the word at the boundary. Keep RSI, as we will need its value.
         self.emit_icache_access(-2, true);
@@ -2317,7 +2484,7 @@ impl<'a> DbtCompiler<'a> {
 fn icache_cross_miss(ctx: &mut Context, pc: u64, patch: usize, insn: u32) {
     let mut op = riscv::decode(insn);
     if crate::get_flags().disassemble {
-        eprintln!("{}", op.pretty_print(pc - 2, insn));
+        eprintln!("{}", riscv::format_instr(pc - 2, insn, &op));
     }
 
     // We must not emit code for protected ops
@@ -2339,6 +2506,9 @@ fn icache_cross_miss(ctx: &mut Context, pc: u64, patch: usize, insn: u32) {
         model.begin_block(this, pc);
         model.before_instruction(this, &op, false);
     });
+    if compiler.hook {
+        compiler.emit_hook_call(&op, insn);
+    }
     compiler.emit_op(&op, false);
 
     let unreachable = match op {
@@ -2362,3 +2532,133 @@ fn icache_cross_miss(ctx: &mut Context, pc: u64, patch: usize, insn: u32) {
         std::ptr::write_unaligned((patch - 8) as *mut u16, (insn >> 16) as u16);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compile `op` alone into a function taking the register file in RDI.
+    fn compile(op: &Op, code: &mut [u8]) -> extern "sysv64" fn(*mut u64) {
+        let mut compiler = DbtCompiler {
+            buffer: code,
+            len: 0,
+            slow_path: Vec::new(),
+            minstret: 0,
+            pc_start: 0,
+            pc_end: 0,
+            pc_cur: 0,
+            instret: 0,
+            cycles: 0,
+            interrupt_stride: 0,
+            insn_since_poll: 0,
+            hook: false,
+            model: None,
+            speculative_len: 0,
+        };
+        compiler.emit(Push(OpReg(Register::RBP)));
+        compiler.emit(Mov(Reg(Register::RBP), OpReg(Register::RDI)));
+        compiler.emit_op(op, false);
+        compiler.emit(Pop(Reg(Register::RBP)));
+        compiler.emit(Ret(0));
+        assert!(compiler.slow_path.is_empty());
+        unsafe { std::mem::transmute(compiler.buffer.as_ptr()) }
+    }
+
+    #[test]
+    fn test_mulh() {
+        let code = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(code, libc::MAP_FAILED);
+        let code = unsafe { std::slice::from_raw_parts_mut(code as *mut u8, 4096) };
+
+        let values = [
+            0,
+            1,
+            2,
+            u64::MAX,
+            i64::MIN as u64,
+            i64::MAX as u64,
+            0x123456789abcdef0,
+            0xfedcba9876543210,
+        ];
+        // Cover distinct, identical and overlapping source and destination registers.
+        let regs = [(10, 11, 12), (10, 11, 11), (11, 11, 12), (12, 11, 12), (10, 0, 11)];
+        let mut ctx = Context::new(0);
+        for &(rd, rs1, rs2) in regs.iter() {
+            for &op in &[
+                Op::Mulh { rd, rs1, rs2 },
+                Op::Mulhsu { rd, rs1, rs2 },
+                Op::Mulhu { rd, rs1, rs2 },
+            ] {
+                let func = compile(&op, code);
+                for &a in values.iter() {
+                    for &b in values.iter() {
+                        let mut registers = [0; 32];
+                        registers[10] = 0xdead;
+                        registers[11] = a;
+                        registers[12] = b;
+                        ctx.registers = registers;
+                        assert_eq!(super::super::interp::step(&mut ctx, &op, false), Ok(()));
+                        func(registers.as_mut_ptr());
+                        assert_eq!(registers, ctx.registers, "{:x} {:x}", a, b);
+                    }
+                }
+            }
+        }
+        unsafe { libc::munmap(code.as_mut_ptr() as *mut _, 4096) };
+    }
+
+    #[test]
+    fn test_interrupt_stride() {
+        // Compile a block of 64 nops, and find the number of instructions retired in the block
+        // before each point at which a pending interrupt is noticed.
+        let polls = |stride| {
+            // Translated code calls helpers, so it must be mapped close to the executable.
+            let code = unsafe {
+                libc::mmap(
+                    0x7ffef0000000 as *mut _,
+                    65536,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                    -1,
+                    0,
+                )
+            };
+            assert_ne!(code, libc::MAP_FAILED);
+            let code = unsafe { std::slice::from_raw_parts_mut(code as *mut u8, 65536) };
+            let mut ctx = Context::new(0);
+            let mut compiler = DbtCompiler::new(&mut ctx, code);
+            compiler.interrupt_stride = stride;
+            compiler.begin(0x1000);
+            for _ in 0..64 {
+                compiler.compile_op(&Op::Addi { rd: 0, rs1: 0, imm: 0 }, false, 0x13);
+            }
+            let mut polls = vec![0];
+            for slow in compiler.slow_path.iter() {
+                if let SlowPath::Interrupt(_, instret, _, _) = *slow {
+                    polls.push(instret);
+                }
+            }
+            compiler.end();
+            unsafe { libc::munmap(compiler.buffer.as_mut_ptr() as *mut _, 65536) };
+            polls.push(64);
+            polls
+        };
+        // The worst-case latency in instructions is the longest stretch without a poll.
+        let latency = |stride| polls(stride).windows(2).map(|w| w[1] - w[0]).max().unwrap();
+
+        // Without a stride, interrupts are only noticed at the end of the block.
+        assert_eq!(polls(0), [0, 64]);
+        assert_eq!(latency(16), 16);
+        assert_eq!(latency(4), 4);
+        assert!(latency(1) < latency(4));
+    }
+}
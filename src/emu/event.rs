@@ -2,7 +2,7 @@
 
 use futures::future::BoxFuture;
 use parking_lot::{Condvar, Mutex, MutexGuard};
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
@@ -10,8 +10,55 @@ use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
+/// Kind of an event, distinguished by when it is queued.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    /// Scheduled for a future cycle, e.g. a timer.
+    Timer,
+    /// Scheduled to fire as soon as possible, e.g. waking up a task when host I/O completes.
+    Wakeup,
+}
+
+/// Write a schedule of fired events, one `cycle kind` pair per line.
+pub fn write_schedule(
+    writer: &mut dyn std::io::Write,
+    schedule: &[(u64, EventKind)],
+) -> std::io::Result<()> {
+    for &(cycle, kind) in schedule {
+        let kind = match kind {
+            EventKind::Timer => "timer",
+            EventKind::Wakeup => "wakeup",
+        };
+        writeln!(writer, "{} {}", cycle, kind)?;
+    }
+    Ok(())
+}
+
+/// Parse a schedule written by [`write_schedule`].
+pub fn parse_schedule(text: &str) -> Result<Vec<(u64, EventKind)>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let mut parts = line.split_whitespace();
+            let cycle = parts.next().and_then(|x| x.parse().ok());
+            let kind = match parts.next() {
+                Some("timer") => Some(EventKind::Timer),
+                Some("wakeup") => Some(EventKind::Wakeup),
+                _ => None,
+            };
+            match (cycle, kind, parts.next()) {
+                (Some(cycle), Some(kind), None) => Ok((cycle, kind)),
+                _ => Err(format!("invalid event on line {}", i + 1)),
+            }
+        })
+        .collect()
+}
+
 struct Entry {
     time: u64,
+    /// Sequence number in the order of queueing, so events of the same time fire in that order.
+    seq: u64,
+    kind: EventKind,
     handler: Box<dyn FnOnce() + Send>,
 }
 
@@ -20,7 +67,7 @@ struct Entry {
 
 impl PartialEq for Entry {
     fn eq(&self, other: &Self) -> bool {
-        self.time == other.time
+        self.time == other.time && self.seq == other.seq
     }
 }
 
@@ -35,7 +82,7 @@ impl PartialOrd for Entry {
 impl Ord for Entry {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         // Smaller time needs to come larger as BinaryHeap is a max-heap.
-        other.time.cmp(&self.time)
+        other.time.cmp(&self.time).then(other.seq.cmp(&self.seq))
     }
 }
 
@@ -57,6 +104,15 @@ pub struct EventLoop {
     /// lockstep mode to non-lockstep mode, `cycle` will be updated, so it does not reflect number
     /// of cycles in lockstep mode, we therefore need a base to keep track.
     lockstep_cycle_base: AtomicU64,
+    /// Cycle and kind of each event fired, if recording.
+    record: Mutex<Option<Vec<(u64, EventKind)>>>,
+    /// Remaining events to fire at exactly the recorded cycles, if replaying.
+    replay: Mutex<Option<VecDeque<(u64, EventKind)>>>,
+    /// Sequence number of the next event queued.
+    next_seq: AtomicU64,
+    /// Whether harts run on their own threads, with time following the host clock, rather than in
+    /// lock-step with this event loop.
+    threaded: AtomicBool,
 }
 
 extern "C" {
@@ -65,8 +121,9 @@ extern "C" {
 }
 
 impl EventLoop {
-    /// Create a new event loop.
-    pub fn new() -> EventLoop {
+    /// Create a new event loop, for harts running in threaded mode if `threaded` is set or in
+    /// lock-step mode otherwise.
+    pub fn new(threaded: bool) -> EventLoop {
         EventLoop {
             cycle: AtomicU64::new(0),
             lockstep_cycle_base: AtomicU64::new(0),
@@ -75,9 +132,49 @@ impl EventLoop {
             condvar: Condvar::new(),
             events: Mutex::new(BinaryHeap::new()),
             shutdown: AtomicBool::new(false),
+            record: Mutex::new(None),
+            replay: Mutex::new(None),
+            next_seq: AtomicU64::new(0),
+            threaded: AtomicBool::new(threaded),
         }
     }
 
+    /// Whether harts run on their own threads rather than in lock-step with this event loop.
+    pub fn threaded(&self) -> bool {
+        self.threaded.load(Ordering::Relaxed)
+    }
+
+    /// Switch between threaded and lock-step mode. This must only be called while the event loop
+    /// and harts are stopped.
+    pub fn set_threaded(&self, threaded: bool) {
+        self.threaded.store(threaded, Ordering::Relaxed);
+    }
+
+    fn entry(&self, time: u64, kind: EventKind, handler: Box<dyn FnOnce() + Send>) -> Entry {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        Entry { time, seq, kind, handler }
+    }
+
+    /// Start recording the cycle and kind of each event fired.
+    pub fn start_recording(&self) {
+        *self.record.lock() = Some(Vec::new());
+    }
+
+    /// Retrieve events recorded so far.
+    pub fn recording(&self) -> Vec<(u64, EventKind)> {
+        self.record.lock().clone().unwrap_or_default()
+    }
+
+    /// Replay a recorded schedule. Instead of firing at their scheduled cycles, events fire in
+    /// order at the recorded cycles. If an event is not yet queued at its recorded cycle, e.g.
+    /// because host I/O is slower than when recorded, the cycle count will not advance until it
+    /// is. This requires lock-step execution.
+    ///
+    /// Replay stops when the schedule is exhausted or diverges from the events queued.
+    pub fn start_replay(&self, schedule: Vec<(u64, EventKind)>) {
+        *self.replay.lock() = Some(schedule.into());
+    }
+
     /// Stop this event loop.
     pub fn shutdown(&self) {
         // Acquire the lock so nobody is modifying the data structures when we are touching some
@@ -85,7 +182,7 @@ impl EventLoop {
         let guard = self.events.lock();
         // As event loops can be restarted after shutdown, we need to synchonize non-threaded and
         // threaded counters.
-        if crate::threaded() {
+        if self.threaded() {
             // This won't conflict with `event_loop_wait` as they are in different mode.
             let cycle = self.cycle();
             let lockstep_cycle = self.get_lockstep_cycles();
@@ -116,7 +213,7 @@ impl EventLoop {
 
     /// Query the current cycle count.
     pub fn cycle(&self) -> u64 {
-        if crate::threaded() {
+        if self.threaded() {
             let duration = Instant::now().duration_since(*self.epoch);
             duration.as_micros() as u64 * 100
         } else {
@@ -127,15 +224,20 @@ impl EventLoop {
     /// Add a new event to the event loop for triggering. If it happens in the past it will be
     /// dequeued and triggered as soon as `cycle` increments for the next time.
     pub fn queue(&self, cycle: u64, handler: Box<dyn FnOnce() + Send>) {
+        let kind = if cycle > self.cycle() { EventKind::Timer } else { EventKind::Wakeup };
         let mut guard = self.events.lock();
-        guard.push(Entry { time: cycle, handler });
+        guard.push(self.entry(cycle, kind, handler));
 
-        if crate::threaded() {
+        if self.threaded() {
             // If the event just queued is the next event, we need to wake the event loop up.
             if guard.peek().unwrap().time == cycle {
                 self.condvar.notify_one();
             }
         } else {
+            // The event loop may be waiting for an event to replay.
+            if self.replay.lock().is_some() {
+                self.condvar.notify_one();
+            }
             // It's okay to be relaxed because guard's release op will order it.
             self.next_event.store(
                 match guard.peek() {
@@ -175,29 +277,95 @@ impl EventLoop {
         self.cycle() / 100
     }
 
+    /// Add a new event triggering at `time`. Times too far in the future to be represented in
+    /// cycles never come, so no event is added for them.
     pub fn queue_time(&self, time: u64, handler: Box<dyn FnOnce() + Send>) {
-        self.queue(time * 100, handler);
+        if let Some(cycle) = time.checked_mul(100) {
+            self.queue(cycle, handler);
+        }
     }
 
     pub fn on_time(&self, time: u64) -> impl Future<Output = ()> + Send + 'static {
         self.on_cycle(time * 100)
     }
 
+    /// Take the next event to replay from the queue. Returns `Err(cycle)` if nothing should fire
+    /// until `cycle`, or `None` if not replaying.
+    fn replay_next(
+        &self,
+        guard: &mut MutexGuard<BinaryHeap<Entry>>,
+        cycle: u64,
+    ) -> Option<Result<Entry, u64>> {
+        let mut replay = self.replay.lock();
+        let (replay_cycle, replay_kind) = match replay.as_ref()?.front() {
+            Some(&v) => v,
+            None => {
+                info!(target: "Event", "replay finished");
+                *replay = None;
+                return None;
+            }
+        };
+        if replay_cycle > cycle {
+            return Some(Err(replay_cycle));
+        }
+
+        // Find the first event of the recorded kind. Others may have been queued earlier than when
+        // recorded, so put them back.
+        let mut skipped = Vec::new();
+        let found = loop {
+            match guard.peek() {
+                Some(entry) if entry.time <= replay_cycle => {
+                    let entry = guard.pop().unwrap();
+                    if entry.kind == replay_kind {
+                        break Some(entry);
+                    }
+                    skipped.push(entry);
+                }
+                _ => break None,
+            }
+        };
+        guard.extend(skipped);
+
+        match found {
+            Some(entry) => {
+                replay.as_mut().unwrap().pop_front();
+                Some(Ok(entry))
+            }
+            // Timers are scheduled by the guest, so a missing one means that execution diverged.
+            None if replay_kind == EventKind::Timer => {
+                warn!(target: "Event", "replay diverged at cycle {}", cycle);
+                *replay = None;
+                None
+            }
+            // Wait for the event to be queued.
+            None => Some(Err(replay_cycle)),
+        }
+    }
+
     /// Handle all events at or before `cycle`, and return the cycle of next event if any.
+    ///
+    /// When replaying, the returned cycle may be at or before `cycle` if the next event to replay
+    /// is not yet queued.
     fn handle_events(
         &self,
         mut guard: &mut MutexGuard<BinaryHeap<Entry>>,
         cycle: u64,
     ) -> Option<u64> {
         loop {
-            let time = match guard.peek() {
-                None => return None,
-                Some(v) => v.time,
+            let entry = match self.replay_next(guard, cycle) {
+                Some(Ok(entry)) => entry,
+                Some(Err(next)) => return Some(next),
+                None => {
+                    let time = guard.peek()?.time;
+                    if time > cycle {
+                        return Some(time);
+                    }
+                    guard.pop().unwrap()
+                }
             };
-            if time > cycle {
-                return Some(time);
+            if let Some(ref mut record) = *self.record.lock() {
+                record.push((cycle, entry.kind));
             }
-            let entry = guard.pop().unwrap();
             MutexGuard::unlocked(&mut guard, || {
                 (entry.handler)();
             });
@@ -213,7 +381,12 @@ impl EventLoop {
             }
             let cycle = self.cycle();
             let result = self.handle_events(&mut guard, cycle);
-            if crate::threaded() {
+            if result.map_or(false, |v| v <= cycle) {
+                // Wait for the event to replay to be queued, without advancing the cycle count.
+                self.condvar.wait(&mut guard);
+                continue;
+            }
+            if self.threaded() {
                 match result {
                     None => {
                         self.condvar.wait(&mut guard);
@@ -271,3 +444,174 @@ impl EventLoop {
         task.wake();
     }
 }
+
+#[cfg(test)]
+impl EventLoop {
+    /// Advance the cycle count to `cycle` as lock-step execution does, firing events when their
+    /// cycles are reached. The current thread must be in lock-step mode.
+    pub fn advance_to(&self, cycle: u64) {
+        let mut guard = self.events.lock();
+        while let Some(next) = self.handle_events(&mut guard, self.cycle()) {
+            if next > cycle {
+                break;
+            }
+            self.cycle.store(next, Ordering::Relaxed);
+        }
+        self.cycle.store(cycle, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run events with a simulated clock until none are left. Timers are queued upfront, while
+    /// wakeups arrive at the given cycles as if host I/O completed. Returns the cycle at which
+    /// each event fires, identified by its index in `timers` followed by `wakeups`.
+    fn run(event_loop: &EventLoop, timers: &[u64], wakeups: &[u64]) -> Vec<(usize, u64)> {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(AtomicU64::new(0));
+        let entry = |id: usize, time: u64, kind: EventKind| {
+            let fired = fired.clone();
+            let clock = clock.clone();
+            let handler = Box::new(move || fired.lock().push((id, clock.load(Ordering::Relaxed))));
+            event_loop.entry(time, kind, handler)
+        };
+
+        let mut guard = event_loop.events.lock();
+        for (i, &time) in timers.iter().enumerate() {
+            guard.push(entry(i, time, EventKind::Timer));
+        }
+        let mut arrivals: Vec<_> = wakeups.iter().enumerate().map(|(i, &x)| (x, i)).collect();
+        arrivals.sort();
+        let mut arrivals = arrivals.into_iter().peekable();
+
+        let mut cycle = 0;
+        let mut waiting = false;
+        loop {
+            while let Some(&(arrival, i)) = arrivals.peek() {
+                // When waiting for an event to replay, the clock stops until it arrives.
+                if arrival > cycle && !waiting {
+                    break;
+                }
+                guard.push(entry(timers.len() + i, 0, EventKind::Wakeup));
+                arrivals.next();
+                waiting = false;
+            }
+            clock.store(cycle, Ordering::Relaxed);
+            let next = event_loop.handle_events(&mut guard, cycle);
+            waiting = next.map_or(false, |v| v <= cycle);
+            if waiting && arrivals.peek().is_some() {
+                continue;
+            }
+            cycle = match (next, arrivals.peek()) {
+                (None, None) => break,
+                (Some(v), None) => v,
+                (None, Some(&(arrival, _))) => arrival,
+                (Some(v), Some(&(arrival, _))) => v.min(arrival),
+            };
+        }
+        let fired = fired.lock().clone();
+        fired
+    }
+
+    #[test]
+    fn test_replay() {
+        let timers = [10, 30];
+
+        let recorder = EventLoop::new(false);
+        recorder.start_recording();
+        let recorded = run(&recorder, &timers, &[15]);
+        assert_eq!(recorded, [(0, 10), (2, 15), (1, 30)]);
+
+        let schedule = recorder.recording();
+        assert_eq!(
+            schedule,
+            [(10, EventKind::Timer), (15, EventKind::Wakeup), (30, EventKind::Timer)]
+        );
+        let mut text = Vec::new();
+        write_schedule(&mut text, &schedule).unwrap();
+        let schedule = parse_schedule(std::str::from_utf8(&text).unwrap()).unwrap();
+
+        // Host I/O completes both earlier and later than when recorded.
+        for &arrival in &[5, 20] {
+            let replayer = EventLoop::new(false);
+            replayer.start_replay(schedule.clone());
+            assert_eq!(run(&replayer, &timers, &[arrival]), recorded);
+        }
+
+        // Without replay the wakeup fires as soon as it arrives.
+        assert_eq!(run(&EventLoop::new(false), &timers, &[5]), [(2, 5), (0, 10), (1, 30)]);
+    }
+
+    /// Run the event loop on a fiber in lock-step with a hart fiber, which queues a wakeup at
+    /// cycle `arrival` and shuts the event loop down at cycle `end`. Timers are queued upfront.
+    /// Returns the order in which events fire, identified by their index in `timers` followed by
+    /// the wakeup, and the schedule recorded.
+    fn run_lockstep(
+        replay: Option<Vec<(u64, EventKind)>>,
+        timers: &[u64],
+        arrival: u64,
+        end: u64,
+    ) -> (Vec<usize>, Vec<(u64, EventKind)>) {
+        let mut event_fiber = fiber::FiberContext::new(EventLoop::new(false));
+        let mut hart_fiber = fiber::FiberContext::new(());
+        let event_loop: &EventLoop = unsafe { &*(event_fiber.data::<EventLoop>() as *const _) };
+        event_loop.start_recording();
+        if let Some(schedule) = replay {
+            event_loop.start_replay(schedule);
+        }
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let handler = |id: usize| -> Box<dyn FnOnce() + Send> {
+            let fired = fired.clone();
+            Box::new(move || fired.lock().push(id))
+        };
+        for (i, &time) in timers.iter().enumerate() {
+            event_loop.queue(time, handler(i));
+        }
+        let wakeup = handler(timers.len());
+
+        fiber::FiberGroup::with(|group| {
+            group.spawn(&mut event_fiber, || event_loop.event_loop());
+            group.spawn(&mut hart_fiber, move || {
+                while event_loop.cycle() < arrival {
+                    fiber::sleep(1);
+                }
+                event_loop.queue(0, wakeup);
+                while event_loop.cycle() < end {
+                    fiber::sleep(1);
+                }
+                event_loop.shutdown();
+            });
+        });
+
+        let fired = fired.lock().clone();
+        (fired, event_loop.recording())
+    }
+
+    #[test]
+    fn test_replay_lockstep() {
+        let timers = [10, 30];
+
+        let (order, schedule) = run_lockstep(None, &timers, 15, 50);
+        assert_eq!(order, [0, 2, 1]);
+        assert_eq!(schedule.iter().map(|x| x.0).collect::<Vec<_>>(), [10, schedule[1].0, 30]);
+        assert_eq!(schedule[1].1, EventKind::Wakeup);
+        assert!(schedule[1].0 >= 15 && schedule[1].0 < 30);
+
+        // A wakeup arriving earlier fires at the cycle recorded.
+        assert_eq!(run_lockstep(Some(schedule.clone()), &timers, 5, 50), (vec![0, 2, 1], schedule));
+
+        // Without replay it fires as soon as it arrives.
+        assert_eq!(run_lockstep(None, &timers, 5, 50).0, [2, 0, 1]);
+    }
+
+    #[test]
+    fn test_same_cycle_order() {
+        let timers = [20, 10, 20, 20, 20, 10, 20, 20, 20];
+        let fired = run(&EventLoop::new(false), &timers, &[]);
+        let fired: Vec<_> = fired.into_iter().map(|x| x.0).collect();
+        assert_eq!(fired, [1, 5, 0, 2, 3, 4, 6, 7, 8]);
+    }
+}
@@ -0,0 +1,325 @@
+//! Fault injection for exercising guest error handling paths.
+//!
+//! Two kinds of faults are supported: bit flips in guest RAM, and poisoned physical addresses
+//! which raise access faults when accessed, similar to uncorrectable ECC errors. ECC errors can
+//! also be injected explicitly, to be detected by a background scrubber.
+
+use super::interp::Context;
+use super::EventLoop;
+use crate::config::FaultConfig;
+use crate::sim::get_memory_model;
+use io::IrqPin;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Poisoned physical addresses, aligned to 8 bytes.
+static POISONED: Lazy<Mutex<BTreeSet<u64>>> = Lazy::new(|| Mutex::new(BTreeSet::new()));
+
+/// Fast check to avoid locking `POISONED` when nothing is poisoned.
+static ANY_POISONED: AtomicBool = AtomicBool::new(false);
+
+/// Poison a physical address. Subsequent data accesses to the 8-byte word containing it will raise
+/// access faults until it is cleared.
+pub fn poison(paddr: u64) {
+    info!(target: "Fault", "poisoning {:x}", paddr);
+    POISONED.lock().insert(paddr & !7);
+    ANY_POISONED.store(true, Ordering::Relaxed);
+
+    // Make sure harts do not bypass the check via L0 data cache.
+    for i in 0..crate::core_count() {
+        crate::shared_context(i).invalidate_cache_physical(paddr);
+    }
+}
+
+/// Clear the poison of a physical address.
+pub fn clear_poison(paddr: u64) {
+    let mut guard = POISONED.lock();
+    guard.remove(&(paddr & !7));
+    ANY_POISONED.store(!guard.is_empty(), Ordering::Relaxed);
+}
+
+/// Flip a bit in guest RAM.
+pub fn flip_bit(paddr: u64, bit: u32) {
+    info!(target: "Fault", "flipping bit {} of {:x}", bit, paddr);
+    unsafe { *(paddr as usize as *mut u8) ^= 1 << (bit & 7) };
+    super::interp::icache_invalidate(paddr as usize, paddr as usize + 1);
+}
+
+/// Check whether a data access hits a poisoned location. If so `cause` and `tval` are set and
+/// `Err` is returned.
+///
+/// This should be called upon L0 data cache misses.
+pub fn check_access(ctx: &mut Context, vaddr: u64, paddr: u64, write: bool) -> Result<(), ()> {
+    if !ANY_POISONED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let cache_line_size_log2 = get_memory_model().cache_line_size_log2();
+    let line_start = paddr >> cache_line_size_log2 << cache_line_size_log2;
+    let line_end = line_start + (1 << cache_line_size_log2);
+    let guard = POISONED.lock();
+    if guard.range(line_start..line_end).next().is_none() {
+        return Ok(());
+    }
+
+    // Never keep a cache line with poisoned words in L0, so every access to it is checked.
+    ctx.shared.invalidate_cache_virtual(vaddr);
+    if guard.contains(&(paddr & !7)) {
+        ctx.cause = if write { 7 } else { 5 };
+        ctx.tval = vaddr;
+        return Err(());
+    }
+    Ok(())
+}
+
+fn schedule_bit_flip(mut rng: StdRng, interval: u64, ram_start: u64, ram_size: u64) {
+    let cycle = crate::event_loop().cycle() + rng.gen_range(1, interval * 2);
+    crate::event_loop().queue(
+        cycle,
+        Box::new(move || {
+            let paddr = ram_start + rng.gen_range(0, ram_size);
+            flip_bit(paddr, rng.gen_range(0, 8));
+            schedule_bit_flip(rng, interval, ram_start, ram_size);
+        }),
+    );
+}
+
+/// Injected ECC errors, and the machine check path detected errors are reported to.
+struct Ecc {
+    /// Errors not yet detected, as physical address and whether the error is uncorrectable.
+    latent: Mutex<Vec<(u64, bool)>>,
+    /// Number of correctable errors detected and corrected.
+    corrected: AtomicU64,
+    /// Interrupt pulsed when an uncorrectable error is detected.
+    irq: Option<Box<dyn IrqPin>>,
+}
+
+impl Ecc {
+    fn inject(&self, paddr: u64, uncorrectable: bool) {
+        self.latent.lock().push((paddr, uncorrectable));
+    }
+
+    /// Detect latent errors, as a scrubbing pass does. Correctable errors are corrected, while
+    /// uncorrectable errors raise the machine check interrupt and are returned for poisoning.
+    fn scrub(&self) -> Vec<u64> {
+        let mut uncorrectable = Vec::new();
+        for (paddr, fatal) in self.latent.lock().drain(..) {
+            if fatal {
+                error!(target: "Fault", "uncorrectable ECC error at {:x}", paddr);
+                uncorrectable.push(paddr);
+            } else {
+                info!(target: "Fault", "corrected ECC error at {:x}", paddr);
+                self.corrected.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if !uncorrectable.is_empty() {
+            if let Some(ref irq) = self.irq {
+                irq.pulse();
+            }
+        }
+        uncorrectable
+    }
+
+    fn scrub_and_poison(&self) {
+        for paddr in self.scrub() {
+            poison(paddr);
+        }
+    }
+}
+
+fn schedule_scrub(event_loop: &'static EventLoop, mut rng: StdRng, interval: u64, ecc: Arc<Ecc>) {
+    let cycle = event_loop.cycle() + rng.gen_range(1, interval * 2);
+    event_loop.queue(
+        cycle,
+        Box::new(move || {
+            ecc.scrub_and_poison();
+            schedule_scrub(event_loop, rng, interval, ecc);
+        }),
+    );
+}
+
+/// Schedule the injection of ECC errors and the scrubbing passes detecting them.
+fn schedule_ecc(
+    event_loop: &'static EventLoop,
+    config: &FaultConfig,
+    irq: Option<Box<dyn IrqPin>>,
+) -> Arc<Ecc> {
+    let ecc = Arc::new(Ecc { latent: Mutex::new(Vec::new()), corrected: AtomicU64::new(0), irq });
+    let scrub_now = config.scrub_interval == 0;
+    for ecc_config in config.ecc.iter() {
+        let ecc = ecc.clone();
+        let (paddr, uncorrectable) = (ecc_config.addr, ecc_config.uncorrectable);
+        event_loop.queue(
+            ecc_config.cycle,
+            Box::new(move || {
+                ecc.inject(paddr, uncorrectable);
+                if scrub_now {
+                    ecc.scrub_and_poison();
+                }
+            }),
+        );
+    }
+    if !scrub_now {
+        // Use a different random stream from bit flips.
+        let rng = StdRng::seed_from_u64(config.seed.wrapping_add(1));
+        schedule_scrub(event_loop, rng, config.scrub_interval, ecc.clone());
+    }
+    ecc
+}
+
+/// Schedule the poisoning of physical addresses.
+fn schedule_poison(event_loop: &EventLoop, config: &FaultConfig) {
+    for poison_config in config.poison.iter() {
+        let paddr = poison_config.addr;
+        event_loop.queue(poison_config.cycle, Box::new(move || poison(paddr)));
+    }
+}
+
+/// Schedule fault injections according to the config.
+pub fn init() {
+    let config = &crate::CONFIG.fault;
+    schedule_poison(crate::event_loop(), config);
+
+    if config.bit_flip_interval != 0 {
+        let rng = StdRng::seed_from_u64(config.seed);
+        let ram_size = crate::CONFIG.memory as u64 * 1024 * 1024;
+        schedule_bit_flip(rng, config.bit_flip_interval, 0x40000000, ram_size);
+    }
+
+    if !config.ecc.is_empty() || config.scrub_interval != 0 {
+        let irq = config.ecc_irq.map(|irq| super::IO_SYSTEM.plic.irq_pin(irq));
+        schedule_ecc(crate::event_loop(), config, irq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ecc_scrub() {
+        struct MockIrq(Arc<AtomicU64>);
+
+        impl IrqPin for MockIrq {
+            fn set_level(&self, level: bool) {
+                if level {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let raised = Arc::new(AtomicU64::new(0));
+        let ecc = Ecc {
+            latent: Mutex::new(Vec::new()),
+            corrected: AtomicU64::new(0),
+            irq: Some(Box::new(MockIrq(raised.clone()))),
+        };
+
+        // Errors are latent until scrubbed.
+        ecc.inject(0x80002000, false);
+        assert_eq!(ecc.corrected.load(Ordering::Relaxed), 0);
+        assert_eq!(ecc.scrub(), []);
+        assert_eq!(ecc.corrected.load(Ordering::Relaxed), 1);
+        assert_eq!(raised.load(Ordering::Relaxed), 0);
+
+        ecc.inject(0x80003008, true);
+        assert_eq!(ecc.scrub(), [0x80003008]);
+        assert_eq!(raised.load(Ordering::Relaxed), 1);
+        assert_eq!(ecc.corrected.load(Ordering::Relaxed), 1);
+
+        // Each error is detected once.
+        assert_eq!(ecc.scrub(), []);
+        assert_eq!(raised.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_ecc_scheduled() {
+        crate::testing::init_harts();
+        let event_loop: &'static EventLoop = Box::leak(Box::new(EventLoop::new(false)));
+        let error =
+            |addr, cycle, uncorrectable| crate::config::EccConfig { addr, cycle, uncorrectable };
+        let config = FaultConfig {
+            ecc: vec![error(0x90002000, 100, false), error(0x90003008, 200, true)],
+            scrub_interval: 1000,
+            ..Default::default()
+        };
+        let ecc = schedule_ecc(event_loop, &config, None);
+
+        // Nothing happens before the errors are injected.
+        event_loop.advance_to(99);
+        assert!(ecc.latent.lock().is_empty());
+        assert_eq!(ecc.corrected.load(Ordering::Relaxed), 0);
+
+        // Scrubbing passes are less than twice the interval apart, so both errors are detected by
+        // then.
+        event_loop.advance_to(200 + 2000);
+        assert!(ecc.latent.lock().is_empty());
+        assert_eq!(ecc.corrected.load(Ordering::Relaxed), 1);
+        assert!(POISONED.lock().contains(&0x90003008));
+        clear_poison(0x90003008);
+
+        // Without scrubbing, errors are detected as soon as they are injected.
+        let config =
+            FaultConfig { ecc: vec![error(0x90002000, 2500, false)], ..Default::default() };
+        let ecc = schedule_ecc(event_loop, &config, None);
+        event_loop.advance_to(2499);
+        assert_eq!(ecc.corrected.load(Ordering::Relaxed), 0);
+        event_loop.advance_to(2500);
+        assert_eq!(ecc.corrected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_poisoned_load() {
+        use riscv::Op;
+
+        crate::testing::init_harts();
+        let event_loop = EventLoop::new(false);
+        let line: &'static mut [u64; 8] = Box::leak(Box::new([0x1234; 8]));
+        let paddr = line.as_ptr() as u64;
+        let config = FaultConfig {
+            poison: vec![crate::config::PoisonConfig { addr: paddr + 4, cycle: 100 }],
+            ..Default::default()
+        };
+        schedule_poison(&event_loop, &config);
+
+        // Poison is global, so clear it even if the test fails.
+        struct Clear(u64);
+        impl Drop for Clear {
+            fn drop(&mut self) {
+                clear_poison(self.0);
+            }
+        }
+        let _clear = Clear(paddr);
+
+        // Run `op` in machine mode with a1 = `addr`, each time with a cold L0 data cache.
+        let run = |op: &Op, addr: u64| {
+            let mut ctx = Context::new(0);
+            ctx.prv = 3;
+            ctx.registers[11] = addr;
+            let result = super::super::interp::step(&mut ctx, op, false);
+            (result, ctx.cause, ctx.tval, ctx.registers[10])
+        };
+        let load = Op::Ld { rd: 10, rs1: 11, imm: 0 };
+        let store = Op::Sd { rs1: 11, rs2: 0, imm: 0 };
+
+        event_loop.advance_to(99);
+        let (result, _, _, value) = run(&load, paddr);
+        assert_eq!((result, value), (Ok(()), 0x1234));
+
+        // Once poisoned, accesses to the word fault, but other words in the line do not.
+        event_loop.advance_to(100);
+        let (result, cause, tval, _) = run(&load, paddr);
+        assert_eq!((result, cause, tval), (Err(()), 5, paddr));
+        let (result, cause, tval, _) = run(&store, paddr);
+        assert_eq!((result, cause, tval), (Err(()), 7, paddr));
+        assert_eq!(run(&load, paddr + 8).0, Ok(()));
+
+        clear_poison(paddr);
+        assert_eq!(run(&load, paddr).0, Ok(()));
+    }
+}
@@ -0,0 +1,478 @@
+//! Server of the GDB remote serial protocol, for debugging guests.
+//!
+//! GDB connects before execution starts, and the boot hart stops before its first instruction.
+//! Whenever a hart stops, either because it reaches a breakpoint, completes a single step, or GDB
+//! interrupts execution, its registers and memory are exposed to GDB and all other harts are
+//! paused until execution is continued. Only one hart is debugged at a time.
+
+use super::interp::Context;
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use riscv::mmu::AccessType;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often the connection is polled for interrupt requests while the guest is running.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Maximum packet size advertised to GDB. Replies to memory reads encode each byte as two hex
+/// digits, so at most half as many bytes can be read at once.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Whether GDB is connected. Checked by `find_block` before looking for breakpoints.
+pub static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Set when GDB asks execution to stop. The next hart looking up a block stops.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Virtual addresses of software breakpoints.
+static BREAKPOINTS: Lazy<Mutex<BTreeSet<u64>>> = Lazy::new(|| Mutex::new(BTreeSet::new()));
+
+#[derive(Clone, Copy)]
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct State {
+    /// Whether a hart is stopped or single stepping. Other harts wait before stopping.
+    halted: bool,
+    /// Address of the context of the stopped hart waiting for commands, if any.
+    stopped: Option<usize>,
+    /// How GDB wants the stopped hart to resume.
+    resume: Option<Resume>,
+    /// Incremented whenever execution is continued, so paused harts know when to proceed.
+    generation: u64,
+}
+
+static STATE: Lazy<Mutex<State>> =
+    Lazy::new(|| Mutex::new(State { halted: false, stopped: None, resume: None, generation: 0 }));
+static CONDVAR: Condvar = Condvar::new();
+
+/// Check whether there is a breakpoint at `pc`.
+pub fn is_breakpoint(pc: u64) -> bool {
+    ACTIVE.load(Ordering::Relaxed) && BREAKPOINTS.lock().contains(&pc)
+}
+
+/// Check whether a hart about to execute the block at `pc` should stop for GDB.
+pub fn should_stop(pc: u64) -> bool {
+    (STOP_REQUESTED.load(Ordering::Relaxed) && STOP_REQUESTED.swap(false, Ordering::Relaxed))
+        || is_breakpoint(pc)
+}
+
+/// Stop the hart, and handle commands from GDB until execution is continued. This must be called
+/// on the hart's own fiber.
+pub fn stop(ctx: &mut Context) {
+    let mut state = STATE.lock();
+    while state.halted {
+        CONDVAR.wait(&mut state);
+    }
+    state.halted = true;
+
+    // Pause all other harts. In lockstep mode they share our thread, so they are paused already.
+    // Harts still waking up from the previous pause need the lock to do so.
+    if crate::threaded() {
+        let generation = state.generation;
+        MutexGuard::unlocked(&mut state, || {
+            for i in 0..crate::core_count() {
+                if i as u64 != ctx.hartid {
+                    crate::shared_context(i).run_on(move || pause(generation));
+                }
+            }
+        });
+    }
+
+    loop {
+        state.stopped = Some(ctx as *mut Context as usize);
+        CONDVAR.notify_all();
+        let resume = loop {
+            match state.resume.take() {
+                Some(resume) => break resume,
+                None => CONDVAR.wait(&mut state),
+            }
+        };
+        match resume {
+            Resume::Continue => break,
+            Resume::Step => MutexGuard::unlocked(&mut state, || super::interp::single_step(ctx)),
+        }
+    }
+
+    state.halted = false;
+    state.generation += 1;
+    CONDVAR.notify_all();
+}
+
+/// Block until execution is continued after `generation`.
+fn pause(generation: u64) {
+    let mut state = STATE.lock();
+    while state.generation == generation {
+        CONDVAR.wait(&mut state);
+    }
+}
+
+/// Ask a hart to stop as soon as possible.
+fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::Relaxed);
+    // Translated blocks branch to each other directly, so force them to be looked up again.
+    super::interp::icache_invalidate_all();
+    for i in 0..crate::core_count() {
+        crate::shared_context(i).alert();
+    }
+}
+
+/// Wait for a hart to stop, and run `f` on its context.
+fn with_stopped<T>(f: impl FnOnce(&mut Context) -> T) -> T {
+    let mut state = STATE.lock();
+    let ctx = loop {
+        match state.stopped {
+            Some(ctx) => break ctx,
+            None => CONDVAR.wait(&mut state),
+        }
+    };
+    // The hart does not touch its context until it is resumed, which requires the lock.
+    f(unsafe { &mut *(ctx as *mut Context) })
+}
+
+/// Resume the stopped hart.
+fn resume(resume: Resume) {
+    let mut state = STATE.lock();
+    state.stopped = None;
+    state.resume = Some(resume);
+    CONDVAR.notify_all();
+}
+
+/// Packet framing of the remote serial protocol.
+struct Connection<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> Connection<S> {
+    fn new(stream: S) -> Self {
+        Connection { stream }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Read a packet and acknowledge it. Acknowledgements and interrupt requests outside packets
+    /// are skipped, as is anything with a wrong checksum.
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if self.read_byte()? != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(data);
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(data)).as_bytes());
+        self.stream.write_all(&packet)
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn to_hex(data: &[u8]) -> Vec<u8> {
+    data.iter().flat_map(|byte| format!("{:02x}", byte).into_bytes()).collect()
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()).collect()
+}
+
+/// Parse a hexadecimal number.
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// Parse `addr,len` as used by memory packets.
+fn parse_range(args: &[u8]) -> Option<(u64, usize)> {
+    let comma = args.iter().position(|&byte| byte == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])? as usize))
+}
+
+/// Access guest virtual memory as the hart would, page by page. `f` is called with the host
+/// memory of each part and its offset from `addr`. Returns `None` if any part is unmapped or is
+/// not RAM, in which case nothing is accessed.
+fn access_memory(
+    ctx: &mut Context,
+    addr: u64,
+    len: usize,
+    access: AccessType,
+    mut f: impl FnMut(&mut [u8], usize),
+) -> Option<()> {
+    let mut parts = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let vaddr = addr.wrapping_add(offset as u64);
+        let part_len = std::cmp::min(len - offset, 4096 - (vaddr & 4095) as usize);
+        let paddr = ctx.translate_addr(vaddr, access).ok();
+        // The parts are disjoint, and the hart is stopped.
+        parts.push(paddr.and_then(|paddr| unsafe { super::memory::host_slice(paddr, part_len) }));
+        offset += part_len;
+    }
+
+    let parts: Vec<_> = parts.into_iter().collect::<Option<_>>()?;
+    let mut offset = 0;
+    for part in parts {
+        f(part, offset);
+        offset += part.len();
+    }
+    Some(())
+}
+
+/// Handle a packet that inspects or modifies the state of a stopped hart, and return the reply.
+fn handle_query(ctx: &mut Context, packet: &[u8]) -> Vec<u8> {
+    let (&kind, args) = match packet.split_first() {
+        Some(v) => v,
+        None => return Vec::new(),
+    };
+    match kind {
+        b'?' => b"S05".to_vec(),
+        // General registers x0-x31 followed by pc.
+        b'g' => {
+            let mut data = Vec::with_capacity(33 * 8);
+            for &value in ctx.registers.iter().chain(std::iter::once(&ctx.pc)) {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            to_hex(&data)
+        }
+        b'G' => match from_hex(args) {
+            Some(ref data) if data.len() >= 33 * 8 => {
+                let mut values = data.chunks(8).map(|chunk| {
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(chunk);
+                    u64::from_le_bytes(bytes)
+                });
+                // x0 is hardwired to zero.
+                values.next();
+                for i in 1..32 {
+                    ctx.registers[i] = values.next().unwrap();
+                }
+                ctx.pc = values.next().unwrap();
+                b"OK".to_vec()
+            }
+            _ => b"E01".to_vec(),
+        },
+        b'm' => {
+            let (addr, len) = match parse_range(args) {
+                Some(v) if v.1 <= PACKET_SIZE / 2 => v,
+                _ => return b"E01".to_vec(),
+            };
+            let mut data = vec![0; len];
+            match access_memory(ctx, addr, len, AccessType::Read, |part, offset| {
+                data[offset..offset + part.len()].copy_from_slice(part)
+            }) {
+                Some(()) => to_hex(&data),
+                None => b"E14".to_vec(),
+            }
+        }
+        b'M' => {
+            let colon = args.iter().position(|&byte| byte == b':');
+            let (addr, len, data) = match colon.and_then(|colon| {
+                let (addr, len) = parse_range(&args[..colon])?;
+                Some((addr, len, from_hex(&args[colon + 1..])?))
+            }) {
+                Some(v) if v.2.len() == v.1 => v,
+                _ => return b"E01".to_vec(),
+            };
+            match access_memory(ctx, addr, len, AccessType::Write, |part, offset| {
+                part.copy_from_slice(&data[offset..offset + part.len()]);
+                let start = part.as_ptr() as usize;
+                super::interp::icache_invalidate(start, start + part.len());
+            }) {
+                Some(()) => b"OK".to_vec(),
+                None => b"E14".to_vec(),
+            }
+        }
+        // All harts share a single stop, so thread selection is ignored.
+        b'H' => b"OK".to_vec(),
+        b'q' if args.starts_with(b"Supported") => {
+            format!("PacketSize={:x}", PACKET_SIZE).into_bytes()
+        }
+        b'q' if args.starts_with(b"Attached") => b"1".to_vec(),
+        // Unsupported packets get an empty reply.
+        _ => Vec::new(),
+    }
+}
+
+/// Handle `Z0`/`z0` packets, which insert or remove a software breakpoint.
+fn handle_breakpoint(insert: bool, args: &[u8]) -> Vec<u8> {
+    if !args.starts_with(b"0,") {
+        return Vec::new();
+    }
+    let addr = match parse_range(&args[2..]) {
+        Some((addr, _)) => addr,
+        None => return b"E01".to_vec(),
+    };
+    let changed =
+        if insert { BREAKPOINTS.lock().insert(addr) } else { BREAKPOINTS.lock().remove(&addr) };
+    if changed {
+        // Blocks containing the address in the middle need to be split. This relies on
+        // `icache_invalidate_all` flushing translated code even when no code page is protected.
+        super::interp::icache_invalidate_all();
+    }
+    b"OK".to_vec()
+}
+
+/// Wait for a hart to stop after execution is resumed, stopping it if GDB asks so.
+fn wait_for_stop(conn: &mut Connection<TcpStream>) -> io::Result<()> {
+    conn.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    loop {
+        {
+            let mut state = STATE.lock();
+            if state.stopped.is_none() {
+                CONDVAR.wait_for(&mut state, POLL_INTERVAL);
+            }
+            if state.stopped.is_some() {
+                break;
+            }
+        }
+        match conn.read_byte() {
+            // Ctrl-C
+            Ok(0x03) => request_stop(),
+            Ok(_) => (),
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => return Err(err),
+        }
+    }
+    conn.stream.set_read_timeout(None)
+}
+
+fn serve(stream: TcpStream) -> io::Result<()> {
+    let mut conn = Connection::new(stream);
+    loop {
+        let packet = conn.read_packet()?;
+        let reply = match packet.first() {
+            Some(&kind @ b'c') | Some(&kind @ b's') => {
+                if let Some(addr) = parse_hex(&packet[1..]) {
+                    with_stopped(|ctx| ctx.pc = addr);
+                }
+                resume(if kind == b'c' { Resume::Continue } else { Resume::Step });
+                wait_for_stop(&mut conn)?;
+                b"S05".to_vec()
+            }
+            Some(b'Z') => handle_breakpoint(true, &packet[1..]),
+            Some(b'z') => handle_breakpoint(false, &packet[1..]),
+            Some(b'D') | Some(b'k') => {
+                conn.write_packet(b"OK")?;
+                return Ok(());
+            }
+            _ => with_stopped(|ctx| handle_query(ctx, &packet)),
+        };
+        conn.write_packet(&reply)?;
+    }
+}
+
+/// Stop debugging and let the guest run freely.
+fn detach() {
+    ACTIVE.store(false, Ordering::Relaxed);
+    STOP_REQUESTED.store(false, Ordering::Relaxed);
+    BREAKPOINTS.lock().clear();
+    super::interp::icache_invalidate_all();
+    if STATE.lock().stopped.is_some() {
+        resume(Resume::Continue);
+    }
+}
+
+/// Wait for GDB to connect on `port`, and serve it from a separate thread. The boot hart stops
+/// before executing its first instruction.
+pub fn start(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for GDB to connect on port {}", port);
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    ACTIVE.store(true, Ordering::Relaxed);
+    STOP_REQUESTED.store(true, Ordering::Relaxed);
+    std::thread::Builder::new().name("gdbstub".to_owned()).spawn(move || {
+        if let Err(err) = serve(stream) {
+            error!(target: "GdbStub", "connection lost: {}", err);
+        }
+        detach();
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_registers() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(b"+$g#67").unwrap();
+            // The acknowledgement followed by a packet with 33 registers.
+            let mut reply = vec![0; 1 + 1 + 33 * 16 + 3];
+            stream.read_exact(&mut reply).unwrap();
+            stream.write_all(b"+").unwrap();
+            reply
+        });
+
+        let mut ctx = Context::new(0);
+        for i in 1..32 {
+            ctx.registers[i] = i as u64 * 0x1111;
+        }
+        ctx.pc = 0x8020_0000;
+        let mut conn = Connection::new(listener.accept().unwrap().0);
+        let packet = conn.read_packet().unwrap();
+        assert_eq!(packet, b"g");
+        conn.write_packet(&handle_query(&mut ctx, &packet)).unwrap();
+
+        let reply = client.join().unwrap();
+        assert_eq!(&reply[..2], b"+$");
+        let data = from_hex(&reply[2..2 + 33 * 16]).unwrap();
+        assert_eq!(reply[2 + 33 * 16], b'#');
+        let checksum = parse_hex(&reply[3 + 33 * 16..]).unwrap();
+        assert_eq!(checksum as u8, checksum_of(&reply[2..2 + 33 * 16]));
+        for (i, chunk) in data.chunks(8).enumerate() {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(chunk);
+            let expected = if i == 32 { ctx.pc } else { ctx.registers[i] };
+            assert_eq!(u64::from_le_bytes(bytes), expected);
+        }
+    }
+
+    #[test]
+    fn test_read_memory_limit() {
+        let mut ctx = Context::new(0);
+        ctx.prv = 3;
+        assert_eq!(handle_query(&mut ctx, b"qSupported"), b"PacketSize=4000");
+        // The reply to a read of more than 0x2000 bytes would not fit in a packet.
+        assert_eq!(handle_query(&mut ctx, b"m80000000,2001"), b"E01");
+    }
+}
@@ -8,13 +8,17 @@
 helper_trap:
     # RDI -> context
     mov rdi, rbp
-    # We use EBX to store the instruction offset within the current basic block
-    # Lower 16 bits are PC offset and upper 16 bits are INSTRET offset.
+    # We use RBX to store the instruction offset within the current basic block
+    # Lower 16 bits are PC offset and next 16 bits are INSTRET offset.
+    # Upper 32 bits are the MINSTRET offset.
     movsx rax, bx
     add [rbp + 0x100], rax
-    shr ebx, 16
-    movsx rax, bx
+    mov rax, rbx
+    shr eax, 16
+    movsx rax, ax
     add [rbp + 0x108], rax
+    shr rbx, 32
+    add [rbp + 0x120], rbx
 
     call trap
     ret
@@ -24,13 +28,17 @@ helper_trap:
 helper_misalign:
 # RDI -> context
     mov rdi, rbp
-    # We use EBX to store the instruction offset within the current basic block
-    # Lower 16 bits are PC offset and upper 16 bits are INSTRET offset.
+    # We use RBX to store the instruction offset within the current basic block
+    # Lower 16 bits are PC offset and next 16 bits are INSTRET offset.
+    # Upper 32 bits are the MINSTRET offset.
     movsx rax, bx
     add [rbp + 0x100], rax
-    shr ebx, 16
-    movsx rax, bx
+    mov rax, rbx
+    shr eax, 16
+    movsx rax, ax
     add [rbp + 0x108], rax
+    shr rbx, 32
+    add [rbp + 0x120], rbx
 
     call handle_misalign
     test al, al
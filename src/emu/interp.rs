@@ -1,9 +1,10 @@
 use crate::sim::get_memory_model;
+use crate::util::RoCell;
 use atomic_ext::AtomicExt;
 use io::IoMemory;
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, MutexGuard};
-use riscv::{mmu::*, Csr, Op};
+use riscv::{mmu::*, pmp, Csr, Op, Trap};
 use softfp::{self, F32, F64};
 use std::cell::UnsafeCell;
 use std::collections::{BTreeMap, BTreeSet};
@@ -35,6 +36,14 @@ impl CacheLine {
     }
 }
 
+/// A data watchpoint, covering `len` bytes starting at virtual address `addr`.
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: u64,
+    /// Whether only stores hit the watchpoint. Otherwise loads hit it as well.
+    pub on_write: bool,
+}
+
 /// Most fields of `{Context}` can only be safely accessed by the execution thread. However we do
 /// ocassionally need to communicate between these threads. This `{SharedContext}` are parts that
 /// can be safely accessed both from the execution thread and other harts.
@@ -86,6 +95,10 @@ pub struct SharedContext {
 
     /// Tasks that needs to be running on the hart-specific thread, such as manipulating I-Cache.
     pub tasks: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+
+    /// The hart's `instret`, published for other threads to read. The hart updates it whenever it
+    /// looks up a block or checks for interrupts, so it may lag behind.
+    pub instret: AtomicU64,
 }
 
 impl SharedContext {
@@ -93,6 +106,7 @@ impl SharedContext {
         // Check the constant used in helper.s
         assert_eq!(offset_of!(Context, pc), 0x100);
         assert_eq!(offset_of!(Context, instret), 0x108);
+        assert_eq!(offset_of!(Context, minstret), 0x120);
         assert_eq!(offset_of!(Context, cycle_offset), 0x128);
         assert_eq!(offset_of!(Context, shared) + offset_of!(SharedContext, alarm), 0x130);
 
@@ -118,6 +132,7 @@ impl SharedContext {
             wfi_mutex: fiber::Mutex::new(()),
             wfi_condvar: fiber::Condvar::new(),
             tasks: Mutex::new(Vec::new()),
+            instret: AtomicU64::new(0),
         }
     }
 
@@ -262,6 +277,8 @@ pub struct Context {
     pub sepc: u64,
     pub satp: u64,
     pub scounteren: u64,
+    /// Supervisor timer compare value of the Sstc extension.
+    pub stimecmp: u64,
 
     // M-mode CSRs
     pub mideleg: u64,
@@ -278,10 +295,180 @@ pub struct Context {
     // Current privilege level
     pub prv: u64,
 
+    /// Index of this hart within the emulator.
     pub hartid: u64,
+
+    /// Architectural hartid, which need not be the same as the index.
+    pub mhartid: u64,
+
+    /// Vendor and implementation IDs reported to the guest.
+    pub identity: crate::config::IdentityConfig,
+
+    /// Unimplemented CSRs which read as zero and ignore writes instead of raising illegal
+    /// instruction exception. Empty unless configured otherwise, i.e. strict by default.
+    pub permissive_csr: Vec<u16>,
+
+    /// PMP configurations, laid out as the RV64 `pmpcfg` CSRs.
+    pub pmpcfg: [u64; 8],
+    pub pmpaddr: [u64; 64],
+
+    /// Whether misaligned loads and stores are emulated instead of raising exceptions.
+    pub emulate_misaligned: bool,
+
+    /// Number of consecutive harts sharing a TLB, which are all flushed by an sfence.vma.
+    pub cluster_size: usize,
+
+    /// Whether translated loops polling memory sleep until the memory changes instead of spinning.
+    /// Only effective in threaded mode.
+    pub spin_detect: bool,
+
+    /// Data watchpoints. Cache lines overlapping them are never kept in the L0 data cache, so every
+    /// access to them goes through `translate_cache_miss` and is checked.
+    pub watchpoints: Vec<Watchpoint>,
+
+    /// Address of an access which hit a watchpoint, to be handled at the next alarm check.
+    pub watch_hit: Option<u64>,
+
+    /// Execution tracer of this hart. Like `instruction_hook`, this must be set before any code of
+    /// this hart is translated.
+    pub tracer: Option<Box<super::trace::Tracer>>,
+
+    /// Callback invoked before each instruction of this hart is executed, for building tracers,
+    /// coverage tools and other instrumentation. `ctx.pc` is the address of the instruction;
+    /// `instret` and `minstret` are not up-to-date.
+    ///
+    /// This must be set before any code of this hart is translated. No code is emitted for it when
+    /// unset.
+    pub instruction_hook: Option<fn(&Context, &Op)>,
+
+    /// Callback consulted before a trap of this hart is delivered to the guest, e.g. to emulate
+    /// instructions missing from the guest's point of view. `ctx.last_trap()` describes the trap,
+    /// and `ctx.pc` is the faulting instruction for exceptions. To resume, the hook must update
+    /// `ctx.pc` and any other state as the instruction would.
+    pub trap_hook: Option<fn(&mut Context) -> TrapAction>,
 }
 
 impl Context {
+    /// Create a context for the given hart. Registers and CSRs are in their reset state.
+    pub fn new(hartid: u64) -> Context {
+        let mut ctx = Context {
+            shared: SharedContext::new(),
+            registers: [0xCCCCCCCCCCCCCCCC; 32],
+            fp_registers: [0xFFFFFFFFFFFFFFFF; 32],
+            frm: 0,
+            instret: 0,
+            lr_addr: 0,
+            lr_value: 0,
+            cause: 0,
+            tval: 0,
+            // FPU turned on by default
+            mstatus: 0x6000,
+            scause: 0,
+            sepc: 0,
+            stval: 0,
+            satp: 0,
+            sscratch: 0,
+            stvec: 0,
+            scounteren: 0,
+            stimecmp: u64::MAX,
+            mideleg: 0,
+            medeleg: 0,
+            mcause: 0,
+            mepc: 0,
+            mtval: 0,
+            mie: 0,
+            mscratch: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            // These are set by setup_mem, so we don't really care now.
+            pc: 0,
+            prv: 0,
+            hartid,
+            mhartid: hartid,
+            identity: Default::default(),
+            permissive_csr: Vec::new(),
+            pmpcfg: [0; 8],
+            pmpaddr: [0; 64],
+            emulate_misaligned: false,
+            cluster_size: 1,
+            spin_detect: false,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            tracer: None,
+            instruction_hook: None,
+            trap_hook: None,
+            minstret: 0,
+            cycle_offset: 0,
+        };
+        // x0 must always be 0
+        ctx.registers[0] = 0;
+        ctx
+    }
+
+    /// Whether code of this hart calls `instruction_hook` before each instruction.
+    pub fn hook_enabled(&self) -> bool {
+        self.instruction_hook.is_some() || self.tracer.is_some()
+    }
+
+    /// Get the trap most recently raised, as described by `cause` and `tval`.
+    pub fn last_trap(&self) -> Trap {
+        Trap::new(self.cause, self.tval)
+    }
+
+    /// Set `cause` and `tval` to describe a trap about to be taken.
+    pub fn set_trap(&mut self, trap: Trap) {
+        self.cause = trap.cause();
+        self.tval = trap.tval();
+    }
+
+    /// Read a CSR by its name, e.g. `"sstatus"`. Like `read_csr`, no privilege checks are
+    /// performed. Returns `Err` if the name is unknown or the read fails.
+    pub fn read_csr_by_name(&mut self, name: &str) -> Result<u64, ()> {
+        read_csr(self, Csr::from_name(name).ok_or(())?)
+    }
+
+    /// Write a CSR by its name, e.g. `"satp"`. Like `write_csr`, no privilege checks are
+    /// performed. Returns `Err` if the name is unknown or the write fails.
+    pub fn write_csr_by_name(&mut self, name: &str, value: u64) -> Result<(), ()> {
+        write_csr(self, Csr::from_name(name).ok_or(())?, value)
+    }
+
+    /// Write a general purpose register or the PC by its name, e.g. `"a0"`, `"x10"` or `"pc"`.
+    /// Writes to `zero` are ignored. Returns `Err` if the name is unknown.
+    pub fn write_register_by_name(&mut self, name: &str, value: u64) -> Result<(), ()> {
+        if name == "pc" {
+            self.pc = value;
+            return Ok(());
+        }
+        let reg = riscv::register_from_name(name).ok_or(())?;
+        if reg != 0 {
+            self.registers[reg as usize] = value;
+        }
+        Ok(())
+    }
+
+    /// Get the dynamic rounding mode, i.e. the value of `frm`.
+    pub fn rounding_mode(&self) -> softfp::RoundingMode {
+        self.frm.try_into().unwrap()
+    }
+
+    /// Set the dynamic rounding mode used by floating point instructions with `rm` set to DYN.
+    /// This is the same as writing to `frm`.
+    pub fn set_rounding_mode(&mut self, rm: softfp::RoundingMode) {
+        self.frm = rm as u32;
+    }
+
+    /// Get the floating point exception flags accrued since they were last cleared, i.e. the
+    /// value of `fflags`.
+    pub fn exception_flags(&self) -> softfp::ExceptionFlags {
+        softfp::ExceptionFlags::from_bits_truncate(self.shared.fflags.load(MemOrder::Relaxed))
+    }
+
+    /// Clear accrued floating point exception flags.
+    pub fn clear_exception_flags(&self) {
+        self.shared.fflags.store(0, MemOrder::Relaxed);
+    }
+
     pub fn test_and_set_fs(&mut self) -> Result<(), ()> {
         if cfg!(not(feature = "float")) {
             self.cause = 2;
@@ -297,10 +484,21 @@ impl Context {
         Ok(())
     }
 
+    /// Check whether the user-level counter `id` (0 for `cycle`, 1 for `time`, 2 for `instret`
+    /// and 3-31 for `hpmcounter3`-`hpmcounter31`) is accessible at the current privilege level.
+    /// M-mode can always access counters. `mcounteren` controls access from S-mode and U-mode, and
+    /// U-mode additionally needs the counter to be enabled in `scounteren`.
+    pub fn counter_enabled(&self, id: u32) -> bool {
+        let mask = 1 << id;
+        match self.prv {
+            3 => true,
+            1 => self.mcounteren & mask != 0,
+            _ => self.mcounteren & self.scounteren & mask != 0,
+        }
+    }
+
     pub fn test_counter(&mut self, id: u32) -> Result<(), ()> {
-        if self.prv < 3 && self.mcounteren & (1 << id) == 0
-            || self.prv < 1 && self.scounteren & (1 << id) == 0
-        {
+        if !self.counter_enabled(id) {
             self.cause = 2;
             self.tval = 0;
             return Err(());
@@ -384,23 +582,113 @@ impl Context {
         }
 
         // MMU off
-        if (self.satp >> 60) == 0 || prv == 3 {
-            return Ok(addr);
+        let paddr = if (self.satp >> 60) == 0 || prv == 3 {
+            addr
+        } else {
+            let pte =
+                walk_page(self.satp, addr >> 12, |addr| crate::emu::read_memory(addr as usize));
+            match check_permission(pte, access, prv as u8, self.mstatus) {
+                Ok(_) => pte >> 10 << 12 | addr & 4095,
+                Err(_) => {
+                    self.cause = match access {
+                        AccessType::Read => 13,
+                        AccessType::Write => 15,
+                        AccessType::Execute => 12,
+                    };
+                    self.tval = addr;
+                    return Err(());
+                }
+            }
+        };
+
+        // Accesses to page tables are not checked against PMP. As the L0 cache is not flushed
+        // between accesses, PMP regions smaller than a cache line are not strictly enforced.
+        if pmp::is_active(&self.pmpcfg)
+            && !pmp::check(&self.pmpcfg, &self.pmpaddr, paddr, access, prv as u8)
+        {
+            self.cause = match access {
+                AccessType::Read => 5,
+                AccessType::Write => 7,
+                AccessType::Execute => 1,
+            };
+            self.tval = addr;
+            return Err(());
+        }
+
+        // Instructions cannot be fetched from I/O memory. For instructions crossing a page
+        // boundary, `addr` is the address of the faulting half, as required by the spec.
+        if access == AccessType::Execute && crate::emu::is_io(paddr) {
+            self.cause = 1;
+            self.tval = addr;
+            return Err(());
+        }
+        Ok(paddr)
+    }
+
+    /// Translate a virtual address into physical address as the hart currently would, e.g. for
+    /// inspecting guest memory from the host. Unlike `translate_vaddr`, the state of the hart is
+    /// not changed; the trap that the access would raise is returned instead.
+    pub fn translate_addr(&mut self, addr: u64, access: AccessType) -> Result<u64, Trap> {
+        let (cause, tval) = (self.cause, self.tval);
+        let result = self.translate_vaddr(addr, access).map_err(|_| self.last_trap());
+        self.cause = cause;
+        self.tval = tval;
+        result
+    }
+
+    /// Watch `len` bytes starting at virtual address `addr`. The hart stops after an access to
+    /// them, either for GDB if the stub is active, or by dumping its state and shutting down.
+    /// Accesses are checked at the granularity of 8-byte words.
+    pub fn set_watch(&mut self, addr: u64, len: u64, on_write: bool) {
+        self.watchpoints.push(Watchpoint { addr, len, on_write });
+        let cache_line_size_log2 = get_memory_model().cache_line_size_log2();
+        let start = addr >> cache_line_size_log2;
+        let end = addr.saturating_add(len.max(1) - 1) >> cache_line_size_log2;
+        for idx in (start..=end).take(1024) {
+            self.shared.invalidate_cache_virtual(idx << cache_line_size_log2);
         }
+    }
 
-        let pte = walk_page(self.satp, addr >> 12, |addr| crate::emu::read_memory(addr as usize));
-        match check_permission(pte, access, prv as u8, self.mstatus) {
-            Ok(_) => Ok(pte >> 10 << 12 | addr & 4095),
-            Err(_) => {
-                self.cause = match access {
-                    AccessType::Read => 13,
-                    AccessType::Write => 15,
-                    AccessType::Execute => 12,
-                };
-                self.tval = addr;
-                Err(())
+    /// Remove all watchpoints starting at virtual address `addr`.
+    pub fn clear_watch(&mut self, addr: u64) {
+        self.watchpoints.retain(|watch| watch.addr != addr);
+    }
+
+    /// Write a `pmpcfg` CSR, `index` being half its number. Configurations of locked entries are
+    /// not changed.
+    fn write_pmpcfg(&mut self, index: usize, value: u64) {
+        let mut cfg = self.pmpcfg[index];
+        for shift in (0..64).step_by(8) {
+            if (cfg >> shift) as u8 & pmp::PMP_L != 0 {
+                continue;
             }
+            // Bits 6:5 are reserved, and so is R = 0, W = 1.
+            let mut entry = (value >> shift) as u8 & !0x60;
+            if entry & (pmp::PMP_R | pmp::PMP_W) == pmp::PMP_W {
+                entry &= !pmp::PMP_W;
+            }
+            cfg = cfg & !(0xFF << shift) | (entry as u64) << shift;
+        }
+        self.pmpcfg[index] = cfg;
+        self.shared.clear_local_cache();
+        self.shared.clear_local_icache();
+    }
+
+    /// Write a `pmpaddr` CSR. Addresses of locked entries, and those serving as the lower bound
+    /// of a locked TOR entry, are not changed.
+    fn write_pmpaddr(&mut self, index: usize, value: u64) {
+        if pmp::entry_cfg(&self.pmpcfg, index) & pmp::PMP_L != 0 {
+            return;
+        }
+        if index + 1 < self.pmpaddr.len()
+            && pmp::entry_cfg(&self.pmpcfg, index + 1) & (pmp::PMP_L | pmp::PMP_A)
+                == pmp::PMP_L | pmp::PMP_TOR
+        {
+            return;
         }
+        self.pmpaddr[index] = value & pmp::PMP_ADDR_MASK;
+        self.shared.clear_local_cache();
+        self.shared.clear_local_icache();
     }
 
     /// Insert a cache line into the L0 instruction cache.
@@ -421,6 +709,29 @@ impl Context {
     }
 }
 
+/// How integer division by zero and signed division overflow are handled.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DivCheck {
+    /// Produce the results defined by the specification without trapping.
+    Spec,
+    /// Produce the results defined by the specification, but log a warning.
+    Log,
+    /// Raise an illegal instruction exception, useful for debugging guests.
+    Trap,
+}
+
+/// Virtual address at which execution stops and the hart state is dumped.
+pub static BREAKPOINT: RoCell<Option<u64>> = RoCell::new(None);
+
+/// What to do with a trap after [`Context::trap_hook`] has seen it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrapAction {
+    /// Deliver the trap to the guest as usual.
+    Deliver,
+    /// Discard the trap and resume execution at `ctx.pc`.
+    Resume,
+}
+
 /// Perform a CSR read on a context. Note that this operation performs no checks before accessing
 /// them.
 /// The caller should ensure:
@@ -455,6 +766,20 @@ fn read_csr(ctx: &mut Context, csr: Csr) -> Result<u64, ()> {
             ctx.test_counter(2)?;
             ctx.instret - 1
         }
+        // Upper halves are RV32 only, but we provide them for compatibility with guests that read
+        // them regardless of XLEN.
+        Csr::Cycleh => {
+            ctx.test_counter(0)?;
+            ctx.get_mcycle() >> 32
+        }
+        Csr::Timeh => {
+            ctx.test_counter(1)?;
+            crate::event_loop().time() >> 32
+        }
+        Csr::Instreth => {
+            ctx.test_counter(2)?;
+            (ctx.instret - 1) >> 32
+        }
         Csr::Sstatus => {
             let mut value = ctx.mstatus & 0xC6122;
             // SSTATUS.FS = dirty, also set SD
@@ -474,8 +799,14 @@ fn read_csr(ctx: &mut Context, csr: Csr) -> Result<u64, ()> {
         Csr::Stval => ctx.stval,
         Csr::Sip => ctx.shared.mip.load(MemOrder::Relaxed) & ctx.mideleg,
         Csr::Satp => ctx.satp,
-        Csr::Mvendorid | Csr::Marchid | Csr::Mimpid => 0,
-        Csr::Mhartid => ctx.hartid,
+        Csr::Stimecmp if sstc_enabled() => {
+            ctx.test_counter(1)?;
+            ctx.stimecmp
+        }
+        Csr::Mvendorid => ctx.identity.mvendorid,
+        Csr::Marchid => ctx.identity.marchid,
+        Csr::Mimpid => ctx.identity.mimpid,
+        Csr::Mhartid => ctx.mhartid,
         Csr::Mstatus => {
             let mut value = ctx.mstatus;
             // MSTATUS.FS = dirty, also set SD
@@ -486,7 +817,8 @@ fn read_csr(ctx: &mut Context, csr: Csr) -> Result<u64, ()> {
             value |= 0x200000000;
             value
         }
-        Csr::Misa => unimplemented!(),
+        // MXL = 64-bit, with the A, C, D, F, I, M, S and U extensions as in the device tree.
+        Csr::Misa => 0x800000000014112d,
         Csr::Medeleg => ctx.medeleg,
         Csr::Mideleg => ctx.mideleg,
         Csr::Mie => ctx.mie,
@@ -497,13 +829,26 @@ fn read_csr(ctx: &mut Context, csr: Csr) -> Result<u64, ()> {
         Csr::Mcause => ctx.mcause,
         Csr::Mtval => ctx.mtval,
         Csr::Mip => ctx.shared.mip.load(MemOrder::Relaxed),
+        // Odd-numbered pmpcfg CSRs only exist in RV32.
+        Csr(id @ 0x3A0..=0x3AF) if id & 1 == 0 => ctx.pmpcfg[(id as usize - 0x3A0) / 2],
+        Csr(id @ 0x3B0..=0x3EF) => ctx.pmpaddr[id as usize - 0x3B0],
         Csr::Mcycle => ctx.get_mcycle(),
         Csr::Mtime => crate::event_loop().time(),
         Csr::Minstret => ctx.instret - 1,
+        // We do not implement any hardware performance monitoring events, so all hpmcounters and
+        // mhpmevents are hardwired to zero. User-level hpmcounters are still gated by counteren.
+        Csr(0xC03..=0xC1F) => {
+            ctx.test_counter(csr.0 as u32 & 0x1F)?;
+            0
+        }
+        Csr(0xB03..=0xB1F) | Csr(0x323..=0x33F) => 0,
+        Csr(id) if ctx.permissive_csr.contains(&id) => {
+            warn!("read unimplemented csr {:x} as zero", id);
+            0
+        }
         _ => {
             error!("read illegal csr {:x}", csr.0);
-            ctx.cause = 2;
-            ctx.tval = 0;
+            ctx.set_trap(Trap::IllegalInstruction(0));
             return Err(());
         }
     })
@@ -550,7 +895,7 @@ fn write_csr(ctx: &mut Context, csr: Csr, value: u64) -> Result<(), ()> {
                 ctx.stvec = value;
             }
         }
-        Csr::Scounteren => ctx.scounteren = value & 0b111,
+        Csr::Scounteren => ctx.scounteren = value & 0xFFFFFFFF,
         Csr::Sscratch => ctx.sscratch = value,
         Csr::Sepc => ctx.sepc = value & !1,
         Csr::Scause => ctx.scause = value,
@@ -569,14 +914,29 @@ fn write_csr(ctx: &mut Context, csr: Csr, value: u64) -> Result<(), ()> {
             match value >> 60 {
                 // No paging
                 0 => ctx.satp = 0,
-                // ASID not yet supported
-                8 => ctx.satp = value,
-                // We only support SV39 at the moment.
+                // SV39 and SV48. ASID not yet supported
+                8 | 9 => ctx.satp = value,
+                // Other modes are not supported, so the write is ignored.
                 _ => (),
             }
             ctx.shared.clear_local_cache();
             ctx.shared.clear_local_icache();
         }
+        Csr::Stimecmp if sstc_enabled() => {
+            ctx.test_counter(1)?;
+            ctx.stimecmp = value;
+            // STIP reflects the new comparison. If the deadline is in the future, wake the hart
+            // up when it is reached so `check_interrupt` can re-evaluate it.
+            ctx.shared.deassert(32);
+            let time = crate::event_loop().time();
+            if value <= time {
+                ctx.shared.assert(32);
+            } else {
+                let hartid = ctx.hartid as usize;
+                crate::event_loop()
+                    .queue_time(value, Box::new(move || crate::shared_context(hartid).alert()));
+            }
+        }
         Csr::Mstatus => {
             // Mask-out non-writable bits
             let old_value = ctx.mstatus;
@@ -609,7 +969,7 @@ fn write_csr(ctx: &mut Context, csr: Csr, value: u64) -> Result<(), ()> {
                 ctx.mtvec = value;
             }
         }
-        Csr::Mcounteren => ctx.mcounteren = value & 0b111,
+        Csr::Mcounteren => ctx.mcounteren = value & 0xFFFFFFFF,
         Csr::Mscratch => ctx.mscratch = value,
         Csr::Mepc => ctx.mepc = value & !1,
         Csr::Mcause => ctx.mcause = value,
@@ -619,6 +979,15 @@ fn write_csr(ctx: &mut Context, csr: Csr, value: u64) -> Result<(), ()> {
             ctx.shared.assert(0x222 & value);
         }
         Csr::Minstret => ctx.instret = value,
+        Csr(id @ 0x3A0..=0x3AF) if id & 1 == 0 => {
+            ctx.write_pmpcfg((id as usize - 0x3A0) / 2, value)
+        }
+        Csr(id @ 0x3B0..=0x3EF) => ctx.write_pmpaddr(id as usize - 0x3B0, value),
+        // Hardwired to zero, writes are ignored.
+        Csr(0xB03..=0xB1F) | Csr(0x323..=0x33F) => (),
+        Csr(id) if ctx.permissive_csr.contains(&id) => {
+            warn!("ignored write to unimplemented csr {:x} = {:x}", id, value);
+        }
         Csr(0x800) if cfg!(feature = "simcsr") => {
             crate::shutdown(crate::ExitReason::SwitchModel(value as usize));
         }
@@ -631,8 +1000,7 @@ fn write_csr(ctx: &mut Context, csr: Csr, value: u64) -> Result<(), ()> {
         }
         _ => {
             error!("write illegal csr {:x} = {:x}", csr.0, value);
-            ctx.cause = 2;
-            ctx.tval = 0;
+            ctx.set_trap(Trap::IllegalInstruction(0));
             return Err(());
         }
     }
@@ -662,26 +1030,40 @@ pub fn icache_invalidate(start: usize, end: usize) {
             // before the software expects a coherence instruction cache.
             crate::shared_context(i).run_on(move || {
                 let mut icache = icache(i as u64);
-                let keys: Vec<u64> = icache.u_map.range(start..end).map(|(k, _)| *k).collect();
-                for key in keys {
-                    let blk = icache.u_map.remove(&key).unwrap();
-                    unsafe { *(blk.1 as *mut u8) = 0xC3 }
-                }
-                let keys: Vec<u64> = icache.s_map.range(start..end).map(|(k, _)| *k).collect();
-                for key in keys {
-                    let blk = icache.s_map.remove(&key).unwrap();
-                    unsafe { *(blk.1 as *mut u8) = 0xC3 }
-                }
-                let keys: Vec<u64> = icache.m_map.range(start..end).map(|(k, _)| *k).collect();
-                for key in keys {
-                    let blk = icache.m_map.remove(&key).unwrap();
-                    unsafe { *(blk.1 as *mut u8) = 0xC3 }
+                for &prv in &[0, 1, 3] {
+                    let keys: Vec<u64> =
+                        icache.map(prv).range(start..end).map(|(k, _)| *k).collect();
+                    for key in keys {
+                        icache.evict(prv, key);
+                    }
                 }
             });
         }
     }
 }
 
+/// Invalidate all translated code on all harts.
+pub fn icache_invalidate_all() {
+    // Code pages are not protected in user-mode emulation, so translated code may exist even if
+    // no page is.
+    let mut prot = CODE_PROT.lock();
+    prot.clear();
+
+    trace!(target: "CodeProt", "invalidate all");
+    for i in 0..crate::core_count() {
+        crate::shared_context(i).run_on(move || {
+            let mut icache = icache(i as u64);
+            let icache = &mut *icache;
+            for map in [&mut icache.u_map, &mut icache.s_map, &mut icache.m_map].iter_mut() {
+                for (_, blk) in std::mem::take(*map) {
+                    unsafe { *(blk.1 as *mut u8) = 0xC3 }
+                }
+            }
+            icache.lru.clear();
+        });
+    }
+}
+
 #[inline(never)]
 #[no_mangle]
 fn insn_translate_cache_miss(ctx: &mut Context, addr: u64) -> Result<u64, ()> {
@@ -703,12 +1085,46 @@ fn insn_translate(ctx: &mut Context, addr: u64) -> Result<u64, ()> {
 #[export_name = "translate_cache_miss"]
 fn translate_cache_miss(ctx: &mut Context, addr: u64, write: bool) -> Result<u64, ()> {
     let out = get_memory_model().data_access(ctx, addr, write)?;
+    super::fault::check_access(ctx, addr, out, write)?;
+    if !ctx.watchpoints.is_empty() {
+        check_watch(ctx, addr, write);
+    }
     if write {
         icache_invalidate(out as usize, out as usize + 1);
     }
     Ok(out)
 }
 
+/// Check whether a data access hits a watchpoint. If so `watch_hit` is set and the hart is alerted
+/// to stop once the access completes.
+fn check_watch(ctx: &mut Context, addr: u64, write: bool) {
+    let cache_line_size_log2 = get_memory_model().cache_line_size_log2();
+    let line_start = addr >> cache_line_size_log2 << cache_line_size_log2;
+    let line_end = line_start + (1 << cache_line_size_log2);
+    let word = addr & !7;
+    let mut overlap = false;
+    let mut hit = false;
+    for watch in ctx.watchpoints.iter() {
+        let end = watch.addr.saturating_add(watch.len);
+        if watch.addr >= line_end || end <= line_start {
+            continue;
+        }
+        overlap = true;
+        if (write || !watch.on_write) && watch.addr < word + 8 && end > word {
+            hit = true;
+        }
+    }
+
+    // Never keep a watched cache line in L0, even after it is refilled following a flush.
+    if overlap {
+        ctx.shared.invalidate_cache_virtual(addr);
+    }
+    if hit {
+        ctx.watch_hit = Some(addr);
+        ctx.shared.alert();
+    }
+}
+
 fn translate_read(ctx: &mut Context, addr: u64) -> Result<usize, ()> {
     let idx = addr >> get_memory_model().cache_line_size_log2();
     let line = &ctx.shared.line[(idx & 1023) as usize];
@@ -720,9 +1136,11 @@ fn translate_read(ctx: &mut Context, addr: u64) -> Result<usize, ()> {
     Ok(paddr as usize)
 }
 
+/// Translate `addr` for a load. The access only counts towards `minstret` if it does not fault.
 fn read_vaddr<T>(ctx: &mut Context, addr: u64) -> Result<&'static T, ()> {
+    let paddr = translate_read(ctx, addr)?;
     ctx.minstret += 1;
-    Ok(unsafe { &*(translate_read(ctx, addr)? as *const T) })
+    Ok(unsafe { &*(paddr as *const T) })
 }
 
 fn translate_write(ctx: &mut Context, addr: u64) -> Result<usize, ()> {
@@ -736,9 +1154,63 @@ fn translate_write(ctx: &mut Context, addr: u64) -> Result<usize, ()> {
     Ok(paddr as usize)
 }
 
+/// Translate `addr` for a store. The access only counts towards `minstret` if it does not fault.
 fn ptr_vaddr_x<T>(ctx: &mut Context, addr: u64) -> Result<&'static mut T, ()> {
+    let paddr = translate_write(ctx, addr)?;
+    ctx.minstret += 1;
+    Ok(unsafe { &mut *(paddr as *mut T) })
+}
+
+/// Emulate the misaligned load or store `op` accessing `addr`, or raise the misaligned exception
+/// if emulation is disabled. Misaligned floating point accesses and atomics always raise the
+/// exception.
+///
+/// The access is done byte by byte, translating each cache line it covers separately, so an access
+/// crossing a page boundary sees the translations of both pages. Stores translate the last byte
+/// first, so a store faulting on the second page leaves memory unmodified. The access only counts
+/// towards `minstret` if it does not fault.
+fn emulate_misaligned(ctx: &mut Context, op: &Op, addr: u64) -> Result<(), ()> {
+    // The register loaded or stored, the size, and whether the access is a store.
+    let (reg, size, store) = match *op {
+        Op::Lh { rd, .. } | Op::Lhu { rd, .. } => (rd, 2, false),
+        Op::Lw { rd, .. } | Op::Lwu { rd, .. } => (rd, 4, false),
+        Op::Ld { rd, .. } => (rd, 8, false),
+        Op::Sh { rs2, .. } => (rs2, 2, true),
+        Op::Sw { rs2, .. } => (rs2, 4, true),
+        Op::Sd { rs2, .. } => (rs2, 8, true),
+        Op::Flw { .. } | Op::Fld { .. } | Op::LrW { .. } | Op::LrD { .. } => {
+            ctx.set_trap(Trap::LoadMisaligned(addr));
+            return Err(());
+        }
+        _ => {
+            ctx.set_trap(Trap::StoreMisaligned(addr));
+            return Err(());
+        }
+    };
+    if !ctx.emulate_misaligned {
+        ctx.set_trap(if store { Trap::StoreMisaligned(addr) } else { Trap::LoadMisaligned(addr) });
+        return Err(());
+    }
+
+    if store {
+        let bytes = ctx.registers[reg as usize].to_le_bytes();
+        translate_write(ctx, addr.wrapping_add(size as u64 - 1))?;
+        ctx.copy_to_virt_addr(addr, &bytes[..size])?;
+    } else {
+        let mut bytes = [0; 8];
+        ctx.copy_from_virt_addr(addr, &mut bytes[..size])?;
+        let value = u64::from_le_bytes(bytes);
+        let value = match *op {
+            Op::Lh { .. } => value as i16 as u64,
+            Op::Lw { .. } => value as i32 as u64,
+            _ => value,
+        };
+        if reg != 0 {
+            ctx.registers[reg as usize] = value;
+        }
+    }
     ctx.minstret += 1;
-    Ok(unsafe { &mut *(translate_write(ctx, addr)? as *mut T) })
+    Ok(())
 }
 
 /// DBT-ed instruction cache
@@ -768,13 +1240,45 @@ fn ptr_vaddr_x<T>(ctx: &mut Context, addr: u64) -> Result<&'static mut T, ()> {
 /// flushed or overwritten basic blocks.
 const HEAP_SIZE: usize = 1024 * 1024 * 32;
 
+/// Size of the inaccessible guard region at the end of each hart's code cache heap, so encoder
+/// bugs writing past the heap fault immediately instead of corrupting another hart's code.
+const HEAP_GUARD_SIZE: usize = 4096;
+
+/// Each translated block is followed by canary bytes, which are validated upon rollover to detect
+/// out-of-bounds emission. We use INT3 so that falling off the end of a block traps as well.
+const CANARY_SIZE: usize = 16;
+const CANARY_BYTE: u8 = 0xCC;
+
 struct ICache {
-    // The tuple stores (start, non-speculative start)
-    u_map: BTreeMap<u64, (usize, usize)>,
-    s_map: BTreeMap<u64, (usize, usize)>,
-    m_map: BTreeMap<u64, (usize, usize)>,
+    // The tuple stores (start, non-speculative start, last use)
+    u_map: BTreeMap<u64, (usize, usize, u64)>,
+    s_map: BTreeMap<u64, (usize, usize, u64)>,
+    m_map: BTreeMap<u64, (usize, usize, u64)>,
+    // Maximum number of blocks in the maps, or 0 if unlimited. Uses are only tracked when capped.
+    block_cap: usize,
+    // Maximum number of instructions in a translated block, or 0 if unlimited
+    max_block_len: usize,
+    // Privilege level and PC of blocks in the maps, keyed by their last use
+    lru: BTreeMap<u64, (u64, u64)>,
+    // Source of last use stamps
+    clock: u64,
     heap_start: usize,
     heap_offset: usize,
+    // Offset of all canaries since last rollover
+    canaries: Vec<usize>,
+    // Number of rollovers so far
+    flushes: u64,
+}
+
+/// Host memory used by translated code.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CodeCacheUsage {
+    /// Bytes of the code cache used since the last flush, including canaries.
+    pub bytes: usize,
+    /// Number of translated blocks reachable from the icache maps.
+    pub blocks: usize,
+    /// Number of times the code cache is flushed.
+    pub flushes: u64,
 }
 
 impl ICache {
@@ -783,8 +1287,79 @@ impl ICache {
             u_map: BTreeMap::default(),
             s_map: BTreeMap::default(),
             m_map: BTreeMap::default(),
+            block_cap: 0,
+            max_block_len: 0,
+            lru: BTreeMap::default(),
+            clock: 0,
             heap_start: ptr,
             heap_offset: 0,
+            canaries: Vec::new(),
+            flushes: 0,
+        }
+    }
+
+    fn usage(&self) -> CodeCacheUsage {
+        CodeCacheUsage {
+            bytes: self.heap_offset,
+            blocks: self.u_map.len() + self.s_map.len() + self.m_map.len(),
+            flushes: self.flushes,
+        }
+    }
+
+    /// Whether the cache should be flushed before translating a block, because the space left may
+    /// not be sufficient, or because `cap` bytes are already used. A `cap` of 0 means no cap.
+    fn needs_rollover(&mut self, cap: usize) -> bool {
+        self.space().len() < 256 * 1024 || (cap != 0 && self.heap_offset >= cap)
+    }
+
+    fn map(&mut self, prv: u64) -> &mut BTreeMap<u64, (usize, usize, u64)> {
+        match prv {
+            0 => &mut self.u_map,
+            1 => &mut self.s_map,
+            3 => &mut self.m_map,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Find the block translated for `pc`, and mark it as the most recently used.
+    fn lookup(&mut self, prv: u64, pc: u64) -> Option<(usize, usize)> {
+        if self.block_cap == 0 {
+            return self.map(prv).get(&pc).map(|blk| (blk.0, blk.1));
+        }
+        self.clock += 1;
+        let clock = self.clock;
+        let blk = self.map(prv).get_mut(&pc)?;
+        let last_use = std::mem::replace(&mut blk.2, clock);
+        let blk = (blk.0, blk.1);
+        self.lru.remove(&last_use);
+        self.lru.insert(clock, (prv, pc));
+        Some(blk)
+    }
+
+    /// Insert a newly translated block. If this exceeds the block cap, the least recently used
+    /// blocks are evicted.
+    fn insert(&mut self, prv: u64, pc: u64, start: usize, nonspec_start: usize) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(old) = self.map(prv).insert(pc, (start, nonspec_start, clock)) {
+            self.lru.remove(&old.2);
+        }
+        if self.block_cap == 0 {
+            return;
+        }
+        self.lru.insert(clock, (prv, pc));
+        while self.lru.len() > self.block_cap {
+            let (prv, pc) = *self.lru.values().next().unwrap();
+            self.evict(prv, pc);
+        }
+    }
+
+    /// Remove a block from the maps. As it may still be reached from blocks chained to it, or be
+    /// executing, its code is kept but its entry is patched to return to the dispatcher.
+    fn evict(&mut self, prv: u64, pc: u64) {
+        if let Some(blk) = self.map(prv).remove(&pc) {
+            self.lru.remove(&blk.2);
+            unsafe { *(blk.1 as *mut u8) = 0xC3 }
         }
     }
 
@@ -793,23 +1368,51 @@ impl ICache {
         unsafe {
             std::slice::from_raw_parts_mut(
                 (self.heap_offset + self.heap_start) as *mut u8,
-                HEAP_SIZE - self.heap_offset,
+                (HEAP_SIZE - HEAP_GUARD_SIZE - CANARY_SIZE).saturating_sub(self.heap_offset),
             )
         }
     }
 
+    /// Validate all canaries. If any is overwritten, the offset of the canary is returned.
+    fn check_canary(&self) -> Result<(), usize> {
+        for &offset in self.canaries.iter() {
+            let canary = unsafe {
+                std::slice::from_raw_parts((self.heap_start + offset) as *const u8, CANARY_SIZE)
+            };
+            if canary.iter().any(|&x| x != CANARY_BYTE) {
+                return Err(offset);
+            }
+        }
+        Ok(())
+    }
+
     fn rollover(&mut self) {
+        if let Err(offset) = self.check_canary() {
+            panic!("icache {:x} corrupted at offset {:x}", self.heap_start, offset);
+        }
         self.heap_offset = 0;
+        self.canaries.clear();
         self.u_map.clear();
         self.s_map.clear();
         self.m_map.clear();
+        self.lru.clear();
+        self.flushes += 1;
         debug!("icache {:x} rollover", self.heap_start);
     }
 
     // Commit space of some size
     fn commit(&mut self, size: usize) {
         self.heap_offset += size;
-        assert!(self.heap_offset <= HEAP_SIZE);
+        assert!(self.heap_offset + CANARY_SIZE <= HEAP_SIZE - HEAP_GUARD_SIZE);
+        unsafe {
+            std::ptr::write_bytes(
+                (self.heap_start + self.heap_offset) as *mut u8,
+                CANARY_BYTE,
+                CANARY_SIZE,
+            )
+        };
+        self.canaries.push(self.heap_offset);
+        self.heap_offset += CANARY_SIZE;
     }
 }
 
@@ -830,19 +1433,33 @@ static ICACHE: Lazy<Vec<Mutex<ICache>>> = Lazy::new(|| {
     let ptr = ptr as usize;
     let mut vec = Vec::with_capacity(core_count);
     for i in 0..core_count {
-        vec.push(Mutex::new(ICache::new(ptr + HEAP_SIZE * i)));
+        let heap = ptr + HEAP_SIZE * i;
+        let guard = heap + HEAP_SIZE - HEAP_GUARD_SIZE;
+        let ret = unsafe { libc::mprotect(guard as *mut _, HEAP_GUARD_SIZE, libc::PROT_NONE) };
+        assert_eq!(ret, 0);
+        let mut icache = ICache::new(heap);
+        icache.block_cap = crate::get_flags().block_cap;
+        icache.max_block_len = crate::get_flags().max_block_len;
+        vec.push(Mutex::new(icache));
     }
 
-    if crate::get_flags().perf {
+    if let Some(ref perf_map) = *PERF_MAP {
         use std::io::Write;
-        let mut perf_map =
-            std::fs::File::create(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
-        writeln!(perf_map, "{:x} {:x} (dbt code)", ptr, size).unwrap();
+        writeln!(perf_map.lock(), "{:x} {:x} (dbt code)", ptr, size).unwrap();
     }
 
     vec
 });
 
+/// Map of translated code for the perf tool, if enabled by `--perf`.
+static PERF_MAP: Lazy<Option<Mutex<std::fs::File>>> = Lazy::new(|| {
+    if !crate::get_flags().perf {
+        return None;
+    }
+    let path = format!("/tmp/perf-{}.map", std::process::id());
+    Some(Mutex::new(std::fs::File::create(path).unwrap()))
+});
+
 /// To prevent needing to flush the entire translation cache when SFENCE.VMA/FENCE.I is
 /// executed, we instead guarantee that the entries active in translation caches truthfully
 /// represent the contents in RAM. i.e. we guarantee that whenever there is a write, the
@@ -864,6 +1481,18 @@ static ICACHE: Lazy<Vec<Mutex<ICache>>> = Lazy::new(|| {
 /// the I-Cache.
 static CODE_PROT: Lazy<Mutex<BTreeSet<u64>>> = Lazy::new(|| Mutex::new(BTreeSet::default()));
 
+/// Get the host memory used by translated code of all harts.
+pub fn code_cache_usage() -> CodeCacheUsage {
+    ICACHE.iter().fold(CodeCacheUsage::default(), |sum, icache| {
+        let usage = icache.lock().usage();
+        CodeCacheUsage {
+            bytes: sum.bytes + usage.bytes,
+            blocks: sum.blocks + usage.blocks,
+            flushes: sum.flushes + usage.flushes,
+        }
+    })
+}
+
 fn icache(hartid: u64) -> MutexGuard<'static, ICache> {
     ICACHE[hartid as usize].lock()
 }
@@ -874,21 +1503,128 @@ pub fn icache_reset() {
         icache.u_map.clear();
         icache.s_map.clear();
         icache.m_map.clear();
+        icache.lru.clear();
         icache.heap_offset = 0;
     }
 }
 
-/// Broadcast sfence
+/// Extend a mask of hart indices to cover all harts in the clusters of the harts in `mask`.
+fn cluster_mask(mask: u64, cluster_size: usize) -> u64 {
+    if cluster_size >= 64 {
+        return if mask == 0 { 0 } else { u64::max_value() };
+    }
+    let cluster = (1u64 << cluster_size) - 1;
+    let mut result = 0;
+    for start in (0..64).step_by(cluster_size) {
+        if mask & (cluster << start) != 0 {
+            result |= cluster << start;
+        }
+    }
+    result
+}
+
+/// Broadcast sfence. Harts in the same cluster as a hart in `mask` are flushed as well, but only
+/// harts in other clusters than the initiating hart count as remote shootdowns.
 fn global_sfence(ctx: &mut Context, mask: u64, asid: Option<u16>, vpn: Option<u64>) {
+    let mask = cluster_mask(mask, ctx.cluster_size);
+    let local = cluster_mask(1 << ctx.hartid, ctx.cluster_size);
     get_memory_model().before_sfence_vma(ctx, mask, asid, vpn);
+    let mut remote = 0;
     for i in 0..crate::core_count() {
         if mask & (1 << i) == 0 {
             continue;
         }
+        if local & (1 << i) == 0 {
+            remote += 1;
+        }
         let ctx = crate::shared_context(i);
         ctx.clear_local_cache();
         ctx.clear_local_icache();
     }
+
+    // Charge the cost of the shootdown to the initiating hart.
+    let cycles = crate::sim::get_coherence_config().remote_sfence * remote;
+    if cycles != 0 {
+        if crate::threaded() {
+            ctx.cycle_offset += cycles as i64;
+        } else {
+            fiber::sleep(cycles);
+        }
+    }
+}
+
+/// Convert a SBI hart mask, which is indexed by architectural hartid, to a mask indexed by hart
+/// index.
+fn hart_mask(mask: u64) -> u64 {
+    let mut result = 0;
+    for i in 0..crate::core_count() {
+        let hartid = crate::hartid(i);
+        if hartid < 64 && mask & (1 << hartid) != 0 {
+            result |= 1 << i;
+        }
+    }
+    result
+}
+
+/// Raise the software interrupt of each hart in `mask` by setting its msip register, so SBI IPIs
+/// share their state with IPIs sent by writing to the CLINT directly.
+fn send_ipi(clint: &dyn IoMemory, mask: u64) {
+    for i in 0..crate::core_count() {
+        if mask & (1 << i) != 0 {
+            clint.write(i * 4, 1, 4);
+        }
+    }
+}
+
+/// Clear the software interrupt of a hart by clearing its msip register.
+fn clear_ipi(clint: &dyn IoMemory, hart: usize) {
+    clint.write(hart * 4, 0, 4);
+}
+
+/// Whether the Sstc extension is available, i.e. S-mode can program its timer through `stimecmp`.
+/// We only provide it when supervisor-level code runs directly on the emulator, as we do not model
+/// `menvcfg` for firmware to enable it.
+fn sstc_enabled() -> bool {
+    crate::get_flags().prv == 1
+}
+
+/// Handle an SBI call from the guest, with arguments and results in registers. Calls to the base
+/// extension return an error code and a value, while legacy calls only return a value.
+fn sbi_ecall(ctx: &mut Context) {
+    let eid = ctx.registers[17];
+    if eid == 0x10 {
+        let (error, value) = match sbi_base_call(ctx, ctx.registers[16], ctx.registers[10]) {
+            Ok(value) => (0, value),
+            Err(error) => (error as u64, 0),
+        };
+        ctx.registers[10] = error;
+        ctx.registers[11] = value;
+        return;
+    }
+    ctx.registers[10] = sbi_call(
+        ctx,
+        eid,
+        ctx.registers[10],
+        ctx.registers[11],
+        ctx.registers[12],
+        ctx.registers[13],
+    );
+}
+
+/// Handle a call to function `fid` of the SBI base extension. Returns the SBI error code on failure.
+fn sbi_base_call(ctx: &Context, fid: u64, arg0: u64) -> Result<u64, i64> {
+    match fid {
+        // Specification version 0.2, the first with the base extension.
+        0 => Ok(2),
+        1 => Ok(ctx.identity.impl_id),
+        2 => Ok(ctx.identity.impl_version),
+        // Probe extension. All legacy extensions are implemented.
+        3 => Ok((arg0 <= 8 || arg0 == 0x10) as u64),
+        4 => Ok(ctx.identity.mvendorid),
+        5 => Ok(ctx.identity.marchid),
+        6 => Ok(ctx.identity.mimpid),
+        _ => Err(-2),
+    }
 }
 
 fn sbi_call(ctx: &mut Context, nr: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
@@ -913,28 +1649,23 @@ fn sbi_call(ctx: &mut Context, nr: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u
             }
         }
         3 => {
-            ctx.shared.deassert(2);
+            clear_ipi(&*super::CLINT, ctx.hartid as usize);
             0
         }
         4 => {
-            let mask: u64 = crate::emu::read_memory(
+            let mask: u64 = hart_mask(crate::emu::read_memory(
                 ctx.translate_vaddr(arg0, AccessType::Read).unwrap() as usize,
-            );
-            for i in 0..crate::core_count() {
-                if mask & (1 << i) == 0 {
-                    continue;
-                }
-                crate::shared_context(i).assert(2);
-            }
+            ));
+            send_ipi(&*super::CLINT, mask);
             0
         }
         5 => {
             let mask: u64 = if arg0 == 0 {
                 u64::max_value()
             } else {
-                crate::emu::read_memory(
-                    ctx.translate_vaddr(arg0, AccessType::Read).unwrap() as usize
-                )
+                hart_mask(crate::emu::read_memory(
+                    ctx.translate_vaddr(arg0, AccessType::Read).unwrap() as usize,
+                ))
             };
             get_memory_model().before_fence_i(ctx, mask);
             for i in 0..crate::core_count() {
@@ -949,9 +1680,9 @@ fn sbi_call(ctx: &mut Context, nr: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u
             let mask: u64 = if arg0 == 0 {
                 u64::max_value()
             } else {
-                crate::emu::read_memory(
-                    ctx.translate_vaddr(arg0, AccessType::Read).unwrap() as usize
-                )
+                hart_mask(crate::emu::read_memory(
+                    ctx.translate_vaddr(arg0, AccessType::Read).unwrap() as usize,
+                ))
             };
             global_sfence(ctx, mask, None, if arg2 == 4096 { Some(arg1 & !4095) } else { None });
             0
@@ -960,9 +1691,9 @@ fn sbi_call(ctx: &mut Context, nr: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u
             let mask: u64 = if arg0 == 0 {
                 u64::max_value()
             } else {
-                crate::emu::read_memory(
-                    ctx.translate_vaddr(arg0, AccessType::Read).unwrap() as usize
-                )
+                hart_mask(crate::emu::read_memory(
+                    ctx.translate_vaddr(arg0, AccessType::Read).unwrap() as usize,
+                ))
             };
             global_sfence(
                 ctx,
@@ -1009,10 +1740,26 @@ pub fn init_fp() {
 //
 // #endregion
 
+/// Handle an integer division by zero, or a signed division overflow if `zero` is false,
+/// according to `mode`.
+fn check_div(ctx: &mut Context, mode: DivCheck, zero: bool) -> Result<(), ()> {
+    match mode {
+        DivCheck::Spec => (),
+        DivCheck::Log => {
+            warn!("{}", if zero { "integer division by zero" } else { "signed division overflow" })
+        }
+        DivCheck::Trap => {
+            ctx.set_trap(Trap::IllegalInstruction(0));
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
 /// Perform a single step of instruction.
 /// This function assumes a post-incremented PC.
 /// This function does not check privilege level, so it must be checked ahead of time.
-fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
+pub(super) fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
     macro_rules! read_reg {
         ($rs: expr) => {{
             let rs = $rs as usize;
@@ -1113,15 +1860,14 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
         };
     }
     macro_rules! trap {
-        ($cause: expr, $tval: expr) => {{
-            ctx.cause = $cause;
-            ctx.tval = $tval;
+        ($trap: expr) => {{
+            ctx.set_trap($trap);
             return Err(());
         }};
     }
 
     match *op {
-        Op::Illegal => trap!(2, 0),
+        Op::Illegal => trap!(Trap::IllegalInstruction(0)),
         /* LOAD */
         Op::Lb { rd, rs1, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
@@ -1130,21 +1876,21 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
         Op::Lh { rd, rs1, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 1 != 0 {
-                trap!(4, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
             write_reg!(rd, *read_vaddr::<u16>(ctx, vaddr)? as i16 as u64);
         }
         Op::Lw { rd, rs1, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 3 != 0 {
-                trap!(4, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
             write_reg!(rd, *read_vaddr::<u32>(ctx, vaddr)? as i32 as u64);
         }
         Op::Ld { rd, rs1, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 7 != 0 {
-                trap!(4, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
             write_reg!(rd, *read_vaddr::<u64>(ctx, vaddr)?);
         }
@@ -1155,20 +1901,21 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
         Op::Lhu { rd, rs1, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 1 != 0 {
-                trap!(4, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
             write_reg!(rd, *read_vaddr::<u16>(ctx, vaddr)? as u64);
         }
         Op::Lwu { rd, rs1, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 3 != 0 {
-                trap!(4, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
             write_reg!(rd, *read_vaddr::<u32>(ctx, vaddr)? as u64);
         }
         /* OP-IMM */
         Op::Addi { rd, rs1, imm } => write_reg!(rd, read_reg!(rs1).wrapping_add(imm as u64)),
         Op::Slli { rd, rs1, imm } => write_reg!(rd, read_reg!(rs1) << imm),
+        Op::Slti { rd: 0, rs1: 0, imm } => perf_marker(ctx, imm as i64, 0),
         Op::Slti { rd, rs1, imm } => {
             write_reg!(rd, ((read_reg!(rs1) as i64) < (imm as i64)) as u64)
         }
@@ -1202,26 +1949,23 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
         Op::Sh { rs1, rs2, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 1 != 0 {
-                trap!(5, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
-            let paddr = ptr_vaddr_x(ctx, vaddr)?;
-            *paddr = read_reg!(rs2) as u16;
+            *ptr_vaddr_x(ctx, vaddr)? = read_reg!(rs2) as u16;
         }
         Op::Sw { rs1, rs2, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 3 != 0 {
-                trap!(5, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
-            let paddr = ptr_vaddr_x(ctx, vaddr)?;
-            *paddr = read_reg!(rs2) as u32;
+            *ptr_vaddr_x(ctx, vaddr)? = read_reg!(rs2) as u32;
         }
         Op::Sd { rs1, rs2, imm } => {
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 7 != 0 {
-                trap!(5, vaddr)
+                return emulate_misaligned(ctx, op, vaddr);
             }
-            let paddr = ptr_vaddr_x(ctx, vaddr)?;
-            *paddr = read_reg!(rs2) as u64;
+            *ptr_vaddr_x(ctx, vaddr)? = read_reg!(rs2) as u64;
         }
         /* OP */
         Op::Add { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1).wrapping_add(read_reg!(rs2))),
@@ -1316,38 +2060,24 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
                         )
                     };
                 } else {
-                    trap!(8, 0)
+                    trap!(Trap::EnvCallFromU)
                 }
             }
             1 => {
                 if crate::get_flags().prv == 1 {
-                    ctx.registers[10] = sbi_call(
-                        ctx,
-                        ctx.registers[17],
-                        ctx.registers[10],
-                        ctx.registers[11],
-                        ctx.registers[12],
-                        ctx.registers[13],
-                    )
+                    sbi_ecall(ctx)
                 } else {
-                    trap!(9, 0)
+                    trap!(Trap::EnvCallFromS)
                 }
             }
             3 => {
                 // Note: We provide SBI interface to our M-mode, so the firmware can simple a few things down.
                 // This is non-standard.
-                ctx.registers[10] = sbi_call(
-                    ctx,
-                    ctx.registers[17],
-                    ctx.registers[10],
-                    ctx.registers[11],
-                    ctx.registers[12],
-                    ctx.registers[13],
-                );
+                sbi_ecall(ctx);
             }
             _ => unreachable!(),
         },
-        Op::Ebreak => trap!(3, 0),
+        Op::Ebreak => trap!(Trap::Breakpoint(0)),
         Op::Csrrw { rd, rs1, csr } => {
             let result = if rd != 0 { read_csr(ctx, csr)? } else { 0 };
             write_csr(ctx, csr, read_reg!(rs1))?;
@@ -1392,7 +2122,7 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
             ctx.test_and_set_fs()?;
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 3 != 0 {
-                trap!(4, vaddr)
+                trap!(Trap::LoadMisaligned(vaddr))
             }
             write_fs!(frd, F32::new(*read_vaddr::<u32>(ctx, vaddr)?));
         }
@@ -1400,7 +2130,7 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
             ctx.test_and_set_fs()?;
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 3 != 0 {
-                trap!(5, vaddr)
+                trap!(Trap::StoreMisaligned(vaddr))
             }
             let paddr = ptr_vaddr_x(ctx, vaddr)?;
             *paddr = read_fs!(frs2).0;
@@ -1573,8 +2303,8 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
         Op::Fld { frd, rs1, imm } => {
             ctx.test_and_set_fs()?;
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
-            if vaddr & 3 != 0 {
-                trap!(4, vaddr)
+            if vaddr & 7 != 0 {
+                trap!(Trap::LoadMisaligned(vaddr))
             }
             write_fd!(frd, F64::new(*read_vaddr::<u64>(ctx, vaddr)?));
         }
@@ -1582,7 +2312,7 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
             ctx.test_and_set_fs()?;
             let vaddr = read_reg!(rs1).wrapping_add(imm as u64);
             if vaddr & 7 != 0 {
-                trap!(5, vaddr)
+                trap!(Trap::StoreMisaligned(vaddr))
             }
             let paddr = ptr_vaddr_x(ctx, vaddr)?;
             *paddr = read_fd!(frs2).0;
@@ -1795,24 +2525,36 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
         Op::Div { rd, rs1, rs2 } => {
             let a = read_reg!(rs1) as i64;
             let b = read_reg!(rs2) as i64;
+            if b == 0 || a == i64::MIN && b == -1 {
+                check_div(ctx, crate::get_flags().div_check, b == 0)?;
+            }
             let r = if b == 0 { -1 } else { a.wrapping_div(b) };
             write_reg!(rd, r as u64);
         }
         Op::Divu { rd, rs1, rs2 } => {
             let a = read_reg!(rs1);
             let b = read_reg!(rs2);
+            if b == 0 {
+                check_div(ctx, crate::get_flags().div_check, true)?;
+            }
             let r = if b == 0 { (-1i64) as u64 } else { a / b };
             write_reg!(rd, r);
         }
         Op::Rem { rd, rs1, rs2 } => {
             let a = read_reg!(rs1) as i64;
             let b = read_reg!(rs2) as i64;
+            if b == 0 || a == i64::MIN && b == -1 {
+                check_div(ctx, crate::get_flags().div_check, b == 0)?;
+            }
             let r = if b == 0 { a } else { a.wrapping_rem(b) };
             write_reg!(rd, r as u64);
         }
         Op::Remu { rd, rs1, rs2 } => {
             let a = read_reg!(rs1);
             let b = read_reg!(rs2);
+            if b == 0 {
+                check_div(ctx, crate::get_flags().div_check, true)?;
+            }
             let r = if b == 0 { a } else { a % b };
             write_reg!(rd, r);
         }
@@ -1822,55 +2564,67 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
         Op::Divw { rd, rs1, rs2 } => {
             let a = read_reg!(rs1) as i32;
             let b = read_reg!(rs2) as i32;
+            if b == 0 || a == i32::MIN && b == -1 {
+                check_div(ctx, crate::get_flags().div_check, b == 0)?;
+            }
             let r = if b == 0 { -1 } else { a.wrapping_div(b) };
             write_reg!(rd, r as u64);
         }
         Op::Divuw { rd, rs1, rs2 } => {
             let a = read_reg!(rs1) as u32;
             let b = read_reg!(rs2) as u32;
+            if b == 0 {
+                check_div(ctx, crate::get_flags().div_check, true)?;
+            }
             let r = if b == 0 { (-1i32) as u32 } else { a / b };
             write_reg!(rd, r as i32 as u64);
         }
         Op::Remw { rd, rs1, rs2 } => {
             let a = read_reg!(rs1) as i32;
             let b = read_reg!(rs2) as i32;
+            if b == 0 || a == i32::MIN && b == -1 {
+                check_div(ctx, crate::get_flags().div_check, b == 0)?;
+            }
             let r = if b == 0 { a } else { a.wrapping_rem(b) };
             write_reg!(rd, r as u64);
         }
         Op::Remuw { rd, rs1, rs2 } => {
             let a = read_reg!(rs1) as u32;
             let b = read_reg!(rs2) as u32;
+            if b == 0 {
+                check_div(ctx, crate::get_flags().div_check, true)?;
+            }
             let r = if b == 0 { a } else { a % b };
             write_reg!(rd, r as i32 as u64);
         }
 
         /* A-extension */
-        Op::LrW { rd, rs1, .. } => {
+        Op::LrW { rd, rs1, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::LoadMisaligned(addr))
             }
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let value = ptr.load(MemOrder::SeqCst) as i32 as u64;
+            let value = ptr.load(aqrl.load_ordering()) as i32 as u64;
             write_reg!(rd, value);
             ctx.lr_addr = addr;
             ctx.lr_value = value;
         }
-        Op::LrD { rd, rs1, .. } => {
+        Op::LrD { rd, rs1, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::LoadMisaligned(addr))
             }
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let value = ptr.load(MemOrder::SeqCst);
+            let value = ptr.load(aqrl.load_ordering());
             write_reg!(rd, value);
             ctx.lr_addr = addr;
             ctx.lr_value = value;
         }
-        Op::ScW { rd, rs1, rs2, .. } => {
+        Op::ScW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let result = if addr != ctx.lr_addr {
@@ -1880,8 +2634,8 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
                 match ptr.compare_exchange(
                     ctx.lr_value as u32,
                     src,
-                    MemOrder::SeqCst,
-                    MemOrder::SeqCst,
+                    aqrl.into(),
+                    aqrl.load_ordering(),
                 ) {
                     Ok(_) => 0,
                     Err(_) => 1,
@@ -1889,204 +2643,293 @@ fn step(ctx: &mut Context, op: &Op, compressed: bool) -> Result<(), ()> {
             };
             write_reg!(rd, result);
         }
-        Op::ScD { rd, rs1, rs2, .. } => {
+        Op::ScD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let result = if addr != ctx.lr_addr {
                 1
             } else {
                 let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-                match ptr.compare_exchange(ctx.lr_value, src, MemOrder::SeqCst, MemOrder::SeqCst) {
+                match ptr.compare_exchange(ctx.lr_value, src, aqrl.into(), aqrl.load_ordering()) {
                     Ok(_) => 0,
                     Err(_) => 1,
                 }
             };
             write_reg!(rd, result)
         }
-        Op::AmoswapW { rd, rs1, rs2, .. } => {
+        Op::AmoswapW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let current = ptr.swap(src, MemOrder::SeqCst);
+            let current = ptr.swap(src, aqrl.into());
             write_32!(rd, current);
         }
-        Op::AmoswapD { rd, rs1, rs2, .. } => {
+        Op::AmoswapD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let current = ptr.swap(src, MemOrder::SeqCst);
+            let current = ptr.swap(src, aqrl.into());
             write_reg!(rd, current);
         }
-        Op::AmoaddW { rd, rs1, rs2, .. } => {
+        Op::AmoaddW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let current = ptr.fetch_add(src, MemOrder::SeqCst);
+            let current = ptr.fetch_add(src, aqrl.into());
             write_32!(rd, current);
         }
-        Op::AmoaddD { rd, rs1, rs2, .. } => {
+        Op::AmoaddD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let current = ptr.fetch_add(src, MemOrder::SeqCst);
+            let current = ptr.fetch_add(src, aqrl.into());
             write_reg!(rd, current);
         }
-        Op::AmoandW { rd, rs1, rs2, .. } => {
+        Op::AmoandW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let current = ptr.fetch_and(src, MemOrder::SeqCst);
+            let current = ptr.fetch_and(src, aqrl.into());
             write_32!(rd, current);
         }
-        Op::AmoandD { rd, rs1, rs2, .. } => {
+        Op::AmoandD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let current = ptr.fetch_and(src, MemOrder::SeqCst);
+            let current = ptr.fetch_and(src, aqrl.into());
             write_reg!(rd, current);
         }
-        Op::AmoorW { rd, rs1, rs2, .. } => {
+        Op::AmoorW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let current = ptr.fetch_or(src, MemOrder::SeqCst);
+            let current = ptr.fetch_or(src, aqrl.into());
             write_32!(rd, current);
         }
-        Op::AmoorD { rd, rs1, rs2, .. } => {
+        Op::AmoorD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let current = ptr.fetch_or(src, MemOrder::SeqCst);
+            let current = ptr.fetch_or(src, aqrl.into());
             write_reg!(rd, current);
         }
-        Op::AmoxorW { rd, rs1, rs2, .. } => {
+        Op::AmoxorW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let current = ptr.fetch_xor(src, MemOrder::SeqCst);
+            let current = ptr.fetch_xor(src, aqrl.into());
             write_32!(rd, current);
         }
-        Op::AmoxorD { rd, rs1, rs2, .. } => {
+        Op::AmoxorD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let current = ptr.fetch_xor(src, MemOrder::SeqCst);
+            let current = ptr.fetch_xor(src, aqrl.into());
             write_reg!(rd, current);
         }
-        Op::AmominW { rd, rs1, rs2, .. } => {
+        Op::AmominW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicI32>(ctx, addr)?;
-            let current = ptr.fetch_min_stable(src as i32, MemOrder::SeqCst);
+            let current = ptr.fetch_min_stable(src as i32, aqrl.into());
             write_32!(rd, current as u32);
         }
-        Op::AmominD { rd, rs1, rs2, .. } => {
+        Op::AmominD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicI64>(ctx, addr)?;
-            let current = ptr.fetch_min_stable(src as i64, MemOrder::SeqCst);
+            let current = ptr.fetch_min_stable(src as i64, aqrl.into());
             write_reg!(rd, current as u64);
         }
-        Op::AmomaxW { rd, rs1, rs2, .. } => {
+        Op::AmomaxW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicI32>(ctx, addr)?;
-            let current = ptr.fetch_max_stable(src as i32, MemOrder::SeqCst);
+            let current = ptr.fetch_max_stable(src as i32, aqrl.into());
             write_32!(rd, current as u32);
         }
-        Op::AmomaxD { rd, rs1, rs2, .. } => {
+        Op::AmomaxD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicI64>(ctx, addr)?;
-            let current = ptr.fetch_max_stable(src as i64, MemOrder::SeqCst);
+            let current = ptr.fetch_max_stable(src as i64, aqrl.into());
             write_reg!(rd, current as u64);
         }
-        Op::AmominuW { rd, rs1, rs2, .. } => {
+        Op::AmominuW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let current = ptr.fetch_min_stable(src, MemOrder::SeqCst);
+            let current = ptr.fetch_min_stable(src, aqrl.into());
             write_32!(rd, current);
         }
-        Op::AmominuD { rd, rs1, rs2, .. } => {
+        Op::AmominuD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let current = ptr.fetch_min_stable(src, MemOrder::SeqCst);
+            let current = ptr.fetch_min_stable(src, aqrl.into());
             write_reg!(rd, current);
         }
-        Op::AmomaxuW { rd, rs1, rs2, .. } => {
+        Op::AmomaxuW { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 3 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2) as u32;
             let ptr = ptr_vaddr_x::<AtomicU32>(ctx, addr)?;
-            let current = ptr.fetch_max_stable(src, MemOrder::SeqCst);
+            let current = ptr.fetch_max_stable(src, aqrl.into());
             write_32!(rd, current);
         }
-        Op::AmomaxuD { rd, rs1, rs2, .. } => {
+        Op::AmomaxuD { rd, rs1, rs2, aqrl } => {
             let addr = read_reg!(rs1);
             if addr & 7 != 0 {
-                trap!(5, addr)
+                trap!(Trap::StoreMisaligned(addr))
             }
             let src = read_reg!(rs2);
             let ptr = ptr_vaddr_x::<AtomicU64>(ctx, addr)?;
-            let current = ptr.fetch_max_stable(src, MemOrder::SeqCst);
+            let current = ptr.fetch_max_stable(src, aqrl.into());
             write_reg!(rd, current);
         }
 
+        /* Zba extension */
+        Op::SlliUw { rd, rs1, imm } => write_reg!(rd, (read_reg!(rs1) as u32 as u64) << imm),
+        Op::Sh1add { rd, rs1, rs2 } => {
+            write_reg!(rd, (read_reg!(rs1) << 1).wrapping_add(read_reg!(rs2)))
+        }
+        Op::Sh2add { rd, rs1, rs2 } => {
+            write_reg!(rd, (read_reg!(rs1) << 2).wrapping_add(read_reg!(rs2)))
+        }
+        Op::Sh3add { rd, rs1, rs2 } => {
+            write_reg!(rd, (read_reg!(rs1) << 3).wrapping_add(read_reg!(rs2)))
+        }
+        Op::AddUw { rd, rs1, rs2 } => {
+            write_reg!(rd, (read_reg!(rs1) as u32 as u64).wrapping_add(read_reg!(rs2)))
+        }
+        Op::Sh1addUw { rd, rs1, rs2 } => {
+            write_reg!(rd, ((read_reg!(rs1) as u32 as u64) << 1).wrapping_add(read_reg!(rs2)))
+        }
+        Op::Sh2addUw { rd, rs1, rs2 } => {
+            write_reg!(rd, ((read_reg!(rs1) as u32 as u64) << 2).wrapping_add(read_reg!(rs2)))
+        }
+        Op::Sh3addUw { rd, rs1, rs2 } => {
+            write_reg!(rd, ((read_reg!(rs1) as u32 as u64) << 3).wrapping_add(read_reg!(rs2)))
+        }
+
+        /* Zbb extension */
+        Op::Clz { rd, rs1 } => write_reg!(rd, read_reg!(rs1).leading_zeros() as u64),
+        Op::Ctz { rd, rs1 } => write_reg!(rd, read_reg!(rs1).trailing_zeros() as u64),
+        Op::Cpop { rd, rs1 } => write_reg!(rd, read_reg!(rs1).count_ones() as u64),
+        Op::SextB { rd, rs1 } => write_reg!(rd, read_reg!(rs1) as i8 as u64),
+        Op::SextH { rd, rs1 } => write_reg!(rd, read_reg!(rs1) as i16 as u64),
+        Op::Rori { rd, rs1, imm } => write_reg!(rd, read_reg!(rs1).rotate_right(imm as u32)),
+        Op::OrcB { rd, rs1 } => {
+            let value = read_reg!(rs1);
+            let mut result = 0;
+            for i in 0..8 {
+                if (value >> (i * 8)) & 0xFF != 0 {
+                    result |= 0xFF << (i * 8);
+                }
+            }
+            write_reg!(rd, result)
+        }
+        Op::Rev8 { rd, rs1 } => write_reg!(rd, read_reg!(rs1).swap_bytes()),
+        Op::Clzw { rd, rs1 } => write_reg!(rd, (read_reg!(rs1) as u32).leading_zeros() as u64),
+        Op::Ctzw { rd, rs1 } => write_reg!(rd, (read_reg!(rs1) as u32).trailing_zeros() as u64),
+        Op::Cpopw { rd, rs1 } => write_reg!(rd, (read_reg!(rs1) as u32).count_ones() as u64),
+        Op::Roriw { rd, rs1, imm } => {
+            write_reg!(rd, (read_reg!(rs1) as u32).rotate_right(imm as u32) as i32 as u64)
+        }
+        Op::Andn { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1) & !read_reg!(rs2)),
+        Op::Orn { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1) | !read_reg!(rs2)),
+        Op::Xnor { rd, rs1, rs2 } => write_reg!(rd, !(read_reg!(rs1) ^ read_reg!(rs2))),
+        Op::Min { rd, rs1, rs2 } => {
+            write_reg!(rd, (read_reg!(rs1) as i64).min(read_reg!(rs2) as i64) as u64)
+        }
+        Op::Minu { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1).min(read_reg!(rs2))),
+        Op::Max { rd, rs1, rs2 } => {
+            write_reg!(rd, (read_reg!(rs1) as i64).max(read_reg!(rs2) as i64) as u64)
+        }
+        Op::Maxu { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1).max(read_reg!(rs2))),
+        Op::Rol { rd, rs1, rs2 } => {
+            write_reg!(rd, read_reg!(rs1).rotate_left((read_reg!(rs2) & 63) as u32))
+        }
+        Op::Ror { rd, rs1, rs2 } => {
+            write_reg!(rd, read_reg!(rs1).rotate_right((read_reg!(rs2) & 63) as u32))
+        }
+        Op::ZextH { rd, rs1 } => write_reg!(rd, read_reg!(rs1) as u16 as u64),
+        Op::Rolw { rd, rs1, rs2 } => {
+            let shamt = (read_reg!(rs2) & 31) as u32;
+            write_reg!(rd, (read_reg!(rs1) as u32).rotate_left(shamt) as i32 as u64)
+        }
+        Op::Rorw { rd, rs1, rs2 } => {
+            let shamt = (read_reg!(rs2) & 31) as u32;
+            write_reg!(rd, (read_reg!(rs1) as u32).rotate_right(shamt) as i32 as u64)
+        }
+
+        /* Zbs extension */
+        Op::Bclri { rd, rs1, imm } => write_reg!(rd, read_reg!(rs1) & !(1 << imm)),
+        Op::Bexti { rd, rs1, imm } => write_reg!(rd, (read_reg!(rs1) >> imm) & 1),
+        Op::Binvi { rd, rs1, imm } => write_reg!(rd, read_reg!(rs1) ^ (1 << imm)),
+        Op::Bseti { rd, rs1, imm } => write_reg!(rd, read_reg!(rs1) | (1 << imm)),
+        Op::Bclr { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1) & !(1 << (read_reg!(rs2) & 63))),
+        Op::Bext { rd, rs1, rs2 } => write_reg!(rd, (read_reg!(rs1) >> (read_reg!(rs2) & 63)) & 1),
+        Op::Binv { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1) ^ (1 << (read_reg!(rs2) & 63))),
+        Op::Bset { rd, rs1, rs2 } => write_reg!(rd, read_reg!(rs1) | (1 << (read_reg!(rs2) & 63))),
+
+        /* Zihintntl extension */
+        // Locality hints do not change any architectural state.
+        Op::NtlP1 | Op::NtlPall | Op::NtlS1 | Op::NtlAll => (),
+
         /* Privileged */
         Op::Mret => {
             ctx.pc = ctx.mepc;
@@ -2165,12 +3008,173 @@ pub fn riscv_step(ctx: &mut Context, op: u64) -> Result<(), ()> {
     step(ctx, &op, false)
 }
 
-fn translate_code(
-    ctx: &mut Context,
-    icache: &mut ICache,
-    prv: u64,
+/// Invoke the instruction hook and the tracer for an op at `ctx.pc`, encoded as `bits`.
+fn before_instruction(ctx: &mut Context, op: &Op, bits: u32) {
+    if let Some(hook) = ctx.instruction_hook {
+        hook(ctx, op);
+    }
+    if let Some(ref mut tracer) = ctx.tracer {
+        tracer.before_instruction(&ctx.registers, ctx.pc, bits);
+    }
+}
+
+/// Invoke the instruction hook and the tracer for an op `pc_offset` bytes from `ctx.pc`, encoded
+/// as `bits`. Called from DBT-ed code.
+#[no_mangle]
+pub fn instruction_hook(ctx: &mut Context, op: u64, pc_offset: i64, bits: u32) {
+    let op: Op = unsafe { std::mem::transmute(op) };
+    let pc = ctx.pc;
+    ctx.pc = pc.wrapping_add(pc_offset as u64);
+    before_instruction(ctx, &op, bits);
+    ctx.pc = pc;
+}
+
+/// A performance marker emitted by the guest to delimit phases of execution.
+///
+/// Markers are encoded as `slti x0, x0, id`, a HINT that executes as a nop on other
+/// implementations, so guests can emit them unconditionally.
+#[derive(Clone, Copy, Debug)]
+pub struct Marker {
+    pub id: i32,
+    pub hartid: u64,
+    pub cycle: u64,
+    pub instret: u64,
+}
+
+static MARKERS: Lazy<Mutex<Vec<Marker>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+fn push_marker(marker: Marker) {
+    MARKERS.lock().push(marker);
+}
+
+/// Get all performance markers recorded so far, in the order they are emitted.
+pub fn markers() -> Vec<Marker> {
+    MARKERS.lock().clone()
+}
+
+/// Record a performance marker for `ctx`, which has retired `instret` instructions not yet
+/// reflected in `ctx.instret`. Called from DBT-ed code.
+#[no_mangle]
+pub fn perf_marker(ctx: &mut Context, id: i64, instret: i64) {
+    push_marker(Marker {
+        id: id as i32,
+        hartid: ctx.mhartid,
+        cycle: ctx.get_mcycle(),
+        instret: ctx.instret.wrapping_add(instret as u64),
+    });
+}
+
+/// How often the polled memory is checked while a hart sleeps in a spin loop.
+const SPIN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_micros(100);
+
+/// Destination, base register, offset and size of a load that can start a spin loop.
+fn spin_load(op: &Op) -> Option<(u8, u8, i32, usize)> {
+    let (rd, rs1, imm, size) = match *op {
+        Op::Lb { rd, rs1, imm } | Op::Lbu { rd, rs1, imm } => (rd, rs1, imm, 1),
+        Op::Lh { rd, rs1, imm } | Op::Lhu { rd, rs1, imm } => (rd, rs1, imm, 2),
+        Op::Lw { rd, rs1, imm } | Op::Lwu { rd, rs1, imm } => (rd, rs1, imm, 4),
+        Op::Ld { rd, rs1, imm } => (rd, rs1, imm, 8),
+        _ => return None,
+    };
+    if rd == 0 || rd == rs1 {
+        return None;
+    }
+    Some((rd, rs1, imm, size))
+}
+
+/// Whether `load` of `load_len` bytes followed by `branch` is a spin loop, i.e. a load from a
+/// fixed address and a conditional branch on the loaded value back to the load. As the loop has
+/// no other effect, once it loops with a value it keeps doing so until the memory changes.
+fn is_spin_loop(load: &Op, load_len: i32, branch: &Op) -> bool {
+    let rd = match spin_load(load) {
+        Some((rd, ..)) => rd,
+        None => return false,
+    };
+    match *branch {
+        Op::Beq { rs1, rs2, imm }
+        | Op::Bne { rs1, rs2, imm }
+        | Op::Blt { rs1, rs2, imm }
+        | Op::Bge { rs1, rs2, imm }
+        | Op::Bltu { rs1, rs2, imm }
+        | Op::Bgeu { rs1, rs2, imm } => imm == -load_len && (rs1 == rd) != (rs2 == rd),
+        _ => false,
+    }
+}
+
+/// Sleep until `changed` returns true or an alarm, e.g. for an interrupt, is fired.
+fn spin_wait(alarm: &AtomicU64, mut changed: impl FnMut() -> bool) {
+    while alarm.load(MemOrder::Relaxed) == 0 && !changed() {
+        std::thread::sleep(SPIN_POLL_INTERVAL);
+    }
+}
+
+thread_local! {
+    /// PC, instret, physical address and value loaded at the last entry of a spin loop on the
+    /// hart running on this thread.
+    static LAST_SPIN: std::cell::Cell<(u64, u64, u64, u64)> = std::cell::Cell::new((0, 0, 0, 0));
+}
+
+/// Called from DBT-ed code on entry of a spin loop starting with `load`. If the previous entry was
+/// the previous iteration of the same loop and loaded the same value, the loop will keep spinning,
+/// so sleep until the memory changes instead.
+#[no_mangle]
+pub fn spin_hint(ctx: &mut Context, load: u64) {
+    let load: Op = unsafe { std::mem::transmute(load) };
+    let (_, rs1, imm, size) = spin_load(&load).unwrap();
+    let vaddr = ctx.registers[rs1 as usize].wrapping_add(imm as u64);
+    if vaddr & (size as u64 - 1) != 0 {
+        return;
+    }
+
+    // Faults and I/O memory are left to the load itself.
+    let (cause, tval) = (ctx.cause, ctx.tval);
+    let paddr = ctx.translate_vaddr(vaddr, AccessType::Read);
+    ctx.cause = cause;
+    ctx.tval = tval;
+    let paddr = match paddr {
+        Ok(paddr) => paddr,
+        Err(_) => return,
+    };
+    if super::is_io(paddr) {
+        return;
+    }
+    let ptr = paddr as usize as *const u8;
+    let read = || unsafe {
+        match size {
+            1 => std::ptr::read_volatile(ptr) as u64,
+            2 => std::ptr::read_volatile(ptr as *const u16) as u64,
+            4 => std::ptr::read_volatile(ptr as *const u32) as u64,
+            _ => std::ptr::read_volatile(ptr as *const u64),
+        }
+    };
+
+    let value = read();
+    let (last_pc, last_instret, last_paddr, last_value) = LAST_SPIN.with(|last| last.get());
+    if last_pc == ctx.pc
+        && last_instret.wrapping_add(2) == ctx.instret
+        && last_paddr == paddr
+        && last_value == value
+    {
+        spin_wait(&ctx.shared.alarm, || read() != value);
+    }
+    LAST_SPIN.with(|last| last.set((ctx.pc, ctx.instret, paddr, value)));
+}
+
+/// Whether a block ending at `phys_pc_end` and containing `len` instructions must not be extended
+/// further. Blocks never cross page boundaries, and are split after `max_len` instructions unless
+/// `max_len` is 0.
+fn block_full(phys_pc_end: u64, len: usize, max_len: usize) -> bool {
+    phys_pc_end & 4095 == 0 || (max_len != 0 && len >= max_len)
+}
+
+/// Translate the block at `phys_pc`. Returns the entry points of the translated code, as
+/// `find_block` does, and the physical address at which the block ends.
+fn translate_code(
+    ctx: &mut Context,
+    icache: &mut ICache,
+    prv: u64,
     phys_pc: u64,
-) -> (usize, usize) {
+) -> (usize, usize, u64) {
     let mut phys_pc_end = phys_pc;
 
     if crate::get_flags().disassemble {
@@ -2179,58 +3183,117 @@ fn translate_code(
 
     // Reserve some space for the DBT compiler.
     // This uses a very relax upper bound, enough for an entire page.
-    let mut code = icache.space();
-    let rollover = code.len() < 256 * 1024;
-    // Rollover if the space is not sufficient for next allocation.
+    // Rollover if the space is not sufficient for next allocation or the soft cap is reached.
+    let rollover = icache.needs_rollover(crate::get_flags().code_cache_cap);
     if rollover {
         icache.rollover();
-        code = icache.space();
+    }
+    let max_len = icache.max_block_len;
+    let code = icache.space();
+
+    // Offset of the breakpoint and the address waited for from the start of this block. They, and
+    // breakpoints set by GDB, must always start a new block so that they are caught by `find_block`.
+    let breakpoint = BREAKPOINT.map(|addr| addr.wrapping_sub(ctx.pc));
+    let watch = Some(super::wait::WATCH_PC.load(MemOrder::Relaxed))
+        .filter(|&addr| addr != u64::MAX)
+        .map(|addr| addr.wrapping_sub(ctx.pc));
+    let block_pc = ctx.pc;
+    let is_breakpoint = |pc: u64| {
+        let offset = Some(pc - phys_pc);
+        offset == breakpoint
+            || offset == watch
+            || super::gdbstub::is_breakpoint(block_pc.wrapping_add(pc - phys_pc))
+    };
+
+    // The last instruction decoded, for recording the control-flow graph.
+    let pc = ctx.pc;
+    let mut last_op = None;
+
+    let mut len = 0;
+
+    /// Decode a instruction at given location. If it will cross a page boundary, then Err is
+    /// returned.
+    fn read_insn(pc: usize) -> Result<(Op, bool, u32), u16> {
+        let bits = crate::emu::read_memory::<u16>(pc);
+        if bits & 3 == 3 {
+            // The instruction will cross page boundary.
+            if pc & 4095 == 4094 {
+                return Err(bits);
+            }
+            let hi_bits = crate::emu::read_memory::<u16>(pc + 2);
+            let bits = (hi_bits as u32) << 16 | bits as u32;
+            let op = riscv::decode(bits);
+            if crate::get_flags().verify_decode {
+                if let Err(expected) = riscv::verify_decode(bits, &op) {
+                    error!(
+                        "{:08x} decoded as {}, but {} is expected",
+                        bits,
+                        riscv::format_instr(pc as u64, bits, &op),
+                        expected
+                    );
+                }
+            }
+            Ok((op, false, bits))
+        } else {
+            let op = riscv::decode_compressed(bits);
+            Ok((op, true, bits as u32))
+        }
     }
 
+    let hook_enabled = ctx.hook_enabled();
+    let spin_detect = ctx.spin_detect;
     let mut compiler = super::dbt::DbtCompiler::new(ctx, code);
     compiler.begin(phys_pc);
 
-    loop {
-        /// Decode a instruction at given location. If it will cross a page boundary, then Err is
-        /// returned.
-        fn read_insn(pc: usize) -> Result<(Op, bool, u32), u16> {
-            let bits = crate::emu::read_memory::<u16>(pc);
-            if bits & 3 == 3 {
-                // The instruction will cross page boundary.
-                if pc & 4095 == 4094 {
-                    return Err(bits);
+    if crate::threaded() && spin_detect {
+        if let Ok((load, c, _)) = read_insn(phys_pc as usize) {
+            let load_len = if c { 2 } else { 4 };
+            if (phys_pc + load_len) & 4095 != 0 {
+                if let Ok((branch, _, _)) = read_insn((phys_pc + load_len) as usize) {
+                    if is_spin_loop(&load, load_len as i32, &branch) {
+                        compiler.emit_spin_hint(&load);
+                    }
                 }
-                let hi_bits = crate::emu::read_memory::<u16>(pc + 2);
-                let bits = (hi_bits as u32) << 16 | bits as u32;
-                let op = riscv::decode(bits);
-                Ok((op, false, bits))
-            } else {
-                let op = riscv::decode_compressed(bits);
-                Ok((op, true, bits as u32))
             }
         }
+    }
+
+    loop {
+        if phys_pc_end != phys_pc && is_breakpoint(phys_pc_end) {
+            compiler.end();
+            break;
+        }
 
         let (mut op, c, bits) = match read_insn(phys_pc_end as usize) {
             Ok(v) => v,
             Err(bits) => {
                 compiler.end_cross(bits);
+                last_op = None;
                 break;
             }
         };
         if crate::get_flags().disassemble {
-            eprintln!("{}", op.pretty_print(phys_pc_end, bits));
+            eprintln!("{}", riscv::format_instr(phys_pc_end, bits, &op));
         }
+        let op_pc = phys_pc_end;
         phys_pc_end += if c { 2 } else { 4 };
+        len += 1;
 
         // We must not emit code for protected ops
         if (prv as u8) < op.min_prv_level() {
             op = Op::Illegal
         }
+        last_op = Some((op_pc, op));
 
         // The way we generate code is a bit slow for branches. The mini-optimisation here
         // captures conditional execution patterns.
-        // Note that this shouldn't be done if the fused macro-op can cross page boundary.
-        if phys_pc_end & 4095 != 0 && compiler.model.as_ref().unwrap().can_fuse_cond_op() {
+        // Note that this shouldn't be done if the fused macro-op can cross page boundary or
+        // exceed the block length limit.
+        if !block_full(phys_pc_end, len, max_len)
+            && !is_breakpoint(phys_pc_end)
+            && !hook_enabled
+            && compiler.model.as_ref().unwrap().can_fuse_cond_op()
+        {
             match op {
                 Op::Beq { imm, .. }
                 | Op::Bne { imm, .. }
@@ -2241,11 +3304,13 @@ fn translate_code(
                     if imm == if c { 4 } else { 6 } {
                         if let Ok((next_op, true, bits)) = read_insn(phys_pc_end as usize) {
                             if crate::get_flags().disassemble {
-                                eprintln!("{}", next_op.pretty_print(phys_pc_end, bits));
+                                eprintln!("{}", riscv::format_instr(phys_pc_end, bits, &next_op));
                             }
+                            last_op = Some((phys_pc_end, next_op));
                             phys_pc_end += 2;
+                            len += 1;
                             compiler.compile_cond_op(&op, c, &next_op, true);
-                            if phys_pc_end & 4095 == 0 {
+                            if block_full(phys_pc_end, len, max_len) {
                                 compiler.end();
                                 break;
                             }
@@ -2255,11 +3320,13 @@ fn translate_code(
                     if imm == if c { 6 } else { 8 } {
                         if let Ok((next_op, false, bits)) = read_insn(phys_pc_end as usize) {
                             if crate::get_flags().disassemble {
-                                eprintln!("{}", next_op.pretty_print(phys_pc_end, bits));
+                                eprintln!("{}", riscv::format_instr(phys_pc_end, bits, &next_op));
                             }
+                            last_op = Some((phys_pc_end, next_op));
                             phys_pc_end += 4;
+                            len += 1;
                             compiler.compile_cond_op(&op, c, &next_op, false);
-                            if phys_pc_end & 4095 == 0 {
+                            if block_full(phys_pc_end, len, max_len) {
                                 compiler.end();
                                 break;
                             }
@@ -2291,8 +3358,10 @@ fn translate_code(
             }
         }
 
-        // Need to stop when crossing page boundary
-        if phys_pc_end & 4095 == 0 {
+        // Need to stop when crossing page boundary or when the block is long enough. The split is
+        // just like a page boundary: the block ends with PC and instret committed, and traps
+        // within each block rewind relative to its own start.
+        if block_full(phys_pc_end, len, max_len) {
             compiler.end();
             break;
         }
@@ -2301,25 +3370,35 @@ fn translate_code(
     // We must not cross page boundary in absolutely any cases.
     assert_eq!(phys_pc & !4095, (phys_pc_end - 1) & !4095, "op crosses page boundary");
 
+    if crate::get_flags().dump_cfg.is_some() {
+        let to_virt = |addr: u64| pc + (addr - phys_pc);
+        super::control_flow::CFG.lock().add_block(
+            pc,
+            to_virt(phys_pc_end),
+            last_op.as_ref().map(|(addr, op)| (to_virt(*addr), op)),
+        );
+    }
+
     let func_len = compiler.len;
     assert!(func_len <= 256 * 1024);
     let spec_len = compiler.speculative_len;
     let code_fn = code.as_ptr() as usize;
     let nonspec_fn = code_fn + spec_len;
-    let map = match prv {
-        0 => &mut icache.u_map,
-        1 => &mut icache.s_map,
-        3 => &mut icache.m_map,
-        _ => unreachable!(),
-    };
-    map.insert(phys_pc, (code_fn, nonspec_fn));
+    icache.insert(prv, phys_pc, code_fn, nonspec_fn);
+
+    // Name each block after the guest code it is translated from.
+    if let Some(ref perf_map) = *PERF_MAP {
+        use std::io::Write;
+        let name = super::loader::SYMBOLS.describe(pc);
+        writeln!(perf_map.lock(), "{:x} {:x} {}", code_fn, func_len, name).unwrap();
+    }
 
     // Actually commit the space we allocated
     icache.commit(func_len);
 
     // We use 0 to indicate that a rollover has happened during the translation, and therefore
     // no code should be patched, but the execution should resume from nonspec_fn instead.
-    (if rollover { 0 } else { code_fn }, nonspec_fn)
+    (if rollover { 0 } else { code_fn }, nonspec_fn, phys_pc_end)
 }
 
 extern "C" {
@@ -2328,7 +3407,21 @@ extern "C" {
 
 #[no_mangle]
 extern "C" fn find_block(ctx: &mut Context) -> (usize, usize) {
+    ctx.shared.instret.store(ctx.instret, MemOrder::Relaxed);
+    if super::gdbstub::ACTIVE.load(MemOrder::Relaxed) && super::gdbstub::should_stop(ctx.pc) {
+        super::gdbstub::stop(ctx);
+    }
     let pc = ctx.pc;
+    if Some(pc) == *BREAKPOINT {
+        eprintln!("hart {} reached breakpoint {}", ctx.hartid, super::loader::SYMBOLS.describe(pc));
+        dump_state(ctx);
+        crate::shutdown(crate::ExitReason::Exit(0));
+        let exit = helper_check_interrupt as unsafe extern "C" fn() as usize;
+        return (exit, exit);
+    }
+    if pc == super::wait::WATCH_PC.load(MemOrder::Relaxed) {
+        super::wait::reached_pc(pc);
+    }
     let phys_pc = match insn_translate(ctx, pc) {
         Ok(pc) => pc,
         Err(_) => {
@@ -2336,15 +3429,11 @@ extern "C" fn find_block(ctx: &mut Context) -> (usize, usize) {
             return (helper_check_interrupt as usize, helper_check_interrupt as usize);
         }
     };
+    // Jumps to GDB breakpoints must not be patched, so the breakpoints are hit every time.
+    let breakpoint = super::gdbstub::is_breakpoint(pc);
     let mut prot = CODE_PROT.lock();
     let mut icache = icache(ctx.hartid);
-    let map = match ctx.prv {
-        0 => &mut icache.u_map,
-        1 => &mut icache.s_map,
-        3 => &mut icache.m_map,
-        _ => unreachable!(),
-    };
-    match map.get(&phys_pc).copied() {
+    let (code_fn, nonspec_fn) = match icache.lookup(ctx.prv, phys_pc) {
         Some(v) => v,
         None => {
             if prot.insert(phys_pc >> 12) {
@@ -2354,9 +3443,11 @@ extern "C" fn find_block(ctx: &mut Context) -> (usize, usize) {
                 }
             }
             std::mem::drop(prot);
-            translate_code(ctx, &mut icache, ctx.prv, phys_pc)
+            let (code_fn, nonspec_fn, _) = translate_code(ctx, &mut icache, ctx.prv, phys_pc);
+            (code_fn, nonspec_fn)
         }
-    }
+    };
+    (if breakpoint { 0 } else { code_fn }, nonspec_fn)
 }
 
 #[no_mangle]
@@ -2364,6 +3455,7 @@ extern "C" fn find_block(ctx: &mut Context) -> (usize, usize) {
 /// If `{Err}` is returned, the running fiber will exit.
 pub fn check_interrupt(ctx: &mut Context) -> Result<(), ()> {
     let alarm = ctx.shared.alarm.swap(0, MemOrder::Acquire);
+    ctx.shared.instret.store(ctx.instret, MemOrder::Relaxed);
 
     if alarm & 2 != 0 {
         return Err(());
@@ -2376,6 +3468,22 @@ pub fn check_interrupt(ctx: &mut Context) -> Result<(), ()> {
         }
     }
 
+    if let Some(addr) = ctx.watch_hit.take() {
+        if super::gdbstub::ACTIVE.load(MemOrder::Relaxed) {
+            super::gdbstub::stop(ctx);
+        } else {
+            let addr = super::loader::SYMBOLS.describe(addr);
+            eprintln!("hart {} hit watchpoint at {}", ctx.hartid, addr);
+            dump_state(ctx);
+            crate::shutdown(crate::ExitReason::Exit(0));
+            return Err(());
+        }
+    }
+
+    if sstc_enabled() && crate::event_loop().time() >= ctx.stimecmp {
+        ctx.shared.mip.fetch_or(32, MemOrder::Relaxed);
+    }
+
     // Find out which interrupts can be taken
     let interrupt_mask = ctx.interrupt_pending();
     // No interrupt pending
@@ -2385,8 +3493,7 @@ pub fn check_interrupt(ctx: &mut Context) -> Result<(), ()> {
     // Find the highest priority interrupt
     let pending = 63 - interrupt_mask.leading_zeros() as u64;
     // Interrupts have the highest bit set
-    ctx.cause = (1 << 63) | pending;
-    ctx.tval = 0;
+    ctx.set_trap(Trap::new((1 << 63) | pending, 0));
     trap(ctx);
     Ok(())
 }
@@ -2394,25 +3501,164 @@ pub fn check_interrupt(ctx: &mut Context) -> Result<(), ()> {
 /// Trigger a trap. pc must be already adjusted properly before calling.
 #[no_mangle]
 pub fn trap(ctx: &mut Context) {
-    if crate::get_flags().prv == 0 {
-        eprintln!("unhandled trap {:x}, tval = {:x}", ctx.cause, ctx.tval);
-        eprintln!("pc  = {:16x}  ra  = {:16x}", ctx.pc, ctx.registers[1]);
-        for i in (2..32).step_by(2) {
-            eprintln!(
-                "{:-3} = {:16x}  {:-3} = {:16x}",
-                riscv::register_name(i as u8),
-                ctx.registers[i],
-                riscv::register_name((i + 1) as u8),
-                ctx.registers[i + 1]
-            );
+    // An exception means the instruction at `ctx.pc` did not retire, so it must not be traced.
+    // Interrupts are taken between instructions and leave the trace alone.
+    if ctx.cause >> 63 == 0 {
+        if let Some(ref mut tracer) = ctx.tracer {
+            tracer.discard(ctx.pc);
+        }
+    }
+
+    if let Some(hook) = ctx.trap_hook {
+        if hook(ctx) == TrapAction::Resume {
+            return;
         }
+    }
+
+    if crate::get_flags().prv == 0 {
+        let pc = super::loader::SYMBOLS.describe(ctx.pc);
+        eprintln!("unhandled trap {:x?} at {}", ctx.last_trap(), pc);
+        dump_registers(ctx);
         std::process::exit(1);
     }
 
+    // Traps can only be taken into M-mode if it is emulated.
+    assert!(crate::get_flags().prv == 3 || trap_to_s(ctx));
+    enter_trap(ctx);
+    fiber::sleep(1)
+}
+
+/// Fetch, decode and execute the instruction at `ctx.pc` with the interpreter.
+fn step_at_pc(ctx: &mut Context) -> Result<(), ()> {
+    let pc = ctx.pc;
+    let bits = unsafe { *(insn_translate(ctx, pc)? as *const u16) };
+    let (mut op, compressed, bits) = if bits & 3 == 3 {
+        let hi_bits = unsafe { *(insn_translate(ctx, pc + 2)? as *const u16) };
+        let bits = (hi_bits as u32) << 16 | bits as u32;
+        (riscv::decode(bits), false, bits)
+    } else {
+        (riscv::decode_compressed(bits), true, bits as u32)
+    };
+    if (ctx.prv as u8) < op.min_prv_level() {
+        op = Op::Illegal
+    }
+    if ctx.hook_enabled() {
+        before_instruction(ctx, &op, bits);
+    }
+    ctx.pc = pc + if compressed { 2 } else { 4 };
+    if let Err(()) = step(ctx, &op, compressed) {
+        ctx.pc = pc;
+        return Err(());
+    }
+    ctx.instret += 1;
+    Ok(())
+}
+
+/// Execute a single instruction with the interpreter, taking the trap if it raises one. This must
+/// be called on the hart's own fiber.
+pub fn single_step(ctx: &mut Context) {
+    if step_at_pc(ctx).is_err() {
+        trap(ctx);
+    }
+}
+
+/// Decode the instruction at a guest virtual address as the hart would fetch it, without executing
+/// it. Returns the op, whether it is compressed, and its disassembly, or `None` if the address
+/// cannot be fetched from. The hart state is not modified.
+pub fn disassemble_at(ctx: &mut Context, vaddr: u64) -> Option<(Op, bool, String)> {
+    let (cause, tval) = (ctx.cause, ctx.tval);
+    let mut fetch = |addr: u64| -> Option<u16> {
+        let paddr = ctx.translate_vaddr(addr, AccessType::Execute).ok()?;
+        Some(crate::emu::read_memory(paddr as usize))
+    };
+    let result = fetch(vaddr).and_then(|bits| {
+        if bits & 3 == 3 {
+            let hi_bits = fetch(vaddr.wrapping_add(2))?;
+            let bits = (hi_bits as u32) << 16 | bits as u32;
+            let op = riscv::decode(bits);
+            Some((op, false, riscv::format_instr(vaddr, bits, &op)))
+        } else {
+            let op = riscv::decode_compressed(bits);
+            Some((op, true, riscv::format_instr(vaddr, bits as u32, &op)))
+        }
+    });
+    ctx.cause = cause;
+    ctx.tval = tval;
+    result
+}
+
+/// Print pc and all general purpose registers to stderr.
+fn dump_registers(ctx: &Context) {
+    let _ = write_registers(&mut std::io::stderr(), ctx);
+}
+
+/// Write pc and all general purpose registers to `out`.
+pub fn write_registers(out: &mut dyn std::io::Write, ctx: &Context) -> std::io::Result<()> {
+    writeln!(out, "pc  = {:16x}  ra  = {:16x}", ctx.pc, ctx.registers[1])?;
+    for i in (2..32).step_by(2) {
+        writeln!(
+            out,
+            "{:-3} = {:16x}  {:-3} = {:16x}",
+            riscv::register_name(i as u8),
+            ctx.registers[i],
+            riscv::register_name((i + 1) as u8),
+            ctx.registers[i + 1]
+        )?;
+    }
+    Ok(())
+}
+
+/// Print pc, privilege level, all general purpose registers and privileged CSRs to stderr.
+fn dump_state(ctx: &mut Context) {
+    const S_CSRS: &[Csr] = &[
+        Csr::Sstatus,
+        Csr::Sie,
+        Csr::Stvec,
+        Csr::Sscratch,
+        Csr::Sepc,
+        Csr::Scause,
+        Csr::Stval,
+        Csr::Sip,
+        Csr::Satp,
+    ];
+    const M_CSRS: &[Csr] = &[
+        Csr::Mstatus,
+        Csr::Medeleg,
+        Csr::Mideleg,
+        Csr::Mie,
+        Csr::Mtvec,
+        Csr::Mscratch,
+        Csr::Mepc,
+        Csr::Mcause,
+        Csr::Mtval,
+        Csr::Mip,
+    ];
+
+    eprintln!("prv = {}  instret = {}", ctx.prv, ctx.instret);
+    if let Some((_, _, disasm)) = disassemble_at(ctx, ctx.pc) {
+        eprintln!("{}", disasm);
+    }
+    dump_registers(ctx);
+    let m_csrs = if crate::get_flags().prv == 3 { M_CSRS } else { &[] };
+    for &csr in S_CSRS.iter().chain(m_csrs) {
+        if let Ok(value) = read_csr(ctx, csr) {
+            eprintln!("{:-8} = {:16x}", csr, value);
+        }
+    }
+}
+
+/// Whether the trap described by `cause` is delegated to S-mode rather than taken in M-mode.
+fn trap_to_s(ctx: &Context) -> bool {
     let deleg_reg = if ctx.cause >> 63 != 0 { ctx.mideleg } else { ctx.medeleg };
-    let deleg_to_s = ctx.prv != 3 && (deleg_reg >> (ctx.cause & 15)) & 1 != 0;
+    ctx.prv != 3 && (deleg_reg >> (ctx.cause & 15)) & 1 != 0
+}
 
-    if deleg_to_s {
+/// Update privilege level and trap CSRs to enter the trap handler.
+///
+/// Only trap-related CSRs are touched; in particular `sscratch` and `mscratch` are preserved, as
+/// trap handlers rely on them to find their stacks.
+fn enter_trap(ctx: &mut Context) {
+    if trap_to_s(ctx) {
         ctx.scause = ctx.cause;
         ctx.stval = ctx.tval;
         ctx.sepc = ctx.pc;
@@ -2441,8 +3687,6 @@ pub fn trap(ctx: &mut Context) {
         ctx.prv = 1;
         ctx.pc = ctx.stvec;
     } else {
-        assert_eq!(crate::get_flags().prv, 3);
-
         ctx.mcause = ctx.cause;
         ctx.mtval = ctx.tval;
         ctx.mepc = ctx.pc;
@@ -2468,8 +3712,6 @@ pub fn trap(ctx: &mut Context) {
         ctx.prv = 3;
         ctx.pc = ctx.mtvec;
     }
-
-    fiber::sleep(1)
 }
 
 /// Handle a misaligned load/store.
@@ -2486,57 +3728,1386 @@ pub fn handle_misalign(ctx: &mut Context, addr: u64) -> Result<(), ()> {
             (riscv::decode_compressed(bits), true)
         }
     };
-    match op {
-        Op::Lh { rd, .. } => {
-            let mut bytes = [0; 2];
-            ctx.copy_from_virt_addr(addr, &mut bytes)?;
-            ctx.registers[rd as usize] = i16::from_le_bytes(bytes) as u64;
-        }
-        Op::Lw { rd, .. } => {
-            let mut bytes = [0; 4];
-            ctx.copy_from_virt_addr(addr, &mut bytes)?;
-            ctx.registers[rd as usize] = i32::from_le_bytes(bytes) as u64;
-        }
-        Op::Ld { rd, .. } => {
-            let mut bytes = [0; 8];
-            ctx.copy_from_virt_addr(addr, &mut bytes)?;
-            ctx.registers[rd as usize] = u64::from_le_bytes(bytes);
-        }
-        Op::Lhu { rd, .. } => {
-            let mut bytes = [0; 2];
-            ctx.copy_from_virt_addr(addr, &mut bytes)?;
-            ctx.registers[rd as usize] = u16::from_le_bytes(bytes) as u64;
-        }
-        Op::Lwu { rd, .. } => {
-            let mut bytes = [0; 4];
-            ctx.copy_from_virt_addr(addr, &mut bytes)?;
-            ctx.registers[rd as usize] = u32::from_le_bytes(bytes) as u64;
-        }
-        Op::Sh { rs2, .. } => {
-            let bytes = (ctx.registers[rs2 as usize] as u16).to_le_bytes();
-            ctx.copy_to_virt_addr(addr, &bytes)?;
-        }
-        Op::Sw { rs2, .. } => {
-            let bytes = (ctx.registers[rs2 as usize] as u32).to_le_bytes();
-            ctx.copy_to_virt_addr(addr, &bytes)?;
-        }
-        Op::Sd { rs2, .. } => {
-            let bytes = (ctx.registers[rs2 as usize] as u64).to_le_bytes();
-            ctx.copy_to_virt_addr(addr, &bytes)?;
-        }
-        _ => {
-            // Otherwise it's misaligned atomic
-            ctx.cause = 6;
-            ctx.tval = addr;
-            return Err(());
-        }
-    }
+    emulate_misaligned(ctx, &op, addr)?;
 
     // Advance PC past misaligned instruction.
     ctx.pc += if compressed { 2 } else { 4 };
     ctx.instret += 1;
-    ctx.minstret += 1;
     fiber::sleep(1);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{program_page, run_translated, stop};
+
+    extern "C" {
+        fn fiber_interp_run();
+    }
+
+    #[test]
+    fn test_code_cache_cap() {
+        let mut heap = vec![0u8; HEAP_SIZE];
+        let mut icache = ICache::new(heap.as_mut_ptr() as usize);
+
+        icache.commit(3000);
+        icache.s_map.insert(0x80000000, (0, 0, 0));
+        assert_eq!(icache.usage(), CodeCacheUsage { bytes: 3016, blocks: 1, flushes: 0 });
+        assert!(!icache.needs_rollover(4096));
+
+        // Exceeding the soft cap flushes the cache.
+        icache.commit(2000);
+        assert!(icache.needs_rollover(4096));
+        assert!(!icache.needs_rollover(0));
+        icache.rollover();
+        assert_eq!(icache.usage(), CodeCacheUsage { bytes: 0, blocks: 0, flushes: 1 });
+        assert!(!icache.needs_rollover(4096));
+    }
+
+    #[test]
+    fn test_block_cap() {
+        let mut heap = vec![0u8; HEAP_SIZE];
+        let mut icache = ICache::new(heap.as_mut_ptr() as usize);
+        icache.block_cap = 2;
+        let code = icache.heap_start;
+        let blocks = [(0x80000000, code), (0x80000040, code + 16), (0x80000080, code + 32)];
+
+        icache.insert(1, blocks[0].0, blocks[0].1, blocks[0].1);
+        icache.insert(1, blocks[1].0, blocks[1].1, blocks[1].1);
+        icache.commit(48);
+        assert_eq!(icache.lookup(1, blocks[0].0), Some((blocks[0].1, blocks[0].1)));
+
+        // The second block is the least recently used, so it is evicted. Its code is kept, but
+        // its entry returns to the dispatcher.
+        icache.insert(1, blocks[2].0, blocks[2].1, blocks[2].1);
+        assert_eq!(icache.usage(), CodeCacheUsage { bytes: 64, blocks: 2, flushes: 0 });
+        assert_eq!(icache.lookup(1, blocks[1].0), None);
+        assert_eq!(heap[16], 0xC3);
+
+        // The blocks still in use remain valid.
+        for &(pc, code) in &[blocks[0], blocks[2]] {
+            assert_eq!(icache.lookup(1, pc), Some((code, code)));
+        }
+        assert_eq!((heap[0], heap[32]), (0, 0));
+
+        // Invalidated blocks no longer count towards the cap.
+        icache.evict(1, blocks[0].0);
+        icache.insert(3, blocks[0].0, blocks[0].1, blocks[0].1);
+        assert_eq!(icache.lookup(1, blocks[2].0), Some((blocks[2].1, blocks[2].1)));
+        assert_eq!(icache.lookup(3, blocks[0].0), Some((blocks[0].1, blocks[0].1)));
+    }
+
+    #[test]
+    fn test_hpmcounter_access() {
+        let mut ctx = Context::new(0);
+        ctx.mcounteren = 1 << 3;
+        ctx.scounteren = 1 << 3;
+
+        // Allowed in all modes when enabled by both mcounteren and scounteren
+        for &prv in &[0, 1, 3] {
+            ctx.prv = prv;
+            assert_eq!(read_csr(&mut ctx, Csr(0xC03)), Ok(0));
+        }
+
+        // hpmcounter4 is not enabled by mcounteren, so it's only accessible from M-mode
+        ctx.prv = 1;
+        ctx.cause = 0;
+        assert_eq!(read_csr(&mut ctx, Csr(0xC04)), Err(()));
+        assert_eq!(ctx.cause, 2);
+        ctx.prv = 3;
+        assert_eq!(read_csr(&mut ctx, Csr(0xC04)), Ok(0));
+
+        // Disabled by scounteren, so U-mode access should trap
+        ctx.prv = 0;
+        ctx.scounteren = 0;
+        ctx.cause = 0;
+        assert_eq!(read_csr(&mut ctx, Csr(0xC03)), Err(()));
+        assert_eq!(ctx.cause, 2);
+
+        // Machine-level counters and event selectors are hardwired to zero
+        ctx.prv = 3;
+        assert_eq!(write_csr(&mut ctx, Csr(0xB03), 42), Ok(()));
+        assert_eq!(write_csr(&mut ctx, Csr(0x323), 42), Ok(()));
+        assert_eq!(read_csr(&mut ctx, Csr(0xB03)), Ok(0));
+        assert_eq!(read_csr(&mut ctx, Csr(0x323)), Ok(0));
+    }
+
+    #[test]
+    fn test_counteren_matrix() {
+        let mut ctx = Context::new(0);
+        ctx.instret = 1;
+        for &prv in &[0, 1, 3] {
+            for &mcounteren in &[0, 0xFFFFFFFF, 0b101, 1 << 31] {
+                for &scounteren in &[0, 0xFFFFFFFF, 0b011, 1 << 31] {
+                    ctx.prv = prv;
+                    ctx.mcounteren = mcounteren;
+                    ctx.scounteren = scounteren;
+                    for id in 0..32 {
+                        let expected = match prv {
+                            3 => true,
+                            1 => mcounteren & (1 << id) != 0,
+                            _ => mcounteren & scounteren & (1 << id) != 0,
+                        };
+                        assert_eq!(ctx.counter_enabled(id), expected);
+
+                        // cycle and time need the event loop, so only read the others.
+                        if id >= 2 {
+                            ctx.cause = 0;
+                            let result = read_csr(&mut ctx, Csr(0xC00 + id as u16));
+                            assert_eq!(result.is_ok(), expected);
+                            assert_eq!(ctx.cause, if expected { 0 } else { 2 });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_div_check() {
+        let mut ctx = Context::new(0);
+        let div = Op::Div { rd: 10, rs1: 11, rs2: 12 };
+        let remw = Op::Remw { rd: 10, rs1: 11, rs2: 12 };
+        let divu = Op::Divu { rd: 10, rs1: 11, rs2: 12 };
+
+        // (op, dividend, divisor, result defined by the specification)
+        let cases = [
+            (div, 42, 0, u64::MAX),
+            (div, i64::MIN as u64, u64::MAX, i64::MIN as u64),
+            (remw, 42, 0, 42),
+            (remw, i32::MIN as u64, u64::MAX, 0),
+            (divu, 42, 0, u64::MAX),
+        ];
+
+        // Tests run with the default mode, which follows the specification.
+        assert_eq!(crate::get_flags().div_check, DivCheck::Spec);
+        for &(op, a, b, expected) in cases.iter() {
+            ctx.registers[11] = a;
+            ctx.registers[12] = b;
+            assert_eq!(step(&mut ctx, &op, false), Ok(()));
+            assert_eq!(ctx.registers[10], expected);
+        }
+
+        // Only the trap mode fails the instruction, with an illegal instruction exception.
+        for &mode in &[DivCheck::Spec, DivCheck::Log, DivCheck::Trap] {
+            let trap = mode == DivCheck::Trap;
+            for &zero in &[true, false] {
+                ctx.cause = 0;
+                assert_eq!(check_div(&mut ctx, mode, zero), if trap { Err(()) } else { Ok(()) });
+                assert_eq!(ctx.cause, if trap { 2 } else { 0 });
+            }
+        }
+
+        // Ordinary divisions are never checked.
+        ctx.registers[11] = 42;
+        ctx.registers[12] = 5;
+        assert_eq!(step(&mut ctx, &div, false), Ok(()));
+        assert_eq!(ctx.registers[10], 8);
+    }
+
+    #[test]
+    fn test_ntl_hints() {
+        let mut ctx = Context::new(0);
+        for (i, reg) in ctx.registers.iter_mut().enumerate().skip(1) {
+            *reg = i as u64 * 0x0101010101010101;
+        }
+        let registers = ctx.registers;
+        for &op in &[Op::NtlP1, Op::NtlPall, Op::NtlS1, Op::NtlAll] {
+            assert_eq!(step(&mut ctx, &op, true), Ok(()));
+            assert_eq!(ctx.registers, registers);
+        }
+    }
+
+    #[test]
+    fn test_bitmanip() {
+        let mut ctx = Context::new(0);
+        let clz = Op::Clz { rd: 10, rs1: 11 };
+        let clzw = Op::Clzw { rd: 10, rs1: 11 };
+        let ctz = Op::Ctz { rd: 10, rs1: 11 };
+        let ctzw = Op::Ctzw { rd: 10, rs1: 11 };
+        let cpopw = Op::Cpopw { rd: 10, rs1: 11 };
+        let rev8 = Op::Rev8 { rd: 10, rs1: 11 };
+        let orc_b = Op::OrcB { rd: 10, rs1: 11 };
+        let rolw = Op::Rolw { rd: 10, rs1: 11, rs2: 12 };
+        let sh2add_uw = Op::Sh2addUw { rd: 10, rs1: 11, rs2: 12 };
+        let min = Op::Min { rd: 10, rs1: 11, rs2: 12 };
+        let bext = Op::Bext { rd: 10, rs1: 11, rs2: 12 };
+
+        // (op, rs1, rs2, expected)
+        let cases = [
+            (clz, 0, 0, 64),
+            (clz, 1, 0, 63),
+            (clz, u64::MAX, 0, 0),
+            (clzw, 0, 0, 32),
+            (clzw, 0xFFFF_FFFF_0000_0001, 0, 31),
+            (ctz, 0, 0, 64),
+            (ctz, 1 << 63, 0, 63),
+            (ctzw, 0, 0, 32),
+            (ctzw, 0x1_0000_0000, 0, 32),
+            (cpopw, u64::MAX, 0, 32),
+            (rev8, 0, 0, 0),
+            (rev8, 0x0102_0304_0506_0708, 0, 0x0807_0605_0403_0201),
+            (orc_b, 0, 0, 0),
+            (orc_b, 0x0100_0000_8000_0010, 0, 0xFF00_0000_FF00_00FF),
+            (rolw, 0x8000_0001, 1, 3),
+            (rolw, 0x4000_0000, 33, 0xFFFF_FFFF_8000_0000),
+            (sh2add_uw, 0xFFFF_FFFF_0000_0001, 1, 5),
+            (min, u64::MAX, 1, u64::MAX),
+            (bext, 1 << 63, 127, 1),
+        ];
+        for &(op, a, b, expected) in cases.iter() {
+            ctx.registers[11] = a;
+            ctx.registers[12] = b;
+            assert_eq!(step(&mut ctx, &op, false), Ok(()));
+            assert_eq!(ctx.registers[10], expected, "{} {:#x}, {:#x}", op, a, b);
+        }
+    }
+
+    #[test]
+    fn test_icache_canary() {
+        let mut heap = vec![0u8; HEAP_SIZE];
+        let mut icache = ICache::new(heap.as_mut_ptr() as usize);
+        for _ in 0..2 {
+            icache.space()[..64].copy_from_slice(&[0x90; 64]);
+            icache.commit(64);
+        }
+        assert_eq!(icache.check_canary(), Ok(()));
+
+        // Emulate an out-of-bound write past the first block
+        heap[64 + 1] = 0x90;
+        assert_eq!(icache.check_canary(), Err(64));
+    }
+
+    #[test]
+    fn test_icache_guard_page() {
+        // Writing to the guard page kills the process, so do it in a process of its own.
+        use std::os::unix::process::ExitStatusExt;
+        let path = concat!(module_path!(), "::test_icache_guard_page");
+        if let Some(status) = crate::testing::isolate(path) {
+            assert_eq!(status.signal(), Some(libc::SIGSEGV));
+            return;
+        }
+
+        crate::testing::init_harts();
+        let heap = icache(0).heap_start;
+        // The end of the heap usable for code is writable, but the guard page right after is not.
+        let space = icache(0).space().len();
+        unsafe {
+            std::ptr::write_volatile((heap + space - 1) as *mut u8, 0xCC);
+            std::ptr::write_volatile((heap + HEAP_SIZE - HEAP_GUARD_SIZE) as *mut u8, 0xCC);
+        }
+        unreachable!("write to the guard page was not caught");
+    }
+
+    #[test]
+    fn test_misa() {
+        let mut ctx = Context::new(0);
+        assert_eq!(ctx.read_csr_by_name("misa"), Ok(0x800000000014112d));
+        // Writes are ignored, as the extensions cannot be changed.
+        assert_eq!(ctx.write_csr_by_name("misa", 0), Ok(()));
+        assert_eq!(ctx.read_csr_by_name("misa"), Ok(0x800000000014112d));
+    }
+
+    #[test]
+    fn test_instreth() {
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.mcounteren = 0b111;
+        // instret is assumed to be incremented already when read
+        ctx.instret = 0x1234_5678_9abc_def0 + 1;
+        assert_eq!(read_csr(&mut ctx, Csr::Instret), Ok(0x1234_5678_9abc_def0));
+        assert_eq!(read_csr(&mut ctx, Csr::Instreth), Ok(0x1234_5678));
+
+        ctx.mcounteren = 0b011;
+        assert_eq!(read_csr(&mut ctx, Csr::Instreth), Err(()));
+    }
+
+    #[test]
+    fn test_permissive_csr() {
+        let mut ctx = Context::new(0);
+        ctx.prv = 3;
+
+        // Strict mode
+        assert_eq!(read_csr(&mut ctx, Csr(0x7C0)), Err(()));
+        assert_eq!(ctx.cause, 2);
+        assert_eq!(write_csr(&mut ctx, Csr(0x7C0), 1), Err(()));
+
+        // Permissive mode
+        ctx.permissive_csr = vec![0x7C0];
+        ctx.cause = 0;
+        assert_eq!(write_csr(&mut ctx, Csr(0x7C0), 1), Ok(()));
+        assert_eq!(read_csr(&mut ctx, Csr(0x7C0)), Ok(0));
+        assert_eq!(read_csr(&mut ctx, Csr(0x7C1)), Err(()));
+    }
+
+    #[test]
+    fn test_fetch_fault_tval() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+
+        // Sv39 page table mapping only the page at 0x1000, executable from S-mode.
+        let code = Box::new(Page([0; 512]));
+        let mut l0 = Box::new(Page([0; 512]));
+        let mut l1 = Box::new(Page([0; 512]));
+        let mut root = Box::new(Page([0; 512]));
+        let table = |page: &Page| (page as *const Page as u64) >> 12 << 10 | 1;
+        l0.0[1] = table(&code) | 0xCA;
+        l1.0[0] = table(&l0);
+        root.0[0] = table(&l1);
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.satp = 8 << 60 | (&*root as *const Page as u64) >> 12;
+
+        assert_eq!(insn_translate(&mut ctx, 0x1ffe), Ok(&code.0 as *const u64 as u64 + 0xffe));
+
+        // Fetch from an unmapped page.
+        assert_eq!(insn_translate(&mut ctx, 0x5000), Err(()));
+        assert_eq!(ctx.cause, 12);
+        assert_eq!(ctx.tval, 0x5000);
+
+        // The second half of an instruction crossing into an unmapped page reports the address of
+        // that half.
+        ctx.cause = 0;
+        assert_eq!(insn_translate(&mut ctx, 0x1ffe + 2), Err(()));
+        assert_eq!(ctx.cause, 12);
+        assert_eq!(ctx.tval, 0x2000);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        #[repr(align(64))]
+        struct Line([u64; 8]);
+
+        let mut line = Box::new(Line([0; 8]));
+        let base = line.0.as_mut_ptr() as u64;
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.set_watch(base + 8, 8, true);
+        ctx.registers[10] = 0x1234;
+
+        // A store to a nearby word in the same cache line does not stop the hart, nor does a load
+        // from the watched word.
+        ctx.registers[11] = base;
+        assert_eq!(step(&mut ctx, &Op::Sd { rs1: 11, rs2: 10, imm: 0 }, false), Ok(()));
+        assert_eq!(step(&mut ctx, &Op::Ld { rd: 12, rs1: 11, imm: 8 }, false), Ok(()));
+        assert_eq!(ctx.watch_hit, None);
+
+        // The watched cache line is refilled after a flush, e.g. on privilege switches, but must
+        // not bypass the check.
+        ctx.shared.clear_local_cache();
+        assert_eq!(step(&mut ctx, &Op::Sd { rs1: 11, rs2: 10, imm: 0 }, false), Ok(()));
+        assert_eq!(step(&mut ctx, &Op::Sd { rs1: 11, rs2: 10, imm: 8 }, false), Ok(()));
+        assert_eq!(ctx.watch_hit, Some(base + 8));
+        assert_eq!(ctx.shared.alarm.load(MemOrder::Relaxed) & 1, 1);
+        assert_eq!(line.0[..2], [0x1234, 0x1234]);
+
+        // Cleared watchpoints are no longer hit.
+        ctx.watch_hit = None;
+        ctx.clear_watch(base + 8);
+        assert_eq!(step(&mut ctx, &Op::Sd { rs1: 11, rs2: 10, imm: 8 }, false), Ok(()));
+        assert_eq!(ctx.watch_hit, None);
+    }
+
+    #[test]
+    fn test_cluster_mask() {
+        // Without clustering, only the harts in the mask are flushed.
+        assert_eq!(cluster_mask(0b0100, 1), 0b0100);
+        // An sfence from hart 2 flushes its peer, hart 3, but not harts 0 and 1.
+        assert_eq!(cluster_mask(0b0100, 2), 0b1100);
+        assert_eq!(cluster_mask(0b0101, 2), 0b1111);
+        // Clusters need not evenly divide 64 harts.
+        assert_eq!(cluster_mask(1 << 63, 3), 1 << 63);
+        assert_eq!(cluster_mask(1 << 4, 3), 0b111 << 3);
+        assert_eq!(cluster_mask(1, 64), u64::max_value());
+        assert_eq!(cluster_mask(0, 64), 0);
+    }
+
+    #[test]
+    fn test_cluster_sfence() {
+        crate::testing::init_harts();
+        // A translation cached by hart 1, which hart 0 does not flush unless they are a cluster.
+        let peer = crate::shared_context(1);
+        let cache = || {
+            peer.line[3].tag.store(0x3000 >> 12 << 1, MemOrder::Relaxed);
+            peer.i_line[3].tag.store(0x3000 >> 12 << 1, MemOrder::Relaxed);
+        };
+        let cached = || {
+            let valid = |tag: &AtomicU64| tag.load(MemOrder::Relaxed) != i64::MAX as u64;
+            (valid(&peer.line[3].tag), valid(&peer.i_line[3].tag))
+        };
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        let sfence = Op::SfenceVma { rs1: 0, rs2: 0 };
+        cache();
+        assert_eq!(step(&mut ctx, &sfence, false), Ok(()));
+        assert_eq!(cached(), (true, true));
+
+        ctx.cluster_size = 2;
+        assert_eq!(step(&mut ctx, &sfence, false), Ok(()));
+        assert_eq!(cached(), (false, false));
+    }
+
+    #[test]
+    fn test_translate_addr() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+
+        // Sv39 page table mapping only the page at 0x1000, readable from S-mode.
+        let data = Box::new(Page([0; 512]));
+        let mut l0 = Box::new(Page([0; 512]));
+        let mut l1 = Box::new(Page([0; 512]));
+        let mut root = Box::new(Page([0; 512]));
+        let table = |page: &Page| (page as *const Page as u64) >> 12 << 10 | 1;
+        l0.0[1] = table(&data) | 0xC2;
+        l1.0[0] = table(&l0);
+        root.0[0] = table(&l1);
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.cause = 8;
+        ctx.tval = 0;
+
+        // With paging off, addresses are untranslated.
+        assert_eq!(ctx.translate_addr(0x1234, AccessType::Read), Ok(0x1234));
+
+        ctx.satp = 8 << 60 | (&*root as *const Page as u64) >> 12;
+        let data_addr = &data.0 as *const u64 as u64;
+        assert_eq!(ctx.translate_addr(0x1234, AccessType::Read), Ok(data_addr + 0x234));
+        assert_eq!(
+            ctx.translate_addr(0x1234, AccessType::Write),
+            Err(Trap::StorePageFault(0x1234))
+        );
+        assert_eq!(ctx.translate_addr(0x5000, AccessType::Read), Err(Trap::LoadPageFault(0x5000)));
+
+        // The state of the hart is left untouched.
+        assert_eq!(ctx.cause, 8);
+        assert_eq!(ctx.tval, 0);
+    }
+
+    #[test]
+    fn test_sstc() {
+        crate::testing::init_harts();
+        let event_loop = crate::event_loop();
+        event_loop.set_threaded(false);
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.mcounteren = 2;
+
+        // A deadline in the past raises STIP immediately.
+        event_loop.advance_to(1000);
+        assert_eq!(write_csr(&mut ctx, Csr::Stimecmp, 10), Ok(()));
+        assert_eq!(ctx.shared.mip.load(MemOrder::Relaxed) & 32, 32);
+
+        // A deadline in the future clears it until the time is reached.
+        assert_eq!(write_csr(&mut ctx, Csr::Stimecmp, 15), Ok(()));
+        assert_eq!(read_csr(&mut ctx, Csr::Stimecmp), Ok(15));
+        assert_eq!(ctx.shared.mip.load(MemOrder::Relaxed) & 32, 0);
+        event_loop.advance_to(1499);
+        check_interrupt(&mut ctx).unwrap();
+        assert_eq!(ctx.shared.mip.load(MemOrder::Relaxed) & 32, 0);
+        event_loop.advance_to(1500);
+        check_interrupt(&mut ctx).unwrap();
+        assert_eq!(ctx.shared.mip.load(MemOrder::Relaxed) & 32, 32);
+
+        // Kernels disable the timer with a deadline that never comes.
+        assert_eq!(write_csr(&mut ctx, Csr::Stimecmp, u64::MAX), Ok(()));
+        assert_eq!(ctx.shared.mip.load(MemOrder::Relaxed) & 32, 0);
+
+        // Access is controlled by the TM bit of mcounteren.
+        ctx.mcounteren = 0;
+        assert_eq!(write_csr(&mut ctx, Csr::Stimecmp, 0), Err(()));
+    }
+
+    #[test]
+    fn test_sv48_gigapage() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+
+        // Sv48 page table with a gigapage at 0xffff_8000_4000_0000, which needs all four levels
+        // of index bits. The gigapage maps to physical address 0x8000_0000.
+        let mut l1 = Box::new(Page([0; 512]));
+        let mut root = Box::new(Page([0; 512]));
+        let table = |page: &Page| (page as *const Page as u64) >> 12 << 10 | 1;
+        l1.0[1] = 0x80000000 >> 12 << 10 | 0xCF;
+        root.0[256] = table(&l1);
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        let satp = 9 << 60 | (&*root as *const Page as u64) >> 12;
+        assert_eq!(write_csr(&mut ctx, Csr::Satp, satp), Ok(()));
+        assert_eq!(ctx.satp, satp);
+
+        let walk = |vaddr: u64| {
+            riscv::mmu::walk_page(satp, vaddr >> 12, |addr| unsafe { *(addr as *const u64) })
+        };
+        let pte = walk(0xffff_8000_4012_3000);
+        assert_eq!(pte >> 10 << 12, 0x8012_3000);
+        assert_eq!(pte & 0xff, 0xCF);
+
+        // Sv48 addresses must be sign-extended from bit 47.
+        assert_eq!(walk(0x8000_4012_3000), 0);
+
+        // The same table under Sv39 cannot reach the gigapage.
+        assert_eq!(
+            riscv::mmu::walk_page(8 << 60 | satp & ((1 << 44) - 1), 0x4012_3000 >> 12, |addr| {
+                unsafe { *(addr as *const u64) }
+            }),
+            0
+        );
+    }
+
+    #[test]
+    fn test_svpbmt() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+
+        // Sv39 page table mapping 0x1000 to physical address 0x1234_5000 as I/O memory.
+        let mut l0 = Box::new(Page([0; 512]));
+        let mut l1 = Box::new(Page([0; 512]));
+        let mut root = Box::new(Page([0; 512]));
+        let table = |page: &Page| (page as *const Page as u64) >> 12 << 10 | 1;
+        l0.0[1] = 2 << 61 | 0x1234_5000 >> 12 << 10 | 0xCF;
+        l1.0[0] = table(&l0);
+        root.0[0] = table(&l1);
+
+        let satp = 8 << 60 | (&*root as *const Page as u64) >> 12;
+        let walk = |vaddr: u64| {
+            riscv::mmu::walk_page(satp, vaddr >> 12, |addr| unsafe { *(addr as *const u64) })
+        };
+        assert_eq!(walk(0x1000) >> 10 << 12, 0x1234_5000);
+
+        // The reserved memory type and memory types in non-leaf PTEs are invalid.
+        l0.0[1] |= 3 << 61;
+        assert_eq!(walk(0x1000), 0);
+        l0.0[1] &= !(1 << 62);
+        l1.0[0] |= 1 << 61;
+        assert_eq!(walk(0x1000), 0);
+    }
+
+    #[test]
+    fn test_svnapot() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+
+        // Sv39 page table with a 64 KiB NAPOT mapping from 0x10000 to 0x1234_0000.
+        let mut l0 = Box::new(Page([0; 512]));
+        let mut l1 = Box::new(Page([0; 512]));
+        let mut root = Box::new(Page([0; 512]));
+        let table = |page: &Page| (page as *const Page as u64) >> 12 << 10 | 1;
+        for pte in l0.0[0x10..0x20].iter_mut() {
+            *pte = 1 << 63 | (0x1234_0000 >> 12 | 0b1000) << 10 | 0xCF;
+        }
+        l1.0[0] = table(&l0);
+        root.0[0] = table(&l1);
+
+        let satp = 8 << 60 | (&*root as *const Page as u64) >> 12;
+        let walk = |vaddr: u64| {
+            riscv::mmu::walk_page(satp, vaddr >> 12, |addr| unsafe { *(addr as *const u64) })
+        };
+        for vaddr in (0x10000..0x20000).step_by(0x1000) {
+            assert_eq!(walk(vaddr) >> 10 << 12, 0x1234_0000 + vaddr - 0x10000);
+        }
+
+        // Other NAPOT sizes are reserved.
+        l0.0[0x10] = 1 << 63 | (0x1234_0000 >> 12 | 0b0100) << 10 | 0xCF;
+        assert_eq!(walk(0x10000), 0);
+    }
+
+    #[test]
+    fn test_faulting_load_not_retired() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+
+        // Sv39 page table mapping only the page at 0x1000, readable from S-mode.
+        let data = Box::new(Page([0x1234; 512]));
+        let mut l0 = Box::new(Page([0; 512]));
+        let mut l1 = Box::new(Page([0; 512]));
+        let mut root = Box::new(Page([0; 512]));
+        let table = |page: &Page| (page as *const Page as u64) >> 12 << 10 | 1;
+        l0.0[1] = table(&data) | 0x42;
+        l1.0[0] = table(&l0);
+        root.0[0] = table(&l1);
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.satp = 8 << 60 | (&*root as *const Page as u64) >> 12;
+        ctx.instret = 10;
+        ctx.minstret = 5;
+
+        // ld a0, 0(a1)
+        let load = Op::Ld { rd: 10, rs1: 11, imm: 0 };
+        ctx.registers[11] = 0x5000;
+        assert_eq!(step(&mut ctx, &load, false), Err(()));
+        assert_eq!(ctx.cause, 13);
+        assert_eq!(ctx.tval, 0x5000);
+        assert_eq!(ctx.instret, 10);
+        assert_eq!(ctx.minstret, 5);
+
+        ctx.registers[11] = 0x1008;
+        assert_eq!(step(&mut ctx, &load, false), Ok(()));
+        assert_eq!(ctx.registers[10], 0x1234);
+        assert_eq!(ctx.minstret, 6);
+
+        // Translated code counts the accesses made before the fault within the block.
+        static FAULT: Lazy<Mutex<Option<(u64, u64, u64)>>> = Lazy::new(Default::default);
+        fn record(ctx: &mut Context) -> TrapAction {
+            assert_eq!(ctx.last_trap(), Trap::LoadPageFault(0x5000));
+            *FAULT.lock() = Some((ctx.pc, ctx.instret, ctx.minstret));
+            ctx.shared.shutdown();
+            // Skip the ebreak to the final `j .`.
+            ctx.pc += 8;
+            TrapAction::Resume
+        }
+
+        // ld a0, 0(a1); addi a2, a2, 1; ld a3, 0(a4); ebreak; j .
+        let pc = program_page(&[0x0005b503, 0x00160613, 0x00073683, 0x00100073, 0x0000006f]);
+        let mut ctx = Context::new(0);
+        ctx.trap_hook = Some(record);
+        // Fetch from host addresses in M-mode, but translate loads as S-mode through MPRV.
+        ctx.prv = 3;
+        ctx.mstatus = 0x20000 | 1 << 11;
+        ctx.satp = 8 << 60 | (&*root as *const Page as u64) >> 12;
+        ctx.pc = pc;
+        ctx.instret = 10;
+        ctx.minstret = 5;
+        ctx.registers[11] = 0x1008;
+        ctx.registers[12] = 0;
+        ctx.registers[14] = 0x5000;
+        let fiber = run_translated(ctx);
+        let ctx = unsafe { &*fiber.data::<UnsafeCell<Context>>().get() };
+        assert_eq!(ctx.registers[10], 0x1234);
+        assert_eq!(ctx.registers[12], 1);
+        assert_eq!(*FAULT.lock(), Some((pc + 8, 12, 6)));
+    }
+
+    #[test]
+    fn test_pmp_store_fault() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+        let mut page = Box::new(Page([0x1234; 512]));
+        let base = page.0.as_mut_ptr() as u64;
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        // A single read-only NAPOT region covering the page.
+        assert_eq!(write_csr(&mut ctx, Csr(0x3B0), (base >> 2) | 0x1FF), Ok(()));
+        assert_eq!(write_csr(&mut ctx, Csr(0x3A0), 0x19), Ok(()));
+        assert_eq!(read_csr(&mut ctx, Csr(0x3A0)), Ok(0x19));
+
+        // sd a0, 8(a1)
+        let store = Op::Sd { rs1: 11, rs2: 10, imm: 8 };
+        ctx.registers[10] = 0x5678;
+        ctx.registers[11] = base;
+        assert_eq!(step(&mut ctx, &store, false), Err(()));
+        assert_eq!(ctx.cause, 7);
+        assert_eq!(ctx.tval, base + 8);
+        assert_eq!(page.0[1], 0x1234);
+
+        // Loads are allowed, but not accesses outside the region.
+        // ld a0, 8(a1)
+        let load = Op::Ld { rd: 10, rs1: 11, imm: 8 };
+        assert_eq!(step(&mut ctx, &load, false), Ok(()));
+        assert_eq!(ctx.registers[10], 0x1234);
+        ctx.registers[11] = base + 4096;
+        assert_eq!(step(&mut ctx, &load, false), Err(()));
+        assert_eq!(ctx.cause, 5);
+    }
+
+    #[test]
+    fn test_misaligned_across_pages() {
+        #[repr(align(4096))]
+        struct Page([u64; 512]);
+
+        // Sv39 page table mapping 0x1000 and 0x2000 to two separately allocated pages.
+        let mut low = Box::new(Page([0; 512]));
+        let mut high = Box::new(Page([0; 512]));
+        let mut l0 = Box::new(Page([0; 512]));
+        let mut l1 = Box::new(Page([0; 512]));
+        let mut root = Box::new(Page([0; 512]));
+        let table = |page: &Page| (page as *const Page as u64) >> 12 << 10 | 1;
+        l0.0[1] = table(&low) | 0xC6;
+        l0.0[2] = table(&high) | 0xC6;
+        l1.0[0] = table(&l0);
+        root.0[0] = table(&l1);
+        low.0[511] = 0x4433_2211_0000_0000;
+        high.0[0] = 0x0000_0000_8877_6655;
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.satp = 8 << 60 | (&*root as *const Page as u64) >> 12;
+
+        // ld a0, 0(a1)
+        let load = Op::Ld { rd: 10, rs1: 11, imm: 0 };
+        ctx.registers[11] = 0x1ffc;
+        assert_eq!(step(&mut ctx, &load, false), Err(()));
+        assert_eq!(ctx.cause, 4);
+        assert_eq!(ctx.tval, 0x1ffc);
+
+        // sd a0, 2(a1)
+        let store = Op::Sd { rs1: 11, rs2: 10, imm: 2 };
+        assert_eq!(step(&mut ctx, &store, false), Err(()));
+        assert_eq!(ctx.cause, 6);
+        assert_eq!(ctx.tval, 0x1ffe);
+        assert_eq!(ctx.minstret, 0);
+
+        ctx.emulate_misaligned = true;
+        assert_eq!(step(&mut ctx, &load, false), Ok(()));
+        assert_eq!(ctx.registers[10], 0x8877_6655_4433_2211);
+        assert_eq!(ctx.minstret, 1);
+
+        assert_eq!(step(&mut ctx, &store, false), Ok(()));
+        assert_eq!(low.0[511], 0x2211_2211_0000_0000);
+        assert_eq!(high.0[0], 0x0000_8877_6655_4433);
+
+        // A store faulting on the second page leaves the first page unmodified.
+        ctx.registers[11] = 0x2ffc;
+        assert_eq!(step(&mut ctx, &store, false), Err(()));
+        assert_eq!(ctx.cause, 15);
+        assert_eq!(high.0[511], 0);
+        assert_eq!(ctx.minstret, 2);
+    }
+
+    #[test]
+    fn test_compressed_pc_relative() {
+        let mut ctx = Context::new(0);
+
+        // PC-relative targets are computed from the address of the instruction itself, but `step`
+        // is called with the PC already past the instruction, so its size must be accounted for.
+        // c.j 8 at 0x1000
+        let op = riscv::decode_compressed(0xa021);
+        assert!(op == Op::Jal { rd: 0, imm: 8 });
+        ctx.pc = 0x1002;
+        assert_eq!(step(&mut ctx, &op, true), Ok(()));
+        assert_eq!(ctx.pc, 0x1008);
+
+        // c.beqz s0, -4 at 0x1000
+        let op = riscv::decode_compressed(0xdc75);
+        assert!(op == Op::Beq { rs1: 8, rs2: 0, imm: -4 });
+        ctx.registers[8] = 0;
+        ctx.pc = 0x1002;
+        assert_eq!(step(&mut ctx, &op, true), Ok(()));
+        assert_eq!(ctx.pc, 0xffc);
+
+        // The same jump uncompressed links the address of the next instruction.
+        // jal ra, 8 at 0x1000
+        ctx.pc = 0x1004;
+        assert_eq!(step(&mut ctx, &Op::Jal { rd: 1, imm: 8 }, false), Ok(()));
+        assert_eq!(ctx.pc, 0x1008);
+        assert_eq!(ctx.registers[1], 0x1004);
+    }
+
+    #[test]
+    fn test_fld_misaligned() {
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        // Enable the FPU.
+        ctx.mstatus |= 0x2000;
+
+        // fld fa0, 4(a1), with a1 8-byte aligned.
+        ctx.registers[11] = 0x1000;
+        let load = Op::Fld { frd: 10, rs1: 11, imm: 4 };
+        assert_eq!(step(&mut ctx, &load, false), Err(()));
+        assert_eq!(ctx.cause, 4);
+        assert_eq!(ctx.tval, 0x1004);
+    }
+
+    #[test]
+    fn test_store_misaligned_cause() {
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        // Enable the FPU.
+        ctx.mstatus |= 0x2000;
+
+        // amoadd.w a0, a2, (a1), with a1 2 bytes past a word boundary.
+        ctx.registers[11] = 0x1002;
+        let amo = Op::AmoaddW { rd: 10, rs1: 11, rs2: 12, aqrl: riscv::Ordering::Relaxed };
+        assert_eq!(step(&mut ctx, &amo, false), Err(()));
+        assert_eq!(ctx.last_trap(), Trap::StoreMisaligned(0x1002));
+
+        // fsd fa0, 4(a1), with a1 8-byte aligned.
+        ctx.registers[11] = 0x1000;
+        let store = Op::Fsd { rs1: 11, frs2: 10, imm: 4 };
+        assert_eq!(step(&mut ctx, &store, false), Err(()));
+        assert_eq!(ctx.last_trap(), Trap::StoreMisaligned(0x1004));
+
+        // lr.w a0, (a1) reports a load misaligned exception instead.
+        ctx.registers[11] = 0x1002;
+        let lr = Op::LrW { rd: 10, rs1: 11, aqrl: riscv::Ordering::Relaxed };
+        assert_eq!(step(&mut ctx, &lr, false), Err(()));
+        assert_eq!(ctx.last_trap(), Trap::LoadMisaligned(0x1002));
+        assert_eq!(ctx.minstret, 0);
+    }
+
+    #[test]
+    fn test_exception_flags() {
+        use softfp::{ExceptionFlags, RoundingMode};
+
+        init_fp();
+        let mut fiber = fiber::FiberContext::new(UnsafeCell::new(Context::new(0)));
+        let ptr = fiber.data::<UnsafeCell<Context>>().get();
+        fiber::FiberGroup::with(|group| {
+            group.spawn(&mut fiber, || {
+                let ctx = unsafe { &mut *ptr };
+                ctx.mstatus = 0x6000;
+                ctx.fp_registers[1] = 1f32.to_bits() as u64;
+                ctx.fp_registers[2] = 3f32.to_bits() as u64;
+                ctx.fp_registers[3] = (-1f32).to_bits() as u64;
+                ctx.fp_registers[4] = 0f32.to_bits() as u64;
+                let fdiv_dyn = Op::FdivS { frd: 10, frs1: 1, frs2: 2, rm: 0b111 };
+
+                // 1 / 3 is inexact, and its rounding follows the dynamic rounding mode.
+                assert_eq!(ctx.rounding_mode(), RoundingMode::TiesToEven);
+                step(ctx, &fdiv_dyn, false).unwrap();
+                assert_eq!(ctx.fp_registers[10] as u32, 0x3eaaaaab);
+                assert_eq!(ctx.exception_flags(), ExceptionFlags::INEXACT);
+                ctx.set_rounding_mode(RoundingMode::TowardZero);
+                step(ctx, &fdiv_dyn, false).unwrap();
+                assert_eq!(ctx.fp_registers[10] as u32, 0x3eaaaaaa);
+
+                // Flags accumulate until cleared.
+                step(ctx, &Op::FdivS { frd: 10, frs1: 1, frs2: 4, rm: 0 }, false).unwrap();
+                step(ctx, &Op::FsqrtS { frd: 10, frs1: 3, rm: 0 }, false).unwrap();
+                assert_eq!(
+                    ctx.exception_flags(),
+                    ExceptionFlags::INEXACT
+                        | ExceptionFlags::DIVIDE_BY_ZERO
+                        | ExceptionFlags::INVALID_OPERATION
+                );
+                ctx.clear_exception_flags();
+                assert!(ctx.exception_flags().is_empty());
+            });
+        });
+    }
+
+    #[test]
+    fn test_sbi_identity() {
+        let mut ctx = Context::new(0);
+        assert_eq!(ctx.identity.impl_id, crate::config::SBI_IMPL_ID_R2VM);
+        ctx.identity = crate::config::IdentityConfig {
+            impl_id: 1,
+            impl_version: 0x10002,
+            mvendorid: 0x489,
+            marchid: 0x8000000000000007,
+            mimpid: 0x20181004,
+        };
+
+        let mut call = |fid: u64, arg0: u64| {
+            ctx.registers[17] = 0x10;
+            ctx.registers[16] = fid;
+            ctx.registers[10] = arg0;
+            sbi_ecall(&mut ctx);
+            (ctx.registers[10] as i64, ctx.registers[11])
+        };
+        assert_eq!(call(1, 0), (0, 1));
+        assert_eq!(call(2, 0), (0, 0x10002));
+        assert_eq!(call(4, 0), (0, 0x489));
+        assert_eq!(call(5, 0), (0, 0x8000000000000007));
+        assert_eq!(call(6, 0), (0, 0x20181004));
+        assert_eq!(call(3, 0x10), (0, 1));
+        assert_eq!(call(3, 0x735049), (0, 0));
+        assert_eq!(call(7, 0), (-2, 0));
+
+        assert_eq!(read_csr(&mut ctx, Csr::Mvendorid), Ok(0x489));
+        assert_eq!(read_csr(&mut ctx, Csr::Marchid), Ok(0x8000000000000007));
+        assert_eq!(read_csr(&mut ctx, Csr::Mimpid), Ok(0x20181004));
+    }
+
+    #[test]
+    fn test_sbi_ipi_uses_msip() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+
+        struct Pin(Arc<AtomicBool>);
+        impl io::IrqPin for Pin {
+            fn set_level(&self, level: bool) {
+                self.0.store(level, MemOrder::Relaxed);
+            }
+        }
+
+        crate::testing::init_harts();
+        let pins = |levels: &[Arc<AtomicBool>]| {
+            levels.iter().map(|x| -> Box<dyn io::IrqPin> { Box::new(Pin(x.clone())) }).collect()
+        };
+        let msip: Vec<_> = (0..2).map(|_| Arc::new(AtomicBool::new(false))).collect();
+        let mtip: Vec<_> = (0..2).map(|_| Arc::new(AtomicBool::new(false))).collect();
+        let ctx = Arc::new(super::super::DirectIoContext);
+        let clint = io::hw::intc::Clint::new(ctx, pins(&msip), pins(&mtip));
+        let pending = |hart: usize| msip[hart].load(MemOrder::Relaxed);
+
+        // Hart 0 sends an IPI to hart 1. Harts beyond the last one are ignored.
+        send_ipi(&clint, hart_mask(0b1110));
+        assert_eq!((clint.read(0, 4), clint.read(4, 4)), (0, 1));
+        assert!(!pending(0) && pending(1));
+
+        // Hart 1 clears its IPI, with the same effect as clearing msip through the CLINT.
+        clear_ipi(&clint, 1);
+        assert_eq!(clint.read(4, 4), 0);
+        assert!(!pending(1));
+        clint.write(4, 1, 4);
+        assert!(pending(1));
+        clint.write(4, 0, 4);
+        assert!(!pending(1));
+    }
+
+    #[test]
+    fn test_perf_marker() {
+        // Markers are plain nops to the decoder, so other implementations ignore them.
+        assert!(riscv::decode(0x07b02013) == Op::Slti { rd: 0, rs1: 0, imm: 123 });
+
+        // slti x0, x0, 1; addi a0, a0, 1; slti x0, x0, 2
+        let program: [u32; 3] = [0x00102013, 0x00150513, 0x00202013];
+        let mut ctx = Context::new(0);
+        ctx.prv = 3;
+        ctx.pc = program.as_ptr() as u64;
+        // Use a hartid no other test uses, as markers are global.
+        ctx.mhartid = 0x6d61726b;
+        ctx.cycle_offset = 1000;
+        ctx.instret = 900;
+        for _ in 0..3 {
+            step_at_pc(&mut ctx).unwrap();
+            ctx.cycle_offset += 1;
+        }
+
+        let recorded: Vec<_> = markers().into_iter().filter(|m| m.hartid == ctx.mhartid).collect();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!((recorded[0].id, recorded[1].id), (1, 2));
+        assert_eq!((recorded[0].cycle, recorded[0].instret), (1000, 900));
+        assert_eq!((recorded[1].cycle, recorded[1].instret), (1002, 902));
+    }
+
+    #[test]
+    fn test_spin_loop() {
+        // lw a0, 0(a1); bnez a0, -4
+        let load = Op::Lw { rd: 10, rs1: 11, imm: 0 };
+        assert!(is_spin_loop(&load, 4, &Op::Bne { rs1: 10, rs2: 0, imm: -4 }));
+        assert!(is_spin_loop(&load, 4, &Op::Bltu { rs1: 12, rs2: 10, imm: -4 }));
+        assert!(!is_spin_loop(&load, 4, &Op::Bne { rs1: 10, rs2: 0, imm: -8 }));
+        assert!(!is_spin_loop(&load, 4, &Op::Bne { rs1: 11, rs2: 0, imm: -4 }));
+        assert!(!is_spin_loop(&load, 4, &Op::Jal { rd: 0, imm: -4 }));
+        // The address changes each iteration.
+        let load = Op::Ld { rd: 10, rs1: 10, imm: 0 };
+        assert!(!is_spin_loop(&load, 4, &Op::Bne { rs1: 10, rs2: 0, imm: -4 }));
+
+        // Memory is polled with a sleep in between until it changes.
+        let alarm = AtomicU64::new(0);
+        let mut polls = 0;
+        let start = std::time::Instant::now();
+        spin_wait(&alarm, || {
+            polls += 1;
+            polls == 5
+        });
+        assert_eq!(polls, 5);
+        assert!(start.elapsed() >= SPIN_POLL_INTERVAL * 4);
+
+        // Alarms, e.g. interrupts, wake the hart even if the memory does not change.
+        alarm.store(1, MemOrder::Relaxed);
+        spin_wait(&alarm, || panic!("memory polled with an alarm pending"));
+
+        // Translated code sleeps in the loop until another thread releases it.
+        // lw a0, 0(a1); bnez a0, -4; ebreak; j .
+        let pc = program_page(&[0x0005a503, 0xfe051ee3, 0x00100073, 0x0000006f]);
+        let flag: &'static AtomicU32 = Box::leak(Box::new(AtomicU32::new(1)));
+        let mut ctx = Context::new(0);
+        ctx.spin_detect = true;
+        ctx.trap_hook = Some(stop);
+        ctx.prv = 3;
+        ctx.pc = pc;
+        ctx.registers[11] = flag as *const AtomicU32 as u64;
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            flag.store(0, MemOrder::Relaxed);
+        });
+        let fiber = run_translated(ctx);
+        releaser.join().unwrap();
+        let ctx = unsafe { &*fiber.data::<UnsafeCell<Context>>().get() };
+        assert_eq!(ctx.registers[10], 0);
+
+        // Spinning would take as much CPU time as the wait.
+        let usage = unsafe {
+            let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+            assert_eq!(libc::getrusage(libc::RUSAGE_THREAD, usage.as_mut_ptr()), 0);
+            usage.assume_init()
+        };
+        let cpu_us = |time: libc::timeval| time.tv_sec as i64 * 1_000_000 + time.tv_usec as i64;
+        assert!(cpu_us(usage.ru_utime) + cpu_us(usage.ru_stime) < 250_000);
+    }
+
+    #[test]
+    fn test_trap_hook() {
+        // Emulate the illegal instruction as if it loaded 42 into a0.
+        fn emulate(ctx: &mut Context) -> TrapAction {
+            if ctx.cause != 2 {
+                return TrapAction::Deliver;
+            }
+            ctx.registers[10] = 42;
+            ctx.pc += 4;
+            TrapAction::Resume
+        }
+        let mut ctx = Context::new(0);
+        ctx.trap_hook = Some(emulate);
+        ctx.prv = 1;
+        ctx.pc = 0x1000;
+        ctx.stvec = 0x2000;
+        assert!(step(&mut ctx, &Op::Illegal, false).is_err());
+        assert_eq!(ctx.cause, 2);
+        trap(&mut ctx);
+
+        // The guest handler is not entered.
+        assert_eq!(ctx.pc, 0x1004);
+        assert_eq!(ctx.prv, 1);
+        assert_eq!(ctx.registers[10], 42);
+        assert_eq!(ctx.sepc, 0);
+        assert_eq!(ctx.mepc, 0);
+    }
+
+    #[test]
+    fn test_trace() {
+        use super::super::trace::{self, Record, Tracer};
+
+        let out = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = Context::new(1);
+        ctx.pc = 0x1000;
+        ctx.tracer = Some(Box::new(Tracer::new(1, out.clone())));
+
+        // addi a0, zero, 5; slli a0, a0, 2; c.addi a0, 1; nop
+        let program: [u32; 4] = [0x00500513, 0x00251513, 0x0505, 0x00000013];
+        let mut offset = 0;
+        let mut retired = 0;
+        for &bits in program.iter() {
+            let compressed = bits & 3 != 3;
+            let op = if compressed {
+                riscv::decode_compressed(bits as u16)
+            } else {
+                riscv::decode(bits)
+            };
+            instruction_hook(&mut ctx, unsafe { std::mem::transmute(op) }, offset, bits);
+            step(&mut ctx, &op, compressed).unwrap();
+            offset += if compressed { 2 } else { 4 };
+            retired += 1;
+        }
+        let mut tracer = ctx.tracer.take().unwrap();
+        tracer.flush(&ctx.registers).unwrap();
+
+        let out = out.lock();
+        let records: Vec<_> = trace::decode(&out).collect();
+        assert_eq!(records.len(), retired);
+        let record = |pc, bits, write| Record { pc, bits, hartid: 1, write };
+        assert_eq!(
+            records,
+            [
+                record(0x1000, 0x00500513, Some((10, 5))),
+                record(0x1004, 0x00251513, Some((10, 20))),
+                record(0x1008, 0x0505, Some((10, 21))),
+                record(0x100a, 0x00000013, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_exception() {
+        use super::super::trace::{self, Record, Tracer};
+
+        fn skip(ctx: &mut Context) -> TrapAction {
+            assert_eq!(ctx.cause, 2);
+            ctx.pc += 4;
+            TrapAction::Resume
+        }
+
+        // addi a0, zero, 5; illegal; addi a0, a0, 1; nop
+        let program: [u32; 4] = [0x00500513, 0x00000000, 0x00150513, 0x00000013];
+        let pc = program.as_ptr() as u64;
+
+        // Machine mode fetches from host addresses.
+        let out = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = Context::new(0);
+        ctx.prv = 3;
+        ctx.pc = pc;
+        ctx.instret = 0;
+        ctx.trap_hook = Some(skip);
+        ctx.tracer = Some(Box::new(Tracer::new(0, out.clone())));
+        while ctx.pc != pc + 16 {
+            if step_at_pc(&mut ctx).is_err() {
+                trap(&mut ctx);
+            }
+        }
+        let mut tracer = ctx.tracer.take().unwrap();
+        tracer.flush(&ctx.registers).unwrap();
+
+        // The illegal instruction is not retired, so minstret and the trace both skip it.
+        let out = out.lock();
+        let records: Vec<_> = trace::decode(&out).collect();
+        assert_eq!(records.len() as u64, ctx.instret);
+        let record = |pc, bits, write| Record { pc, bits, hartid: 0, write };
+        assert_eq!(
+            records,
+            [
+                record(pc, 0x00500513, Some((10, 5))),
+                record(pc + 8, 0x00150513, Some((10, 6))),
+                record(pc + 12, 0x00000013, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_instruction_hook() {
+        static TRACE: Lazy<Mutex<Vec<(u64, &'static str, u64)>>> = Lazy::new(Default::default);
+        fn record(ctx: &Context, op: &Op) {
+            TRACE.lock().push((ctx.pc, op.mnemonic(), ctx.registers[10]));
+        }
+
+        // addi a0, zero, 5; slli a0, a0, 2; c.addi a0, 1
+        let program: [u16; 5] = [0x0513, 0x0050, 0x1513, 0x0025, 0x0505];
+        let pc = program.as_ptr() as u64;
+
+        // Execute with the interpreter. Machine mode fetches from host addresses.
+        let mut ctx = Context::new(0);
+        ctx.instruction_hook = Some(record);
+        ctx.prv = 3;
+        ctx.pc = pc;
+        for _ in 0..3 {
+            step_at_pc(&mut ctx).unwrap();
+        }
+
+        // Execute the way DBT-ed code does: PC is only updated at the end of the block.
+        ctx.pc = 0x1000;
+        let ops = [
+            Op::Addi { rd: 10, rs1: 0, imm: 5 },
+            Op::Slli { rd: 10, rs1: 10, imm: 2 },
+            Op::Xori { rd: 10, rs1: 10, imm: 1 },
+        ];
+        for (i, op) in ops.iter().enumerate() {
+            instruction_hook(&mut ctx, unsafe { std::mem::transmute(*op) }, i as i64 * 4, 0);
+            step(&mut ctx, op, false).unwrap();
+        }
+
+        assert_eq!(ctx.pc, 0x1000);
+        assert_eq!(ctx.registers[10], 21);
+        assert_eq!(
+            std::mem::take(&mut *TRACE.lock()),
+            [
+                (pc, "addi", 0xCCCCCCCCCCCCCCCC),
+                (pc + 4, "slli", 5),
+                (pc + 8, "addi", 20),
+                (0x1000, "addi", 21),
+                (0x1004, "slli", 5),
+                (0x1008, "xori", 20),
+            ]
+        );
+
+        // Translated code calls the hook before each instruction.
+        // addi a0, zero, 5; slli a0, a0, 2; addi a0, a0, 1; ebreak; j .
+        let pc = program_page(&[0x00500513, 0x00251513, 0x00150513, 0x00100073, 0x0000006f]);
+        let mut ctx = Context::new(0);
+        ctx.instruction_hook = Some(record);
+        ctx.trap_hook = Some(stop);
+        ctx.prv = 3;
+        ctx.pc = pc;
+        let fiber = run_translated(ctx);
+        let ctx = unsafe { &*fiber.data::<UnsafeCell<Context>>().get() };
+        assert_eq!(ctx.registers[10], 21);
+        assert_eq!(
+            *TRACE.lock(),
+            [
+                (pc, "addi", 0xCCCCCCCCCCCCCCCC),
+                (pc + 4, "slli", 5),
+                (pc + 8, "addi", 20),
+                (pc + 12, "ebreak", 21),
+                (pc + 16, "jal", 21),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_at() {
+        // addi a0, a0, 1; c.li a1, 5
+        let code: [u16; 3] = [0x0513, 0x0015, 0x4595];
+        let mut ctx = Context::new(0);
+        ctx.prv = 3;
+        let pc = code.as_ptr() as u64;
+
+        let (op, compressed, disasm) = disassemble_at(&mut ctx, pc).unwrap();
+        assert!(op == Op::Addi { rd: 10, rs1: 10, imm: 1 });
+        assert!(!compressed);
+        assert!(disasm.ends_with("00150513        addi    a0, a0, 1"), "{}", disasm);
+
+        let (op, compressed, disasm) = disassemble_at(&mut ctx, pc + 4).unwrap();
+        assert!(op == Op::Addi { rd: 11, rs1: 0, imm: 5 });
+        assert!(compressed);
+        assert!(disasm.ends_with("4595            addi    a1, zero, 5"), "{}", disasm);
+
+        // Unmapped addresses cannot be decoded, and the hart state is left untouched.
+        ctx.prv = 1;
+        ctx.satp = 8 << 60 | (Box::leak(Box::new([0u64; 1024])).as_ptr() as u64 + 4095) >> 12;
+        ctx.cause = 0;
+        assert!(disassemble_at(&mut ctx, 0x1000).is_none());
+        assert_eq!(ctx.cause, 0);
+    }
+
+    #[test]
+    fn test_unimp_traps() {
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+
+        // c.unimp, the all-zero halfword, and the all-ones word
+        let ops = [(riscv::decode_compressed(0), true), (riscv::decode(!0), false)];
+        for &(op, compressed) in ops.iter() {
+            ctx.cause = 0;
+            ctx.tval = 0xdead;
+            assert_eq!(step(&mut ctx, &op, compressed), Err(()));
+            assert_eq!(ctx.cause, 2);
+            assert_eq!(ctx.tval, 0);
+        }
+    }
+
+    #[test]
+    fn test_icache_invalidate_all() {
+        crate::testing::init_harts();
+        // nop; j .
+        let pc = program_page(&[0x00000013, 0x0000006f]);
+        let mut ctx = Context::new(1);
+        ctx.prv = 3;
+        ctx.pc = pc;
+        translate_code(&mut ctx, &mut icache(1), 3, pc);
+        assert!(icache(1).lookup(3, pc).is_some());
+
+        // The page is not protected, as in user-mode emulation, but the code is flushed anyway.
+        icache_invalidate_all();
+        let tasks: Vec<_> = crate::shared_context(1).tasks.lock().drain(..).collect();
+        for task in tasks {
+            task();
+        }
+        assert!(icache(1).lookup(3, pc).is_none());
+    }
+
+    #[test]
+    fn test_block_len_limit() {
+        #[repr(align(4096))]
+        struct Page([u32; 1024]);
+
+        // Translated code calls helpers, so it must be mapped close to the executable.
+        let heap = unsafe {
+            libc::mmap(
+                0x7ffef0000000 as *mut _,
+                HEAP_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(heap, libc::MAP_FAILED);
+        // The address is only a hint, so check the helpers are still within rel32 reach.
+        let helper = fiber_interp_run as usize as i64;
+        for addr in [heap as i64, heap as i64 + HEAP_SIZE as i64] {
+            assert!((addr - helper).abs() < 1 << 31, "code heap at {:p} is too far", heap);
+        }
+        let mut icache = ICache::new(heap as usize);
+
+        // Split a page of straight-line 4-byte instructions into blocks.
+        let page = Box::new(Page([0x00000013; 1024]));
+        let start = page.0.as_ptr() as u64;
+        let mut ctx = Context::new(0);
+        ctx.prv = 3;
+        let mut split = |max_len| {
+            icache.max_block_len = max_len;
+            let mut blocks = Vec::new();
+            let mut pc = start;
+            while pc != start + 4096 {
+                ctx.pc = pc;
+                let (_, _, end) = translate_code(&mut ctx, &mut icache, 3, pc);
+                blocks.push((pc - start, (end - pc) / 4));
+                pc = end;
+            }
+            blocks
+        };
+
+        let blocks = split(100);
+        assert_eq!(blocks.len(), 11);
+        assert!(blocks[..10].iter().enumerate().all(|(i, &b)| b == (i as u64 * 400, 100)));
+        assert_eq!(blocks[10], (4000, 24));
+
+        // Without a limit the whole page is a single block.
+        assert_eq!(split(0), vec![(0, 1024)]);
+        unsafe { libc::munmap(heap, HEAP_SIZE) };
+    }
+
+    #[test]
+    fn test_scratch_swap_across_trap() {
+        const USER_SP: u64 = 0x7fff0000;
+        const KERNEL_SP: u64 = 0xffffffe000010000;
+        // csrrw sp, sscratch, sp
+        let swap = Op::Csrrw { rd: 2, rs1: 2, csr: Csr::Sscratch };
+
+        let mut ctx = Context::new(0);
+        ctx.prv = 0;
+        ctx.medeleg = 0xB35D;
+        ctx.stvec = 0xffffffe000000000;
+        ctx.sscratch = KERNEL_SP;
+        ctx.mscratch = 0x80000000;
+        ctx.registers[2] = USER_SP;
+
+        // Environment call from U-mode, delegated to S-mode
+        ctx.pc = 0x10000;
+        ctx.cause = 8;
+        ctx.tval = 0;
+        enter_trap(&mut ctx);
+        assert_eq!(ctx.prv, 1);
+        assert_eq!(ctx.pc, ctx.stvec);
+        assert_eq!(ctx.sscratch, KERNEL_SP);
+
+        // Trap entry: switch to kernel stack
+        step(&mut ctx, &swap, false).unwrap();
+        assert_eq!(ctx.registers[2], KERNEL_SP);
+        assert_eq!(ctx.sscratch, USER_SP);
+
+        // Flushing caches must not affect hart-local scratch registers
+        ctx.shared.clear_local_cache();
+        ctx.shared.clear_local_icache();
+        assert_eq!(ctx.sscratch, USER_SP);
+
+        // Trap exit: switch back to user stack and return
+        step(&mut ctx, &swap, false).unwrap();
+        assert_eq!(ctx.registers[2], USER_SP);
+        assert_eq!(ctx.sscratch, KERNEL_SP);
+        ctx.sepc += 4;
+        step(&mut ctx, &Op::Sret, false).unwrap();
+        assert_eq!(ctx.prv, 0);
+        assert_eq!(ctx.pc, 0x10004);
+        assert_eq!(ctx.registers[2], USER_SP);
+        assert_eq!(ctx.sscratch, KERNEL_SP);
+        assert_eq!(ctx.mscratch, 0x80000000);
+    }
+
+    #[test]
+    fn test_undelegated_trap() {
+        let mut ctx = Context::new(0);
+        ctx.prv = 1;
+        ctx.medeleg = 0xB35D & !(1 << 2);
+        ctx.mtvec = 0x8000_0000;
+        ctx.stvec = 0xffff_ffe0_0000_0000;
+        // Set MIE.
+        ctx.mstatus |= 0x8;
+
+        // Illegal instructions from S-mode are not delegated, so they trap into M-mode.
+        ctx.pc = 0x1000;
+        assert_eq!(step(&mut ctx, &Op::Illegal, false), Err(()));
+        enter_trap(&mut ctx);
+        assert_eq!(ctx.prv, 3);
+        assert_eq!(ctx.pc, 0x8000_0000);
+        assert_eq!((ctx.mcause, ctx.mepc), (2, 0x1000));
+        assert_eq!(ctx.scause, 0);
+        // MPP is S-mode, MPIE is set and MIE is cleared.
+        assert_eq!(ctx.mstatus & 0x1888, 0x880);
+
+        // Return to S-mode, restoring MIE.
+        ctx.mepc += 4;
+        step(&mut ctx, &Op::Mret, false).unwrap();
+        assert_eq!(ctx.prv, 1);
+        assert_eq!(ctx.pc, 0x1004);
+        assert_eq!(ctx.mstatus & 0x1888, 0x88);
+    }
+}
@@ -1,6 +1,10 @@
 use super::abi;
 use super::interp::Context;
-use rand::RngCore;
+use crate::config::ImageConfig;
+use crate::util::RoCell;
+use rand::{RngCore, SeedableRng};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::ffi::CStr;
 use std::fs::File;
 use std::io::Write;
@@ -13,12 +17,16 @@ const PF_X: u32 = 0x1;
 const ET_EXEC: libc::Elf64_Half = 2;
 const ET_DYN: libc::Elf64_Half = 3;
 const EM_RISCV: libc::Elf64_Half = 243;
+const SHT_SYMTAB: libc::Elf64_Word = 2;
+const SHN_UNDEF: libc::Elf64_Half = 0;
+const STT_FUNC: u8 = 2;
 
 #[repr(C)]
 pub struct Loader {
     fd: libc::c_int,
     file_size: libc::c_ulong,
     memory: *mut libc::c_void,
+    symbols: Symbols,
 }
 
 impl Drop for Loader {
@@ -48,6 +56,180 @@ impl<'a> Iterator for PhdrIter<'a> {
     }
 }
 
+/// Call `f` with each defined symbol in the symbol table of an ELF image and its name, until it
+/// returns `true`. Returns `None` if the image is malformed.
+fn for_each_symbol(elf: &[u8], mut f: impl FnMut(&libc::Elf64_Sym, &[u8]) -> bool) -> Option<()> {
+    fn read<T: Copy>(elf: &[u8], offset: usize) -> Option<T> {
+        let bytes = elf.get(offset..offset + std::mem::size_of::<T>())?;
+        Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
+
+    let ehdr: libc::Elf64_Ehdr = read(elf, 0)?;
+    let shdr = |idx: usize| -> Option<libc::Elf64_Shdr> {
+        read(elf, ehdr.e_shoff as usize + ehdr.e_shentsize as usize * idx)
+    };
+    for i in 0..(ehdr.e_shnum as usize) {
+        let symtab = shdr(i)?;
+        if symtab.sh_type != SHT_SYMTAB {
+            continue;
+        }
+        let strtab = shdr(symtab.sh_link as usize)?;
+        let strings =
+            elf.get(strtab.sh_offset as usize..(strtab.sh_offset + strtab.sh_size) as usize)?;
+        let entsize = std::cmp::max(symtab.sh_entsize, 1);
+        for j in 0..(symtab.sh_size / entsize) {
+            let sym: libc::Elf64_Sym = read(elf, (symtab.sh_offset + j * entsize) as usize)?;
+            if sym.st_shndx == SHN_UNDEF {
+                continue;
+            }
+            let name = strings.get(sym.st_name as usize..)?;
+            let name = &name[..name.iter().position(|&x| x == 0)?];
+            if f(&sym, name) {
+                return Some(());
+            }
+        }
+    }
+    Some(())
+}
+
+/// Look up a symbol in the symbol table of an ELF image and return its link-time address.
+fn find_symbol(elf: &[u8], name: &str) -> Option<u64> {
+    let mut addr = None;
+    for_each_symbol(elf, |sym, sym_name| {
+        if sym_name == name.as_bytes() {
+            addr = Some(sym.st_value);
+        }
+        addr.is_some()
+    })?;
+    addr
+}
+
+/// Function and object symbols of an ELF image, for showing addresses as `function+offset`.
+#[derive(Default)]
+pub struct Symbols {
+    /// Link-time address, size and name of each symbol, sorted by address.
+    symbols: Vec<(u64, u64, String)>,
+    /// Difference between run-time and link-time addresses.
+    bias: u64,
+}
+
+impl Symbols {
+    /// Read the symbol table of an ELF image. Images without one have no symbols.
+    pub fn parse(elf: &[u8]) -> Symbols {
+        let mut symbols = Vec::new();
+        for_each_symbol(elf, |sym, name| {
+            let ty = sym.st_info & 0xf;
+            // Skip section and file symbols, and mapping symbols and local labels of assemblers.
+            if ty <= STT_FUNC && !name.is_empty() && name[0] != b'$' && !name.starts_with(b".L") {
+                let name = String::from_utf8_lossy(name).into_owned();
+                symbols.push((sym.st_value, ty == STT_FUNC, sym.st_size, name));
+            }
+            false
+        });
+        Self::from_entries(symbols)
+    }
+
+    /// Build the table from the address, whether it is a function, size and name of symbols.
+    fn from_entries(mut symbols: Vec<(u64, bool, u64, String)>) -> Symbols {
+        // Of symbols at the same address, keep a function over other symbols, and then the largest.
+        symbols.sort_by(|a, b| {
+            a.0.cmp(&b.0).then(b.1.cmp(&a.1)).then(b.2.cmp(&a.2)).then_with(|| a.3.cmp(&b.3))
+        });
+        symbols.dedup_by_key(|sym| sym.0);
+        let symbols = symbols.into_iter().map(|(addr, _, size, name)| (addr, size, name)).collect();
+        Symbols { symbols, bias: 0 }
+    }
+
+    /// Set the load bias of the image, so run-time addresses can be described.
+    pub fn with_bias(mut self, bias: u64) -> Symbols {
+        self.bias = bias;
+        self
+    }
+
+    /// Find the symbol containing the link-time address `addr`, and return its name and the offset
+    /// of `addr` within it. Symbols without a size only match their exact address.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let idx = self.symbols.partition_point(|sym| sym.0 <= addr).checked_sub(1)?;
+        let (start, size, ref name) = self.symbols[idx];
+        let offset = addr - start;
+        if offset < size || offset == 0 {
+            Some((name, offset))
+        } else {
+            None
+        }
+    }
+
+    /// Format the run-time address `addr` as `function+0xoffset` if a symbol contains it, or in hex
+    /// otherwise.
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr.wrapping_sub(self.bias)) {
+            Some((name, 0)) => format!("{:x} <{}>", addr, name),
+            Some((name, offset)) => format!("{:x} <{}+{:#x}>", addr, name, offset),
+            None => format!("{:x}", addr),
+        }
+    }
+}
+
+/// Symbols of the program or kernel being run, used for diagnostics.
+pub static SYMBOLS: RoCell<Symbols> = RoCell::new(Symbols { symbols: Vec::new(), bias: 0 });
+
+/// Scan the bounds of the loadable segments of an ELF image, rounded to pages.
+unsafe fn segment_bounds(elf: &[u8]) -> (u64, u64) {
+    let ehdr = &*(elf.as_ptr() as *const libc::Elf64_Ehdr);
+    let mut loaddr = u64::max_value();
+    let mut hiaddr = 0;
+    for h in (PhdrIter { i: 0, ehdr }) {
+        if h.p_type == libc::PT_LOAD {
+            loaddr = std::cmp::min(loaddr, h.p_vaddr);
+            hiaddr = std::cmp::max(hiaddr, h.p_vaddr + h.p_memsz);
+        }
+    }
+    (loaddr & !4095, (hiaddr + 4095) & !4095)
+}
+
+/// Size of the memory an image occupies once loaded by [`load_images`].
+pub fn image_size(image: &[u8]) -> u64 {
+    if image.starts_with(b"\x7FELF") {
+        let (loaddr, hiaddr) = unsafe { segment_bounds(image) };
+        hiaddr.saturating_sub(loaddr)
+    } else {
+        image.len() as u64
+    }
+}
+
+/// Copy the loadable segments of an ELF image into memory, with its lowest page placed at
+/// `load_addr`. Returns the size of the loaded image and the relocated entry point.
+unsafe fn load_segments(elf: &[u8], load_addr: u64) -> (u64, u64) {
+    let ehdr = &*(elf.as_ptr() as *const libc::Elf64_Ehdr);
+    let phdr = || PhdrIter { i: 0, ehdr };
+    let (loaddr, hiaddr) = segment_bounds(elf);
+
+    for h in phdr() {
+        if h.p_type == libc::PT_LOAD {
+            // size in memory cannot be smaller than size in file
+            if h.p_filesz > h.p_memsz {
+                panic!("invalid elf file: constraint p_filesz <= p_memsz is not satisified");
+            }
+
+            // Copy across.
+            libc::memcpy(
+                (h.p_vaddr - loaddr + load_addr) as usize as _,
+                (elf.as_ptr() as usize + h.p_offset as usize) as _,
+                h.p_filesz as usize,
+            );
+
+            // Zero-out the rest
+            libc::memset(
+                (h.p_vaddr + h.p_filesz - loaddr + load_addr) as usize as _,
+                0,
+                (h.p_memsz - h.p_filesz) as usize,
+            );
+        }
+    }
+
+    (hiaddr - loaddr, ehdr.e_entry - loaddr + load_addr)
+}
+
 impl Loader {
     pub fn as_slice(&self) -> &[u8] {
         unsafe { std::slice::from_raw_parts(self.memory as *const u8, self.file_size as _) }
@@ -79,7 +261,12 @@ impl Loader {
         if memory == libc::MAP_FAILED {
             return Err(std::io::Error::last_os_error());
         }
-        Ok(Loader { fd: file.into_raw_fd(), file_size, memory })
+        let fd = file.into_raw_fd();
+        let mut loader = Loader { fd, file_size, memory, symbols: Symbols::default() };
+        if loader.is_elf() {
+            loader.symbols = Symbols::parse(loader.as_slice());
+        }
+        Ok(loader)
     }
 
     pub fn is_elf(&self) -> bool {
@@ -93,6 +280,22 @@ impl Loader {
         true
     }
 
+    /// Find the link-time address of a symbol in the ELF's symbol table.
+    pub fn find_symbol(&self, name: &str) -> Option<u64> {
+        find_symbol(self.as_slice(), name)
+    }
+
+    /// Find the symbol containing a link-time address, and return its name and the offset of the
+    /// address within it.
+    pub fn symbol_for_addr(&self, addr: u64) -> Option<(&str, u64)> {
+        self.symbols.lookup(addr)
+    }
+
+    /// Drop the loader, keeping only its symbols.
+    pub fn into_symbols(mut self) -> Symbols {
+        std::mem::take(&mut self.symbols)
+    }
+
     pub fn validate_elf(&self) -> Result<(), &'static str> {
         let header = self.ehdr();
 
@@ -270,46 +473,23 @@ impl Loader {
     }
 
     pub unsafe fn load_kernel(&self, load_addr: u64) -> u64 {
-        // Scan the bounds of the image.
-        let mut loaddr = u64::max_value();
-        let mut hiaddr = 0;
-        for h in self.phdr() {
-            if h.p_type == libc::PT_LOAD {
-                loaddr = std::cmp::min(loaddr, h.p_vaddr);
-                hiaddr = std::cmp::max(hiaddr, h.p_vaddr + h.p_memsz);
-            }
-        }
-
-        loaddr &= !4095;
-        hiaddr = (hiaddr + 4095) & !4095;
-
-        for h in self.phdr() {
-            if h.p_type == libc::PT_LOAD {
-                // size in memory cannot be smaller than size in file
-                if h.p_filesz > h.p_memsz {
-                    panic!("invalid elf file: constraint p_filesz <= p_memsz is not satisified");
-                }
+        load_segments(self.as_slice(), load_addr).0
+    }
 
-                // Copy across.
-                libc::memcpy(
-                    (h.p_vaddr - loaddr + load_addr) as usize as _,
-                    (self.memory as usize + h.p_offset as usize) as _,
-                    h.p_filesz as usize,
-                );
-
-                // Zero-out the rest
-                libc::memset(
-                    (h.p_vaddr + h.p_filesz - loaddr + load_addr) as usize as _,
-                    0,
-                    (h.p_memsz - h.p_filesz) as usize,
-                );
-            }
+    /// Difference between the run-time and link-time addresses of the image, given where its entry
+    /// point is loaded. Only position-independent images run where they are loaded; kernels linked
+    /// at fixed addresses run at their link-time addresses once paging is enabled.
+    fn load_bias(&self, entry: u64) -> u64 {
+        if self.ehdr().e_type == ET_DYN {
+            entry.wrapping_sub(self.ehdr().e_entry)
+        } else {
+            0
         }
-
-        hiaddr - loaddr
     }
 
-    unsafe fn load_elf(&self, sp: &mut u64) -> u64 {
+    /// Load the program and its interpreter, and set up auxillary vectors. Returns the address to
+    /// start execution at and the load bias of the program.
+    unsafe fn load_elf(&self, sp: &mut u64) -> (u64, u64) {
         let mut load_addr = 0;
         let mut brk = 0;
         let entry = self.load_image(&mut load_addr, &mut brk);
@@ -355,7 +535,7 @@ impl Loader {
         push(entry);
         push(abi::AT_ENTRY);
 
-        actual_entry
+        (actual_entry, self.load_bias(entry))
     }
 
     unsafe fn load_bin(&self, location: u64) {
@@ -363,11 +543,13 @@ impl Loader {
     }
 }
 
+/// Load the program or kernel, and set up harts to execute it. Returns its load bias, which is
+/// added to link-time addresses to find where they are at run time.
 pub unsafe fn load(
     file: &Loader,
     args: &mut dyn Iterator<Item = String>,
     ctxs: &mut [&mut Context],
-) {
+) -> u64 {
     if crate::get_flags().prv == 0 {
         // Set sp to be the highest possible address.
         let mut sp: u64 = 0x7fff0000;
@@ -392,8 +574,13 @@ pub unsafe fn load(
         let mut env_pointers = Vec::new();
         let mut arg_pointers = Vec::new();
 
+        let print_cmdline = crate::get_flags().print_cmdline;
+
         // Copy all environment variables into guest user space.
         for (var_k, var_v) in std::env::vars() {
+            if print_cmdline {
+                eprintln!("envp[{}] = {:?}", env_pointers.len(), format!("{}={}", var_k, var_v));
+            }
             sp_alloc(&mut sp, 1)[0] = 0;
             sp_alloc(&mut sp, var_v.len()).copy_from_slice(var_v.as_bytes());
             sp_alloc(&mut sp, 1)[0] = b'=';
@@ -403,6 +590,9 @@ pub unsafe fn load(
 
         // Copy all arguments into guest user space.
         for arg in args {
+            if print_cmdline {
+                eprintln!("argv[{}] = {:?}", arg_pointers.len(), arg);
+            }
             sp_alloc(&mut sp, 1)[0] = 0;
             sp_alloc(&mut sp, arg.len()).copy_from_slice(arg.as_bytes());
             arg_pointers.push(sp);
@@ -417,7 +607,11 @@ pub unsafe fn load(
         };
 
         // Random data
-        let mut rng = rand::rngs::OsRng;
+        let mut rng: Box<dyn RngCore> = if crate::get_flags().deterministic {
+            Box::new(rand::rngs::StdRng::seed_from_u64(crate::DETERMINISTIC_SEED))
+        } else {
+            Box::new(rand::rngs::OsRng)
+        };
         push(&mut sp, rng.next_u64());
         push(&mut sp, rng.next_u64());
         push(&mut sp, rng.next_u64());
@@ -429,7 +623,7 @@ pub unsafe fn load(
         push(&mut sp, abi::AT_NULL);
 
         // Initialize context, and set up ELF-specific auxillary vectors.
-        let start = file.load_elf(&mut sp);
+        let (start, bias) = file.load_elf(&mut sp);
 
         push(&mut sp, libc::getuid() as _);
         push(&mut sp, abi::AT_UID);
@@ -468,32 +662,294 @@ pub unsafe fn load(
         // libc adds this value into exit hook, so we need to make sure it is zero.
         ctx.registers[10] = 0;
         ctx.prv = 0;
+        bias
     } else {
-        let size = if file.is_elf() {
-            file.load_kernel(0x40000000)
+        let (size, bias) = if file.is_elf() {
+            let (size, entry) = load_segments(file.as_slice(), 0x40000000);
+            (size, file.load_bias(entry))
         } else {
             file.load_bin(0x40000000);
-            file.file_size
+            (file.file_size, 0)
         };
 
-        let device_tree = fdt::encode(&crate::emu::device_tree());
+        let device_tree = crate::emu::device_tree();
+        if let Some(ref path) = crate::get_flags().dump_dts {
+            std::fs::write(path, fdt::to_dts(&device_tree)).unwrap();
+        }
+        let device_tree = fdt::encode(&device_tree);
 
         if let Some(ref path) = crate::get_flags().dump_fdt {
             let mut file = File::create(path).unwrap();
             file.write_all(&device_tree).unwrap();
         }
 
+        if crate::get_flags().print_cmdline {
+            // Decode the blob so what gets printed is exactly what the guest will see.
+            let bootargs = fdt::decode(&device_tree)
+                .ok()
+                .and_then(|root| {
+                    let value = root.find_node("chosen")?.find_prop("bootargs")?;
+                    <&str>::try_from(value).ok().map(|x| x.to_owned())
+                })
+                .unwrap_or_default();
+            eprintln!("bootargs = {:?}", bootargs);
+        }
+
         let target =
             std::slice::from_raw_parts_mut((0x40000000 + size) as *mut u8, device_tree.len());
         target.copy_from_slice(&device_tree[..]);
 
         for ctx in ctxs {
-            // a0 is the current hartid
-            ctx.registers[10] = ctx.hartid;
-            // a1 should be the device tree
-            ctx.registers[11] = 0x40000000 + size;
-            ctx.pc = 0x40000000;
-            ctx.prv = 1;
+            enter_kernel(ctx, 0x40000000, 0x40000000 + size);
+        }
+        bias
+    }
+}
+
+/// Set up a hart's registers to start executing a supervisor-mode kernel at `entry`.
+fn enter_kernel(ctx: &mut Context, entry: u64, device_tree: u64) {
+    // a0 is the current hartid
+    ctx.registers[10] = ctx.mhartid;
+    // a1 should be the device tree
+    ctx.registers[11] = device_tree;
+    ctx.pc = entry;
+    ctx.prv = 1;
+}
+
+/// Set up a hart's registers to start executing machine-mode firmware at `entry`. The address
+/// the hart would otherwise start from is passed in `a2`.
+pub fn enter_firmware(ctx: &mut Context, entry: u64) {
+    ctx.registers[12] = ctx.pc;
+    ctx.pc = entry;
+    ctx.prv = 3;
+}
+
+/// Load additional images into memory, each given as its content and configuration. If one of
+/// them is the designated entry, all harts start executing it as firmware.
+pub unsafe fn load_images(images: &[(&[u8], &ImageConfig)], ctxs: &mut [&mut Context]) {
+    for &(image, config) in images {
+        let entry = if image.starts_with(b"\x7FELF") {
+            load_segments(image, config.addr).1
+        } else {
+            libc::memcpy(config.addr as usize as _, image.as_ptr() as _, image.len());
+            config.addr
+        };
+        if config.entry {
+            for ctx in ctxs.iter_mut() {
+                enter_firmware(ctx, entry);
+            }
+        }
+    }
+}
+
+/// Override register values of harts with those configured. `registers` must have been validated
+/// by [`Config::check_registers`].
+///
+/// [`Config::check_registers`]: crate::config::Config::check_registers
+pub fn set_initial_registers(ctxs: &mut [&mut Context], registers: &[BTreeMap<String, u64>]) {
+    for (ctx, registers) in ctxs.iter_mut().zip(registers) {
+        for (name, &value) in registers {
+            ctx.write_register_by_name(name, value).unwrap();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::interp;
+
+    #[test]
+    fn test_boot_hartid() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            core = 2
+            kernel = "vmlinux"
+            hartid = [4, 7]
+            boot_hart = 7
+            "#,
+        )
+        .unwrap();
+        let hartids = config.hartids().unwrap();
+        assert_eq!(hartids, [7, 4]);
+
+        // The boot hart is created first.
+        let mut ctx = Context::new(0);
+        ctx.mhartid = hartids[0];
+        enter_kernel(&mut ctx, 0x40000000, 0x40200000);
+        assert_eq!(ctx.registers[10], 7);
+        assert_eq!(ctx.read_csr_by_name("mhartid"), Ok(7));
+    }
+
+    #[test]
+    fn test_initial_registers() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            core = 2
+            kernel = "vmlinux"
+
+            [[registers]]
+            pc = 0x40001000
+            a0 = 0x1234
+            x11 = 0x5678
+            fp = 0x40100000
+            zero = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.check_registers(), Ok(()));
+
+        let mut ctx0 = Context::new(0);
+        let mut ctx1 = Context::new(1);
+        ctx1.mhartid = 1;
+        enter_kernel(&mut ctx0, 0x40000000, 0x40200000);
+        enter_kernel(&mut ctx1, 0x40000000, 0x40200000);
+        set_initial_registers(&mut [&mut ctx0, &mut ctx1], &config.registers);
+
+        // The first instruction executed by hart 0 sees the configured values.
+        assert_eq!(ctx0.pc, 0x40001000);
+        assert_eq!(ctx0.registers[0], 0);
+        assert_eq!(ctx0.registers[8], 0x40100000);
+        assert_eq!(ctx0.registers[10], 0x1234);
+        assert_eq!(ctx0.registers[11], 0x5678);
+
+        // Hart 1 has no configured values and is left as set up by the loader.
+        assert_eq!(ctx1.pc, 0x40000000);
+        assert_eq!(ctx1.registers[10], 1);
+        assert_eq!(ctx1.registers[11], 0x40200000);
+
+        let invalid: crate::config::Config =
+            toml::from_str("kernel = \"vmlinux\"\n[[registers]]\nx32 = 0").unwrap();
+        assert!(invalid.check_registers().is_err());
+    }
+
+    /// Build an ELF image with a single segment containing `data` at `vaddr`, followed by
+    /// `bss` zero bytes.
+    fn build_elf(vaddr: u64, entry: u64, data: &[u8], bss: u64) -> Vec<u8> {
+        let ehsize = std::mem::size_of::<libc::Elf64_Ehdr>();
+        let phsize = std::mem::size_of::<libc::Elf64_Phdr>();
+        let mut ehdr: libc::Elf64_Ehdr = unsafe { std::mem::zeroed() };
+        ehdr.e_ident[..4].copy_from_slice(b"\x7FELF");
+        ehdr.e_type = ET_EXEC;
+        ehdr.e_machine = EM_RISCV;
+        ehdr.e_entry = entry;
+        ehdr.e_phoff = ehsize as u64;
+        ehdr.e_phentsize = phsize as u16;
+        ehdr.e_phnum = 1;
+        let mut phdr: libc::Elf64_Phdr = unsafe { std::mem::zeroed() };
+        phdr.p_type = libc::PT_LOAD;
+        phdr.p_flags = PF_R | PF_X;
+        phdr.p_offset = (ehsize + phsize) as u64;
+        phdr.p_vaddr = vaddr;
+        phdr.p_filesz = data.len() as u64;
+        phdr.p_memsz = data.len() as u64 + bss;
+
+        let mut elf = vec![0u8; ehsize + phsize];
+        unsafe {
+            std::ptr::write_unaligned(elf.as_mut_ptr() as *mut libc::Elf64_Ehdr, ehdr);
+            std::ptr::write_unaligned(elf[ehsize..].as_mut_ptr() as *mut libc::Elf64_Phdr, phdr);
+        }
+        elf.extend_from_slice(data);
+        elf
+    }
+
+    #[test]
+    fn test_load_images() {
+        // Host memory stands in for guest physical memory.
+        let mut memory = vec![0xffu8; 0x3000];
+        let base = memory.as_mut_ptr() as u64;
+        let firmware = build_elf(0x80000000, 0x80000010, b"firmware", 8);
+        let kernel = build_elf(0xffffffe000200000, 0xffffffe000200000, b"kernel", 0);
+        let images = [
+            ImageConfig { path: "fw".into(), addr: base + 0x1000, entry: true },
+            ImageConfig { path: "vmlinux".into(), addr: base + 0x2000, entry: false },
+        ];
+
+        let mut ctx = Context::new(0);
+        enter_kernel(&mut ctx, base + 0x2000, 0);
+        unsafe {
+            load_images(&[(&firmware, &images[0]), (&kernel, &images[1])], &mut [&mut ctx]);
+        }
+        assert_eq!(&memory[0x1000..0x1010], b"firmware\0\0\0\0\0\0\0\0");
+        assert_eq!(&memory[0x2000..0x2006], b"kernel");
+        assert_eq!(memory[0x2006], 0xff);
+        assert_eq!(ctx.pc, base + 0x1010);
+        assert_eq!(ctx.prv, 3);
+        assert_eq!(ctx.registers[12], base + 0x2000);
+
+        // Other files are copied verbatim.
+        let dtb = ImageConfig { path: "dtb".into(), addr: base, entry: false };
+        unsafe { load_images(&[(&b"\xd0\x0d\xfe\xed"[..], &dtb)], &mut [&mut ctx]) };
+        assert_eq!(&memory[..5], b"\xd0\x0d\xfe\xed\xff");
+        assert_eq!(ctx.pc, base + 0x1010);
+    }
+
+    #[test]
+    fn test_check_images() {
+        let config = |addr: u64| -> crate::config::Config {
+            let config = format!(
+                "kernel = \"vmlinux\"\nmemory = 1\n[[image]]\npath = \"fw\"\naddr = {}",
+                addr
+            );
+            toml::from_str(&config).unwrap()
+        };
+        // An ELF image occupies whole pages, including its bss.
+        let size = image_size(&build_elf(0x80000000, 0x80000000, b"firmware", 0x1000));
+        assert_eq!(size, 0x2000);
+        assert_eq!(image_size(b"\xd0\x0d\xfe\xed"), 4);
+
+        // Memory spans 1MiB from 0x40000000.
+        assert!(config(0x40000000).check_images(&[size]).is_ok());
+        assert!(config(0x400fe000).check_images(&[size]).is_ok());
+        assert!(config(0x400ff000).check_images(&[size]).is_err());
+        assert!(config(0x3ffff000).check_images(&[size]).is_err());
+        assert!(config(0x400fe000).check_images(&[u64::max_value()]).is_err());
+    }
+
+    #[test]
+    fn test_find_symbol() {
+        // The test binary is itself an ELF, so look up two of our own functions in it. The binary
+        // may be position-independent, so compare the distance between them.
+        let elf = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let check_interrupt = find_symbol(&elf, "check_interrupt").unwrap();
+        let trap = find_symbol(&elf, "trap").unwrap();
+        let expected = (interp::check_interrupt as fn(&mut _) -> _ as usize)
+            .wrapping_sub(interp::trap as fn(&mut _) as usize);
+        assert_eq!(check_interrupt.wrapping_sub(trap), expected as u64);
+        assert_eq!(find_symbol(&elf, "check_interrup"), None);
+        assert_eq!(find_symbol(&elf, "nonexistent symbol"), None);
+    }
+
+    #[test]
+    fn test_symbol_for_addr() {
+        let elf = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let symbols = Symbols::parse(&elf);
+        let check_interrupt = find_symbol(&elf, "check_interrupt").unwrap();
+        assert_eq!(symbols.lookup(check_interrupt), Some(("check_interrupt", 0)));
+        assert_eq!(symbols.lookup(check_interrupt + 4), Some(("check_interrupt", 4)));
+        assert_eq!(
+            symbols.describe(check_interrupt + 4),
+            format!("{:x} <check_interrupt+0x4>", check_interrupt + 4)
+        );
+        assert_eq!(symbols.lookup(0), None);
+        assert_eq!(Symbols::default().describe(0x1234), "1234");
+    }
+
+    #[test]
+    fn test_symbol_preference() {
+        let symbols = Symbols::from_entries(vec![
+            (0x1000, false, 0x100, "object".to_owned()),
+            (0x1000, true, 0x10, "small".to_owned()),
+            (0x1000, true, 0x20, "large".to_owned()),
+            (0x2000, false, 0, "label".to_owned()),
+            (0x2000, false, 8, "sized".to_owned()),
+        ]);
+        assert_eq!(symbols.lookup(0x1018), Some(("large", 0x18)));
+        assert_eq!(symbols.lookup(0x2004), Some(("sized", 4)));
+
+        // Run-time addresses of relocated images are described by their link-time symbols.
+        let symbols = symbols.with_bias(0x4000);
+        assert_eq!(symbols.describe(0x5004), "5004 <large+0x4>");
+        assert_eq!(symbols.describe(0x1004), "1004");
+    }
+}
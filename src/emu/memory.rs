@@ -0,0 +1,93 @@
+//! Direct host access to guest physical memory.
+
+use crate::util::RoCell;
+use std::ops::Range;
+
+/// Guest physical range backed by host memory. Empty until the emulator is initialised.
+static RAM: RoCell<Range<u64>> = RoCell::new(0..0);
+
+/// Set the guest physical range backed by host memory.
+///
+/// # Safety
+/// The range must be mapped readable and writable for the rest of the program's lifetime, and
+/// this must not be called concurrently with any access.
+pub unsafe fn set_ram(range: Range<u64>) {
+    RoCell::replace(&RAM, range);
+}
+
+/// Fill the guest physical range `range` by repeating `pattern`, which must not be empty. The
+/// pattern is aligned to the start of the range.
+///
+/// # Safety
+/// The range must be mapped writable and must not be accessed concurrently.
+pub unsafe fn fill(range: Range<u64>, pattern: &[u8]) {
+    let mem = std::slice::from_raw_parts_mut(
+        range.start as usize as *mut u8,
+        (range.end - range.start) as usize,
+    );
+    for chunk in mem.chunks_mut(pattern.len()) {
+        chunk.copy_from_slice(&pattern[..chunk.len()]);
+    }
+}
+
+/// Get a host slice aliasing the guest physical range `gpa..gpa + len`.
+///
+/// This allows devices to transfer data to and from guest memory without an intermediate buffer.
+/// `None` is returned if the range is not entirely within RAM, e.g. it covers I/O memory or is
+/// out of bounds; callers should fall back to copying in that case.
+///
+/// The guest may access the memory concurrently, so the content should be treated as volatile.
+///
+/// # Safety
+/// Every call hands out a new mutable borrow of guest memory. The caller must ensure that slices
+/// of overlapping ranges are not in use at the same time.
+pub unsafe fn host_slice(gpa: u64, len: usize) -> Option<&'static mut [u8]> {
+    slice_within(&RAM, gpa, len)
+}
+
+/// Get a host slice aliasing the guest physical range `gpa..gpa + len`, if it is entirely within
+/// `ram`. See [`host_slice`].
+unsafe fn slice_within(ram: &Range<u64>, gpa: u64, len: usize) -> Option<&'static mut [u8]> {
+    let end = gpa.checked_add(len as u64)?;
+    if gpa < ram.start || end > ram.end {
+        return None;
+    }
+    Some(std::slice::from_raw_parts_mut(gpa as usize as *mut u8, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_slice_alias() {
+        let mut ram = vec![0u8; 8192];
+        let start = ram.as_mut_ptr() as u64;
+        let range = start..start + 8192;
+
+        let slice = unsafe { slice_within(&range, start + 4096, 16) }.unwrap();
+        slice.copy_from_slice(b"zero-copy write!");
+        assert_eq!(&ram[4096..4112], b"zero-copy write!");
+
+        unsafe {
+            assert!(slice_within(&range, start + 8000, 192).is_some());
+            assert!(slice_within(&range, start + 8000, 193).is_none());
+            assert!(slice_within(&range, start - 1, 16).is_none());
+            assert!(slice_within(&range, u64::max_value(), 2).is_none());
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut ram = vec![0u8; 8192];
+        let start = ram.as_mut_ptr() as u64;
+        unsafe { fill(start..start + 8191, &[0xde, 0xad, 0xbe]) };
+
+        // A write by the guest only affects the written bytes.
+        ram[4096..4100].copy_from_slice(&[0; 4]);
+        assert_eq!(&ram[..7], &[0xde, 0xad, 0xbe, 0xde, 0xad, 0xbe, 0xde]);
+        assert_eq!(&ram[4095..4101], &[0xde, 0, 0, 0, 0, 0xbe]);
+        // The pattern is truncated at the end, and memory outside the range is untouched.
+        assert_eq!(&ram[8187..], &[0xde, 0xad, 0xbe, 0xde, 0]);
+    }
+}
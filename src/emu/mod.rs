@@ -2,23 +2,36 @@ use std::collections::BTreeMap;
 
 use futures::future::BoxFuture;
 use io::hw::intc::{Clint, Plic};
+use io::hw::pci::PciHost;
+use io::hw::power::{Syscon, SysconAction};
 use io::hw::rtc::ZyncMp;
-use io::hw::virtio::{Block, Console, Mmio, Rng, P9};
+use io::hw::uart::Ns16550;
+use io::hw::virtio::{Block, Console, Mmio, Pci, Rng, P9};
 use io::{IoMemory, IrqPin};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
+use std::io::Write;
 use std::sync::Arc;
 use std::time::Duration;
 
 pub mod interp;
 #[rustfmt::skip]
 mod abi;
+pub mod control;
+pub mod control_flow;
 pub mod dbt;
-mod event;
+pub mod event;
+pub mod fault;
+pub mod gdbstub;
 pub mod loader;
+pub mod memory;
 pub mod signal;
+pub mod state;
 pub mod syscall;
+pub mod trace;
+pub mod wait;
 pub use event::EventLoop;
+pub use interp::disassemble_at;
 pub use syscall::syscall;
 
 struct DirectIoContext;
@@ -83,28 +96,92 @@ impl io::RuntimeContext for DirectIoContext {
     }
 }
 
-struct CoreIrq(usize, u64);
+/// Cycles between an interrupt level change and the hart observing it.
+static INTERRUPT_LATENCY: crate::util::RoCell<u64> = crate::util::RoCell::new(0);
+
+/// Interrupt line into `mip` of a hart.
+struct CoreIrq {
+    shared: &'static interp::SharedContext,
+    mask: u64,
+    latency: u64,
+}
+
+impl CoreIrq {
+    fn new(hartid: usize, mask: u64) -> CoreIrq {
+        CoreIrq { shared: crate::shared_context(hartid), mask, latency: *INTERRUPT_LATENCY }
+    }
+}
 
 impl IrqPin for CoreIrq {
     fn set_level(&self, level: bool) {
-        if level {
-            crate::shared_context(self.0).assert(self.1);
+        let shared = self.shared;
+        let mask = self.mask;
+        let deliver = move || {
+            if level {
+                shared.assert(mask);
+            } else {
+                shared.deassert(mask);
+            }
+        };
+        // Level changes are queued in order, and events of the same cycle fire in queueing order,
+        // so the hart observes them in the order they are made.
+        if self.latency == 0 {
+            deliver()
         } else {
-            crate::shared_context(self.0).deassert(self.1);
+            let event_loop = crate::event_loop();
+            event_loop.queue(event_loop.cycle() + self.latency, Box::new(deliver))
         }
     }
 }
 
+/// A PCI host bridge, and the interrupts of devices attached to it.
+struct PciBus {
+    host: Arc<PciHost>,
+    /// Base address of the configuration space.
+    base: usize,
+    /// Size of the memory window following the configuration space.
+    window: usize,
+    /// Device number and PLIC interrupt of each device.
+    irqs: Vec<(u32, u32)>,
+}
+
+/// Log of I/O memory accesses, enabled by `--trace-mmio`.
+struct MmioTrace {
+    out: Mutex<Box<dyn Write + Send>>,
+    /// Source of timestamps, which is `event_loop().time()` outside tests.
+    clock: fn() -> u64,
+}
+
+impl MmioTrace {
+    /// Log an access of `size` bytes at `addr`, which belongs to device `name` at `base`.
+    fn record(&self, write: bool, addr: usize, size: u32, value: u64, name: &str, base: usize) {
+        let kind = if write { 'w' } else { 'r' };
+        let time = (self.clock)();
+        let mut out = self.out.lock();
+        writeln!(out, "{} {} {:#x} {} {:#x} {}@{:#x}", time, kind, addr, size, value, name, base)
+            .unwrap();
+    }
+}
+
 /// This describes all I/O aspects of the system.
 struct IoSystem {
-    /// The IO memory map.
-    map: BTreeMap<usize, (usize, Arc<dyn IoMemory>)>,
+    /// The IO memory map. Each region has a size, the name of the device, and the device.
+    map: BTreeMap<usize, (usize, &'static str, Arc<dyn IoMemory>)>,
+
+    /// Log of all accesses to I/O memory, if enabled.
+    trace: Option<MmioTrace>,
 
     /// The PLIC instance. It always exist.
     plic: Arc<Plic>,
 
+    /// The PCI host bridge virtio devices are attached to, if any.
+    pci: Option<PciBus>,
+
     // Types below are useful only for initialisation
     next_irq: u32,
+    /// Next free phandle. Phandles up to core_count + 1 are taken by the CPU interrupt controllers
+    /// and the PLIC.
+    next_phandle: u32,
     boundary: usize,
 
     /// The "soc" node for
@@ -112,13 +189,13 @@ struct IoSystem {
 }
 
 impl IoSystem {
-    pub fn new() -> IoSystem {
-        assert_ne!(crate::get_flags().prv, 0);
-
+    /// Create the I/O system of a machine with `core_count` harts and the PLIC at `plic_base`.
+    pub fn new(core_count: usize, plic_base: usize) -> IoSystem {
         // Instantiate PLIC and corresponding device tre
-        let core_count = crate::core_count();
         let plic = Arc::new(Plic::new(
-            (0..core_count).map(|i| -> Box<dyn IrqPin> { Box::new(CoreIrq(i, 512)) }).collect(),
+            (0..core_count)
+                .map(|i| -> Box<dyn IrqPin> { Box::new(CoreIrq::new(i, 512)) })
+                .collect(),
         ));
 
         let mut soc = fdt::Node::new("soc");
@@ -127,12 +204,14 @@ impl IoSystem {
         soc.add_prop("#address-cells", 2u32);
         soc.add_prop("#size-cells", 2u32);
 
-        let plic_node = soc.add_node("plic@200000");
+        let plic_node = soc.add_node(format!("plic@{:x}", plic_base));
         plic_node.add_prop("#interrupt-cells", 1u32);
+        // Needed for the PCI interrupt map to refer to the PLIC.
+        plic_node.add_prop("#address-cells", 0u32);
         plic_node.add_prop("interrupt-controller", ());
         plic_node.add_prop("compatible", "sifive,plic-1.0.0");
         plic_node.add_prop("riscv,ndev", 31u32);
-        plic_node.add_prop("reg", &[0x200000u64, 0x400000][..]);
+        plic_node.add_prop("reg", &[plic_base as u64, 0x400000][..]);
         let mut vec: Vec<u32> = Vec::with_capacity(core_count * 2);
         for i in 0..(core_count as u32) {
             vec.push(i + 1);
@@ -143,31 +222,31 @@ impl IoSystem {
 
         let mut sys = IoSystem {
             map: BTreeMap::default(),
+            trace: None,
             plic: plic.clone(),
+            pci: None,
             next_irq: 1,
+            next_phandle: core_count as u32 + 2,
             boundary: 0x600000,
             fdt: soc,
         };
 
-        sys.register_io_mem(0x200000, 0x400000, plic);
-
-        if let Some(ref config) = crate::CONFIG.clint {
-            let base = config.io_base.unwrap_or_else(|| {
-                let mem = sys.boundary;
-                sys.boundary += 0x10000;
-                mem
-            });
-            sys.register_io_mem(base, 0x10000, Arc::new(&*CLINT));
-        }
+        sys.register_io_mem(plic_base, 0x400000, "plic", plic);
         sys
     }
 
-    pub fn register_io_mem(&mut self, base: usize, size: usize, mem: Arc<dyn IoMemory>) {
+    pub fn register_io_mem(
+        &mut self,
+        base: usize,
+        size: usize,
+        name: &'static str,
+        mem: Arc<dyn IoMemory>,
+    ) {
         if let Some((k, v)) = self.map.range(..(base + size)).next_back() {
             let last_end = *k + v.0;
             assert!(base >= last_end);
         }
-        self.map.insert(base, (size, mem));
+        self.map.insert(base, (size, name, mem));
     }
 
     /// Add a virtio device
@@ -178,12 +257,26 @@ impl IoSystem {
         let irq = self.next_irq;
         self.next_irq += 1;
 
+        let device = Box::new(f(self.plic.irq_pin(irq)));
+        self.attach_virtio(device, irq, crate::CONFIG.virtio_prefetch);
+    }
+
+    /// Attach a virtio device interrupting with `irq`. It uses the PCI transport if there is a
+    /// PCI host bridge, and the MMIO transport otherwise.
+    fn attach_virtio(&mut self, device: Box<dyn io::hw::virtio::Device>, irq: u32, prefetch: u16) {
+        if let Some(ref mut pci) = self.pci {
+            let virtio = Pci::new(Arc::new(DirectIoContext), device).with_prefetch(prefetch);
+            let slot = pci.host.add(Box::new(virtio));
+            pci.irqs.push((slot, irq));
+            return;
+        }
+
         let mem = self.boundary;
         self.boundary += 4096;
 
-        let device = Box::new(f(self.plic.irq_pin(irq)));
-        let virtio = Arc::new(Mutex::new(Mmio::new(Arc::new(DirectIoContext), device)));
-        self.register_io_mem(mem, 4096, virtio);
+        let virtio = Mmio::new(Arc::new(DirectIoContext), device).with_prefetch(prefetch);
+        let virtio = Arc::new(Mutex::new(virtio));
+        self.register_io_mem(mem, 4096, "virtio", virtio);
 
         let core_count = crate::core_count();
         let node = self.fdt.add_node(format!("virtio@{:x}", mem));
@@ -192,22 +285,94 @@ impl IoSystem {
         node.add_prop("interrupts-extended", &[core_count as u32 + 1, irq][..]);
     }
 
-    pub fn find_io_mem(&self, ptr: usize) -> Option<(usize, &'_ dyn IoMemory)> {
+    /// Add the device tree node of the PCI host bridge, if any. This must be called once all
+    /// devices are added, as the node describes their interrupts.
+    fn finish_pci(&mut self) {
+        let pci = match self.pci.take() {
+            None => return,
+            Some(pci) => pci,
+        };
+        let window = pci.base + io::hw::pci::ECAM_SIZE;
+        let node = self.fdt.add_node(format!("pci@{:x}", pci.base));
+        node.add_prop("compatible", "pci-host-ecam-generic");
+        node.add_prop("device_type", "pci");
+        node.add_prop("#address-cells", 3u32);
+        node.add_prop("#size-cells", 2u32);
+        node.add_prop("#interrupt-cells", 1u32);
+        node.add_prop("reg", &[pci.base as u64, io::hw::pci::ECAM_SIZE as u64][..]);
+        node.add_prop("bus-range", &[0u32, 0][..]);
+        node.add_prop("dma-coherent", ());
+        // The memory window is mapped at the same address on the bus, as 32-bit memory if it fits.
+        let space = if (window + pci.window) >> 32 == 0 { 0x02000000 } else { 0x03000000 };
+        let (hi, lo) = ((window >> 32) as u32, window as u32);
+        let (size_hi, size_lo) = ((pci.window >> 32) as u32, pci.window as u32);
+        node.add_prop("ranges", &[space, hi, lo, hi, lo, size_hi, size_lo][..]);
+        // Each device uses INTA, routed to its own PLIC interrupt.
+        let plic = crate::core_count() as u32 + 1;
+        node.add_prop("interrupt-map-mask", &[0xf800u32, 0, 0, 7][..]);
+        let mut map = Vec::with_capacity(pci.irqs.len() * 6);
+        for &(slot, irq) in pci.irqs.iter() {
+            map.extend_from_slice(&[slot << 11, 0, 0, 1, plic, irq]);
+        }
+        node.add_prop("interrupt-map", map.as_slice());
+    }
+
+    /// Find the device containing `ptr`, returning its base address, name and the device itself.
+    pub fn find_device(&self, ptr: usize) -> Option<(usize, &'static str, &'_ dyn IoMemory)> {
         if let Some((k, v)) = self.map.range(..=ptr).next_back() {
             let last_end = *k + v.0;
-            if ptr >= last_end { None } else { Some((*k, &*v.1)) }
+            if ptr >= last_end { None } else { Some((*k, v.1, &*v.2)) }
         } else {
             None
         }
     }
+
+    pub fn read(&self, addr: usize, size: u32) -> u64 {
+        match self.find_device(addr) {
+            Some((base, name, v)) => {
+                let value = v.read(addr - base, size);
+                if let Some(ref trace) = self.trace {
+                    trace.record(false, addr, size, value, name, base);
+                }
+                value
+            }
+            None => {
+                error!("out-of-bound I/O memory read 0x{:x}", addr);
+                0
+            }
+        }
+    }
+
+    pub fn write(&self, addr: usize, value: u64, size: u32) {
+        match self.find_device(addr) {
+            Some((base, name, v)) => {
+                if let Some(ref trace) = self.trace {
+                    trace.record(true, addr, size, value, name, base);
+                }
+                v.write(addr - base, value, size)
+            }
+            None => {
+                error!("out-of-bound I/O memory write 0x{:x} = 0x{:x}", addr, value);
+            }
+        }
+    }
 }
 
 static IO_SYSTEM: Lazy<IoSystem> = Lazy::new(|| {
-    let mut sys = IoSystem::new();
-    init_virtio(&mut sys);
-    if crate::CONFIG.rtc {
-        init_rtc(&mut sys);
+    assert_ne!(crate::get_flags().prv, 0);
+
+    let plic_base = crate::CONFIG.plic.io_base.unwrap_or(0x200000);
+    let mut sys = IoSystem::new(crate::core_count(), plic_base);
+    if let Some(ref path) = crate::get_flags().trace_mmio {
+        let file = std::io::LineWriter::new(std::fs::File::create(path).unwrap());
+        sys.trace =
+            Some(MmioTrace { out: Mutex::new(Box::new(file)), clock: || crate::event_loop().time() });
     }
+    // Devices are checked by `check_devices` before any is created.
+    for device in devices(&crate::CONFIG).unwrap() {
+        init_device(&mut sys, device);
+    }
+    sys.finish_pci();
     sys
 });
 
@@ -217,12 +382,12 @@ pub static CLINT: Lazy<Clint> = Lazy::new(|| {
         Arc::new(DirectIoContext),
         (0..core_count)
             .map(|i| -> Box<dyn IrqPin> {
-                Box::new(CoreIrq(i, if crate::get_flags().prv == 1 { 2 } else { 8 }))
+                Box::new(CoreIrq::new(i, if crate::get_flags().prv == 1 { 2 } else { 8 }))
             })
             .collect(),
         (0..core_count)
             .map(|i| -> Box<dyn IrqPin> {
-                Box::new(CoreIrq(i, if crate::get_flags().prv == 1 { 32 } else { 128 }))
+                Box::new(CoreIrq::new(i, if crate::get_flags().prv == 1 { 32 } else { 128 }))
             })
             .collect(),
     )
@@ -264,118 +429,293 @@ pub static CONSOLE: Lazy<io::serial::Console> = Lazy::new(|| {
         }
         None
     });
+    console.set_output_tap(wait::console_output);
     console
 });
 
 #[cfg(feature = "usernet")]
-fn init_network(sys: &mut IoSystem) {
+fn init_network(
+    sys: &mut IoSystem,
+    ty: NetworkType,
+    config: &crate::config::DeviceConfig<crate::config::NetworkConfig>,
+) {
     use io::hw::virtio::Network;
     use io::network::Usernet;
 
-    for config in crate::CONFIG.network.iter() {
-        let mac = eui48::MacAddress::parse_str(&config.config.mac).expect("unexpected mac address");
-        let usernet = Usernet::new(Arc::new(DirectIoContext));
-        for fwd in config.config.forward.iter() {
-            usernet
-                .add_host_forward(
-                    fwd.protocol == crate::config::ForwardProtocol::Udp,
-                    fwd.host_addr,
-                    fwd.host_port,
-                    fwd.guest_port,
-                )
-                .expect("cannot establish port forwarding");
+    let mac = eui48::MacAddress::parse_str(&config.config.mac).expect("unexpected mac address");
+    let usernet = Usernet::new(Arc::new(DirectIoContext));
+    for fwd in config.config.forward.iter() {
+        usernet
+            .add_host_forward(
+                fwd.protocol == crate::config::ForwardProtocol::Udp,
+                fwd.host_addr,
+                fwd.host_port,
+                fwd.guest_port,
+            )
+            .expect("cannot establish port forwarding");
+    }
+
+    match ty {
+        NetworkType::Virtio => {
+            sys.add_virtio(|irq| Network::new(Arc::new(DirectIoContext), irq, usernet, mac));
         }
+        NetworkType::XemacLite => {
+            let irq = sys.next_irq;
+            sys.next_irq += 1;
+
+            let base = match config.io_base {
+                None => {
+                    let mem = sys.boundary;
+                    sys.boundary += 0x2000;
+                    mem
+                }
+                Some(v) => v,
+            };
 
-        match config.config.r#type.as_str() {
-            "virtio" => {
-                sys.add_virtio(|irq| Network::new(Arc::new(DirectIoContext), irq, usernet, mac));
-            }
-            "xemaclite" => {
-                let irq = sys.next_irq;
-                sys.next_irq += 1;
-
-                let base = match config.io_base {
-                    None => {
-                        let mem = sys.boundary;
-                        sys.boundary += 0x2000;
-                        mem
-                    }
-                    Some(v) => v,
-                };
+            use io::hw::network::XemacLite;
+            let xemaclite =
+                XemacLite::new(Arc::new(DirectIoContext), sys.plic.irq_pin(irq), Box::new(usernet));
+            sys.register_io_mem(base, 0x2000, "xemaclite", Arc::new(xemaclite));
+            let core_count = crate::core_count();
+            sys.fdt.child.push(XemacLite::build_dt(
+                (base as u64, 0x2000),
+                (core_count as u32 + 1, irq),
+                mac.to_array(),
+            ));
+        }
+    }
+}
+
+#[cfg(not(feature = "usernet"))]
+fn init_network(
+    _sys: &mut IoSystem,
+    _ty: NetworkType,
+    _config: &crate::config::DeviceConfig<crate::config::NetworkConfig>,
+) {
+    unreachable!("network devices require the usernet feature")
+}
+
+/// Types of network adapters.
+enum NetworkType {
+    Virtio,
+    XemacLite,
+}
+
+/// Get the type of network adapter named `name`, or a message naming it if it is not supported.
+fn network_type(name: &str) -> Result<NetworkType, String> {
+    let ty = match name {
+        "virtio" => NetworkType::Virtio,
+        "xemaclite" => NetworkType::XemacLite,
+        _ => {
+            return Err(format!(
+                "unsupported network device type '{}', expected 'virtio' or 'xemaclite'",
+                name
+            ));
+        }
+    };
+    if !cfg!(feature = "usernet") {
+        return Err(format!("network device type '{}' requires the usernet feature", name));
+    }
+    Ok(ty)
+}
+
+/// A device requested by the configuration, with its type resolved.
+enum Device<'a> {
+    Clint(&'a crate::config::DeviceConfig<crate::config::ClintConfig>),
+    Pci(&'a crate::config::DeviceConfig<crate::config::PciConfig>),
+    Drive(&'a crate::config::DriveConfig),
+    Random(usize, &'a crate::config::RandomConfig),
+    Share(&'a crate::config::ShareConfig),
+    Network(NetworkType, &'a crate::config::DeviceConfig<crate::config::NetworkConfig>),
+    Console,
+    Rtc,
+    Uart,
+    Syscon(&'a crate::config::DeviceConfig<crate::config::SysconConfig>),
+}
+
+/// List the devices requested by `config` in the order they are created, or a message naming the
+/// first one that is not supported.
+fn devices(config: &crate::config::Config) -> Result<Vec<Device<'_>>, String> {
+    let mut devices = Vec::new();
+    devices.extend(config.clint.iter().map(Device::Clint));
+    // The host bridge must exist before virtio devices are attached to it.
+    devices.extend(config.pci.iter().map(Device::Pci));
+    devices.extend(config.drive.iter().map(Device::Drive));
+    devices.extend(config.random.iter().enumerate().map(|(i, config)| Device::Random(i, config)));
+    devices.extend(config.share.iter().map(Device::Share));
+    for network in config.network.iter() {
+        if eui48::MacAddress::parse_str(&network.config.mac).is_err() {
+            return Err(format!("invalid MAC address '{}'", network.config.mac));
+        }
+        let ty = network_type(&network.config.r#type)?;
+        devices.push(Device::Network(ty, network));
+    }
+    if config.console.virtio {
+        devices.push(Device::Console);
+    }
+    if config.rtc {
+        devices.push(Device::Rtc);
+    }
+    if config.console.uart {
+        devices.push(Device::Uart);
+    }
+    devices.extend(config.syscon.iter().map(Device::Syscon));
+    Ok(devices)
+}
+
+/// Check that all devices requested by `config` are supported, so unsupported ones are reported
+/// before any device is created.
+pub fn check_devices(config: &crate::config::Config) -> Result<(), String> {
+    devices(config).map(|_| ())
+}
 
-                use io::hw::network::XemacLite;
-                let xemaclite = XemacLite::new(
+/// Create a device and add it to the I/O system.
+fn init_device(sys: &mut IoSystem, device: Device) {
+    match device {
+        Device::Clint(config) => init_clint(sys, config),
+        Device::Pci(config) => init_pci(sys, config),
+        Device::Drive(config) => init_drive(sys, config),
+        Device::Random(index, config) => {
+            let source = random_source(config, index);
+            sys.add_virtio(|irq| Rng::new(Arc::new(DirectIoContext), irq, source));
+        }
+        Device::Share(config) => {
+            use io::fs::Passthrough;
+            sys.add_virtio(|irq| {
+                P9::new(
                     Arc::new(DirectIoContext),
-                    sys.plic.irq_pin(irq),
-                    Box::new(usernet),
-                );
-                sys.register_io_mem(base, 0x2000, Arc::new(xemaclite));
-                let core_count = crate::core_count();
-                sys.fdt.child.push(XemacLite::build_dt(
-                    (base as u64, 0x2000),
-                    (core_count as u32 + 1, irq),
-                    mac.to_array(),
-                ));
-            }
-            _ => panic!("unknown device type"),
+                    irq,
+                    &config.tag,
+                    Passthrough::new(&config.path).unwrap(),
+                )
+            });
+        }
+        Device::Network(ty, config) => init_network(sys, ty, config),
+        Device::Console => {
+            sys.add_virtio(|irq| {
+                Console::new(
+                    Arc::new(DirectIoContext),
+                    irq,
+                    Box::new(&*CONSOLE),
+                    crate::CONFIG.console.resize,
+                )
+            });
         }
+        Device::Rtc => init_rtc(sys),
+        Device::Uart => init_uart(sys),
+        Device::Syscon(config) => init_syscon(sys, config),
     }
 }
 
-#[cfg(not(feature = "usernet"))]
-fn init_network(_sys: &mut IoSystem) {}
-
-fn init_virtio(sys: &mut IoSystem) {
-    for config in crate::CONFIG.drive.iter() {
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(!config.shadow)
-            .open(&config.path)
-            .unwrap();
-        let file = io::block::File::new(file).unwrap();
-        let file: Box<dyn io::block::Block + Send> =
-            if config.shadow { Box::new(io::block::Shadow::new(file)) } else { Box::new(file) };
-        sys.add_virtio(|irq| Block::new(Arc::new(DirectIoContext), irq, file));
-    }
-
-    for config in crate::CONFIG.random.iter() {
-        sys.add_virtio(|irq| {
-            use io::entropy::rand::SeedableRng;
-            use io::entropy::{Entropy, Os, Seeded};
-            let source: Box<dyn Entropy + Send + 'static> = match config.r#type {
-                crate::config::RandomType::Pseudo => Box::new(Seeded::seed_from_u64(config.seed)),
-                crate::config::RandomType::OS => Box::new(Os),
-            };
-            Rng::new(Arc::new(DirectIoContext), irq, source)
-        });
+/// Replays a recording of entropy, exiting once the recording runs out as the run can no longer
+/// be reproduced.
+struct ReplayOrExit {
+    replay: io::entropy::Replay<std::io::BufReader<std::fs::File>>,
+    path: std::path::PathBuf,
+}
+
+impl io::entropy::Entropy for ReplayOrExit {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_le_bytes(buf)
     }
 
-    for config in crate::CONFIG.share.iter() {
-        use io::fs::Passthrough;
-        sys.add_virtio(|irq| {
-            P9::new(
-                Arc::new(DirectIoContext),
-                irq,
-                &config.tag,
-                Passthrough::new(&config.path).unwrap(),
-            )
-        });
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        if self.replay.try_fill_bytes(dest).is_err() {
+            eprintln!("{}: recorded entropy is exhausted", self.path.display());
+            std::process::exit(1);
+        }
     }
 
-    init_network(sys);
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), io::entropy::rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
 
-    if crate::CONFIG.console.virtio {
-        sys.add_virtio(|irq| {
-            Console::new(
-                Arc::new(DirectIoContext),
-                irq,
-                Box::new(&*CONSOLE),
-                crate::CONFIG.console.resize,
-            )
-        });
+/// Create the entropy source of the `index`-th random device.
+fn random_source(
+    config: &crate::config::RandomConfig,
+    index: usize,
+) -> Box<dyn io::entropy::Entropy + Send + 'static> {
+    use io::entropy::rand::SeedableRng;
+    use io::entropy::{Entropy, Os, Record, Replay, Seeded};
+    let file_or_exit = |path: &std::path::Path, file: std::io::Result<std::fs::File>| {
+        file.unwrap_or_else(|err| {
+            eprintln!("{}: {}", path.display(), err);
+            std::process::exit(1);
+        })
+    };
+    let source: Box<dyn Entropy + Send + 'static> = match (&config.replay, &config.r#type) {
+        (Some(path), _) => {
+            let file = file_or_exit(path, std::fs::File::open(path));
+            let replay = Replay::new(std::io::BufReader::new(file));
+            Box::new(ReplayOrExit { replay, path: path.clone() })
+        }
+        (None, crate::config::RandomType::OS) if !crate::get_flags().deterministic => Box::new(Os),
+        _ => Box::new(Seeded::seed_from_u64(config.seed(index))),
+    };
+    match config.record {
+        Some(ref path) => {
+            Box::new(Record::new(source, file_or_exit(path, std::fs::File::create(path))))
+        }
+        None => source,
     }
 }
 
+fn init_drive(sys: &mut IoSystem, config: &crate::config::DriveConfig) {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!config.shadow && !config.readonly)
+        .open(&config.path)
+        .unwrap();
+    let file = io::block::File::new(file).unwrap();
+    let file: Box<dyn io::block::Block + Send> = match config.format {
+        crate::config::DriveFormat::Raw => Box::new(file),
+        crate::config::DriveFormat::Qcow2 => {
+            Box::new(io::block::Qcow2::new(file).unwrap_or_else(|err| {
+                eprintln!("{}: {}", config.path.display(), err);
+                std::process::exit(1);
+            }))
+        }
+    };
+    let file: Box<dyn io::block::Block + Send> =
+        if config.shadow { Box::new(io::block::Shadow::new(file)) } else { file };
+    let id = match config.id {
+        Some(ref id) => id.clone(),
+        None => config.path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+    };
+    let cache_mode = match config.cache {
+        crate::config::CacheMode::Writeback => io::hw::virtio::CacheMode::Writeback,
+        crate::config::CacheMode::Writethrough => io::hw::virtio::CacheMode::Writethrough,
+        crate::config::CacheMode::None => io::hw::virtio::CacheMode::None,
+    };
+    sys.add_virtio(|irq| {
+        Block::new(Arc::new(DirectIoContext), irq, file)
+            .with_id(&id)
+            .with_cache_mode(cache_mode)
+            .with_readonly(config.readonly)
+    });
+}
+
+fn init_pci(sys: &mut IoSystem, config: &crate::config::DeviceConfig<crate::config::PciConfig>) {
+    let base = config.io_base.unwrap_or(0x30000000);
+    let window = config.config.window;
+    let host = Arc::new(PciHost::new());
+    let window_base = base + io::hw::pci::ECAM_SIZE;
+    sys.register_io_mem(base, io::hw::pci::ECAM_SIZE, "pci", host.clone());
+    let host_window = Arc::new(host.window(window_base as u64));
+    sys.register_io_mem(window_base, window, "pci-window", host_window);
+    sys.pci = Some(PciBus { host, base, window, irqs: Vec::new() });
+}
+
 fn init_rtc(sys: &mut IoSystem) {
     let irq = sys.next_irq;
     sys.next_irq += 2;
@@ -383,8 +723,11 @@ fn init_rtc(sys: &mut IoSystem) {
     let mem = sys.boundary;
     sys.boundary += 4096;
 
-    let rtc = Arc::new(ZyncMp::new(sys.plic.irq_pin(irq), sys.plic.irq_pin(irq + 1)));
-    sys.register_io_mem(mem, 4096, rtc);
+    let mut rtc = ZyncMp::new(sys.plic.irq_pin(irq), sys.plic.irq_pin(irq + 1));
+    if crate::get_flags().deterministic {
+        rtc = rtc.with_epoch(Arc::new(DirectIoContext), crate::wall_clock_epoch());
+    }
+    sys.register_io_mem(mem, 4096, "rtc", Arc::new(rtc));
 
     let node = sys.fdt.add_node(format!("rtc@{:x}", mem));
     node.add_prop("compatible", "xlnx,zynqmp-rtc");
@@ -395,6 +738,91 @@ fn init_rtc(sys: &mut IoSystem) {
     node.add_prop("interrupt-names", &["alarm", "sec"][..]);
 }
 
+fn init_uart(sys: &mut IoSystem) {
+    let irq = sys.next_irq;
+    sys.next_irq += 1;
+
+    let mem = sys.boundary;
+    sys.boundary += 4096;
+
+    let uart = Ns16550::new(Arc::new(DirectIoContext), sys.plic.irq_pin(irq), Box::new(&*CONSOLE));
+    sys.register_io_mem(mem, 4096, "uart", Arc::new(uart));
+
+    let node = sys.fdt.add_node(format!("serial@{:x}", mem));
+    node.add_prop("compatible", "ns16550a");
+    node.add_prop("reg", &[mem as u64, 0x100][..]);
+    node.add_prop("clock-frequency", 3686400u32);
+    let core_count = crate::core_count();
+    node.add_prop("interrupt-parent", core_count as u32 + 1);
+    node.add_prop("interrupts", irq);
+}
+
+fn init_clint(
+    sys: &mut IoSystem,
+    config: &crate::config::DeviceConfig<crate::config::ClintConfig>,
+) {
+    let base = config.io_base.unwrap_or_else(|| {
+        let mem = sys.boundary;
+        sys.boundary += 0x10000;
+        mem
+    });
+    sys.register_io_mem(base, 0x10000, "clint", Arc::new(&*CLINT));
+
+    let core_count = crate::core_count();
+    let node = sys.fdt.add_node(format!("clint@{:x}", base));
+    node.add_prop("compatible", "riscv,clint0");
+    node.add_prop("reg", &[base as u64, 0x10000][..]);
+    // Software and timer interrupts, at the same privilege level as the pins of `CLINT`.
+    let (msip, mtip) = if crate::get_flags().prv == 1 { (1, 5) } else { (3, 7) };
+    let mut vec: Vec<u32> = Vec::with_capacity(core_count * 4);
+    for i in 0..(core_count as u32) {
+        vec.extend_from_slice(&[i + 1, msip, i + 1, mtip]);
+    }
+    node.add_prop("interrupts-extended", vec.as_slice());
+}
+
+fn init_syscon(
+    sys: &mut IoSystem,
+    config: &crate::config::DeviceConfig<crate::config::SysconConfig>,
+) {
+    let base = config.io_base.unwrap_or_else(|| {
+        let mem = sys.boundary;
+        sys.boundary += 4096;
+        mem
+    });
+
+    let poweroff = config.config.poweroff;
+    let reboot = config.config.reboot;
+    let syscon = Syscon::new(poweroff, reboot, |action| {
+        match action {
+            SysconAction::Poweroff => info!("guest requested poweroff"),
+            // We cannot reset the guest, so treat reboot as poweroff.
+            SysconAction::Reboot => info!("guest requested reboot, exiting instead"),
+        }
+        crate::shutdown(crate::ExitReason::Exit(0));
+    });
+    sys.register_io_mem(base, 4096, "syscon", Arc::new(syscon));
+
+    let phandle = sys.next_phandle;
+    sys.next_phandle += 1;
+    let node = sys.fdt.add_node(format!("syscon@{:x}", base));
+    node.add_prop("compatible", "syscon");
+    node.add_prop("reg", &[base as u64, 0x1000][..]);
+    node.add_prop("phandle", phandle);
+
+    let node = sys.fdt.add_node("poweroff");
+    node.add_prop("compatible", "syscon-poweroff");
+    node.add_prop("regmap", phandle);
+    node.add_prop("offset", 0u32);
+    node.add_prop("value", poweroff);
+
+    let node = sys.fdt.add_node("reboot");
+    node.add_prop("compatible", "syscon-reboot");
+    node.add_prop("regmap", phandle);
+    node.add_prop("offset", 0u32);
+    node.add_prop("value", reboot);
+}
+
 /// This governs the boundary between RAM and I/O memory. If an address is strictly below this
 /// location, then it is considered I/O. For user-space applications, we consider all memory
 /// locations as RAM, so the default value here is 0.
@@ -404,10 +832,12 @@ pub fn init() {
     unsafe {
         // The memory map looks like this:
         // 0 MiB - 2 MiB (reserved for null)
-        // 2 MiB - 6 MiB PLIC
+        // 2 MiB - 6 MiB PLIC, unless configured elsewhere
         // 6 MiB -       VIRTIO
         // 1 GiB -       main memory
         crate::util::RoCell::replace(&IO_BOUNDARY, 0x40000000);
+        crate::util::RoCell::replace(&INTERRUPT_LATENCY, crate::CONFIG.interrupt_latency);
+        crate::sim::set_coherence_config(crate::CONFIG.coherence);
 
         // If firmware is present give it 2MiB of extra memory.
         let phys_size = (crate::CONFIG.memory
@@ -435,8 +865,19 @@ pub fn init() {
         if result != 0 {
             panic!("mmap failed while initing");
         }
+        memory::set_ram(0x40000000..phys_limit as u64);
+        if !crate::CONFIG.memory_fill.is_empty() {
+            memory::fill(0x40000000..phys_limit as u64, &crate::CONFIG.memory_fill);
+        }
     }
     Lazy::force(&IO_SYSTEM);
+    fault::init();
+}
+
+/// Add the node describing boot parameters to the device tree.
+fn add_chosen(root: &mut fdt::Node, config: &crate::config::Config) {
+    let chosen = root.add_node("chosen");
+    chosen.add_prop("bootargs", config.cmdline.as_str());
 }
 
 pub fn device_tree() -> fdt::Node {
@@ -446,8 +887,7 @@ pub fn device_tree() -> fdt::Node {
     root.add_prop("#address-cells", 2u32);
     root.add_prop("#size-cells", 2u32);
 
-    let chosen = root.add_node("chosen");
-    chosen.add_prop("bootargs", crate::CONFIG.cmdline.as_str());
+    add_chosen(&mut root, &crate::CONFIG);
 
     let cpus = root.add_node("cpus");
     cpus.add_prop("timebase-frequency", 1000000u32);
@@ -457,13 +897,21 @@ pub fn device_tree() -> fdt::Node {
     let core_count = crate::core_count() as u32;
 
     for i in 0..core_count {
-        let cpu = cpus.add_node(format!("cpu@{:x}", i));
+        let hartid = crate::hartid(i as usize) as u32;
+        let cpu = cpus.add_node(format!("cpu@{:x}", hartid));
         cpu.add_prop("clock-frequency", 0u32);
-        cpu.add_prop("mmu-type", "riscv,sv39");
-        cpu.add_prop("riscv,isa", "rv64imafdc");
+        // The largest paging mode satp accepts; Linux falls back to smaller modes by itself.
+        cpu.add_prop("mmu-type", "riscv,sv48");
+        // Sstc is only available when we run supervisor-level code directly.
+        let isa = if crate::get_flags().prv == 1 {
+            "rv64imafdc_zba_zbb_zbs_sstc"
+        } else {
+            "rv64imafdc_zba_zbb_zbs"
+        };
+        cpu.add_prop("riscv,isa", isa);
         cpu.add_prop("compatible", "riscv");
         cpu.add_prop("status", "okay");
-        cpu.add_prop("reg", i);
+        cpu.add_prop("reg", hartid);
         cpu.add_prop("device_type", "cpu");
 
         let intc = cpu.add_node("interrupt-controller");
@@ -482,6 +930,11 @@ pub fn device_tree() -> fdt::Node {
     root
 }
 
+/// Check whether a physical address belongs to I/O memory rather than RAM.
+pub fn is_io(addr: u64) -> bool {
+    addr < *IO_BOUNDARY as u64
+}
+
 // TODO: Remove these 2 functions
 pub fn read_memory<T: Copy>(addr: usize) -> T {
     assert!(addr >= *IO_BOUNDARY, "{:x} access out-of-bound", addr);
@@ -490,21 +943,338 @@ pub fn read_memory<T: Copy>(addr: usize) -> T {
 
 pub fn io_read(addr: usize, size: u32) -> u64 {
     assert!(addr < *IO_BOUNDARY, "{:x} access out-of-bound", addr);
-    match IO_SYSTEM.find_io_mem(addr) {
-        Some((base, v)) => v.read(addr - base, size),
-        None => {
-            error!("out-of-bound I/O memory read 0x{:x}", addr);
-            0
-        }
-    }
+    IO_SYSTEM.read(addr, size)
 }
 
 pub fn io_write(addr: usize, value: u64, size: u32) {
     assert!(addr < *IO_BOUNDARY, "{:x} access out-of-bound", addr);
-    match IO_SYSTEM.find_io_mem(addr) {
-        Some((base, v)) => v.write(addr - base, value, size),
-        None => {
-            error!("out-of-bound I/O memory write 0x{:x} = 0x{:x}", addr, value);
+    IO_SYSTEM.write(addr, value, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_plic_base() {
+        crate::testing::init_harts();
+        let sys = IoSystem::new(1, 0xc000000);
+        assert!(sys.find_device(0x200004).is_none());
+
+        // Accesses at the configured base reach the PLIC, e.g. the priority of interrupt 1.
+        let (base, name, plic) = sys.find_device(0xc000004).unwrap();
+        assert_eq!(base, 0xc000000);
+        assert_eq!(name, "plic");
+        assert_eq!(plic as *const dyn IoMemory as *const u8, Arc::as_ptr(&sys.plic) as *const u8);
+
+        let node = sys.fdt.find_node("plic@c000000").unwrap();
+        let reg = <Box<[u64]>>::try_from(node.find_prop("reg").unwrap()).unwrap();
+        assert_eq!(&*reg, &[0xc000000, 0x400000]);
+    }
+
+    #[test]
+    fn test_syscon() {
+        // Powering off shuts down the harts shared by all tests, so do it in a process of its own.
+        let path = concat!(module_path!(), "::test_syscon");
+        if let Some(status) = crate::testing::isolate(path) {
+            assert!(status.success());
+            return;
         }
+
+        crate::testing::init_harts();
+        let mut sys = IoSystem::new(crate::core_count(), 0xc000000);
+        let config: crate::config::DeviceConfig<crate::config::SysconConfig> =
+            toml::from_str("").unwrap();
+        init_syscon(&mut sys, &config);
+        init_syscon(&mut sys, &config);
+
+        fn str_prop<'a>(node: &'a fdt::Node, name: &str) -> &'a str {
+            <&str>::try_from(node.find_prop(name).unwrap()).unwrap()
+        }
+        fn u32_prop(node: &fdt::Node, name: &str) -> u32 {
+            u32::try_from(node.find_prop(name).unwrap()).unwrap()
+        }
+
+        // Each syscon gets a phandle of its own, following those of the harts and the PLIC.
+        for (i, &base) in [0x600000, 0x601000].iter().enumerate() {
+            let node = sys.fdt.find_node(&format!("syscon@{:x}", base)).unwrap();
+            assert_eq!(str_prop(node, "compatible"), "syscon");
+            let reg = <Box<[u64]>>::try_from(node.find_prop("reg").unwrap()).unwrap();
+            assert_eq!(&*reg, &[base as u64, 0x1000]);
+            assert_eq!(u32_prop(node, "phandle"), 4 + i as u32);
+        }
+        let nodes: Vec<_> = sys.fdt.child.iter().filter(|node| node.name == "poweroff").collect();
+        assert_eq!(nodes.len(), 2);
+        for (i, node) in nodes.into_iter().enumerate() {
+            assert_eq!(str_prop(node, "compatible"), "syscon-poweroff");
+            assert_eq!(u32_prop(node, "regmap"), 4 + i as u32);
+            assert_eq!(u32_prop(node, "offset"), 0);
+            assert_eq!(u32_prop(node, "value"), 0x5555);
+        }
+        let node = sys.fdt.find_node("reboot").unwrap();
+        assert_eq!(str_prop(node, "compatible"), "syscon-reboot");
+        assert_eq!(u32_prop(node, "value"), 0x7777);
+
+        // Writing the poweroff value exits.
+        sys.write(0x600000, 0x5555, 4);
+        assert!(matches!(*crate::EXIT_REASON.lock(), Some(crate::ExitReason::Exit(0))));
+        let alarm = crate::shared_context(0).alarm.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(alarm & 2, 2);
+    }
+
+    #[test]
+    fn test_pci() {
+        use io::hw::virtio::{Device, DeviceId, Queue};
+
+        crate::testing::init_harts();
+
+        /// A virtio block device without queues.
+        struct Dummy;
+
+        impl Device for Dummy {
+            fn device_id(&self) -> DeviceId {
+                DeviceId::Block
+            }
+            fn device_feature(&self) -> u32 {
+                0x1234
+            }
+            fn get_status(&self) -> u32 {
+                0
+            }
+            fn set_status(&mut self, _status: u32) {}
+            fn num_queues(&self) -> usize {
+                0
+            }
+            fn reset(&mut self) {}
+            fn queue_ready(&mut self, _idx: usize, _queue: Queue) {}
+        }
+
+        let mut sys = IoSystem::new(crate::core_count(), 0xc000000);
+        let config: crate::config::DeviceConfig<crate::config::PciConfig> =
+            toml::from_str("").unwrap();
+        init_pci(&mut sys, &config);
+        sys.attach_virtio(Box::new(Dummy), 5, 0);
+        sys.attach_virtio(Box::new(Dummy), 6, 0);
+
+        // Devices are attached to the bus instead of having MMIO registers.
+        assert!(sys.find_device(0x600000).is_none());
+        assert_eq!(sys.read(0x30000000, 4), 0x1042_1af4);
+        assert_eq!(sys.read(0x30008000, 4), 0x1042_1af4);
+        assert_eq!(sys.read(0x30010000, 4), 0xffff_ffff);
+
+        // Once BAR 0 of the second device is programmed, its structures appear in the window.
+        sys.write(0x30008010, 0x30104000, 4);
+        sys.write(0x30008014, 0, 4);
+        sys.write(0x30008004, 2, 2);
+        assert_eq!(sys.read(0x30104004, 4), 0x1234);
+
+        sys.finish_pci();
+        let node = sys.fdt.find_node("pci@30000000").unwrap();
+        assert_eq!(node.find_prop("compatible").unwrap().0[..], b"pci-host-ecam-generic\0"[..]);
+        let reg = <Box<[u64]>>::try_from(node.find_prop("reg").unwrap()).unwrap();
+        assert_eq!(&*reg, &[0x30000000, 0x100000]);
+        let ranges = <Box<[u32]>>::try_from(node.find_prop("ranges").unwrap()).unwrap();
+        assert_eq!(&*ranges, &[0x02000000, 0, 0x30100000, 0, 0x30100000, 0, 0x1000000]);
+        // INTA of each device is routed to its PLIC interrupt.
+        let plic = crate::core_count() as u32 + 1;
+        let map = <Box<[u32]>>::try_from(node.find_prop("interrupt-map").unwrap()).unwrap();
+        assert_eq!(&*map, &[0, 0, 0, 1, plic, 5, 0x800, 0, 0, 1, plic, 6]);
+    }
+
+    #[test]
+    fn test_trace_mmio() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        crate::testing::init_harts();
+
+        struct Scratch(AtomicU64);
+        impl IoMemory for Scratch {
+            fn read(&self, addr: usize, _size: u32) -> u64 {
+                self.0.load(Ordering::Relaxed) + addr as u64
+            }
+            fn write(&self, _addr: usize, value: u64, _size: u32) {
+                self.0.store(value, Ordering::Relaxed);
+            }
+        }
+
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let mut sys = IoSystem::new(1, 0xc000000);
+        sys.register_io_mem(0x600000, 4096, "scratch", Arc::new(Scratch(AtomicU64::new(0))));
+        sys.register_io_mem(0x601000, 4096, "other", Arc::new(Scratch(AtomicU64::new(0))));
+        sys.trace = Some(MmioTrace { out: Mutex::new(Box::new(buffer.clone())), clock: || 42 });
+
+        sys.write(0x600008, 0x1234, 4);
+        assert_eq!(sys.read(0x600010, 8), 0x1244);
+        sys.write(0x601000, 7, 1);
+        assert_eq!(sys.read(0x601004, 4), 11);
+        // Accesses outside all devices are not traced.
+        assert_eq!(sys.read(0x700000, 4), 0);
+
+        let trace = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "42 w 0x600008 4 0x1234 scratch@0x600000\n\
+             42 r 0x600010 8 0x1244 scratch@0x600000\n\
+             42 w 0x601000 1 0x7 other@0x601000\n\
+             42 r 0x601004 4 0xb other@0x601000\n"
+        );
+    }
+
+    #[test]
+    fn test_random_seeds() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            kernel = "vmlinux"
+
+            [[random]]
+            type = "pseudo"
+            seed = 1
+
+            [[random]]
+            type = "pseudo"
+            seed = 2
+
+            [[random]]
+            type = "pseudo"
+
+            [[random]]
+            type = "pseudo"
+            "#,
+        )
+        .unwrap();
+        let bytes = |index: usize| {
+            let mut buf = [0; 32];
+            random_source(&config.random[index], index).fill_bytes(&mut buf);
+            buf
+        };
+
+        // Streams are reproducible, and independent across devices even without explicit seeds.
+        assert_eq!(bytes(0), bytes(0));
+        assert_ne!(bytes(0), bytes(1));
+        assert_eq!(bytes(2), bytes(2));
+        assert_ne!(bytes(2), bytes(3));
+    }
+
+    #[test]
+    fn test_unsupported_device() {
+        let config = |ty: &str| -> crate::config::Config {
+            toml::from_str(&format!(
+                r#"
+                kernel = "vmlinux"
+
+                [[network]]
+                type = "{}"
+                "#,
+                ty
+            ))
+            .unwrap()
+        };
+        // Supported network adapters still need the user-mode network stack.
+        assert_eq!(check_devices(&config("xemaclite")).is_ok(), cfg!(feature = "usernet"));
+        assert_eq!(
+            check_devices(&config("e1000")),
+            Err("unsupported network device type 'e1000', expected 'virtio' or 'xemaclite'"
+                .to_owned())
+        );
+
+        let mut config = config("virtio");
+        config.network[0].config.mac = "02:00:00".to_owned();
+        assert_eq!(check_devices(&config), Err("invalid MAC address '02:00:00'".to_owned()));
+    }
+
+    #[test]
+    fn test_interrupt_latency() {
+        use std::cell::UnsafeCell;
+        use std::sync::atomic::Ordering;
+
+        let event_loop = crate::event_loop();
+        event_loop.set_threaded(false);
+        event_loop.advance_to(100);
+        let mut fiber = fiber::FiberContext::new(UnsafeCell::new(interp::Context::new(0)));
+        let ctx = unsafe { &mut *fiber.data::<UnsafeCell<interp::Context>>().get() };
+        // The hart's shared state is referenced by the IRQ pin, as `shared_context` does.
+        let shared = unsafe { &*(&ctx.shared as *const interp::SharedContext) };
+        let irq = CoreIrq { shared, mask: 512, latency: 30 };
+
+        fiber::FiberGroup::with(|group| {
+            group.spawn(&mut fiber, || {
+                // Supervisor external interrupts are delegated and enabled.
+                ctx.prv = 1;
+                ctx.mstatus |= 2;
+                ctx.mie = 512;
+                ctx.mideleg = 512;
+                ctx.stvec = 0x2000;
+                ctx.pc = 0x1000;
+                let mut taken_at = |cycle: u64| {
+                    event_loop.advance_to(cycle);
+                    interp::check_interrupt(ctx).unwrap();
+                    ctx.pc == 0x2000
+                };
+
+                // The trap is taken once the latency has elapsed.
+                irq.raise();
+                assert!(!taken_at(100));
+                assert!(!taken_at(129));
+                assert!(taken_at(130));
+                assert_eq!(ctx.scause, 1 << 63 | 9);
+                assert_eq!(ctx.sepc, 0x1000);
+            });
+        });
+
+        // A pulse is delayed as a whole, keeping its width.
+        let level = || shared.mip.load(Ordering::Relaxed) & 512 != 0;
+        irq.lower();
+        event_loop.advance_to(170);
+        irq.raise();
+        event_loop.advance_to(175);
+        irq.lower();
+        event_loop.advance_to(199);
+        assert!(!level());
+        event_loop.advance_to(200);
+        assert!(level());
+        event_loop.advance_to(204);
+        assert!(level());
+        event_loop.advance_to(205);
+        assert!(!level());
+
+        // Without latency, the level changes immediately.
+        let irq = CoreIrq { latency: 0, ..irq };
+        irq.raise();
+        assert!(level());
+    }
+
+    #[test]
+    fn test_append_cmdline() {
+        let mut config: crate::config::Config = toml::from_str(
+            r#"
+            kernel = "vmlinux"
+            cmdline = "console=hvc0 rw "
+            "#,
+        )
+        .unwrap();
+        config.append_cmdline(" loglevel=8  earlycon");
+        config.append_cmdline("");
+        config.append_cmdline("debug");
+
+        let mut root = fdt::Node::new("");
+        add_chosen(&mut root, &config);
+        let bootargs = root.find_node("chosen").unwrap().find_prop("bootargs").unwrap();
+        assert_eq!(
+            <&str>::try_from(bootargs).unwrap(),
+            "console=hvc0 rw loglevel=8 earlycon debug"
+        );
     }
 }
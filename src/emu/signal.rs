@@ -1,3 +1,8 @@
+use super::interp::Context;
+use riscv::mmu::{check_permission, walk_page, AccessType};
+use std::cell::UnsafeCell;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use x86::{Location, Memory, Op, Operand, Register, Size};
 
 const REG_RAX: usize = libc::REG_RAX as usize;
@@ -156,31 +161,50 @@ unsafe extern "C" fn handle_fpe(
     ctx.uc_mcontext.gregs[REG_RIP] = reader.0 as i64;
 }
 
-unsafe extern "C" fn handle_segv(
-    _: libc::c_int,
-    _: &mut libc::siginfo_t,
-    ctx: &mut libc::ucontext_t,
-) {
+/// Decode the instruction that caused a SIGSEGV or SIGBUS. If it is an access that can be replayed
+/// as an I/O memory access, return it, the memory operand and the address of the next instruction.
+unsafe fn faulting_access(ctx: &libc::ucontext_t) -> Option<(Op, Memory, usize)> {
     let current_ip = ctx.uc_mcontext.gregs[REG_RIP];
 
     // Decode the faulting instruction
     let mut reader = MemReader(current_ip as usize);
     let op = x86::decode(&mut reader.iter_func());
 
-    // Replay the read/write, as if they are accessing directly to guest physical memory
+    let mem = match op {
+        Op::Mov(Location::Reg(_), Operand::Mem(mem))
+        | Op::Movzx(_, Location::Mem(mem))
+        | Op::Mov(Location::Mem(mem), Operand::Reg(_))
+        | Op::Movsx(_, Location::Mem(mem)) => mem,
+        _ => return None,
+    };
+    Some((op, mem, reader.0))
+}
+
+/// Handle a SIGSEGV or SIGBUS. Accesses to I/O memory are replayed, as if they are accessing
+/// directly to guest physical memory. Returns false if the fault cannot be resolved that way, in
+/// which case the crashing hart is dumped to `out` with `window` bytes of memory around its PC.
+unsafe fn handle_fault(ctx: &mut libc::ucontext_t, out: &mut dyn Write, window: u64) -> bool {
+    let (op, mem, next_ip) = match faulting_access(ctx) {
+        Some((op, mem, next_ip)) if crate::emu::is_io(eval_memory_location(ctx, &mem) as u64) => {
+            (op, mem, next_ip)
+        }
+        _ => {
+            let _ = crash_dump(out, window);
+            return false;
+        }
+    };
+
+    let address = eval_memory_location(ctx, &mem);
     match op {
-        Op::Mov(Location::Reg(reg), Operand::Mem(mem)) | Op::Movzx(reg, Location::Mem(mem)) => {
-            let address = eval_memory_location(ctx, &mem);
+        Op::Mov(Location::Reg(reg), Operand::Mem(_)) | Op::Movzx(reg, Location::Mem(_)) => {
             let data = crate::emu::io_read(address, mem.size.bytes() as u32);
             write_location(ctx, &Location::Reg(reg), data);
         }
-        Op::Mov(Location::Mem(mem), Operand::Reg(reg)) => {
-            let address = eval_memory_location(ctx, &mem);
+        Op::Mov(Location::Mem(_), Operand::Reg(reg)) => {
             let data = read_location(ctx, &Location::Reg(reg));
             crate::emu::io_write(address, data, mem.size.bytes() as u32);
         }
-        Op::Movsx(reg, Location::Mem(mem)) => {
-            let address = eval_memory_location(ctx, &mem);
+        Op::Movsx(reg, Location::Mem(_)) => {
             let data = crate::emu::io_read(address, mem.size.bytes() as u32);
             let data = match mem.size {
                 Size::Qword => unreachable!(),
@@ -190,11 +214,156 @@ unsafe extern "C" fn handle_segv(
             };
             write_location(ctx, &Location::Reg(reg), data)
         }
-        _ => unimplemented!("{:x} {:?}", current_ip, op),
+        _ => unreachable!(),
     };
 
     // Advance to next ip.
-    ctx.uc_mcontext.gregs[REG_RIP] = reader.0 as i64;
+    ctx.uc_mcontext.gregs[REG_RIP] = next_ip as i64;
+    true
+}
+
+/// Output buffer on the stack, so crash dumps can be formatted without allocating in a signal
+/// handler. Output beyond its capacity is dropped.
+struct StackBuf {
+    data: [u8; 1024],
+    len: usize,
+}
+
+impl StackBuf {
+    fn new() -> Self {
+        StackBuf { data: [0; 1024], len: 0 }
+    }
+
+    /// Write out the buffered output with a single call, and empty the buffer.
+    fn flush_to(&mut self, out: &mut dyn Write) -> std::io::Result<()> {
+        let len = std::mem::replace(&mut self.len, 0);
+        out.write_all(&self.data[..len])
+    }
+}
+
+impl Write for StackBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.data.len() - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&buf[..len]);
+        self.len += len;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Standard error without the lock of `std::io::stderr`, which the crashing thread may hold.
+struct RawStderr;
+
+impl Write for RawStderr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let ret = unsafe { libc::write(libc::STDERR_FILENO, buf.as_ptr() as _, buf.len()) };
+        if ret < 0 { Err(std::io::Error::last_os_error()) } else { Ok(ret as usize) }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Set while a crash is being dumped, so a crash within the dump does not start another.
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
+/// Translate the virtual address of an instruction fetch by `ctx` without side effects. Unlike
+/// `Context::translate_vaddr`, page tables are only read within RAM, and PMP is not checked.
+fn translate_fetch(ctx: &Context, vaddr: u64) -> Option<u64> {
+    if (ctx.satp >> 60) == 0 || ctx.prv == 3 {
+        return Some(vaddr);
+    }
+    // An unreadable PTE reads as 0, which is invalid and ends the walk.
+    let pte = walk_page(ctx.satp, vaddr >> 12, |addr| {
+        unsafe { super::memory::host_slice(addr, 8) }
+            .map_or(0, |bytes| unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const u64) })
+    });
+    check_permission(pte, AccessType::Execute, ctx.prv as u8, ctx.mstatus).ok()?;
+    Some(pte >> 10 << 12 | vaddr & 4095)
+}
+
+/// Write the state of the hart running on the current thread, and `window` bytes of guest memory
+/// around its PC, for post-mortem debugging of a crash.
+///
+/// This is called from signal handlers, so it does not allocate or take locks, and each part of
+/// the dump is formatted on the stack before it is written out.
+fn crash_dump(out: &mut dyn Write, window: u64) -> std::io::Result<()> {
+    if DUMPING.swap(true, Ordering::Acquire) {
+        return Ok(());
+    }
+    let result = crash_dump_unguarded(out, window);
+    DUMPING.store(false, Ordering::Release);
+    result
+}
+
+fn crash_dump_unguarded(out: &mut dyn Write, window: u64) -> std::io::Result<()> {
+    let ctx = if fiber::in_fiber() {
+        fiber::try_with_context(|data: &UnsafeCell<Context>| data.get())
+    } else {
+        None
+    };
+    let mut buf = StackBuf::new();
+    let ctx = match ctx {
+        Some(ctx) => unsafe { &*ctx },
+        None => {
+            writeln!(buf, "crashed outside of a hart")?;
+            return buf.flush_to(out);
+        }
+    };
+
+    writeln!(buf, "hart {} crashed, prv = {}  instret = {}", ctx.hartid, ctx.prv, ctx.instret)?;
+    super::interp::write_registers(&mut buf, ctx)?;
+    buf.flush_to(out)?;
+    let start = ctx.pc.wrapping_sub(window / 2) & !15;
+    for vaddr in (0..window.div_ceil(16)).map(|i| start.wrapping_add(i * 16)) {
+        let paddr = translate_fetch(ctx, vaddr);
+        match paddr.and_then(|paddr| unsafe { super::memory::host_slice(paddr, 16) }) {
+            Some(bytes) => {
+                write!(buf, "{:16x}:", vaddr)?;
+                for byte in bytes.iter() {
+                    write!(buf, " {:02x}", byte)?;
+                }
+                writeln!(buf)?;
+            }
+            None => writeln!(buf, "{:16x}: not accessible", vaddr)?,
+        }
+        buf.flush_to(out)?;
+    }
+    Ok(())
+}
+
+/// Restore the default action of `sig` after dumping the crash, so that the crash proceeds as if
+/// the signal is not handled.
+unsafe fn reset_signal(sig: libc::c_int) {
+    let mut act: libc::sigaction = std::mem::zeroed();
+    act.sa_sigaction = libc::SIG_DFL;
+    libc::sigaction(sig, &act, std::ptr::null_mut());
+}
+
+unsafe extern "C" fn handle_segv(
+    sig: libc::c_int,
+    _: &mut libc::siginfo_t,
+    ctx: &mut libc::ucontext_t,
+) {
+    if !handle_fault(ctx, &mut RawStderr, crate::get_flags().crash_dump as u64) {
+        // Returning re-executes the faulting instruction, which now terminates the process.
+        reset_signal(sig);
+    }
+}
+
+/// Handle SIGABRT, e.g. from a panic, to dump the crashing hart before aborting.
+unsafe extern "C" fn handle_abrt(
+    sig: libc::c_int,
+    _: &mut libc::siginfo_t,
+    _: &mut libc::ucontext_t,
+) {
+    let _ = crash_dump(&mut RawStderr, crate::get_flags().crash_dump as u64);
+    reset_signal(sig);
+    libc::raise(sig);
 }
 
 /// Handle SIGINT to gracefully exit when hitting Ctrl+C in userspace-only simulation mode.
@@ -213,7 +382,53 @@ pub fn init() {
         libc::sigaction(libc::SIGSEGV, &act, std::ptr::null_mut());
         libc::sigaction(libc::SIGBUS, &act, std::ptr::null_mut());
 
+        act.sa_sigaction = handle_abrt as unsafe extern "C" fn(_, _, _) as usize;
+        libc::sigaction(libc::SIGABRT, &act, std::ptr::null_mut());
+
         act.sa_sigaction = handle_int as usize;
         libc::sigaction(libc::SIGINT, &act, std::ptr::null_mut());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crash_dump() {
+        // mov rax, [rbx]
+        let code: [u8; 3] = [0x48, 0x8b, 0x03];
+        let mut uc: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        uc.uc_mcontext.gregs[REG_RIP] = code.as_ptr() as i64;
+        uc.uc_mcontext.gregs[libc::REG_RBX as usize] = 0x1000;
+
+        // The access could be replayed if it were to I/O memory.
+        let (_, _, next_ip) = unsafe { faulting_access(&uc) }.unwrap();
+        assert_eq!(next_ip, code.as_ptr() as usize + 3);
+
+        // But there is no I/O memory here, so it is a genuine crash within a hart.
+        let mut fiber = fiber::FiberContext::new(UnsafeCell::new(Context::new(0)));
+        let mut out = Vec::new();
+        fiber::FiberGroup::with(|group| {
+            group.spawn(&mut fiber, || {
+                fiber::with_context(|data: &UnsafeCell<Context>| unsafe {
+                    (*data.get()).pc = 0x80001008;
+                });
+                assert!(!unsafe { handle_fault(&mut uc, &mut out, 32) });
+            });
+        });
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("hart 0 crashed"), "{}", out);
+        assert!(out.contains("pc  =         80001008"), "{}", out);
+        assert!(out.contains("        80000ff0: not accessible"), "{}", out);
+        assert!(out.contains("        80001000: not accessible"), "{}", out);
+        assert_eq!(uc.uc_mcontext.gregs[REG_RIP], code.as_ptr() as i64);
+
+        // A crash while dumping, e.g. from a fault within the dump, does not dump again.
+        DUMPING.store(true, Ordering::Relaxed);
+        let mut out = Vec::new();
+        assert!(!unsafe { handle_fault(&mut uc, &mut out, 32) });
+        assert!(out.is_empty());
+        DUMPING.store(false, Ordering::Relaxed);
+    }
+}
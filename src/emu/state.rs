@@ -0,0 +1,179 @@
+//! Human-editable dump of the register state of harts.
+//!
+//! The state is represented as TOML, with one `[[hart]]` table per hart. Values are written as
+//! hexadecimal strings, as TOML integers cannot represent all 64-bit values. When loading, any
+//! register or CSR can be omitted to leave it unchanged, and plain integers are also accepted.
+
+use super::interp::Context;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// CSRs included in a dump. Counters, `mip` and read-only CSRs are not part of the state.
+const CSRS: [&str; 18] = [
+    "fcsr",
+    "mstatus",
+    "medeleg",
+    "mideleg",
+    "mie",
+    "mtvec",
+    "mcounteren",
+    "mscratch",
+    "mepc",
+    "mcause",
+    "mtval",
+    "stvec",
+    "scounteren",
+    "sscratch",
+    "sepc",
+    "scause",
+    "stval",
+    "satp",
+];
+
+/// A 64-bit value, serialized as a hexadecimal string.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Hex(u64);
+
+impl Serialize for Hex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:#x}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Int(u64),
+            Str(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Int(value) => Ok(Hex(value)),
+            Repr::Str(text) => {
+                let value = match text.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => text.parse(),
+                };
+                value
+                    .map(Hex)
+                    .map_err(|_| serde::de::Error::custom(format!("invalid value {}", text)))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct HartState {
+    pc: Option<Hex>,
+    prv: Option<u64>,
+    #[serde(default)]
+    registers: BTreeMap<String, Hex>,
+    #[serde(default)]
+    fp_registers: BTreeMap<String, Hex>,
+    #[serde(default)]
+    csrs: BTreeMap<String, Hex>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    hart: Vec<HartState>,
+}
+
+/// Dump the registers and CSRs of the given harts.
+pub fn dump_regs(ctxs: &mut [&mut Context]) -> String {
+    let hart = ctxs
+        .iter_mut()
+        .map(|ctx| HartState {
+            pc: Some(Hex(ctx.pc)),
+            prv: Some(ctx.prv),
+            registers: (1..32)
+                .map(|i| (riscv::register_name(i).to_owned(), Hex(ctx.registers[i as usize])))
+                .collect(),
+            fp_registers: (0..32).map(|i| (format!("f{}", i), Hex(ctx.fp_registers[i]))).collect(),
+            csrs: CSRS
+                .iter()
+                .map(|&name| (name.to_owned(), Hex(ctx.read_csr_by_name(name).unwrap())))
+                .collect(),
+        })
+        .collect();
+    toml::to_string(&State { hart }).unwrap()
+}
+
+/// Load registers and CSRs produced by [`dump_regs`] into the given harts. Harts, registers and
+/// CSRs not mentioned are left unchanged.
+pub fn load_regs(ctxs: &mut [&mut Context], text: &str) -> Result<(), String> {
+    let state: State = toml::from_str(text).map_err(|err| err.to_string())?;
+    if state.hart.len() > ctxs.len() {
+        return Err(format!(
+            "state of {} harts given but there are only {}",
+            state.hart.len(),
+            ctxs.len()
+        ));
+    }
+    for (ctx, hart) in ctxs.iter_mut().zip(state.hart.iter()) {
+        if let Some(Hex(pc)) = hart.pc {
+            ctx.pc = pc;
+        }
+        if let Some(prv) = hart.prv {
+            if prv != 0 && prv != 1 && prv != 3 {
+                return Err(format!("invalid privilege level {}", prv));
+            }
+            ctx.prv = prv;
+        }
+        for (name, &Hex(value)) in hart.registers.iter() {
+            ctx.write_register_by_name(name, value)
+                .map_err(|_| format!("unknown register {}", name))?;
+        }
+        for (name, &Hex(value)) in hart.fp_registers.iter() {
+            let index = name
+                .strip_prefix('f')
+                .and_then(|num| num.parse::<usize>().ok())
+                .filter(|&index| index < 32)
+                .ok_or_else(|| format!("unknown floating point register {}", name))?;
+            ctx.fp_registers[index] = value;
+        }
+        for (name, &Hex(value)) in hart.csrs.iter() {
+            if !CSRS.contains(&name.as_str()) {
+                return Err(format!("unsupported CSR {}", name));
+            }
+            ctx.write_csr_by_name(name, value).map_err(|_| format!("cannot write CSR {}", name))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regs_round_trip() {
+        let mut ctx = Context::new(0);
+        ctx.pc = 0xffffffe000200000;
+        ctx.prv = 1;
+        ctx.registers[10] = 0x1234;
+        ctx.fp_registers[3] = 0x3ff0000000000000;
+        ctx.write_csr_by_name("stvec", 0xffffffe000001000).unwrap();
+        let text = dump_regs(&mut [&mut ctx]);
+
+        // Edit the dump as a test fixture would.
+        let text = text.replace("a0 = \"0x1234\"", "a0 = \"0x5678\"");
+        let mut other = Context::new(0);
+        load_regs(&mut [&mut other], &text).unwrap();
+        assert_eq!(other.pc, ctx.pc);
+        assert_eq!(other.prv, 1);
+        assert_eq!(other.registers[10], 0x5678);
+        assert_eq!(other.registers[1..10], ctx.registers[1..10]);
+        assert_eq!(other.fp_registers, ctx.fp_registers);
+        assert_eq!(other.stvec, 0xffffffe000001000);
+        assert_eq!(dump_regs(&mut [&mut other]), text);
+
+        // Partial state only changes what is given.
+        load_regs(&mut [&mut other], "[[hart]]\nregisters = { sp = 16 }").unwrap();
+        assert_eq!(other.registers[2], 16);
+        assert_eq!(other.registers[10], 0x5678);
+        assert!(load_regs(&mut [&mut other], "[[hart]]\ncsrs = { mip = 0 }").is_err());
+    }
+}
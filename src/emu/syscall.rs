@@ -3,7 +3,7 @@ use std::borrow::Cow;
 use std::ffi::{CStr, CString};
 use std::fmt::{self, Write};
 use std::path::Path;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 use super::abi;
 
@@ -14,10 +14,7 @@ static mut HEAP_END: u64 = 0;
 
 /// The reference point when the user space asks for the current time. This is to allow
 /// user-space applications to do timing properly in lockstep mode.
-static EPOCH: Lazy<Duration> = Lazy::new(|| {
-    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap()
-        - Duration::from_micros(crate::event_loop().time())
-});
+static EPOCH: Lazy<Duration> = Lazy::new(crate::wall_clock_epoch);
 
 #[inline]
 fn strace() -> bool {
@@ -0,0 +1,131 @@
+//! Execution tracing, enabled by `--trace`.
+//!
+//! Each executed instruction produces a 24-byte little-endian record:
+//!
+//! | Offset | Size | Content                                                    |
+//! |--------|------|------------------------------------------------------------|
+//! | 0      | 8    | PC                                                         |
+//! | 8      | 4    | Raw instruction bits; compressed instructions use 16 bits  |
+//! | 12     | 1    | Hart index                                                 |
+//! | 13     | 1    | Index of the GPR written, or 0 if none changed             |
+//! | 14     | 2    | Reserved, zero                                             |
+//! | 16     | 8    | Value written to the GPR                                   |
+//!
+//! Records of all harts are interleaved in chunks, but records of each hart are in program order.
+//! An instruction is recorded once the next one starts, as only then its effects are known. An
+//! instruction raising an exception does not retire and is not recorded, so a hart has exactly one
+//! record per retired instruction.
+//! Use [`decode`] to read a trace back.
+
+use parking_lot::Mutex;
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+/// Size of a record in bytes.
+pub const RECORD_SIZE: usize = 24;
+
+/// Number of records buffered by each hart before they are written out.
+const BUFFER_RECORDS: usize = 4096;
+
+/// A decoded trace record.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Record {
+    pub pc: u64,
+    pub bits: u32,
+    pub hartid: u8,
+    /// The GPR written and its new value, if any.
+    pub write: Option<(u8, u64)>,
+}
+
+/// Per-hart execution tracer.
+pub struct Tracer {
+    hartid: u8,
+    /// Records not yet written out. Preallocated so tracing does not allocate per instruction.
+    buffer: Vec<u8>,
+    out: Arc<Mutex<dyn Write + Send>>,
+    /// PC and bits of the instruction executing, to be recorded when the next one starts.
+    pending: Option<(u64, u32)>,
+    /// GPRs before the pending instruction is executed.
+    registers: [u64; 32],
+}
+
+impl Tracer {
+    pub fn new(hartid: usize, out: Arc<Mutex<dyn Write + Send>>) -> Tracer {
+        Tracer {
+            hartid: hartid as u8,
+            buffer: Vec::with_capacity(BUFFER_RECORDS * RECORD_SIZE),
+            out,
+            pending: None,
+            registers: [0; 32],
+        }
+    }
+
+    /// Record the previous instruction of the hart, and remember the one at `pc` for the next
+    /// call. `registers` are the GPRs before the instruction at `pc` executes.
+    pub fn before_instruction(&mut self, registers: &[u64; 32], pc: u64, bits: u32) {
+        self.record_pending(registers);
+        self.pending = Some((pc, bits));
+        self.registers = *registers;
+    }
+
+    /// Drop the pending instruction if it is the one at `pc`, as it raised an exception instead of
+    /// retiring.
+    pub fn discard(&mut self, pc: u64) {
+        if let Some((pending_pc, _)) = self.pending {
+            if pending_pc == pc {
+                self.pending = None;
+            }
+        }
+    }
+
+    fn record_pending(&mut self, registers: &[u64; 32]) {
+        let (pc, bits) = match self.pending.take() {
+            Some(v) => v,
+            None => return,
+        };
+        let (reg, value) = (1..32)
+            .find(|&i| registers[i] != self.registers[i])
+            .map_or((0, 0), |i| (i as u8, registers[i]));
+
+        let mut record = [0; RECORD_SIZE];
+        record[0..8].copy_from_slice(&pc.to_le_bytes());
+        record[8..12].copy_from_slice(&bits.to_le_bytes());
+        record[12] = self.hartid;
+        record[13] = reg;
+        record[16..24].copy_from_slice(&value.to_le_bytes());
+        self.buffer.extend_from_slice(&record);
+        if self.buffer.len() == self.buffer.capacity() {
+            self.write_out().unwrap();
+        }
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        self.out.lock().write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Record the last instruction, with `registers` being the current GPRs, and write all
+    /// buffered records out.
+    pub fn flush(&mut self, registers: &[u64; 32]) -> io::Result<()> {
+        self.record_pending(registers);
+        self.write_out()?;
+        self.out.lock().flush()
+    }
+}
+
+/// Decode a trace. Trailing bytes not forming a complete record are ignored.
+pub fn decode(trace: &[u8]) -> impl Iterator<Item = Record> + '_ {
+    trace.chunks_exact(RECORD_SIZE).map(|record| {
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(record[offset..offset + 8].try_into().unwrap())
+        };
+        Record {
+            pc: u64_at(0),
+            bits: u32::from_le_bytes(record[8..12].try_into().unwrap()),
+            hartid: record[12],
+            write: if record[13] == 0 { None } else { Some((record[13], u64_at(16))) },
+        }
+    })
+}
@@ -0,0 +1,151 @@
+//! Waiting for the guest to reach a condition, for orchestrating guest runs from host code.
+
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often memory is polled for [`Condition::Memory`].
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A guest condition to wait for.
+pub enum Condition {
+    /// A hart reaches the given virtual address. Only one address can be waited for at a time.
+    Pc(u64),
+    /// The guest prints the given bytes to the console.
+    Console(Vec<u8>),
+    /// The 64-bit word at the given guest physical address has the given value.
+    Memory { addr: u64, value: u64 },
+}
+
+struct Waiter {
+    condition: Condition,
+    state: Mutex<WaiterState>,
+    condvar: Condvar,
+}
+
+struct WaiterState {
+    satisfied: bool,
+    /// Console output that may still be the start of the pattern.
+    console: Vec<u8>,
+}
+
+impl Waiter {
+    fn satisfy(&self, state: &mut WaiterState) {
+        state.satisfied = true;
+        self.condvar.notify_all();
+    }
+
+    fn memory_matches(&self) -> bool {
+        match self.condition {
+            Condition::Memory { addr, value } => unsafe { super::memory::host_slice(addr, 8) }
+                .map_or(false, |slice| u64::from_le_bytes((&*slice).try_into().unwrap()) == value),
+            _ => false,
+        }
+    }
+}
+
+static WAITERS: Lazy<Mutex<Vec<Arc<Waiter>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Virtual address waited for by [`Condition::Pc`], or `u64::MAX` if there is none. A block must
+/// start at this address so that it is seen by `find_block`.
+pub static WATCH_PC: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Block until `condition` is satisfied or `timeout` elapses. Returns whether the condition is
+/// satisfied. Only console output printed and addresses reached after the call are considered.
+pub fn wait_for(condition: Condition, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let pc = match condition {
+        Condition::Pc(pc) => Some(pc),
+        _ => None,
+    };
+    let waiter = Arc::new(Waiter {
+        condition,
+        state: Mutex::new(WaiterState { satisfied: false, console: Vec::new() }),
+        condvar: Condvar::new(),
+    });
+    WAITERS.lock().push(waiter.clone());
+    if let Some(pc) = pc {
+        WATCH_PC.store(pc, Ordering::Relaxed);
+        // Code already translated may contain the address in the middle of a block.
+        super::interp::icache_invalidate_all();
+    }
+
+    let mut state = waiter.state.lock();
+    let satisfied = loop {
+        if state.satisfied || waiter.memory_matches() {
+            break true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            break false;
+        }
+        waiter.condvar.wait_for(&mut state, (deadline - now).min(POLL_INTERVAL));
+    };
+    drop(state);
+
+    if pc.is_some() {
+        WATCH_PC.store(u64::MAX, Ordering::Relaxed);
+    }
+    WAITERS.lock().retain(|w| !Arc::ptr_eq(w, &waiter));
+    satisfied
+}
+
+/// Notify waiters that a hart has reached `pc`.
+pub fn reached_pc(pc: u64) {
+    for waiter in WAITERS.lock().iter() {
+        if let Condition::Pc(target) = waiter.condition {
+            if target == pc {
+                waiter.satisfy(&mut waiter.state.lock());
+            }
+        }
+    }
+}
+
+/// Notify waiters of bytes printed to the console.
+pub fn console_output(data: &[u8]) {
+    for waiter in WAITERS.lock().iter() {
+        if let Condition::Console(ref pattern) = waiter.condition {
+            let mut state = waiter.state.lock();
+            if state.satisfied {
+                continue;
+            }
+            state.console.extend_from_slice(data);
+            if pattern.is_empty()
+                || state.console.windows(pattern.len()).any(|window| window == &pattern[..])
+            {
+                state.console.clear();
+                waiter.satisfy(&mut state);
+            } else {
+                // Keep only what could be a prefix of a match spanning later output.
+                let keep = state.console.len().min(pattern.len().saturating_sub(1));
+                let start = state.console.len() - keep;
+                state.console.drain(..start);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_for_console() {
+        let printer = std::thread::spawn(|| {
+            // Wait until the waiter is registered, as earlier output is not considered.
+            while WAITERS.lock().is_empty() {
+                std::thread::yield_now();
+            }
+            // Feed the output tap of the console directly, so nothing reaches the host terminal.
+            console_output(b"Welcome to ");
+            console_output(b"Buildroot\nbuildroot login: ");
+        });
+        assert!(wait_for(Condition::Console(b"to Buildroot\n".to_vec()), Duration::from_secs(10)));
+        printer.join().unwrap();
+
+        assert!(!wait_for(Condition::Console(b"# ".to_vec()), Duration::from_millis(50)));
+    }
+}
@@ -8,6 +8,9 @@ pub mod emu;
 pub mod sim;
 pub mod util;
 
+#[cfg(test)]
+mod testing;
+
 use std::cell::UnsafeCell;
 use std::ffi::CString;
 use std::path::PathBuf;
@@ -19,11 +22,35 @@ macro_rules! usage_string {
 Options:
   --strace              Log system calls.
   --disassemble         Log decoded instructions.
+  --trace               Save a binary trace of executed instructions to the specified path.
   --perf                Generate /tmp/perf-<PID>.map for perf tool.
   --lockstep            Use lockstep non-threaded mode for execution.
   --wfi-nop             Treat WFI as nops in lock-step mode.
+  --emulate-misaligned  Emulate misaligned loads and stores instead of raising exceptions. Always
+                        enabled in user-mode emulation.
+  --spin-detect         Sleep in loops polling memory instead of spinning in threaded mode.
+  --verify-decode       Cross-check decoded instructions against a reference decoder.
+  --deterministic       Eliminate nondeterminism so repeated runs behave identically.
+  --interrupt-stride    Poll for interrupts every N instructions within a block.
+  --max-block-len       Split translated blocks after N instructions.
+  --code-cache-cap      Flush the code cache of a hart once it holds N MiB of translated code.
+  --block-cap           Keep at most N translated blocks per hart, dropping the least recently used.
+  --cluster-size        Group every N harts into a cluster sharing a TLB for sfence.vma.
+  --div-check           Handling of integer division by zero and overflow: spec, log or trap.
+  --rounding-mode       Initial dynamic FP rounding mode: rne, rtz, rdn, rup or rmm.
   --sysroot             Change the sysroot to a non-default value.
   --dump-fdt            Save FDT to the specified path.
+  --dump-dts            Save FDT decompiled to DTS source to the specified path.
+  --dump-cfg            Save the control-flow graph of decoded blocks as DOT on exit.
+  --record-events       Save the cycle each event fires at to the specified path. Implies lockstep.
+  --replay-events       Fire events at the cycles recorded by --record-events. Implies lockstep.
+  --trace-mmio          Log every I/O memory access to the specified path.
+  --gdb                 Wait for GDB to connect on the given port before execution.
+  --control             Serve a socket at the specified path to query, pause or shut down harts.
+  --run-to              Run until the given symbol or hex address is reached, then dump state.
+  --crash-dump          Bytes of guest memory around the PC dumped if the emulator crashes.
+  --print-cmdline       Print the command line and environment passed to the guest.
+  --append              Append the given tokens to the kernel command line in the config.
   --help                Display this help message.
 "
     };
@@ -33,6 +60,9 @@ pub struct Flags {
     // A flag to determine whether to print instruction out when it is decoded.
     disassemble: bool,
 
+    /// Path to save the trace of executed instructions to
+    trace: Option<String>,
+
     // The highest privilege mode emulated
     prv: u8,
 
@@ -51,9 +81,79 @@ pub struct Flags {
     /// Whether WFI should be treated as NOP in lock-step mode
     wfi_nop: bool,
 
+    /// Whether misaligned loads and stores are emulated instead of raising exceptions
+    emulate_misaligned: bool,
+
+    /// Whether harts sleep in detected spin loops until the polled memory changes
+    spin_detect: bool,
+
+    /// Whether decoded instructions are cross-checked against the reference decoder
+    verify_decode: bool,
+
+    /// Whether all sources of nondeterminism should be eliminated. This implies lockstep mode.
+    deterministic: bool,
+
+    /// Number of instructions after which pending interrupts are polled within a translated block.
+    /// 0 means interrupts are only checked at block boundaries.
+    interrupt_stride: usize,
+
+    /// Maximum number of instructions in a translated block. 0 means blocks only end at control
+    /// flow changes and page boundaries.
+    max_block_len: usize,
+
+    /// Bytes of translated code after which the code cache of a hart is flushed. 0 means the code
+    /// cache is only flushed when it is full.
+    code_cache_cap: usize,
+
+    /// Number of translated blocks of a hart that can be looked up, beyond which the least
+    /// recently used ones are evicted. 0 means no limit.
+    block_cap: usize,
+
+    /// Number of consecutive harts in a cluster. An sfence.vma flushes all harts in the cluster.
+    cluster_size: usize,
+
+    /// Dynamic floating point rounding mode (`frm`) of each hart at reset
+    rounding_mode: softfp::RoundingMode,
+
+    /// Handling of integer division by zero and signed division overflow
+    div_check: emu::interp::DivCheck,
+
     /// Dump FDT option
     dump_fdt: Option<String>,
 
+    /// Path to save the FDT decompiled to DTS source to
+    dump_dts: Option<String>,
+
+    /// Path to save the control-flow graph of decoded blocks to on exit
+    dump_cfg: Option<String>,
+
+    /// Path to save the schedule of fired events to on exit
+    record_events: Option<String>,
+
+    /// Path of a schedule of events to replay
+    replay_events: Option<String>,
+
+    /// Path to log every I/O memory access to
+    trace_mmio: Option<String>,
+
+    /// Port on which to wait for GDB to connect before execution
+    gdb: Option<u16>,
+
+    /// Path of the Unix socket for controlling the emulator
+    control: Option<String>,
+
+    /// Symbol or address at which execution stops and the hart state is dumped
+    run_to: Option<String>,
+
+    /// Number of bytes of guest memory around the PC dumped when the emulator crashes
+    crash_dump: usize,
+
+    /// Whether the exact bootargs (or argv and envp in user mode) given to the guest should be printed
+    print_cmdline: bool,
+
+    /// Extra tokens appended to the configured kernel command line, in the order given
+    append: Vec<String>,
+
     /// A flag to determine whether to trace all system calls. If true then all guest system calls will be logged.
     strace: bool,
 
@@ -65,12 +165,62 @@ pub struct Flags {
     sysroot: PathBuf,
 }
 
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            disassemble: false,
+            trace: None,
+            prv: 1,
+            perf: false,
+            thread: true,
+            blocking_io: false,
+            model_id: 0,
+            wfi_nop: false,
+            emulate_misaligned: false,
+            spin_detect: false,
+            verify_decode: false,
+            deterministic: false,
+            interrupt_stride: 0,
+            max_block_len: 0,
+            code_cache_cap: 0,
+            block_cap: 0,
+            cluster_size: 1,
+            rounding_mode: softfp::RoundingMode::TiesToEven,
+            div_check: emu::interp::DivCheck::Spec,
+            dump_fdt: None,
+            dump_dts: None,
+            dump_cfg: None,
+            record_events: None,
+            replay_events: None,
+            trace_mmio: None,
+            gdb: None,
+            control: None,
+            run_to: None,
+            crash_dump: 64,
+            print_cmdline: false,
+            append: Vec::new(),
+            strace: false,
+            exec_path: CString::default(),
+            sysroot: "/opt/riscv/sysroot".into(),
+        }
+    }
+}
+
 static FLAGS: RoCell<Flags> = unsafe { RoCell::new_uninit() };
 
+#[cfg(not(test))]
 pub fn get_flags() -> &'static Flags {
     &FLAGS
 }
 
+/// Tests do not parse the command line, so they run with the default flags.
+#[cfg(test)]
+pub fn get_flags() -> &'static Flags {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| unsafe { RoCell::init(&FLAGS, Flags::default()) });
+    &FLAGS
+}
+
 static SHARED_CONTEXTS: RoCell<Vec<&'static emu::interp::SharedContext>> =
     unsafe { RoCell::new_uninit() };
 
@@ -78,6 +228,18 @@ pub fn shared_context(id: usize) -> &'static emu::interp::SharedContext {
     SHARED_CONTEXTS[id]
 }
 
+static HARTIDS: RoCell<Vec<u64>> = unsafe { RoCell::new_uninit() };
+
+/// Get the architectural hartid of the hart with the given index.
+pub fn hartid(id: usize) -> u64 {
+    HARTIDS[id]
+}
+
+/// Get the index of the hart with the given architectural hartid.
+pub fn hart_index(hartid: u64) -> Option<usize> {
+    HARTIDS.iter().position(|&id| id == hartid)
+}
+
 pub fn core_count() -> usize {
     let cnt = SHARED_CONTEXTS.len();
     assert_ne!(cnt, 0);
@@ -86,12 +248,39 @@ pub fn core_count() -> usize {
 
 static EVENT_LOOP: RoCell<&'static emu::EventLoop> = unsafe { RoCell::new_uninit() };
 
+#[cfg(not(test))]
 pub fn event_loop() -> &'static emu::EventLoop {
     &EVENT_LOOP
 }
 
+#[cfg(test)]
+thread_local! {
+    static TEST_EVENT_LOOP: &'static emu::EventLoop =
+        Box::leak(Box::new(emu::EventLoop::new(get_flags().thread)));
+}
+
+/// Tests do not run the event loop, so each test thread gets its own, which stays at cycle 0
+/// unless the test advances it.
+#[cfg(test)]
+pub fn event_loop() -> &'static emu::EventLoop {
+    TEST_EVENT_LOOP.with(|event_loop| *event_loop)
+}
+
 pub fn threaded() -> bool {
-    get_flags().thread
+    event_loop().threaded()
+}
+
+/// Seed used for all random sources in deterministic mode.
+pub const DETERMINISTIC_SEED: u64 = 0;
+
+/// Get the wall-clock time that corresponds to time 0 of the emulated system. In deterministic
+/// mode this is fixed at 2020-01-01 00:00:00 UTC instead of derived from the host clock.
+pub fn wall_clock_epoch() -> std::time::Duration {
+    if get_flags().deterministic {
+        return std::time::Duration::from_secs(1577836800);
+    }
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap()
+        - std::time::Duration::from_micros(event_loop().time())
 }
 
 static EXIT_REASON: parking_lot::Mutex<Option<ExitReason>> =
@@ -119,6 +308,16 @@ fn shutdown(reason: ExitReason) {
 
 static CONFIG: RoCell<config::Config> = unsafe { RoCell::new_uninit() };
 
+/// Get the config of the emulated system. There is no config in user-space emulation, so `None` is
+/// returned instead.
+pub fn system_config() -> Option<&'static config::Config> {
+    if get_flags().prv == 0 {
+        None
+    } else {
+        Some(&CONFIG)
+    }
+}
+
 extern "C" {
     fn fiber_interp_run();
 }
@@ -131,6 +330,7 @@ pub fn main() {
     emu::signal::init();
     emu::interp::init_fp();
     pretty_env_logger::init();
+    util::install_exit_flush();
 
     let mut args = std::env::args();
 
@@ -138,19 +338,7 @@ pub fn main() {
     let mut item = args.next();
     let interp_name = item.expect("program name should not be absent");
 
-    let mut flags = Flags {
-        disassemble: false,
-        prv: 1,
-        perf: false,
-        thread: true,
-        blocking_io: false,
-        model_id: 0,
-        wfi_nop: false,
-        dump_fdt: None,
-        strace: false,
-        exec_path: CString::default(),
-        sysroot: "/opt/riscv/sysroot".into(),
-    };
+    let mut flags = Flags::default();
 
     item = args.next();
     while let Some(ref arg) = item {
@@ -168,6 +356,15 @@ pub fn main() {
                 flags.blocking_io = true;
             }
             "--wfi-nop" => flags.wfi_nop = true,
+            "--emulate-misaligned" => flags.emulate_misaligned = true,
+            "--spin-detect" => flags.spin_detect = true,
+            "--verify-decode" => flags.verify_decode = true,
+            "--deterministic" => {
+                flags.deterministic = true;
+                flags.model_id = 1;
+                flags.blocking_io = true;
+            }
+            "--print-cmdline" => flags.print_cmdline = true,
             "--help" => {
                 eprintln!(usage_string!(), interp_name);
                 std::process::exit(0);
@@ -175,9 +372,101 @@ pub fn main() {
             _ => {
                 if arg.starts_with("--sysroot=") {
                     flags.sysroot = arg["--sysroot=".len()..].into();
+                } else if arg.starts_with("--interrupt-stride=") {
+                    let stride = &arg["--interrupt-stride=".len()..];
+                    flags.interrupt_stride = stride.parse().unwrap_or_else(|_| {
+                        eprintln!("{}: invalid interrupt stride '{}'", interp_name, stride);
+                        std::process::exit(1);
+                    });
+                } else if arg.starts_with("--max-block-len=") {
+                    let len = &arg["--max-block-len=".len()..];
+                    flags.max_block_len = len.parse().unwrap_or_else(|_| {
+                        eprintln!("{}: invalid maximum block length '{}'", interp_name, len);
+                        std::process::exit(1);
+                    });
+                } else if arg.starts_with("--code-cache-cap=") {
+                    let cap = &arg["--code-cache-cap=".len()..];
+                    let cap: usize = cap.parse().unwrap_or_else(|_| {
+                        eprintln!("{}: invalid code cache cap '{}'", interp_name, cap);
+                        std::process::exit(1);
+                    });
+                    flags.code_cache_cap = cap * 1024 * 1024;
+                } else if arg.starts_with("--block-cap=") {
+                    let cap = &arg["--block-cap=".len()..];
+                    flags.block_cap = cap.parse().unwrap_or_else(|_| {
+                        eprintln!("{}: invalid block cap '{}'", interp_name, cap);
+                        std::process::exit(1);
+                    });
+                } else if arg.starts_with("--cluster-size=") {
+                    let size = &arg["--cluster-size=".len()..];
+                    flags.cluster_size = match size.parse() {
+                        Ok(size) if size != 0 => size,
+                        _ => {
+                            eprintln!("{}: invalid cluster size '{}'", interp_name, size);
+                            std::process::exit(1);
+                        }
+                    };
+                } else if arg.starts_with("--rounding-mode=") {
+                    use softfp::RoundingMode;
+                    flags.rounding_mode = match &arg["--rounding-mode=".len()..] {
+                        "rne" => RoundingMode::TiesToEven,
+                        "rtz" => RoundingMode::TowardZero,
+                        "rdn" => RoundingMode::TowardNegative,
+                        "rup" => RoundingMode::TowardPositive,
+                        "rmm" => RoundingMode::TiesToAway,
+                        mode => {
+                            eprintln!("{}: invalid rounding mode '{}'", interp_name, mode);
+                            std::process::exit(1);
+                        }
+                    };
+                } else if arg.starts_with("--div-check=") {
+                    use emu::interp::DivCheck;
+                    flags.div_check = match &arg["--div-check=".len()..] {
+                        "spec" => DivCheck::Spec,
+                        "log" => DivCheck::Log,
+                        "trap" => DivCheck::Trap,
+                        mode => {
+                            eprintln!("{}: invalid division check mode '{}'", interp_name, mode);
+                            std::process::exit(1);
+                        }
+                    };
+                } else if arg.starts_with("--crash-dump=") {
+                    let len = &arg["--crash-dump=".len()..];
+                    flags.crash_dump = len.parse().unwrap_or_else(|_| {
+                        eprintln!("{}: invalid crash dump size '{}'", interp_name, len);
+                        std::process::exit(1);
+                    });
+                } else if arg.starts_with("--gdb=") {
+                    let port = &arg["--gdb=".len()..];
+                    flags.gdb = Some(port.parse().unwrap_or_else(|_| {
+                        eprintln!("{}: invalid port '{}'", interp_name, port);
+                        std::process::exit(1);
+                    }));
+                } else if arg.starts_with("--control=") {
+                    flags.control = Some(arg["--control=".len()..].to_owned());
+                } else if arg.starts_with("--run-to=") {
+                    flags.run_to = Some(arg["--run-to=".len()..].to_owned());
+                } else if arg.starts_with("--append=") {
+                    flags.append.push(arg["--append=".len()..].to_owned());
                 } else if arg.starts_with("--dump-fdt=") {
                     let path_slice = &arg["--dump-fdt=".len()..];
                     flags.dump_fdt = Some(path_slice.to_owned());
+                } else if arg.starts_with("--dump-dts=") {
+                    flags.dump_dts = Some(arg["--dump-dts=".len()..].to_owned());
+                } else if arg.starts_with("--trace=") {
+                    flags.trace = Some(arg["--trace=".len()..].to_owned());
+                } else if arg.starts_with("--dump-cfg=") {
+                    flags.dump_cfg = Some(arg["--dump-cfg=".len()..].to_owned());
+                } else if arg.starts_with("--record-events=") {
+                    flags.record_events = Some(arg["--record-events=".len()..].to_owned());
+                    flags.model_id = 1;
+                    flags.blocking_io = true;
+                } else if arg.starts_with("--trace-mmio=") {
+                    flags.trace_mmio = Some(arg["--trace-mmio=".len()..].to_owned());
+                } else if arg.starts_with("--replay-events=") {
+                    flags.replay_events = Some(arg["--replay-events=".len()..].to_owned());
+                    flags.model_id = 1;
+                    flags.blocking_io = true;
                 } else {
                     eprintln!("{}: unrecognized option '{}'", interp_name, arg);
                     std::process::exit(1);
@@ -214,10 +503,14 @@ pub fn main() {
         // Full-system emulation is needed. Originally we uses kernel path as "program name"
         // directly, but as full-system emulation requires many peripheral devices as well,
         // we decided to only accept config files.
-        let config: config::Config = toml::from_slice(loader.as_slice()).unwrap_or_else(|err| {
-            eprintln!("{}: invalid config file: {}", interp_name, err);
-            std::process::exit(1);
-        });
+        let mut config: config::Config =
+            toml::from_slice(loader.as_slice()).unwrap_or_else(|err| {
+                eprintln!("{}: invalid config file: {}", interp_name, err);
+                std::process::exit(1);
+            });
+        for extra in get_flags().append.iter() {
+            config.append_cmdline(extra);
+        }
         unsafe { RoCell::init(&CONFIG, config) };
 
         // Currently due to our icache implementation, we cannot efficiently support >32 cores
@@ -226,7 +519,16 @@ pub fn main() {
             std::process::exit(1);
         }
 
-        if CONFIG.firmware.is_some() {
+        if let Err(msg) = CONFIG
+            .hartids()
+            .and_then(|_| CONFIG.check_registers())
+            .and_then(|_| emu::check_devices(&CONFIG))
+        {
+            eprintln!("{}: {}", interp_name, msg);
+            std::process::exit(1);
+        }
+
+        if CONFIG.machine_mode() {
             unsafe { RoCell::as_mut(&FLAGS).prv = 3 }
         }
 
@@ -241,53 +543,53 @@ pub fn main() {
     let mut contexts = Vec::new();
     let mut shared_contexts = Vec::new();
 
-    let num_cores = if get_flags().prv == 0 { 1 } else { CONFIG.core };
+    let hartids = system_config().map_or_else(|| vec![0], |config| config.hartids().unwrap());
+    let num_cores = hartids.len();
 
     // Create a fiber for event-driven simulation, e.g. timer, I/O
-    let event_fiber = fiber::FiberContext::new(emu::EventLoop::new());
+    let event_fiber = fiber::FiberContext::new(emu::EventLoop::new(get_flags().thread));
     unsafe { RoCell::init(&EVENT_LOOP, std::mem::transmute(event_fiber.data::<emu::EventLoop>())) }
+    if get_flags().record_events.is_some() {
+        event_loop().start_recording();
+    }
+    if let Some(ref path) = get_flags().replay_events {
+        let schedule = std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| emu::event::parse_schedule(&text))
+            .unwrap_or_else(|err| {
+                eprintln!("{}: cannot load event schedule {}: {}", interp_name, path, err);
+                std::process::exit(1);
+            });
+        event_loop().start_replay(schedule);
+    }
     fibers.push(event_fiber);
 
+    // All harts write their traces to the same file in chunks.
+    let trace = get_flags().trace.as_ref().map(|path| {
+        let file = std::fs::File::create(path).unwrap_or_else(|err| {
+            eprintln!("{}: cannot create trace {}: {}", interp_name, path, err);
+            std::process::exit(1);
+        });
+        let out: std::sync::Arc<parking_lot::Mutex<dyn std::io::Write + Send>> =
+            std::sync::Arc::new(parking_lot::Mutex::new(std::io::BufWriter::new(file)));
+        out
+    });
+
     for i in 0..num_cores {
-        let mut newctx = emu::interp::Context {
-            shared: emu::interp::SharedContext::new(),
-            registers: [0xCCCCCCCCCCCCCCCC; 32],
-            fp_registers: [0xFFFFFFFFFFFFFFFF; 32],
-            frm: 0,
-            instret: 0,
-            lr_addr: 0,
-            lr_value: 0,
-            cause: 0,
-            tval: 0,
-            // FPU turned on by default
-            mstatus: 0x6000,
-            scause: 0,
-            sepc: 0,
-            stval: 0,
-            satp: 0,
-            sscratch: 0,
-            stvec: 0,
-            scounteren: 0,
-            mideleg: 0,
-            medeleg: 0,
-            mcause: 0,
-            mepc: 0,
-            mtval: 0,
-            mie: 0,
-            mscratch: 0,
-            mtvec: 0,
-            mcounteren: 0,
-            // These are set by setup_mem, so we don't really care now.
-            pc: 0,
-            prv: 0,
-            hartid: i as u64,
-            minstret: 0,
-            cycle_offset: 0,
-        };
-        // x0 must always be 0
-        newctx.registers[0] = 0;
+        let mut newctx = emu::interp::Context::new(i as u64);
+        newctx.tracer = trace.clone().map(|out| Box::new(emu::trace::Tracer::new(i, out)));
+        newctx.mhartid = hartids[i];
+        if let Some(config) = system_config() {
+            newctx.identity = config.identity;
+            newctx.permissive_csr = config.permissive_csr.clone();
+        }
+        newctx.set_rounding_mode(get_flags().rounding_mode);
+        // There is no kernel to emulate misaligned accesses for user-mode programs.
+        newctx.emulate_misaligned = get_flags().emulate_misaligned || get_flags().prv == 0;
+        newctx.cluster_size = get_flags().cluster_size;
+        newctx.spin_detect = get_flags().spin_detect;
 
-        if CONFIG.firmware.is_none() {
+        if !system_config().map_or(false, |config| config.machine_mode()) {
             newctx.mideleg = 0x222;
             newctx.medeleg = 0xB35D;
             newctx.mcounteren = 0b111;
@@ -301,6 +603,7 @@ pub fn main() {
     }
 
     unsafe { RoCell::init(&SHARED_CONTEXTS, shared_contexts) };
+    unsafe { RoCell::init(&HARTIDS, hartids) };
 
     // These should only be initialised for full-system emulation
     if get_flags().prv != 0 {
@@ -308,13 +611,28 @@ pub fn main() {
     }
 
     // Load the program
-    unsafe {
+    let bias = unsafe {
         emu::loader::load(&loader, &mut std::iter::once(program_name).chain(args), &mut contexts)
     };
-    std::mem::drop(loader);
+
+    if let Some(ref target) = get_flags().run_to {
+        // Symbols are relocated along with position-independent programs.
+        let addr = if target.starts_with("0x") {
+            u64::from_str_radix(&target[2..], 16).ok()
+        } else {
+            loader.find_symbol(target).map(|addr| addr.wrapping_add(bias))
+        };
+        let addr = addr.unwrap_or_else(|| {
+            eprintln!("{}: cannot find symbol '{}'", interp_name, target);
+            std::process::exit(1);
+        });
+        unsafe { RoCell::replace(&emu::interp::BREAKPOINT, Some(addr)) };
+    }
+    unsafe { RoCell::replace(&emu::loader::SYMBOLS, loader.into_symbols().with_bias(bias)) };
 
     // Load firmware if present
-    if let Some(ref firmware) = CONFIG.firmware {
+    let firmware = system_config().and_then(|config| config.firmware.as_ref());
+    if let Some(firmware) = firmware {
         let loader = emu::loader::Loader::new(firmware).unwrap_or_else(|err| {
             eprintln!("{}: cannot load {}: {}", interp_name, firmware.to_string_lossy(), err);
             std::process::exit(1);
@@ -324,16 +642,66 @@ pub fn main() {
         unsafe { loader.load_kernel(location as u64) };
 
         for ctx in contexts.iter_mut() {
-            ctx.registers[12] = ctx.pc;
-            ctx.pc = location as u64;
-            ctx.prv = 3;
+            emu::loader::enter_firmware(ctx, location as u64);
+        }
+    }
+
+    let images = system_config().map_or(&[][..], |config| &config.image[..]);
+    if !images.is_empty() {
+        let loaders: Vec<_> = images
+            .iter()
+            .map(|image| {
+                emu::loader::Loader::new(&image.path).unwrap_or_else(|err| {
+                    eprintln!("{}: cannot load {}: {}", interp_name, image.path.display(), err);
+                    std::process::exit(1);
+                })
+            })
+            .collect();
+        let sizes: Vec<_> =
+            loaders.iter().map(|loader| emu::loader::image_size(loader.as_slice())).collect();
+        if let Err(msg) = CONFIG.check_images(&sizes) {
+            eprintln!("{}: {}", interp_name, msg);
+            std::process::exit(1);
         }
+        let images: Vec<_> =
+            loaders.iter().map(|loader| loader.as_slice()).zip(images.iter()).collect();
+        unsafe { emu::loader::load_images(&images, &mut contexts) };
+    }
+
+    if let Some(config) = system_config() {
+        emu::loader::set_initial_registers(&mut contexts, &config.registers);
+    }
+
+    if let Some(port) = get_flags().gdb {
+        emu::gdbstub::start(port).unwrap_or_else(|err| {
+            eprintln!("{}: cannot serve GDB on port {}: {}", interp_name, port, err);
+            std::process::exit(1);
+        });
     }
 
     unsafe {
         crate::sim::switch_model(FLAGS.model_id);
-        let threaded = !crate::sim::get_memory_model().require_lockstep();
-        RoCell::as_mut(&FLAGS).thread = threaded;
+        event_loop().set_threaded(!crate::sim::get_memory_model().require_lockstep());
+    }
+
+    if let Some(ref path) = get_flags().control {
+        let harts = contexts
+            .iter()
+            .map(|ctx| emu::control::Hart {
+                hartid: ctx.mhartid,
+                shared: shared_context(ctx.hartid as usize),
+            })
+            .collect();
+        let control = emu::control::Control::new(
+            harts,
+            || if threaded() { core_count() } else { 1 },
+            || event_loop().cycle(),
+            || shutdown(ExitReason::Exit(0)),
+        );
+        emu::control::start(path.as_ref(), control).unwrap_or_else(|err| {
+            eprintln!("{}: cannot serve control socket {}: {}", interp_name, path, err);
+            std::process::exit(1);
+        });
     }
 
     loop {
@@ -385,12 +753,15 @@ pub fn main() {
         }
 
         match EXIT_REASON.lock().as_ref().unwrap() {
+            &ExitReason::SwitchModel(_) if get_flags().deterministic => {
+                warn!("model switching is disabled in deterministic mode");
+            }
             &ExitReason::SwitchModel(id) => {
                 unsafe {
                     crate::sim::switch_model(id);
                     RoCell::as_mut(&FLAGS).model_id = id;
                     let threaded = !crate::sim::get_memory_model().require_lockstep();
-                    RoCell::as_mut(&FLAGS).thread = threaded;
+                    event_loop().set_threaded(threaded);
                     info!("switching to model={} threaded={}", id, threaded);
                 }
 
@@ -403,6 +774,19 @@ pub fn main() {
             }
             &ExitReason::Exit(code) => {
                 print_stats(&mut contexts).unwrap();
+                if let Some(ref path) = get_flags().dump_cfg {
+                    let mut file = std::fs::File::create(path).unwrap();
+                    emu::control_flow::CFG.lock().write_dot(&mut file).unwrap();
+                }
+                if let Some(ref path) = get_flags().record_events {
+                    let mut file = std::fs::File::create(path).unwrap();
+                    emu::event::write_schedule(&mut file, &event_loop().recording()).unwrap();
+                }
+                for ctx in contexts.iter_mut() {
+                    if let Some(ref mut tracer) = ctx.tracer {
+                        tracer.flush(&ctx.registers).unwrap();
+                    }
+                }
                 std::process::exit(code);
             }
             ExitReason::ClearStats => {
@@ -413,6 +797,7 @@ pub fn main() {
                 }
                 for ctx in contexts.iter_mut() {
                     ctx.instret = 0;
+                    ctx.shared.instret.store(0, std::sync::atomic::Ordering::Relaxed);
                     ctx.minstret = 0;
                     ctx.cycle_offset = 0;
                 }
@@ -459,6 +844,22 @@ fn print_stats(ctxs: &[&mut emu::interp::Context]) -> std::io::Result<()> {
         )?;
     }
     writeln!(stderr, "Total: CYCLE = {}, INSTRET = {}, MINSTRET = {}", cycle, instret, minstret)?;
+    for marker in emu::interp::markers() {
+        writeln!(
+            stderr,
+            "Marker {}: HART = {}, CYCLE = {}, INSTRET = {}",
+            marker.id,
+            marker.hartid,
+            marker.cycle.wrapping_sub(unsafe { CYCLE_BASE }),
+            marker.instret
+        )?;
+    }
+    let code_cache = emu::interp::code_cache_usage();
+    writeln!(
+        stderr,
+        "Code cache: BYTES = {}, BLOCKS = {}, FLUSHES = {}",
+        code_cache.bytes, code_cache.blocks, code_cache.flushes
+    )?;
     writeln!(stderr)?;
     crate::sim::get_memory_model().print_stats(&mut stderr)?;
     Ok(())
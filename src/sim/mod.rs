@@ -3,6 +3,7 @@ pub mod pipeline;
 pub use memory::MemoryModel;
 pub use pipeline::PipelineModel;
 
+use crate::config::CoherenceConfig;
 use crate::util::RoCell;
 
 type PipelineFactory = fn(usize) -> Box<dyn pipeline::PipelineModel>;
@@ -10,13 +11,27 @@ type PipelineFactory = fn(usize) -> Box<dyn pipeline::PipelineModel>;
 // As in register_memory_model will use Box::from_raw, we must use a ZST type to avoid freeing underlying memory.
 static MEMORY_MODEL: RoCell<&'static dyn MemoryModel> = RoCell::new(&memory::AtomicModel);
 static PIPELINE_MODEL: RoCell<PipelineFactory> = RoCell::new(|_| Box::new(pipeline::AtomicModel));
+static COHERENCE: RoCell<CoherenceConfig> =
+    RoCell::new(CoherenceConfig { amo: 0, lr: 0, sc: 0, remote_sfence: 0 });
 
 pub fn get_memory_model() -> &'static dyn MemoryModel {
     *MEMORY_MODEL
 }
 
 pub fn new_pipeline_model(hartid: usize) -> Box<dyn PipelineModel> {
-    (*PIPELINE_MODEL)(hartid)
+    let model = (*PIPELINE_MODEL)(hartid);
+    if *COHERENCE == CoherenceConfig::default() {
+        return model;
+    }
+    Box::new(pipeline::CoherenceModel::new(model, *COHERENCE))
+}
+
+pub fn get_coherence_config() -> &'static CoherenceConfig {
+    &COHERENCE
+}
+
+pub unsafe fn set_coherence_config(config: CoherenceConfig) {
+    RoCell::replace(&COHERENCE, config);
 }
 
 unsafe fn register_memory_model(model: Box<dyn MemoryModel>) -> Box<dyn MemoryModel> {
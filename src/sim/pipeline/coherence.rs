@@ -0,0 +1,124 @@
+use super::PipelineModel;
+use crate::config::CoherenceConfig;
+use crate::emu::dbt::DbtCompiler;
+use riscv::{Extension, Op};
+
+/// A wrapper around another pipeline model which charges extra cycles to atomic memory operations,
+/// to approximate the overhead of cache coherence traffic.
+pub struct CoherenceModel {
+    inner: Box<dyn PipelineModel>,
+    config: CoherenceConfig,
+}
+
+impl CoherenceModel {
+    pub fn new(inner: Box<dyn PipelineModel>, config: CoherenceConfig) -> Self {
+        CoherenceModel { inner, config }
+    }
+}
+
+/// Number of extra cycles an op takes due to coherence traffic.
+fn op_cycles(config: &CoherenceConfig, op: &Op) -> usize {
+    match op {
+        Op::LrW { .. } | Op::LrD { .. } => config.lr,
+        Op::ScW { .. } | Op::ScD { .. } => config.sc,
+        _ if op.extension() == Extension::A => config.amo,
+        _ => 0,
+    }
+}
+
+impl PipelineModel for CoherenceModel {
+    fn can_fuse_cond_op(&self) -> bool {
+        self.inner.can_fuse_cond_op()
+    }
+
+    fn begin_block(&mut self, compiler: &mut DbtCompiler, pc: u64) {
+        self.inner.begin_block(compiler, pc)
+    }
+
+    fn before_instruction(&mut self, compiler: &mut DbtCompiler, op: &Op, compressed: bool) {
+        self.inner.before_instruction(compiler, op, compressed)
+    }
+
+    fn after_instruction(&mut self, compiler: &mut DbtCompiler, op: &Op, compressed: bool) {
+        self.inner.after_instruction(compiler, op, compressed);
+        compiler.insert_cycle_count(op_cycles(&self.config, op));
+    }
+
+    fn after_taken_branch(&mut self, compiler: &mut DbtCompiler, op: &Op, compressed: bool) {
+        self.inner.after_taken_branch(compiler, op, compressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::interp::Context;
+    use riscv::Ordering;
+    use std::cell::UnsafeCell;
+
+    #[test]
+    fn test_atomic_loop_cost() {
+        let config = CoherenceConfig { amo: 20, lr: 10, sc: 10, remote_sfence: 0 };
+        let aqrl = Ordering::Relaxed;
+        let atomic = [
+            Op::LrW { rd: 10, rs1: 11, aqrl },
+            Op::Addi { rd: 10, rs1: 10, imm: 1 },
+            Op::ScW { rd: 12, rs1: 11, rs2: 10, aqrl },
+            Op::AmoaddW { rd: 0, rs1: 13, rs2: 10, aqrl },
+        ];
+        let plain = [
+            Op::Lw { rd: 10, rs1: 11, imm: 0 },
+            Op::Addi { rd: 10, rs1: 10, imm: 1 },
+            Op::Sw { rs1: 11, rs2: 10, imm: 0 },
+            Op::Sw { rs1: 13, rs2: 10, imm: 0 },
+        ];
+        let cost = |ops: &[Op]| ops.iter().map(|op| op_cycles(&config, op)).sum::<usize>();
+        assert_eq!(cost(&atomic), 40);
+        assert_eq!(cost(&plain), 0);
+
+        // No extra cost when disabled.
+        let cost = |ops: &[Op]| {
+            ops.iter().map(|op| op_cycles(&CoherenceConfig::default(), op)).sum::<usize>()
+        };
+        assert_eq!(cost(&atomic), 0);
+    }
+
+    #[test]
+    fn test_atomic_loop_mcycle() {
+        // The coherence config is global, so only set it in a process of its own.
+        let path = concat!(module_path!(), "::test_atomic_loop_mcycle");
+        if let Some(status) = crate::testing::isolate(path) {
+            assert!(status.success());
+            return;
+        }
+        let config = CoherenceConfig { amo: 20, ..Default::default() };
+        unsafe { crate::sim::set_coherence_config(config) };
+        // Charge cycles to the hart instead of sleeping, so mcycle only counts the loop.
+        crate::event_loop().set_threaded(true);
+
+        // Run `body` 100 times with a1 pointing to a word and a2 = 1, and return the final word
+        // and mcycle.
+        let run = |body| {
+            // body; addi a0, a0, -1; bnez a0, -8; ebreak; j .
+            let program = [body, 0xfff50513, 0xfe051ce3, 0x00100073, 0x0000006f];
+            let word = Box::leak(Box::new(0u32));
+            let mut ctx = Context::new(0);
+            ctx.trap_hook = Some(crate::testing::stop);
+            ctx.prv = 3;
+            ctx.pc = crate::testing::program_page(&program);
+            ctx.registers[10] = 100;
+            ctx.registers[11] = word as *mut u32 as u64;
+            ctx.registers[12] = 1;
+            let fiber = crate::testing::run_translated(ctx);
+            let ctx = unsafe { &*fiber.data::<UnsafeCell<Context>>().get() };
+            (*word, ctx.get_mcycle())
+        };
+        // amoadd.w zero, a2, (a1)
+        let (sum, atomic) = run(0x00c5a02f);
+        assert_eq!(sum, 100);
+        // sw a2, 0(a1)
+        let (word, plain) = run(0x00c5a023);
+        assert_eq!(word, 1);
+        assert_eq!(atomic - plain, 100 * 20);
+    }
+}
@@ -103,6 +103,47 @@ impl PipelineModel for InOrderModel {
             | Op::Sllw { .. }
             | Op::Srlw { .. }
             | Op::Sraw { .. } => 1,
+            Op::SlliUw { .. }
+            | Op::Sh1add { .. }
+            | Op::Sh2add { .. }
+            | Op::Sh3add { .. }
+            | Op::AddUw { .. }
+            | Op::Sh1addUw { .. }
+            | Op::Sh2addUw { .. }
+            | Op::Sh3addUw { .. }
+            | Op::Clz { .. }
+            | Op::Ctz { .. }
+            | Op::Cpop { .. }
+            | Op::SextB { .. }
+            | Op::SextH { .. }
+            | Op::Rori { .. }
+            | Op::OrcB { .. }
+            | Op::Rev8 { .. }
+            | Op::Clzw { .. }
+            | Op::Ctzw { .. }
+            | Op::Cpopw { .. }
+            | Op::Roriw { .. }
+            | Op::Andn { .. }
+            | Op::Orn { .. }
+            | Op::Xnor { .. }
+            | Op::Min { .. }
+            | Op::Minu { .. }
+            | Op::Max { .. }
+            | Op::Maxu { .. }
+            | Op::Rol { .. }
+            | Op::Ror { .. }
+            | Op::ZextH { .. }
+            | Op::Rolw { .. }
+            | Op::Rorw { .. }
+            | Op::Bclri { .. }
+            | Op::Bexti { .. }
+            | Op::Binvi { .. }
+            | Op::Bseti { .. }
+            | Op::Bclr { .. }
+            | Op::Bext { .. }
+            | Op::Binv { .. }
+            | Op::Bset { .. } => 1,
+            Op::NtlP1 | Op::NtlPall | Op::NtlS1 | Op::NtlAll => 1,
             Op::Mul { .. } => {
                 self.stall_reg = rd;
                 11
@@ -1,7 +1,9 @@
 use crate::emu::dbt::DbtCompiler;
 use riscv::Op;
 
+mod coherence;
 mod in_order;
+pub use coherence::CoherenceModel;
 pub use in_order::InOrderModel;
 
 pub trait PipelineModel {
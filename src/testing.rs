@@ -0,0 +1,73 @@
+//! Setup shared by unit tests, which run without parsing the command line or creating harts.
+
+use crate::emu::interp::{Context, TrapAction};
+use crate::util::RoCell;
+use riscv::Trap;
+use std::cell::UnsafeCell;
+
+extern "C" {
+    fn fiber_interp_run();
+}
+
+/// Create two harts with hartids 0 and 1 for tests that look harts up by index or hartid.
+///
+/// Tests do not create fibers for the harts, so only their shared contexts exist.
+pub fn init_harts() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let shared = (0..2)
+            .map(|_| &*Box::leak(Box::new(crate::emu::interp::SharedContext::new())))
+            .collect();
+        unsafe { RoCell::init(&crate::SHARED_CONTEXTS, shared) };
+        unsafe { RoCell::init(&crate::HARTIDS, vec![0, 1]) };
+    });
+}
+
+/// Run the test at `path`, i.e. `module_path!()` followed by the test name, again in a child
+/// process of its own. This is for tests that change global state or are expected to crash.
+/// Returns how the child exited, or `None` in the child, which should then run the actual test.
+pub fn isolate(path: &str) -> Option<std::process::ExitStatus> {
+    const VAR: &str = "R2VM_ISOLATED_TEST";
+    if std::env::var_os(VAR).is_some() {
+        return None;
+    }
+    // Test names do not include the crate name.
+    let name = path.splitn(2, "::").nth(1).unwrap();
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(&["--exact", name, "--test-threads=1", "--nocapture"])
+        .env(VAR, "1")
+        .status()
+        .unwrap();
+    Some(status)
+}
+
+/// Copy `program` to a page of its own. The page is never freed, so translations of it cannot be
+/// found by later tests reusing its address.
+pub fn program_page(program: &[u32]) -> u64 {
+    #[repr(align(4096))]
+    struct Page([u32; 1024]);
+
+    let page = Box::leak(Box::new(Page([0; 1024])));
+    page.0[..program.len()].copy_from_slice(program);
+    page.0.as_ptr() as u64
+}
+
+/// Trap hook which shuts the hart down at an `ebreak`, to be followed by `j .` so the hart sees
+/// the shutdown when the jump checks for interrupts.
+pub fn stop(ctx: &mut Context) -> TrapAction {
+    assert_eq!(ctx.last_trap(), Trap::Breakpoint(0));
+    ctx.shared.shutdown();
+    ctx.pc += 4;
+    TrapAction::Resume
+}
+
+/// Run translated code of a hart in a fiber of its own until it is shut down, e.g. by `stop`.
+/// Machine mode fetches from host addresses.
+pub fn run_translated(ctx: Context) -> fiber::FiberContext {
+    init_harts();
+    let mut fiber = fiber::FiberContext::new(UnsafeCell::new(ctx));
+    fiber::FiberGroup::with(|group| {
+        group.spawn(&mut fiber, || unsafe { fiber_interp_run() });
+    });
+    fiber
+}
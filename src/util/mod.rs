@@ -1,6 +1,8 @@
 mod ro_cell;
 pub use ro_cell::RoCell;
 
+use std::io::Write;
+
 pub fn cpu_time() -> std::time::Duration {
     unsafe {
         let mut timespec = std::mem::MaybeUninit::uninit();
@@ -10,3 +12,52 @@ pub fn cpu_time() -> std::time::Duration {
         std::time::Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32)
     }
 }
+
+/// Flush stdout and stderr.
+extern "C" fn flush_output() {
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+}
+
+/// Make sure buffered output is written out when the process exits. Besides normal exit, this
+/// covers paths that do not run Rust's own cleanup, such as `libc::exit` and panics, which abort.
+pub fn install_exit_flush() {
+    unsafe { libc::atexit(flush_output) };
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        hook(info);
+        flush_output();
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHILD_ENV: &str = "R2VM_TEST_EXIT_FLUSH_CHILD";
+
+    /// Run by `test_exit_flush` in a child process; does nothing otherwise.
+    #[test]
+    fn exit_flush_child() {
+        if std::env::var_os(CHILD_ENV).is_none() {
+            return;
+        }
+        install_exit_flush();
+        // Bypass the test harness's output capturing, and leave the output in stdout's buffer by
+        // not terminating the line.
+        std::io::stdout().write_all(b"tail of output").unwrap();
+        unsafe { libc::exit(0) };
+    }
+
+    #[test]
+    fn test_exit_flush() {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&["--exact", "util::tests::exit_flush_child", "--test-threads=1"])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("tail of output"), "output lost: {:?}", stdout);
+    }
+}
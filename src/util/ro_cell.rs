@@ -1,35 +1,60 @@
 use std::cell::UnsafeCell;
 use std::mem::MaybeUninit;
 use std::ops::Deref;
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// A cell that is readonly.
 /// It is expected to remain readonly for most time. Some use cases include set-once global
 /// variables. Construction and mutation of RoCell are allowed in unsafe code, and the safety
 /// must be ensured by the caller.
-pub struct RoCell<T>(UnsafeCell<MaybeUninit<T>>);
+///
+/// In debug builds, whether the cell is initialised is tracked, and accessing an uninitialised
+/// cell panics instead of reading garbage.
+pub struct RoCell<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    #[cfg(debug_assertions)]
+    initialized: AtomicBool,
+}
 
 unsafe impl<T: Sync> Sync for RoCell<T> {}
 
 impl<T> Drop for RoCell<T> {
     fn drop(&mut self) {
-        unsafe { std::mem::replace(&mut *(self.0.get()), MaybeUninit::uninit()).assume_init() };
+        #[cfg(debug_assertions)]
+        {
+            if !*self.initialized.get_mut() {
+                return;
+            }
+        }
+        unsafe { std::mem::replace(&mut *(self.value.get()), MaybeUninit::uninit()).assume_init() };
     }
 }
 
 impl<T> RoCell<T> {
     pub const fn new(value: T) -> Self {
-        RoCell(UnsafeCell::new(MaybeUninit::new(value)))
+        RoCell {
+            value: UnsafeCell::new(MaybeUninit::new(value)),
+            #[cfg(debug_assertions)]
+            initialized: AtomicBool::new(true),
+        }
     }
 
     /// RoCell can be read in safe code. Therefore, we make its construction unsafe, therefore
     /// permitting uninit value. If `T` needs drop, the caller must ensure that RoCell is
     /// initialised or forgotten before it is dropped.
     pub const unsafe fn new_uninit() -> Self {
-        RoCell(UnsafeCell::new(MaybeUninit::uninit()))
+        RoCell {
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            #[cfg(debug_assertions)]
+            initialized: AtomicBool::new(false),
+        }
     }
 
     pub unsafe fn init(this: &Self, value: T) {
-        std::ptr::write((*this.0.get()).as_mut_ptr(), value);
+        std::ptr::write((*this.value.get()).as_mut_ptr(), value);
+        #[cfg(debug_assertions)]
+        this.initialized.store(true, Ordering::Release);
     }
 
     pub unsafe fn replace(this: &Self, value: T) -> T {
@@ -37,7 +62,18 @@ impl<T> RoCell<T> {
     }
 
     pub unsafe fn as_mut(this: &Self) -> &mut T {
-        &mut *(*this.0.get()).as_mut_ptr()
+        this.check_init();
+        &mut *(*this.value.get()).as_mut_ptr()
+    }
+
+    #[inline]
+    fn check_init(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if !self.initialized.load(Ordering::Acquire) {
+                panic!("RoCell<{}> accessed before initialisation", std::any::type_name::<T>());
+            }
+        }
     }
 }
 
@@ -46,7 +82,8 @@ impl<T> Deref for RoCell<T> {
 
     #[inline]
     fn deref(&self) -> &T {
-        unsafe { &*(*self.0.get()).as_ptr() }
+        self.check_init();
+        unsafe { &*(*self.value.get()).as_ptr() }
     }
 }
 
@@ -55,3 +92,25 @@ impl<T: std::fmt::Debug> std::fmt::Debug for RoCell<T> {
         std::fmt::Debug::fmt(self.deref(), f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "accessed before initialisation")]
+    fn test_uninit_access() {
+        let cell: RoCell<u32> = unsafe { RoCell::new_uninit() };
+        let _ = *cell;
+    }
+
+    #[test]
+    fn test_init_access() {
+        let cell: RoCell<u32> = unsafe { RoCell::new_uninit() };
+        unsafe { RoCell::init(&cell, 42) };
+        assert_eq!(*cell, 42);
+        unsafe { RoCell::replace(&cell, 1) };
+        assert_eq!(*cell, 1);
+    }
+}
@@ -0,0 +1,157 @@
+//! Hand-assembled RISC-V programs, and helpers to run them with the emulator.
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Output};
+
+/// Encoders for the instructions used by test programs.
+pub mod asm {
+    pub const SP: u8 = 2;
+    pub const A0: u8 = 10;
+    pub const A1: u8 = 11;
+    pub const A2: u8 = 12;
+    pub const A3: u8 = 13;
+    pub const A4: u8 = 14;
+    pub const A7: u8 = 17;
+
+    fn i_type(opcode: u32, funct3: u32, rd: u8, rs1: u8, imm: i32) -> u32 {
+        (imm as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode
+    }
+
+    pub fn addi(rd: u8, rs1: u8, imm: i32) -> u32 {
+        i_type(0x13, 0, rd, rs1, imm)
+    }
+
+    pub fn li(rd: u8, imm: i32) -> u32 {
+        addi(rd, 0, imm)
+    }
+
+    pub fn ld(rd: u8, rs1: u8, imm: i32) -> u32 {
+        i_type(0x03, 3, rd, rs1, imm)
+    }
+
+    pub fn nop() -> u32 {
+        addi(0, 0, 0)
+    }
+
+    /// `csrrs rd, csr, zero`
+    pub fn csrr(rd: u8, csr: u16) -> u32 {
+        i_type(0x73, 2, rd, 0, csr as i32)
+    }
+
+    pub fn ecall() -> u32 {
+        0x73
+    }
+
+    pub fn auipc(rd: u8, imm: i32) -> u32 {
+        (imm as u32) << 12 | (rd as u32) << 7 | 0x17
+    }
+
+    pub fn jal(rd: u8, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (imm >> 20 & 1) << 31
+            | (imm >> 1 & 0x3ff) << 21
+            | (imm >> 11 & 1) << 20
+            | (imm >> 12 & 0xff) << 12
+            | (rd as u32) << 7
+            | 0x6f
+    }
+
+    pub fn bne(rs1: u8, rs2: u8, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (imm >> 12 & 1) << 31
+            | (imm >> 5 & 0x3f) << 25
+            | (rs2 as u32) << 20
+            | (rs1 as u32) << 15
+            | 1 << 12
+            | (imm >> 1 & 0xf) << 8
+            | (imm >> 11 & 1) << 7
+            | 0x63
+    }
+
+    /// `exit(code)`
+    pub fn exit(code: i32) -> Vec<u32> {
+        vec![li(A0, code), li(A7, 93), ecall()]
+    }
+}
+
+/// Offset of the code within the image, both in the file and in memory.
+const CODE_OFFSET: usize = 0x1000;
+
+/// Build a RISC-V ELF executable whose entry point is the start of `code`, with a symbol table
+/// defining `symbols` at the given instruction indices. Position-independent executables are
+/// linked at 0, others at 0x10000.
+pub fn elf(code: &[u32], symbols: &[(&str, usize)], pie: bool) -> Vec<u8> {
+    fn push(out: &mut Vec<u8>, fields: &[(u64, usize)]) {
+        for &(value, size) in fields {
+            out.extend_from_slice(&value.to_le_bytes()[..size]);
+        }
+    }
+
+    let base = if pie { 0 } else { 0x10000 };
+    let code_end = CODE_OFFSET + code.len() * 4;
+
+    // String and symbol tables follow the code, and section headers follow them.
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 24];
+    for &(name, index) in symbols {
+        let st_name = strtab.len() as u64;
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+        // A global function defined in section 1.
+        let value = (base + CODE_OFFSET + index * 4) as u64;
+        push(&mut symtab, &[(st_name, 4), (0x12, 1), (0, 1), (1, 2), (value, 8), (0, 8)]);
+    }
+    let symtab_offset = code_end;
+    let strtab_offset = symtab_offset + symtab.len();
+    let shdr_offset = (strtab_offset + strtab.len() + 7) & !7;
+
+    let mut out = Vec::new();
+    // ELF header: 64-bit, little endian, RISC-V, one program header and three section headers.
+    out.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    let e_type = if pie { 3 } else { 2 };
+    let entry = (base + CODE_OFFSET) as u64;
+    push(&mut out, &[(e_type, 2), (243, 2), (1, 4), (entry, 8), (64, 8)]);
+    push(&mut out, &[(shdr_offset as u64, 8), (0, 4), (64, 2), (56, 2), (1, 2), (64, 2), (3, 2)]);
+    push(&mut out, &[(0, 2)]);
+
+    // A single readable and executable PT_LOAD segment mapping the file up to the end of code.
+    let size = code_end as u64;
+    push(&mut out, &[(1, 4), (5, 4), (0, 8), (base as u64, 8), (base as u64, 8)]);
+    push(&mut out, &[(size, 8), (size, 8), (0x1000, 8)]);
+
+    out.resize(CODE_OFFSET, 0);
+    for &insn in code {
+        out.extend_from_slice(&insn.to_le_bytes());
+    }
+    out.extend_from_slice(&symtab);
+    out.extend_from_slice(&strtab);
+    out.resize(shdr_offset, 0);
+
+    // Section headers: null, .symtab and .strtab. Sections are unnamed.
+    out.resize(out.len() + 64, 0);
+    let (symtab_size, strtab_size) = (symtab.len() as u64, strtab.len() as u64);
+    push(&mut out, &[(0, 4), (2, 4), (0, 8), (0, 8), (symtab_offset as u64, 8), (symtab_size, 8)]);
+    push(&mut out, &[(2, 4), (1, 4), (8, 8), (24, 8)]);
+    push(&mut out, &[(0, 4), (3, 4), (0, 8), (0, 8), (strtab_offset as u64, 8), (strtab_size, 8)]);
+    push(&mut out, &[(0, 4), (0, 4), (1, 8), (0, 8)]);
+    out
+}
+
+/// Write a test program to a file unique to this test process.
+pub fn write_program(name: &str, elf: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("r2vm-test-{}-{}", std::process::id(), name));
+    std::fs::write(&path, elf).unwrap();
+    path
+}
+
+/// Run the emulator with the given arguments and wait for it to exit.
+pub fn r2vm<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(args: I) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_r2vm")).args(args).output().unwrap()
+}
+
+/// Start the emulator with the given arguments without waiting for it.
+pub fn spawn_r2vm<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(args: I) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_r2vm")).args(args).spawn().unwrap()
+}
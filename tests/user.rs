@@ -0,0 +1,196 @@
+//! Tests running hand-assembled programs with user-space emulation.
+
+mod common;
+
+use common::asm::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+#[test]
+fn test_exit_code() {
+    let program = common::write_program("exit", &common::elf(&exit(42), &[], false));
+    let output = common::r2vm(&[&program]);
+    assert_eq!(output.status.code(), Some(42), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_deterministic() {
+    let mut code = vec![
+        // gettimeofday(sp - 16, NULL), which depends on the host clock unless deterministic.
+        addi(A0, SP, -16),
+        li(A1, 0),
+        li(A7, 169),
+        ecall(),
+        ld(A3, SP, -16),
+        ld(A4, SP, -8),
+        // write(1, message, 14)
+        li(A0, 1),
+        auipc(A1, 0),
+        addi(A1, A1, 9 * 4),
+        li(A2, 14),
+        li(A7, 64),
+        ecall(),
+        // done:
+        nop(),
+    ];
+    code.extend(exit(0));
+    code.extend(
+        b"deterministic\n\0\0"
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])),
+    );
+    let program = common::elf(&code, &[("done", 12)], false);
+    let program = common::write_program("deterministic", &program);
+
+    // Stop at `done` so the hart state is dumped, and compare two runs.
+    let run = || {
+        common::r2vm(&["--deterministic".as_ref(), "--run-to=done".as_ref(), program.as_os_str()])
+    };
+    let (first, second) = (run(), run());
+    let stderr = String::from_utf8_lossy(&first.stderr);
+    assert!(first.status.success(), "{}", stderr);
+    assert!(stderr.contains("reached breakpoint"), "{}", stderr);
+    assert_eq!(first.stdout, b"deterministic\n");
+    assert_eq!(first.stdout, second.stdout);
+
+    // The statistics printed on exit include the CPU time used by the host, which is the only
+    // output expected to differ.
+    let state = |output: &std::process::Output| {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        stderr.lines().filter(|line| !line.starts_with("CPU TIME")).collect::<Vec<_>>().join("\n")
+    };
+    assert_eq!(state(&first), state(&second));
+}
+
+#[test]
+fn test_run_to_pie() {
+    let mut code = vec![
+        li(A0, 1),
+        // target:
+        li(A0, 2),
+    ];
+    code.extend(exit(3));
+    let program = common::write_program("run-to", &common::elf(&code, &[("target", 1)], true));
+
+    // The program is relocated, so the breakpoint must be too.
+    let output = common::r2vm(&["--run-to=target".as_ref(), program.as_os_str()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("reached breakpoint"), "{}", stderr);
+    // The relocated address is described by the symbol it was linked at.
+    assert!(stderr.contains("<target>"), "{}", stderr);
+    assert!(stderr.contains("a0  =                1"), "{}", stderr);
+}
+
+#[test]
+fn test_dump_cfg() {
+    let mut code = vec![
+        li(A0, 10),
+        // 11004: addi a0, a0, -1; bnez a0, 11004
+        addi(A0, A0, -1),
+        bne(A0, 0, -4),
+    ];
+    code.extend(exit(0));
+    let program = common::write_program("cfg", &common::elf(&code, &[], false));
+
+    let dot = program.with_extension("dot");
+    let output =
+        common::r2vm(&[format!("--dump-cfg={}", dot.display()).as_ref(), program.as_os_str()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let dot = std::fs::read_to_string(dot).unwrap();
+    assert!(dot.contains("b11000 -> b11004 [style=solid];"), "{}", dot);
+    assert!(dot.contains("b11000 -> b1100c [style=dashed];"), "{}", dot);
+    // The back edge of the loop
+    assert!(dot.contains("b11004 -> b11004 [style=solid];"), "{}", dot);
+}
+
+#[test]
+fn test_trace() {
+    let mut code = vec![
+        li(A0, 2),
+        // 11004: addi a0, a0, -1; bnez a0, 11004
+        addi(A0, A0, -1),
+        bne(A0, 0, -4),
+    ];
+    code.extend(exit(7));
+    let program = common::write_program("trace", &common::elf(&code, &[], false));
+
+    let trace = program.with_extension("trace");
+    let output =
+        common::r2vm(&[format!("--trace={}", trace.display()).as_ref(), program.as_os_str()]);
+    assert_eq!(output.status.code(), Some(7), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Records are the PC, instruction bits, hart, and the GPR written with its value.
+    let trace = std::fs::read(trace).unwrap();
+    let records: Vec<_> = trace
+        .chunks_exact(24)
+        .map(|record| {
+            let u64_at = |offset: usize| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&record[offset..offset + 8]);
+                u64::from_le_bytes(bytes)
+            };
+            let bits = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+            (u64_at(0), bits, record[12], record[13], u64_at(16))
+        })
+        .collect();
+    assert_eq!(trace.len(), records.len() * 24);
+    let expected = [
+        (0x11000, code[0], 0, A0, 2),
+        (0x11004, code[1], 0, A0, 1),
+        (0x11008, code[2], 0, 0, 0),
+        (0x11004, code[1], 0, A0, 0),
+        (0x11008, code[2], 0, 0, 0),
+        (0x1100c, code[3], 0, A0, 7),
+        (0x11010, code[4], 0, A7, 93),
+    ];
+    assert_eq!(records[..7], expected);
+    // The exit call is recorded last, though it never returns.
+    assert_eq!(records.len(), 8);
+    assert_eq!((records[7].0, records[7].1), (0x11014, code[5]));
+}
+
+/// Send a packet of the GDB remote protocol and wait for the reply.
+fn gdb_packet(stream: &mut TcpStream, data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    write!(stream, "${}#{:02x}", data, checksum).unwrap();
+    let mut bytes = stream.bytes().map(|byte| byte.unwrap());
+    // Skip the acknowledgement.
+    while bytes.next().expect("connection closed") != b'$' {}
+    let reply: Vec<_> = bytes.by_ref().take_while(|&byte| byte != b'#').collect();
+    bytes.nth(1).unwrap();
+    stream.write_all(b"+").unwrap();
+    String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn test_gdb_breakpoint_in_loop() {
+    let mut code = vec![
+        li(A0, 3),
+        // 11004: addi a0, a0, -1; bnez a0, 11004
+        addi(A0, A0, -1),
+        bne(A0, 0, -4),
+    ];
+    code.extend(exit(0));
+    let program = common::write_program("gdb", &common::elf(&code, &[], false));
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut child = common::spawn_r2vm(&[format!("--gdb={}", port).as_ref(), program.as_os_str()]);
+    let mut stream = (0..1000)
+        .find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            TcpStream::connect(("127.0.0.1", port)).ok()
+        })
+        .unwrap();
+
+    // The breakpoint is hit on every iteration, before the decrement.
+    assert_eq!(gdb_packet(&mut stream, "Z0,11004,4"), "OK");
+    for &a0 in &[3u64, 2, 1] {
+        assert_eq!(gdb_packet(&mut stream, "c"), "S05");
+        let registers = gdb_packet(&mut stream, "g");
+        assert_eq!(&registers[10 * 16..11 * 16], format!("{:016x}", a0.swap_bytes()));
+        assert_eq!(&registers[32 * 16..], "0410010000000000");
+    }
+    assert_eq!(gdb_packet(&mut stream, "D"), "OK");
+    assert!(child.wait().unwrap().success());
+}